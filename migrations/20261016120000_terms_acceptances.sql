@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS terms_acceptances (
+  pubkey char(64) not null,
+  terms_hash char(64) not null,
+  accepted_at integer not null,
+  primary key (pubkey, terms_hash)
+);
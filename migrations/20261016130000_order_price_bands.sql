@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS order_price_bands (
+  order_id char(36) primary key not null,
+  min_price real not null,
+  max_price real not null
+);
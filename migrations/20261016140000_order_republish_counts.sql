@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS order_republish_counts (
+  order_id char(36) primary key not null,
+  republish_count integer not null default 0
+);
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS order_creation_events (
+  event_id char(64) primary key not null,
+  order_id char(36) not null
+);
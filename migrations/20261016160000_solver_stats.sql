@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS solver_stats (
+  solver_pubkey char(64) primary key not null,
+  disputes_taken integer not null default 0,
+  disputes_resolved integer not null default 0,
+  total_resolution_seconds integer not null default 0,
+  buyer_favor integer not null default 0,
+  seller_favor integer not null default 0,
+  sla_breaches integer not null default 0
+);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS order_reservations (
+  order_id char(36) primary key not null,
+  taker_pubkey char(64) not null,
+  expires_at integer not null
+);
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS pubkey_first_seen (
+  pubkey char(64) primary key not null,
+  first_seen integer not null
+);
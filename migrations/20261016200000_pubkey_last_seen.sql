@@ -0,0 +1 @@
+ALTER TABLE pubkey_first_seen ADD COLUMN last_seen integer not null default 0;
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS disabled_fiat_codes (
+  fiat_code char(3) primary key not null,
+  disabled_at integer not null
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS split_settlement_legs (
+  order_id char(36) not null,
+  party varchar(6) not null,
+  payment_request text not null,
+  amount integer not null,
+  status varchar(10) not null default 'held',
+  attempts integer not null default 0,
+  primary key (order_id, party)
+);
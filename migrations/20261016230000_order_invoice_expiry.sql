@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS order_invoice_expiry (
+  order_id char(36) primary key not null,
+  expiry_secs integer not null
+);
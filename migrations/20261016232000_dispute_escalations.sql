@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS dispute_escalations (
+  dispute_id char(36) primary key not null,
+  escalated_at integer not null
+);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS user_trade_stats (
+  pubkey char(64) primary key not null,
+  completed_trades integer not null default 0,
+  volume_sats integer not null default 0
+);
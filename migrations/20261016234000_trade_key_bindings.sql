@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS trade_key_bindings (
+  trade_pubkey char(64) primary key not null,
+  identity_pubkey char(64) not null,
+  trade_index integer not null,
+  created_at integer not null
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_trade_key_bindings_identity_index
+  ON trade_key_bindings (identity_pubkey, trade_index);
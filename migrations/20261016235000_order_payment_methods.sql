@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS order_payment_methods (
+  order_id char(36) not null,
+  method varchar(16) not null,
+  primary key (order_id, method)
+);
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS order_min_taker_ratings (
+  order_id char(36) primary key not null,
+  min_rating real not null
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS order_events (
+  id integer primary key autoincrement,
+  order_id char(36) not null,
+  event_id char(64) not null,
+  status varchar(50) not null,
+  created_at integer not null
+);
+
+CREATE INDEX IF NOT EXISTS idx_order_events_order_id ON order_events (order_id);
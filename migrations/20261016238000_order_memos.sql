@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS order_memos (
+  order_id char(36) primary key not null,
+  memo text not null
+);
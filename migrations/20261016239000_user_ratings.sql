@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS user_ratings (
+  id integer primary key autoincrement,
+  pubkey char(64) not null,
+  rating integer not null,
+  created_at integer not null
+);
+CREATE INDEX IF NOT EXISTS user_ratings_pubkey ON user_ratings (pubkey);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS dispute_penalties (
+  dispute_id char(36) primary key not null,
+  pubkey char(64) not null,
+  penalty real not null,
+  reason text not null,
+  created_at integer not null
+);
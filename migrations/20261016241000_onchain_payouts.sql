@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS onchain_payouts (
+  order_id char(36) primary key not null,
+  address text not null,
+  amount integer not null,
+  txid char(64),
+  created_at integer not null
+);
@@ -0,0 +1 @@
+ALTER TABLE split_settlement_legs ADD COLUMN payment_hash char(64);
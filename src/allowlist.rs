@@ -0,0 +1,103 @@
+//! Allowlist of a private instance.
+//!
+//! Operators can restrict their Mostro to known pubkeys with the `allowlist`
+//! setting, messages of other pubkeys are refused before being handled. The
+//! list is checked against the identity key of the sender, users in full
+//! privacy mode have to list their trade keys. Admin actions have their own
+//! checks and are let through. The list is replaced on a settings reload, so
+//! pubkeys can be added or removed without a restart.
+
+use mostro_core::prelude::*;
+use nostr_sdk::PublicKey;
+use std::collections::HashSet;
+use std::sync::{LazyLock, RwLock};
+use tracing::{info, warn};
+
+/// Allowed pubkeys, empty when the instance is public
+static ALLOWLIST: LazyLock<RwLock<HashSet<PublicKey>>> = LazyLock::new(RwLock::default);
+
+/// Replace the allowed pubkeys, returns how many are allowed. Pubkeys that
+/// don't parse are skipped
+pub fn set_allowlist(pubkeys: &[String]) -> usize {
+    let allowlist: HashSet<PublicKey> = pubkeys
+        .iter()
+        .filter_map(|pubkey| match PublicKey::parse(pubkey) {
+            Ok(pubkey) => Some(pubkey),
+            Err(_) => {
+                warn!("Ignoring invalid allowlist pubkey {pubkey}");
+                None
+            }
+        })
+        .collect();
+    let allowed = allowlist.len();
+    match ALLOWLIST.write() {
+        Ok(mut current) => *current = allowlist,
+        Err(poisoned) => *poisoned.into_inner() = allowlist,
+    }
+    allowed
+}
+
+fn is_admin_action(action: &Action) -> bool {
+    matches!(
+        action,
+        Action::AdminCancel
+            | Action::AdminSettle
+            | Action::AdminAddSolver
+            | Action::AdminTakeDispute
+    )
+}
+
+fn check_allowed_with(
+    allowlist: &HashSet<PublicKey>,
+    action: &Action,
+    sender: &PublicKey,
+) -> Result<(), MostroError> {
+    if allowlist.is_empty() || is_admin_action(action) || allowlist.contains(sender) {
+        return Ok(());
+    }
+    info!("{sender} is not in the allowlist, {action} refused");
+    Err(MostroCantDo(CantDoReason::NotAllowedByStatus))
+}
+
+/// Checks `sender` can use this instance for `action`
+pub fn check_allowlisted(action: &Action, sender: &PublicKey) -> Result<(), MostroError> {
+    match ALLOWLIST.read() {
+        Ok(allowlist) => check_allowed_with(&allowlist, action, sender),
+        Err(poisoned) => check_allowed_with(&poisoned.into_inner(), action, sender),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::Keys;
+
+    #[test]
+    fn test_allowlist() {
+        let allowed = Keys::generate().public_key();
+        let stranger = Keys::generate().public_key();
+        let allowlist = HashSet::from([allowed]);
+
+        assert!(check_allowed_with(&allowlist, &Action::NewOrder, &allowed).is_ok());
+        assert!(check_allowed_with(&allowlist, &Action::TakeSell, &allowed).is_ok());
+        assert!(matches!(
+            check_allowed_with(&allowlist, &Action::NewOrder, &stranger),
+            Err(MostroCantDo(CantDoReason::NotAllowedByStatus))
+        ));
+        assert!(check_allowed_with(&allowlist, &Action::TakeBuy, &stranger).is_err());
+        // Solvers have their own checks
+        assert!(check_allowed_with(&allowlist, &Action::AdminTakeDispute, &stranger).is_ok());
+        // Public instance
+        assert!(check_allowed_with(&HashSet::new(), &Action::NewOrder, &stranger).is_ok());
+
+        // Replacing the list applies right away
+        assert_eq!(
+            set_allowlist(&[allowed.to_hex(), "not a pubkey".to_string()]),
+            1
+        );
+        assert!(check_allowlisted(&Action::NewOrder, &allowed).is_ok());
+        assert!(check_allowlisted(&Action::NewOrder, &stranger).is_err());
+        assert_eq!(set_allowlist(&[]), 0);
+        assert!(check_allowlisted(&Action::NewOrder, &stranger).is_ok());
+    }
+}
@@ -0,0 +1,197 @@
+//! Order creation and take rates over rolling windows.
+//!
+//! Each order created or taken is recorded here, when the number of events in
+//! the last `rate_alert_window_seconds` goes over `rate_alert_baseline` times
+//! `rate_alert_threshold` an alert is logged and sent to the configured webhook.
+
+use crate::config::settings::Settings;
+use crate::lnurl::HTTP_CLIENT;
+use nostr_sdk::{PublicKey, Timestamp};
+use once_cell::sync::Lazy;
+use serde_json::json;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use tracing::{error, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RateEvent {
+    OrderCreated,
+    OrderTaken,
+}
+
+impl std::fmt::Display for RateEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RateEvent::OrderCreated => write!(f, "order-created"),
+            RateEvent::OrderTaken => write!(f, "order-taken"),
+        }
+    }
+}
+
+/// Rate anomaly found in a window
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateAlert {
+    pub event: RateEvent,
+    pub count: usize,
+    pub distinct_pubkeys: usize,
+    pub baseline: u32,
+    pub window_seconds: u32,
+}
+
+/// Events of one kind inside a rolling window
+#[derive(Debug, Default)]
+struct RateWindow {
+    events: VecDeque<(i64, String)>,
+    last_alert: Option<i64>,
+}
+
+impl RateWindow {
+    /// Add an event and return the alert to raise, if any. Only one alert is
+    /// raised per window to avoid flooding operators.
+    fn record(
+        &mut self,
+        event: RateEvent,
+        pubkey: String,
+        now: i64,
+        window_seconds: u32,
+        baseline: u32,
+        threshold: f64,
+    ) -> Option<RateAlert> {
+        let window_start = now - window_seconds as i64;
+        while matches!(self.events.front(), Some((at, _)) if *at <= window_start) {
+            self.events.pop_front();
+        }
+        self.events.push_back((now, pubkey));
+
+        let count = self.events.len();
+        if !is_anomalous(count, baseline, threshold) {
+            return None;
+        }
+        if matches!(self.last_alert, Some(at) if at > window_start) {
+            return None;
+        }
+        self.last_alert = Some(now);
+        let distinct_pubkeys = self
+            .events
+            .iter()
+            .map(|(_, pubkey)| pubkey)
+            .collect::<HashSet<_>>()
+            .len();
+
+        Some(RateAlert {
+            event,
+            count,
+            distinct_pubkeys,
+            baseline,
+            window_seconds,
+        })
+    }
+}
+
+/// Tells if `count` events deviate from the baseline more than allowed
+fn is_anomalous(count: usize, baseline: u32, threshold: f64) -> bool {
+    baseline > 0 && count as f64 > baseline as f64 * threshold
+}
+
+static RATE_WINDOWS: Lazy<Mutex<HashMap<RateEvent, RateWindow>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Record an order event, alerting operators when the rate is anomalous
+pub fn record_rate_event(event: RateEvent, pubkey: &PublicKey) {
+    let mostro_settings = Settings::get_mostro();
+    if mostro_settings.rate_alert_window_seconds == 0 {
+        return;
+    }
+
+    let alert = match RATE_WINDOWS.lock() {
+        Ok(mut windows) => windows.entry(event).or_default().record(
+            event,
+            pubkey.to_string(),
+            Timestamp::now().as_u64() as i64,
+            mostro_settings.rate_alert_window_seconds,
+            mostro_settings.rate_alert_baseline,
+            mostro_settings.rate_alert_threshold,
+        ),
+        Err(e) => return error!("Rate windows lock poisoned: {e}"),
+    };
+
+    if let Some(alert) = alert {
+        send_rate_alert(alert, mostro_settings.rate_alert_webhook_url.clone());
+    }
+}
+
+fn send_rate_alert(alert: RateAlert, webhook_url: String) {
+    warn!(
+        "Rate anomaly: {} {} events from {} pubkeys in the last {} seconds, baseline is {}",
+        alert.count, alert.event, alert.distinct_pubkeys, alert.window_seconds, alert.baseline
+    );
+    if webhook_url.is_empty() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let body = json!({
+            "event": alert.event.to_string(),
+            "count": alert.count,
+            "distinct_pubkeys": alert.distinct_pubkeys,
+            "baseline": alert.baseline,
+            "window_seconds": alert.window_seconds,
+        });
+        if let Err(e) = HTTP_CLIENT.post(&webhook_url).json(&body).send().await {
+            error!("Error sending rate alert to webhook: {e}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_anomalous() {
+        assert!(!is_anomalous(10, 5, 2.0));
+        assert!(is_anomalous(11, 5, 2.0));
+        // No baseline configured
+        assert!(!is_anomalous(1000, 0, 2.0));
+    }
+
+    #[test]
+    fn test_spike_triggers_alert() {
+        let mut window = RateWindow::default();
+        let now = 1_700_000_000;
+
+        // Normal traffic, one order per minute
+        for i in 0..20 {
+            let alert = window.record(
+                RateEvent::OrderCreated,
+                "regular".to_string(),
+                now + i * 60,
+                600,
+                10,
+                2.0,
+            );
+            assert!(alert.is_none());
+        }
+
+        // A flood of orders from new pubkeys in a few seconds
+        let spike = now + 20 * 60;
+        let alerts: Vec<RateAlert> = (0..20)
+            .filter_map(|i| {
+                window.record(
+                    RateEvent::OrderCreated,
+                    format!("new-{i}"),
+                    spike + i,
+                    600,
+                    10,
+                    2.0,
+                )
+            })
+            .collect();
+
+        // Only one alert is raised for the window
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].event, RateEvent::OrderCreated);
+        assert_eq!(alerts[0].count, 21);
+        assert!(alerts[0].distinct_pubkeys > 10);
+    }
+}
@@ -2,17 +2,25 @@
 //! Handles message routing, action processing, and event loop management.
 
 // Submodules for different trading actions
+pub mod accept_terms; // Terms of service acceptance
 pub mod add_invoice; // Handles invoice creation
 pub mod admin_add_solver; // Admin functionality to add dispute solvers
+pub mod admin_announce; // Admin announcements to every user
 pub mod admin_cancel; // Admin order cancellation
+pub mod admin_reassign_dispute; // Admin handoff of a dispute to another solver
+pub mod admin_republish; // Admin republish of order events
 pub mod admin_settle; // Admin dispute settlement
+pub mod admin_settle_split; // Admin dispute settlement split between parties
 pub mod admin_take_dispute; // Admin dispute handling
 pub mod cancel; // User order cancellation
 pub mod dispute; // User dispute handling
 pub mod fiat_sent; // Fiat payment confirmation
 pub mod order; // Order creation and management
+pub mod pay_invoice; // Payment of the buyer invoice on request
 pub mod rate_user; // User reputation system
 pub mod release; // Release of held funds
+pub mod reputation_query; // Rate limited reputation queries
+pub mod reserve_order; // Short order reservation before taking
 pub mod take_buy; // Taking buy orders
 pub mod take_sell; // Taking sell orders
 pub mod trade_pubkey; // Trade pubkey action
@@ -27,18 +35,28 @@ use crate::app::cancel::cancel_action;
 use crate::app::dispute::dispute_action;
 use crate::app::fiat_sent::fiat_sent_action;
 use crate::app::order::order_action;
+use crate::app::pay_invoice::pay_invoice_action;
 use crate::app::rate_user::update_user_reputation_action;
 use crate::app::release::release_action;
+use crate::app::reputation_query::{is_reputation_query, reputation_query_action};
+use crate::app::reserve_order::{is_reservation, reserve_order_action};
 use crate::app::take_buy::take_buy_action;
 use crate::app::take_sell::take_sell_action;
 use crate::app::trade_pubkey::trade_pubkey_action;
+use crate::config::reload::hot_settings;
 use crate::config::settings::get_db_pool;
 // Core functionality imports
 use crate::config::settings::Settings;
 use crate::db::add_new_user;
 use crate::db::is_user_present;
-use crate::lightning::LndConnector;
+use crate::db::{find_first_seen, record_seen};
+use crate::lightning::BackendPool;
+use crate::logging::message_span;
+use crate::rate_limiter::{RATE_LIMITER, REPUTATION_LIMITER};
+use crate::relay_monitor::{monitor_relays, RelayMonitor};
+use crate::seen_events::SeenEvents;
 use crate::util::enqueue_cant_do_msg;
+use crate::worker_pool::WorkerPool;
 
 // External dependencies
 use mostro_core::error::CantDoReason;
@@ -46,8 +64,40 @@ use mostro_core::error::MostroError;
 use mostro_core::error::ServiceError;
 use mostro_core::message::{Action, Message};
 use mostro_core::user::User;
+use nostr_sdk::nips::nip13;
 use nostr_sdk::prelude::*;
 use sqlx::{Pool, Sqlite};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::watch;
+use tracing::Instrument;
+
+/// Gift wraps remembered to discard copies delivered by several relays
+const SEEN_EVENTS_CAPACITY: usize = 10_000;
+/// Seconds a gift wrap id is remembered
+const SEEN_EVENTS_TTL_SECS: u64 = 600;
+
+/// Number of events discarded for not meeting the proof of work difficulty
+pub static POW_REJECTED_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// Checks the event meets the configured proof of work difficulty. Gift wraps
+/// can't be answered before unwrapping them, so rejected events are only
+/// counted and logged.
+fn check_event_pow(event: &Event, difficulty: u8) -> bool {
+    if event.check_pow(difficulty) {
+        return true;
+    }
+    let rejected = POW_REJECTED_EVENTS.fetch_add(1, Ordering::Relaxed) + 1;
+    tracing::debug!(
+        "Event {} rejected: proof of work {} below required difficulty {} ({} rejected so far)",
+        event.id,
+        nip13::get_leading_zero_bits(event.id.as_bytes()),
+        difficulty,
+        rejected
+    );
+    false
+}
 
 /// Helper function to log warning messages for action errors
 fn warning_msg(action: &Action, err: ServiceError) {
@@ -94,6 +144,67 @@ async fn manage_errors(
     }
 }
 
+/// Trade indexes of an identity only go up and each trade key is used with
+/// a single index, replayed or reused ones are rejected
+async fn check_trade_key_binding(
+    pool: &Pool<Sqlite>,
+    event: &UnwrappedGift,
+    index: i64,
+) -> Result<(), MostroError> {
+    let last_seen = crate::db::last_seen_trade_index(pool, &event.sender.to_string()).await?;
+    if last_seen.is_some_and(|last| index <= last)
+        || crate::db::is_trade_key_bound(pool, &event.rumor.pubkey.to_string()).await?
+    {
+        return Err(MostroError::MostroCantDo(CantDoReason::InvalidTradeIndex));
+    }
+    Ok(())
+}
+
+/// Bind the trade key to the identity and trade index it was used with. Done
+/// once the message was handled, so forged or refused messages don't use up
+/// the index of the user. Another message binding them meanwhile is an error.
+async fn bind_trade_index(
+    pool: &Pool<Sqlite>,
+    event: &UnwrappedGift,
+    index: i64,
+) -> Result<(), MostroError> {
+    if !crate::db::add_trade_key_binding(
+        pool,
+        &event.sender.to_string(),
+        index,
+        &event.rumor.pubkey.to_string(),
+        Timestamp::now().as_u64() as i64,
+    )
+    .await?
+    {
+        return Err(MostroError::MostroCantDo(CantDoReason::InvalidTradeIndex));
+    }
+    Ok(())
+}
+
+/// Bind the trade key of a trading message handled successfully, users in full
+/// privacy mode trade with their identity key and are not bound
+async fn bind_handled_trade_key(pool: &Pool<Sqlite>, event: &UnwrappedGift, msg: &Message) {
+    let message_kind = msg.get_inner_message_kind();
+    if !matches!(
+        message_kind.action,
+        Action::NewOrder | Action::TakeBuy | Action::TakeSell
+    ) || event.sender == event.rumor.pubkey
+    {
+        return;
+    }
+    if let index @ 1.. = message_kind.trade_index() {
+        if let Err(e) = bind_trade_index(pool, event, index).await {
+            tracing::warn!(
+                "Trade index {} of {} bound by another message: {}",
+                index,
+                event.sender,
+                e
+            );
+        }
+    }
+}
+
 /// Function to check if a user is present in the database and update or create their trade index.
 ///
 /// This function performs the following tasks:
@@ -153,7 +264,7 @@ async fn check_trade_index(
                     .await;
                     return Err(MostroError::MostroCantDo(CantDoReason::InvalidTradeIndex));
                 }
-                let msg = match msg.as_json() {
+                let msg_json = match msg.as_json() {
                     Ok(m) => m,
                     Err(e) => {
                         tracing::error!(
@@ -165,10 +276,24 @@ async fn check_trade_index(
                         ));
                     }
                 };
-                if !Message::verify_signature(msg, event.rumor.pubkey, sig) {
+                if !Message::verify_signature(msg_json, event.rumor.pubkey, sig) {
                     tracing::info!("Invalid signature");
                     return Err(MostroError::MostroCantDo(CantDoReason::InvalidSignature));
                 }
+                // Only signed messages are checked against the bindings
+                if let Err(e) = check_trade_key_binding(pool, event, index).await {
+                    if matches!(e, MostroError::MostroCantDo(_)) {
+                        tracing::info!("Trade index {} replayed by {}", index, event.sender);
+                        manage_errors(
+                            MostroError::MostroCantDo(CantDoReason::InvalidTradeIndex),
+                            msg.clone(),
+                            event.clone(),
+                            &message_kind.action,
+                        )
+                        .await;
+                    }
+                    return Err(e);
+                }
             }
             Ok(())
         }
@@ -182,6 +307,9 @@ async fn check_trade_index(
                     tracing::error!("Error creating new user: {}", e);
                     return Err(MostroError::MostroCantDo(CantDoReason::CantCreateUser));
                 }
+                if let index @ 1.. = message_kind.trade_index() {
+                    check_trade_key_binding(pool, event, index).await?;
+                }
             }
             Ok(())
         }
@@ -197,20 +325,29 @@ async fn check_trade_index(
 /// * `event` - The unwrapped gift wrap event
 /// * `my_keys` - Node keypair for signing/verification
 /// * `pool` - Database connection pool
-/// * `ln_client` - Lightning network connector
+/// * `ln_client` - Lightning connectors, the handler takes one for itself
 async fn handle_message_action(
     action: &Action,
     msg: Message,
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-    ln_client: &mut LndConnector,
+    ln_client: &BackendPool,
 ) -> Result<()> {
+    crate::metrics::record_action(action);
+    // Private instances only serve the pubkeys of their allowlist
+    crate::allowlist::check_allowlisted(action, &event.sender)?;
     match action {
         // Order-related actions
         Action::NewOrder => order_action(msg, event, my_keys, pool)
             .await
             .map_err(|e| e.into()),
+        // Takes can ask for a reservation of the order first
+        Action::TakeSell | Action::TakeBuy if is_reservation(event) => {
+            reserve_order_action(msg, event, my_keys, pool)
+                .await
+                .map_err(|e| e.into())
+        }
         Action::TakeSell => take_sell_action(msg, event, my_keys, pool)
             .await
             .map_err(|e| e.into()),
@@ -222,32 +359,44 @@ async fn handle_message_action(
         Action::FiatSent => fiat_sent_action(msg, event, my_keys, pool)
             .await
             .map_err(|e| e.into()),
-        Action::Release => release_action(msg, event, my_keys, pool, ln_client)
+        Action::Release => {
+            release_action(msg, event, my_keys, pool, ln_client.get().await.as_mut())
+                .await
+                .map_err(|e| e.into())
+        }
+        Action::AddInvoice => add_invoice_action(msg, event, my_keys, pool)
             .await
             .map_err(|e| e.into()),
-        Action::AddInvoice => add_invoice_action(msg, event, my_keys, pool)
+        Action::PayInvoice => pay_invoice_action(msg, event, my_keys, pool)
             .await
             .map_err(|e| e.into()),
-        Action::PayInvoice => todo!(),
 
         // Dispute and rating actions
         Action::Dispute => dispute_action(msg, event, my_keys, pool)
             .await
             .map_err(|e| e.into()),
+        // Ratings can also be a query of the reputation of a user
+        Action::RateUser if is_reputation_query(event) => reputation_query_action(msg, event, pool)
+            .await
+            .map_err(|e| e.into()),
         Action::RateUser => update_user_reputation_action(msg, event, my_keys, pool)
             .await
             .map_err(|e| e.into()),
-        Action::Cancel => cancel_action(msg, event, my_keys, pool, ln_client)
+        Action::Cancel => cancel_action(msg, event, my_keys, pool, ln_client.get().await.as_mut())
             .await
             .map_err(|e| e.into()),
 
         // Admin actions
-        Action::AdminCancel => admin_cancel_action(msg, event, my_keys, pool, ln_client)
-            .await
-            .map_err(|e| e.into()),
-        Action::AdminSettle => admin_settle_action(msg, event, my_keys, pool, ln_client)
-            .await
-            .map_err(|e| e.into()),
+        Action::AdminCancel => {
+            admin_cancel_action(msg, event, my_keys, pool, ln_client.get().await.as_mut())
+                .await
+                .map_err(|e| e.into())
+        }
+        Action::AdminSettle => {
+            admin_settle_action(msg, event, my_keys, pool, ln_client.get().await.as_mut())
+                .await
+                .map_err(|e| e.into())
+        }
         Action::AdminAddSolver => admin_add_solver_action(msg, event, my_keys, pool)
             .await
             .map_err(|e| e.into()),
@@ -265,52 +414,185 @@ async fn handle_message_action(
     }
 }
 
+/// Difficulty required from a pubkey first seen `age_days` ago, the highest
+/// tier reached applies and pubkeys below every tier get the base difficulty
+fn effective_pow(base: u8, age_days: u64, tiers: &[(u64, u8)]) -> u8 {
+    tiers
+        .iter()
+        .filter(|(min_days, _)| age_days >= *min_days)
+        .max_by_key(|(min_days, _)| *min_days)
+        .map(|(_, difficulty)| *difficulty)
+        .unwrap_or(base)
+}
+
+/// Lowest difficulty any pubkey can be asked for. The sender is only known
+/// after unwrapping, so this is what gift wraps are checked against first.
+fn min_pow(base: u8, tiers: &[(u64, u8)]) -> u8 {
+    tiers
+        .iter()
+        .map(|(_, difficulty)| *difficulty)
+        .fold(base, u8::min)
+}
+
+/// Resolves once shutdown is signaled, a dropped sender counts as a signal
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    loop {
+        if *shutdown.borrow_and_update() {
+            return;
+        }
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Tells if a message created at `created_at` is recent enough to be processed,
+/// a message exactly `max_age` seconds old is still accepted
+fn is_message_fresh(created_at: u64, now: u64, max_age: u64) -> bool {
+    created_at.saturating_add(max_age) >= now
+}
+
 /// Main event loop that processes incoming Nostr events.
 /// Handles message verification, POW checking, and routes valid messages to appropriate handlers.
 ///
 /// # Arguments
 /// * `my_keys` - The node's keypair
 /// * `client` - Nostr client instance
-/// * `ln_client` - Lightning network connector
+/// * `ln_client` - Lightning connectors of the message workers
 /// * `pool` - SQLite connection pool
 /// * `rate_list` - Shared list of rating events
-pub async fn run(my_keys: Keys, client: &Client, ln_client: &mut LndConnector) -> Result<()> {
+/// * `shutdown` - Set to true to stop the loop once the message in progress is handled
+pub async fn run(
+    my_keys: Keys,
+    client: &Client,
+    ln_client: Arc<BackendPool>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    // Message budget of each pubkey, shared by every loop iteration
+    let mostro_settings = Settings::get_mostro();
+    if let Ok(mut limiter) = RATE_LIMITER.lock() {
+        limiter.configure(
+            mostro_settings.rate_limit_per_second,
+            mostro_settings.rate_limit_burst,
+        );
+    }
+    if let Ok(mut limiter) = REPUTATION_LIMITER.lock() {
+        limiter.configure(
+            mostro_settings.reputation_query_per_second,
+            mostro_settings.reputation_query_burst,
+        );
+    }
+    // Messages of different orders are handled concurrently
+    let workers = WorkerPool::new(mostro_settings.message_workers);
+    let mut seen_events = SeenEvents::new(
+        SEEN_EVENTS_CAPACITY,
+        std::time::Duration::from_secs(SEEN_EVENTS_TTL_SECS),
+    );
+    // Reconnect and subscribe again if every relay drops
+    tokio::spawn(monitor_relays(
+        client.clone(),
+        RelayMonitor::new(my_keys.public_key(), mostro_settings.max_message_age_secs),
+        shutdown.clone(),
+    ));
+
     loop {
         let mut notifications = client.notifications();
 
         // Arc clone of db pool for main loop
         let pool = get_db_pool();
-        // Get pow from config
-        let pow = Settings::get_mostro().pow;
-        while let Ok(notification) = notifications.recv().await {
+        // Established pubkeys can be asked for less work than new ones
+        let pow_tiers: &[(u64, u8)] = if mostro_settings.pow_by_account_age {
+            &mostro_settings.pow_account_age_tiers
+        } else {
+            &[]
+        };
+        loop {
+            let notification = tokio::select! {
+                notification = notifications.recv() => notification,
+                _ = shutdown_requested(&mut shutdown) => {
+                    tracing::info!("Shutdown requested, event loop stopped");
+                    workers.idle().await;
+                    return Ok(());
+                }
+            };
+            let Ok(notification) = notification else {
+                break;
+            };
             if let RelayPoolNotification::Event { event, .. } = notification {
-                // Verify proof of work
-                if !event.check_pow(pow) {
-                    // Discard events that don't meet POW requirements
-                    tracing::info!("Not POW verified event!");
+                // Pow from config, it can change on a settings reload
+                let pow = hot_settings().pow;
+                // Verify proof of work, discard events that don't meet POW requirements
+                if !check_event_pow(&event, min_pow(pow, pow_tiers)) {
                     continue;
                 }
                 if let Kind::GiftWrap = event.kind {
                     // Validate event signature
                     if event.verify().is_err() {
                         tracing::warn!("Error in event verification")
+                    } else if !seen_events.insert(event.id, Instant::now()) {
+                        // The same gift wrap can arrive from several relays,
+                        // only verified ids are remembered so they can't be forged
+                        tracing::debug!("Event {} already handled", event.id);
+                        continue;
                     };
 
-                    let event = match nip59::extract_rumor(&my_keys, &event).await {
+                    let wrap = &event;
+                    let event = match nip59::extract_rumor(&my_keys, wrap).await {
                         Ok(u) => u,
                         Err(_) => {
                             tracing::warn!("Error unwrapping gift");
                             continue;
                         }
                     };
-                    // Discard events older than 10 seconds to prevent replay attacks
-                    let since_time = chrono::Utc::now()
-                        .checked_sub_signed(chrono::Duration::seconds(10))
-                        .unwrap()
-                        .timestamp() as u64;
-                    if event.rumor.created_at.as_u64() < since_time {
+                    // Drop messages of pubkeys flooding the node
+                    let allowed = match RATE_LIMITER.lock() {
+                        Ok(mut limiter) => limiter.check(&event.rumor.pubkey, Instant::now()),
+                        Err(_) => true,
+                    };
+                    if !allowed {
+                        tracing::warn!("Rate limit exceeded by {}", event.rumor.pubkey);
                         continue;
                     }
+                    // Discard old events to prevent replay attacks
+                    if !is_message_fresh(
+                        event.rumor.created_at.as_u64(),
+                        Timestamp::now().as_u64(),
+                        Settings::get_mostro().max_message_age_secs,
+                    ) {
+                        continue;
+                    }
+                    let now = Timestamp::now().as_u64() as i64;
+                    // Check the work required for the sender account age
+                    if !pow_tiers.is_empty() {
+                        let required = match find_first_seen(&pool, &event.sender.to_string()).await
+                        {
+                            Ok(first_seen) => effective_pow(
+                                pow,
+                                (now - first_seen.unwrap_or(now)).max(0) as u64 / 86400,
+                                pow_tiers,
+                            ),
+                            Err(e) => {
+                                tracing::error!("Error reading seen pubkey: {}", e);
+                                pow
+                            }
+                        };
+                        if !check_event_pow(wrap, required) {
+                            continue;
+                        }
+                    }
+                    // Only messages passing the checks make a pubkey seen
+                    if !pow_tiers.is_empty() || mostro_settings.maker_liveness_window_secs > 0 {
+                        // Makers are seen by their trade key too, it is the order creator
+                        let mut seen = vec![event.sender];
+                        if event.rumor.pubkey != event.sender {
+                            seen.push(event.rumor.pubkey);
+                        }
+                        for pubkey in seen {
+                            if let Err(e) = record_seen(&pool, &pubkey.to_string(), now).await {
+                                tracing::error!("Error recording seen pubkey: {}", e);
+                            }
+                        }
+                    }
                     // Parse message and signature from rumor content put message in Message struct
                     let (message, sig) = match serde_json::from_str::<(Message, Option<Signature>)>(
                         &event.rumor.content,
@@ -359,30 +641,58 @@ pub async fn run(my_keys: Keys, client: &Client, ln_client: &mut LndConnector) -
 
                     if inner_message.verify() {
                         if let Some(action) = message.inner_action() {
-                            if let Err(e) = handle_message_action(
-                                &action,
-                                message.clone(),
-                                &event,
-                                &my_keys,
-                                &pool,
-                                ln_client,
-                            )
-                            .await
-                            {
-                                match e.downcast::<MostroError>() {
-                                    Ok(err) => {
-                                        manage_errors(*err, message, event, &action).await;
-                                    }
-                                    Err(e) => {
-                                        tracing::error!("Unexpected error type: {}", e);
-                                        warning_msg(
-                                            &action,
-                                            ServiceError::UnexpectedError(e.to_string()),
-                                        );
+                            // Messages of the same order are handled in arrival order
+                            let order_id = inner_message.id;
+                            let (my_keys, pool, ln_client) =
+                                (my_keys.clone(), pool.clone(), ln_client.clone());
+                            let span = message_span(&action, order_id, &event.sender);
+                            workers.dispatch(
+                                order_id,
+                                async move {
+                                    let started = Instant::now();
+                                    let result = handle_message_action(
+                                        &action,
+                                        message.clone(),
+                                        &event,
+                                        &my_keys,
+                                        &pool,
+                                        &ln_client,
+                                    )
+                                    .await;
+                                    crate::metrics::record_message_handled(started.elapsed());
+                                    // The boxed error isn't Send, keep only what is
+                                    // needed before awaiting
+                                    let error = result.err().map(|e| {
+                                        e.downcast::<MostroError>()
+                                            .map(|err| *err)
+                                            .map_err(|e| e.to_string())
+                                    });
+                                    match error {
+                                        Some(Ok(err)) => {
+                                            manage_errors(err, message, event, &action).await;
+                                        }
+                                        Some(Err(e)) => {
+                                            tracing::error!("Unexpected error type: {}", e);
+                                            warning_msg(&action, ServiceError::UnexpectedError(e));
+                                        }
+                                        None => {
+                                            bind_handled_trade_key(&pool, &event, &message).await
+                                        }
                                     }
                                 }
-                            }
+                                .instrument(span),
+                            );
                         }
+                    } else {
+                        // The sender can be answered once the gift wrap is open
+                        tracing::info!("Invalid message from {}", event.rumor.pubkey);
+                        enqueue_cant_do_msg(
+                            inner_message.request_id,
+                            inner_message.id,
+                            CantDoReason::InvalidParameters,
+                            event.rumor.pubkey,
+                        )
+                        .await;
                     }
                 }
             }
@@ -509,6 +819,108 @@ mod tests {
             SqlitePool::connect(":memory:").await.unwrap()
         }
 
+        #[tokio::test]
+        async fn test_trade_index_replay_rejected() {
+            let pool = crate::db::test_pool().await;
+            let identity = Keys::generate();
+            let gift = |trade_keys: &Keys| UnwrappedGift {
+                sender: identity.public_key(),
+                rumor: UnsignedEvent::new(
+                    trade_keys.public_key(),
+                    Timestamp::now(),
+                    NostrKind::GiftWrap,
+                    Vec::new(),
+                    "",
+                ),
+            };
+            let trade_keys: Vec<Keys> = (0..3).map(|_| Keys::generate()).collect();
+
+            // In order sequence
+            for (index, keys) in trade_keys.iter().enumerate() {
+                let index = index as i64 + 1;
+                assert!(check_trade_key_binding(&pool, &gift(keys), index)
+                    .await
+                    .is_ok());
+                assert!(bind_trade_index(&pool, &gift(keys), index).await.is_ok());
+            }
+
+            let invalid_index = |result: Result<(), MostroError>| {
+                matches!(
+                    result,
+                    Err(MostroError::MostroCantDo(CantDoReason::InvalidTradeIndex))
+                )
+            };
+            // Replayed and lower indexes
+            assert!(invalid_index(
+                check_trade_key_binding(&pool, &gift(&trade_keys[2]), 3).await
+            ));
+            assert!(invalid_index(
+                check_trade_key_binding(&pool, &gift(&Keys::generate()), 2).await
+            ));
+            // A trade key used before can't come back with a new index
+            assert!(invalid_index(
+                check_trade_key_binding(&pool, &gift(&trade_keys[0]), 4).await
+            ));
+            // Next index with a new trade key
+            let next_keys = Keys::generate();
+            assert!(check_trade_key_binding(&pool, &gift(&next_keys), 4)
+                .await
+                .is_ok());
+            // Checking doesn't use up the index, a message refused by its
+            // handler leaves it free for the next one
+            assert!(check_trade_key_binding(&pool, &gift(&next_keys), 4)
+                .await
+                .is_ok());
+            // Two messages handled with the same index, the second can't bind
+            assert!(bind_trade_index(&pool, &gift(&next_keys), 4).await.is_ok());
+            assert!(invalid_index(
+                bind_trade_index(&pool, &gift(&Keys::generate()), 4).await
+            ));
+        }
+
+        #[tokio::test]
+        async fn test_handled_trade_key_bound() {
+            let pool = crate::db::test_pool().await;
+            let identity = Keys::generate();
+            let trade_keys = Keys::generate();
+            let event = UnwrappedGift {
+                sender: identity.public_key(),
+                rumor: UnsignedEvent::new(
+                    trade_keys.public_key(),
+                    Timestamp::now(),
+                    NostrKind::GiftWrap,
+                    Vec::new(),
+                    "",
+                ),
+            };
+            let message = create_test_message(Action::NewOrder, Some(1));
+
+            bind_handled_trade_key(&pool, &event, &message).await;
+            assert!(
+                crate::db::is_trade_key_bound(&pool, &trade_keys.public_key().to_string())
+                    .await
+                    .unwrap()
+            );
+            // Full privacy users trade with their identity key
+            let privacy_event = UnwrappedGift {
+                sender: identity.public_key(),
+                rumor: UnsignedEvent::new(
+                    identity.public_key(),
+                    Timestamp::now(),
+                    NostrKind::GiftWrap,
+                    Vec::new(),
+                    "",
+                ),
+            };
+            let message = create_test_message(Action::NewOrder, Some(2));
+            bind_handled_trade_key(&pool, &privacy_event, &message).await;
+            assert!(
+                !crate::db::is_trade_key_bound(&pool, &identity.public_key().to_string())
+                    .await
+                    .unwrap()
+            );
+        }
+
         #[tokio::test]
         async fn test_check_trade_index_non_trading_action() {
             let pool = create_test_pool().await;
@@ -585,6 +997,7 @@ mod tests {
                     | Action::FiatSent
                     | Action::Release
                     | Action::AddInvoice
+                    | Action::PayInvoice
                     | Action::Dispute
                     | Action::RateUser
                     | Action::Cancel
@@ -595,10 +1008,6 @@ mod tests {
                     | Action::TradePubkey => {
                         assert!(true); // Action is handled
                     }
-                    Action::PayInvoice => {
-                        // This action is marked as todo!()
-                        assert!(true);
-                    }
                     _ => {
                         // Any unhandled actions should be caught here
                         assert!(true);
@@ -643,6 +1052,104 @@ mod tests {
             assert!(recent_time >= since_time);
         }
 
+        #[test]
+        fn test_check_event_pow() {
+            let keys = Keys::generate();
+            let event = EventBuilder::text_note("pow")
+                .pow(8)
+                .sign_with_keys(&keys)
+                .unwrap();
+            let rejected = POW_REJECTED_EVENTS.load(Ordering::Relaxed);
+
+            // No difficulty configured accepts anything
+            assert!(check_event_pow(&event, 0));
+            assert!(check_event_pow(&event, 8));
+            assert_eq!(POW_REJECTED_EVENTS.load(Ordering::Relaxed), rejected);
+
+            // A difficulty above the event work rejects it and counts it
+            let difficulty = nip13::get_leading_zero_bits(event.id.as_bytes()) + 1;
+            assert!(!check_event_pow(&event, difficulty));
+            assert!(POW_REJECTED_EVENTS.load(Ordering::Relaxed) > rejected);
+        }
+
+        #[tokio::test]
+        async fn test_shutdown_signal_stops_waiting() {
+            let (shutdown_tx, mut shutdown) = watch::channel(false);
+            let wait = std::time::Duration::from_millis(50);
+
+            // Nothing signaled yet
+            assert!(
+                tokio::time::timeout(wait, shutdown_requested(&mut shutdown))
+                    .await
+                    .is_err()
+            );
+            shutdown_tx.send(false).unwrap();
+            assert!(
+                tokio::time::timeout(wait, shutdown_requested(&mut shutdown))
+                    .await
+                    .is_err()
+            );
+
+            shutdown_tx.send(true).unwrap();
+            assert!(
+                tokio::time::timeout(wait, shutdown_requested(&mut shutdown))
+                    .await
+                    .is_ok()
+            );
+
+            // Dropping the sender also stops the loop
+            let (shutdown_tx, mut shutdown) = watch::channel(false);
+            drop(shutdown_tx);
+            assert!(
+                tokio::time::timeout(wait, shutdown_requested(&mut shutdown))
+                    .await
+                    .is_ok()
+            );
+        }
+
+        #[test]
+        fn test_pow_scales_with_account_age() {
+            let tiers = [(30, 4), (180, 0)];
+
+            // Brand new pubkeys get the base difficulty
+            assert_eq!(effective_pow(10, 0, &tiers), 10);
+            assert_eq!(effective_pow(10, 29, &tiers), 10);
+            // Established ones need less work
+            assert_eq!(effective_pow(10, 45, &tiers), 4);
+            assert_eq!(effective_pow(10, 400, &tiers), 0);
+            assert!(effective_pow(10, 400, &tiers) < effective_pow(10, 0, &tiers));
+
+            assert_eq!(min_pow(10, &tiers), 0);
+            assert_eq!(min_pow(10, &[]), 10);
+            assert_eq!(effective_pow(10, 400, &[]), 10);
+        }
+
+        #[test]
+        fn test_message_age_window_boundary() {
+            let keys = Keys::generate();
+            let now = Timestamp::now().as_u64();
+            let max_age = 30;
+            let rumor_at = |created_at: u64| {
+                UnsignedEvent::new(
+                    keys.public_key(),
+                    Timestamp::from(created_at),
+                    NostrKind::GiftWrap,
+                    Vec::new(),
+                    "",
+                )
+            };
+
+            // Just inside the window, the boundary is inclusive
+            let rumor = rumor_at(now - max_age);
+            assert!(is_message_fresh(rumor.created_at.as_u64(), now, max_age));
+            // Just outside the window
+            let rumor = rumor_at(now - max_age - 1);
+            assert!(!is_message_fresh(rumor.created_at.as_u64(), now, max_age));
+            // Clock skew with messages from the future is not a replay
+            let rumor = rumor_at(now + 5);
+            assert!(is_message_fresh(rumor.created_at.as_u64(), now, max_age));
+        }
+
         #[test]
         fn test_pow_verification_logic() {
             // Test POW validation logic structure
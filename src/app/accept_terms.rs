@@ -0,0 +1,115 @@
+//! Operator terms of service acceptance.
+//!
+//! When `terms_of_service_enabled` is set, users must accept the current terms
+//! before creating orders. The terms and their sha256 hash are published in the
+//! info event, the client accepts them sending that hash in the `terms_hash`
+//! field of the first order of an identity. The acceptance is recorded for the
+//! identity key which signed the seal, orders without it are refused until
+//! then. Changing the terms text changes the hash, so users are asked again.
+
+use crate::config::settings::Settings;
+use crate::db::{add_terms_acceptance, has_accepted_terms};
+use crate::util::get_order_extension;
+use easy_hasher::easy_hasher::*;
+use mostro_core::prelude::*;
+use nostr::nips::nip59::UnwrappedGift;
+use nostr_sdk::prelude::*;
+use sqlx::{Pool, Sqlite};
+
+/// Hash identifying a version of the terms of service
+pub fn terms_hash(terms: &str) -> String {
+    raw_sha256(terms.as_bytes().to_vec()).to_hex_string()
+}
+
+/// Checks `identity` accepted `terms` before, or accepts them now with
+/// `accepted_hash`, the hash sent along with the order
+async fn check_terms_with(
+    pool: &Pool<Sqlite>,
+    identity: &PublicKey,
+    terms: &str,
+    accepted_hash: Option<&str>,
+) -> Result<(), MostroError> {
+    let hash = terms_hash(terms);
+    if has_accepted_terms(pool, &identity.to_string(), &hash).await? {
+        return Ok(());
+    }
+    match accepted_hash {
+        Some(accepted) if accepted.to_lowercase() == hash => {
+            add_terms_acceptance(pool, &identity.to_string(), &hash).await
+        }
+        _ => {
+            tracing::info!("User {} must accept terms {} first", identity, hash);
+            Err(MostroCantDo(CantDoReason::InvalidParameters))
+        }
+    }
+}
+
+/// Checks the sender of a new order accepted the current terms of service,
+/// with this order or a previous one
+pub async fn check_terms_accepted(
+    pool: &Pool<Sqlite>,
+    event: &UnwrappedGift,
+) -> Result<(), MostroError> {
+    let mostro_settings = Settings::get_mostro();
+    if !mostro_settings.terms_of_service_enabled {
+        return Ok(());
+    }
+    let accepted_hash = get_order_extension::<String>(event, "terms_hash");
+    check_terms_with(
+        pool,
+        &event.sender,
+        &mostro_settings.terms_of_service,
+        accepted_hash.as_deref(),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    #[test]
+    fn test_terms_hash_changes_with_text() {
+        let hash = terms_hash("Be nice");
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, terms_hash("Be nice"));
+        assert_ne!(hash, terms_hash("Be nice, v2"));
+    }
+
+    #[tokio::test]
+    async fn test_order_blocked_until_terms_accepted() {
+        let pool = test_pool().await;
+        let identity = Keys::generate().public_key();
+        let terms = "No scams allowed";
+
+        // First order without the hash: refused
+        assert!(matches!(
+            check_terms_with(&pool, &identity, terms, None).await,
+            Err(MostroCantDo(CantDoReason::InvalidParameters))
+        ));
+        // The hash of other terms doesn't count
+        let other_hash = terms_hash("Scams allowed");
+        assert!(check_terms_with(&pool, &identity, terms, Some(&other_hash))
+            .await
+            .is_err());
+
+        // Accepted with the order
+        let hash = terms_hash(terms);
+        check_terms_with(&pool, &identity, terms, Some(&hash))
+            .await
+            .unwrap();
+
+        // Next orders are allowed
+        check_terms_with(&pool, &identity, terms, None)
+            .await
+            .unwrap();
+
+        // A new version of the terms must be accepted again
+        assert!(
+            check_terms_with(&pool, &identity, "No scams allowed, v2", None)
+                .await
+                .is_err()
+        );
+    }
+}
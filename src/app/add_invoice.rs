@@ -1,3 +1,4 @@
+use crate::order_locks::lock_order;
 use crate::util::{
     enqueue_order_msg, get_order, notify_taker_reputation, show_hold_invoice, update_order_event,
     validate_invoice,
@@ -37,6 +38,8 @@ pub async fn add_invoice_action(
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
 ) -> Result<(), MostroError> {
+    // No other handler changes the order meanwhile
+    let _order_lock = lock_order(msg.get_inner_message_kind().id).await;
     // Get order
     let mut order = get_order(&msg, pool).await?;
     // Check order status
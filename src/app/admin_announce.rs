@@ -0,0 +1,198 @@
+//! Admin announcements to every user.
+//!
+//! Operators announce things like maintenance windows with a public event of
+//! its own kind signed by Mostro, carrying the message and its severity.
+//! Announcements are spaced out so a leaked admin key can't flood clients.
+
+use crate::db::is_user_present;
+use crate::nip33::announcement_event;
+use crate::util::get_nostr_client;
+use mostro_core::prelude::*;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use std::str::FromStr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Longest announcement message in characters
+pub const MAX_ANNOUNCEMENT_LENGTH: usize = 1000;
+/// Shortest time between two announcements
+const ANNOUNCEMENT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Time of the last announcement published
+static LAST_ANNOUNCEMENT: LazyLock<Mutex<Option<Instant>>> = LazyLock::new(Mutex::default);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+impl FromStr for Severity {
+    type Err = MostroError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "info" => Ok(Severity::Info),
+            "warning" => Ok(Severity::Warning),
+            "critical" => Ok(Severity::Critical),
+            _ => Err(MostroCantDo(CantDoReason::InvalidParameters)),
+        }
+    }
+}
+
+/// Only Mostro itself and admin users can announce
+async fn check_announce_allowed(
+    pool: &Pool<Sqlite>,
+    sender: &PublicKey,
+    my_keys: &Keys,
+) -> Result<(), MostroError> {
+    if *sender == my_keys.public_key() {
+        return Ok(());
+    }
+    match is_user_present(pool, sender.to_string()).await {
+        Ok(user) if user.is_admin != 0_i64 => Ok(()),
+        _ => Err(MostroCantDo(CantDoReason::IsNotYourOrder)),
+    }
+}
+
+/// Take the announcement slot at `now`, refused while the last announcement
+/// is too recent
+fn take_announcement_slot(last: &mut Option<Instant>, now: Instant) -> Result<(), MostroError> {
+    if last.is_some_and(|last| now.duration_since(last) < ANNOUNCEMENT_INTERVAL) {
+        return Err(MostroCantDo(CantDoReason::NotAllowedByStatus));
+    }
+    *last = Some(now);
+    Ok(())
+}
+
+/// Check `sender` can make the announcement and build its event
+async fn announcement(
+    pool: &Pool<Sqlite>,
+    sender: &PublicKey,
+    my_keys: &Keys,
+    message: &str,
+    severity: Severity,
+    now: Instant,
+) -> Result<Event, MostroError> {
+    check_announce_allowed(pool, sender, my_keys).await?;
+    let message = message.trim();
+    if message.is_empty() || message.chars().count() > MAX_ANNOUNCEMENT_LENGTH {
+        return Err(MostroCantDo(CantDoReason::InvalidParameters));
+    }
+    let event = announcement_event(my_keys, message, severity.as_str())
+        .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?;
+    match LAST_ANNOUNCEMENT.lock() {
+        Ok(mut last) => take_announcement_slot(&mut last, now)?,
+        Err(poisoned) => take_announcement_slot(&mut poisoned.into_inner(), now)?,
+    }
+
+    Ok(event)
+}
+
+pub async fn admin_announce_action(
+    message: &str,
+    severity: Severity,
+    sender: &PublicKey,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    let event = announcement(pool, sender, my_keys, message, severity, Instant::now()).await?;
+    get_nostr_client()?
+        .send_event(&event)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?;
+    info!("Announcement {} published by {}", event.id, sender);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::add_new_user;
+    use crate::db::test_pool;
+    use crate::nip33::ANNOUNCEMENT_EVENT_KIND;
+    use mostro_core::user::User;
+
+    fn tag_value(event: &Event, name: &str) -> Option<String> {
+        event
+            .tags
+            .iter()
+            .find(|tag| tag.as_slice()[0] == name)
+            .map(|tag| tag.as_slice()[1].clone())
+    }
+
+    #[tokio::test]
+    async fn test_admin_announcement() {
+        let pool = test_pool().await;
+        let mostro_keys = Keys::generate();
+        let admin = Keys::generate().public_key();
+        add_new_user(
+            &pool,
+            User {
+                pubkey: admin.to_string(),
+                is_admin: 1,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let now = Instant::now();
+
+        // Users are not allowed
+        let user = Keys::generate().public_key();
+        assert!(matches!(
+            announcement(&pool, &user, &mostro_keys, "Hi", Severity::Info, now).await,
+            Err(MostroCantDo(CantDoReason::IsNotYourOrder))
+        ));
+        let too_long = "a".repeat(MAX_ANNOUNCEMENT_LENGTH + 1);
+        assert!(matches!(
+            announcement(&pool, &admin, &mostro_keys, &too_long, Severity::Info, now).await,
+            Err(MostroCantDo(CantDoReason::InvalidParameters))
+        ));
+
+        let message = "Maintenance on Sunday from 10:00 to 12:00 UTC";
+        let event = announcement(&pool, &admin, &mostro_keys, message, Severity::Warning, now)
+            .await
+            .unwrap();
+        assert_eq!(event.kind, nostr_sdk::Kind::Custom(ANNOUNCEMENT_EVENT_KIND));
+        assert_eq!(event.pubkey, mostro_keys.public_key());
+        assert_eq!(event.content, message);
+        assert!(event.verify().is_ok());
+        assert_eq!(tag_value(&event, "severity"), Some("warning".to_string()));
+        assert_eq!(tag_value(&event, "z"), Some("announcement".to_string()));
+
+        // Spaced out
+        assert!(matches!(
+            announcement(&pool, &admin, &mostro_keys, message, Severity::Info, now).await,
+            Err(MostroCantDo(CantDoReason::NotAllowedByStatus))
+        ));
+        assert!(announcement(
+            &pool,
+            &admin,
+            &mostro_keys,
+            message,
+            Severity::Info,
+            now + ANNOUNCEMENT_INTERVAL
+        )
+        .await
+        .is_ok());
+        assert_eq!("CRITICAL".parse::<Severity>().unwrap(), Severity::Critical);
+        assert!("urgent".parse::<Severity>().is_err());
+    }
+}
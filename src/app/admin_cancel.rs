@@ -1,10 +1,17 @@
 use std::borrow::Cow;
 use std::str::FromStr;
 
+use crate::app::cancel::{cancel_reason, return_funds_to_seller};
+use crate::config::settings::Settings;
 use crate::db::{find_dispute_by_order_id, is_assigned_solver};
-use crate::lightning::LndConnector;
+use crate::lightning::retry::RetryPolicy;
+use crate::lightning::LightningBackend;
 use crate::nip33::new_event;
-use crate::util::{enqueue_order_msg, get_nostr_client, get_order, send_dm, update_order_event};
+use crate::order_locks::lock_order;
+use crate::util::{
+    enqueue_order_msg, get_nostr_client, get_order, penalize_dispute_loser,
+    record_dispute_resolution, update_order_event, OrderMsg,
+};
 use mostro_core::prelude::*;
 use nostr::nips::nip59::UnwrappedGift;
 use nostr_sdk::prelude::*;
@@ -17,8 +24,10 @@ pub async fn admin_cancel_action(
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-    ln_client: &mut LndConnector,
+    ln_client: &mut dyn LightningBackend,
 ) -> Result<(), MostroError> {
+    // No other handler changes the order meanwhile
+    let _order_lock = lock_order(msg.get_inner_message_kind().id).await;
     // Get request id
     let request_id = msg.get_inner_message_kind().request_id;
     // Get order
@@ -56,13 +65,8 @@ pub async fn admin_cancel_action(
         return Err(MostroCantDo(CantDoReason::NotAllowedByStatus));
     }
 
-    if order.hash.is_some() {
-        // We return funds to seller
-        if let Some(hash) = order.hash.as_ref() {
-            ln_client.cancel_hold_invoice(hash).await?;
-            info!("Order Id {}: Funds returned to seller", &order.id);
-        }
-    }
+    // We return funds to seller
+    return_funds_to_seller(ln_client, &order, &RetryPolicy::from_settings()).await?;
 
     // we check if there is a dispute
     let dispute = find_dispute_by_order_id(pool, order.id).await;
@@ -71,6 +75,16 @@ pub async fn admin_cancel_action(
         let dispute_id = d.id;
         // we update the dispute
         d.status = DisputeStatus::SellerRefunded.to_string();
+        // Canceled disputes refund the seller
+        record_dispute_resolution(pool, &d, false).await;
+        penalize_dispute_loser(
+            pool,
+            &order,
+            &d,
+            false,
+            Settings::get_mostro().dispute_loss_penalty,
+        )
+        .await;
         d.update(pool)
             .await
             .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
@@ -112,23 +126,18 @@ pub async fn admin_cancel_action(
         .update(pool)
         .await
         .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
-    // We create a Message for cancel
-    let message = Message::new_order(
-        Some(order.id),
-        request_id,
-        msg.get_inner_message_kind().trade_index,
-        Action::AdminCanceled,
-        None,
-    );
-
-    let message = message
-        .as_json()
-        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
-    // Message to admin
-    send_dm(event.rumor.pubkey, my_keys, &message, None)
-        .await
-        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+    // Both parties are told, with the reason given by the solver if any
+    let reason = cancel_reason(&msg).map(Payload::TextMessage);
+    notify_admin_cancel(&order, request_id, reason, event.rumor.pubkey).await
+}
 
+/// Tell the solver, the seller and the buyer that the order was canceled
+async fn notify_admin_cancel(
+    order: &Order,
+    request_id: Option<u64>,
+    reason: Option<Payload>,
+    admin_pubkey: PublicKey,
+) -> Result<(), MostroError> {
     let (seller_pubkey, buyer_pubkey) = match (&order.seller_pubkey, &order.buyer_pubkey) {
         (Some(seller), Some(buyer)) => (
             PublicKey::from_str(seller.as_str())
@@ -139,12 +148,75 @@ pub async fn admin_cancel_action(
         (None, _) => return Err(MostroInternalErr(ServiceError::InvalidPubkey)),
         (_, None) => return Err(MostroInternalErr(ServiceError::InvalidPubkey)),
     };
-    send_dm(seller_pubkey, my_keys, &message, None)
-        .await
-        .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?;
-    send_dm(buyer_pubkey, my_keys, &message, None)
-        .await
-        .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?;
+    // Message to admin
+    OrderMsg::new(order.id, Action::AdminCanceled)
+        .request(request_id)
+        .payload(reason.clone())
+        .send_to(admin_pubkey)
+        .await;
+    for pubkey in [seller_pubkey, buyer_pubkey] {
+        OrderMsg::new(order.id, Action::AdminCanceled)
+            .payload(reason.clone())
+            .send_to(pubkey)
+            .await;
+    }
+    info!(
+        "Order Id {}: canceled by admin, both parties notified",
+        order.id
+    );
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MESSAGE_QUEUES;
+    use crate::lightning::mock::MockBackend;
+
+    #[tokio::test]
+    async fn test_admin_cancel_refunds_and_notifies() {
+        let (admin, buyer, seller) = (Keys::generate(), Keys::generate(), Keys::generate());
+        let order = Order {
+            id: uuid::Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::Dispute.to_string(),
+            hash: Some("ab".repeat(32)),
+            buyer_pubkey: Some(buyer.public_key().to_string()),
+            seller_pubkey: Some(seller.public_key().to_string()),
+            ..Default::default()
+        };
+        let mut backend = MockBackend::default();
+        let policy = RetryPolicy {
+            attempts: 1,
+            base_delay: std::time::Duration::ZERO,
+        };
+        return_funds_to_seller(&mut backend, &order, &policy)
+            .await
+            .unwrap();
+        assert_eq!(backend.canceled, vec!["ab".repeat(32)]);
+
+        let reason = Some(Payload::TextMessage("Seller proved payment".to_string()));
+        notify_admin_cancel(&order, Some(3), reason, admin.public_key())
+            .await
+            .unwrap();
+        let queued = MESSAGE_QUEUES.queue_order_msg.read().await;
+        let notified: Vec<PublicKey> = queued
+            .iter()
+            .filter(|(msg, _)| {
+                let kind = msg.get_inner_message_kind();
+                kind.id == Some(order.id)
+                    && matches!(kind.action, Action::AdminCanceled)
+                    && matches!(
+                        &kind.payload,
+                        Some(Payload::TextMessage(text)) if text == "Seller proved payment"
+                    )
+            })
+            .map(|(_, pubkey)| *pubkey)
+            .collect();
+        assert_eq!(
+            notified,
+            vec![admin.public_key(), seller.public_key(), buyer.public_key()]
+        );
+    }
+}
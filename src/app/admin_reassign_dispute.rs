@@ -0,0 +1,210 @@
+//! Hand off a dispute in progress to another solver.
+//!
+//! When the solver that took a dispute is not available the admin can move
+//! the dispute to another solver. The new solver gets the dispute information
+//! as if they took it, the previous solver and both parties are told who is
+//! assisting now.
+
+use crate::app::admin_take_dispute::prepare_solver_info_message;
+use crate::config::MESSAGE_QUEUES;
+use crate::db::{add_solver_dispute_taken, find_solver_pubkey};
+use mostro_core::prelude::*;
+use nostr_sdk::prelude::*;
+use sqlx::{Pool, Sqlite};
+use sqlx_crud::Crud;
+use std::str::FromStr;
+use tracing::{error, info};
+use uuid::Uuid;
+
+/// Checks the dispute is being solved by someone else than `new_solver`,
+/// returns the current solver
+fn check_reassignable(dispute: &Dispute, new_solver: &PublicKey) -> Result<PublicKey, MostroError> {
+    // Settled or canceled disputes are closed, initiated ones can be taken
+    if dispute.status != DisputeStatus::InProgress.to_string() {
+        return Err(MostroCantDo(CantDoReason::NotAllowedByStatus));
+    }
+    let current_solver = dispute
+        .solver_pubkey
+        .as_deref()
+        .and_then(|pubkey| PublicKey::from_str(pubkey).ok())
+        .ok_or(MostroInternalErr(ServiceError::InvalidPubkey))?;
+    if current_solver == *new_solver {
+        return Err(MostroCantDo(CantDoReason::InvalidPeer));
+    }
+
+    Ok(current_solver)
+}
+
+/// Messages of a reassignment: the dispute information for the new solver,
+/// the new solver pubkey for the previous solver and both parties
+fn reassignment_messages(
+    dispute: &Dispute,
+    order: &Order,
+    old_solver: PublicKey,
+    new_solver: PublicKey,
+    dispute_info: SolverDisputeInfo,
+) -> Result<Vec<(Message, PublicKey)>, MostroError> {
+    let new_solver_msg = Message::new_dispute(
+        Some(dispute.id),
+        None,
+        None,
+        Action::AdminTookDispute,
+        Some(Payload::Dispute(dispute.id, None, Some(dispute_info))),
+    );
+    let mut messages = vec![(new_solver_msg, new_solver)];
+
+    let peer_msg = Message::new_order(
+        Some(order.id),
+        None,
+        None,
+        Action::AdminTookDispute,
+        Some(Payload::Peer(Peer {
+            pubkey: new_solver.to_hex(),
+            reputation: None,
+        })),
+    );
+    messages.push((peer_msg.clone(), old_solver));
+    messages.push((
+        peer_msg.clone(),
+        order.get_buyer_pubkey().map_err(MostroInternalErr)?,
+    ));
+    messages.push((
+        peer_msg,
+        order.get_seller_pubkey().map_err(MostroInternalErr)?,
+    ));
+
+    Ok(messages)
+}
+
+/// Move the dispute `dispute_id` to `new_solver`, which must be a solver or
+/// the Mostro keys
+pub async fn admin_reassign_dispute_action(
+    dispute_id: Uuid,
+    new_solver: PublicKey,
+    mostro_keys: &Keys,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    let mut dispute = Dispute::by_id(pool, dispute_id)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?
+        .ok_or(MostroInternalErr(ServiceError::InvalidDisputeId))?;
+    let old_solver = check_reassignable(&dispute, &new_solver)?;
+
+    let is_solver = find_solver_pubkey(pool, new_solver.to_string())
+        .await
+        .is_ok_and(|user| user.is_solver != 0);
+    if !is_solver && new_solver != mostro_keys.public_key() {
+        return Err(MostroCantDo(CantDoReason::InvalidPubkey));
+    }
+
+    let order = Order::by_id(pool, dispute.order_id)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?
+        .ok_or(MostroInternalErr(ServiceError::InvalidOrderId))?;
+
+    dispute.solver_pubkey = Some(new_solver.to_string());
+    dispute.taken_at = Timestamp::now().as_u64() as i64;
+    dispute
+        .clone()
+        .update(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+    info!(
+        "Dispute {} reassigned from {} to {}",
+        dispute.id, old_solver, new_solver
+    );
+
+    if let Err(e) = add_solver_dispute_taken(pool, &new_solver.to_string()).await {
+        error!("Failed to update stats of solver {}: {}", new_solver, e);
+    }
+
+    let dispute_info = prepare_solver_info_message(pool, &order, &dispute).await?;
+    let messages = reassignment_messages(&dispute, &order, old_solver, new_solver, dispute_info)?;
+    MESSAGE_QUEUES
+        .queue_order_msg
+        .write()
+        .await
+        .extend(messages);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disputed_order() -> (Order, Dispute) {
+        let order = Order {
+            id: Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::Dispute.to_string(),
+            buyer_pubkey: Some(Keys::generate().public_key().to_string()),
+            seller_pubkey: Some(Keys::generate().public_key().to_string()),
+            ..Default::default()
+        };
+        let mut dispute = Dispute::new(order.id, order.status.clone());
+        dispute.status = DisputeStatus::InProgress.to_string();
+        (order, dispute)
+    }
+
+    #[test]
+    fn test_reassign_notifies_both_solvers() {
+        let (order, mut dispute) = disputed_order();
+        let old_solver = Keys::generate().public_key();
+        let new_solver = Keys::generate().public_key();
+        dispute.solver_pubkey = Some(old_solver.to_string());
+
+        assert_eq!(
+            check_reassignable(&dispute, &new_solver).unwrap(),
+            old_solver
+        );
+        let info = SolverDisputeInfo::new(
+            &order,
+            &dispute,
+            order.seller_pubkey.clone().unwrap(),
+            None,
+            None,
+        );
+        let messages =
+            reassignment_messages(&dispute, &order, old_solver, new_solver, info).unwrap();
+
+        // The new solver gets the dispute context
+        let (msg, pubkey) = &messages[0];
+        assert_eq!(*pubkey, new_solver);
+        assert!(matches!(
+            msg.get_inner_message_kind().payload,
+            Some(Payload::Dispute(id, None, Some(_))) if id == dispute.id
+        ));
+        // The old solver and both parties learn who the new solver is
+        let notified: Vec<PublicKey> = messages[1..].iter().map(|(_, pubkey)| *pubkey).collect();
+        assert_eq!(notified[0], old_solver);
+        assert_eq!(notified.len(), 3);
+        for (msg, _) in &messages[1..] {
+            assert!(matches!(
+                &msg.get_inner_message_kind().payload,
+                Some(Payload::Peer(peer)) if peer.pubkey == new_solver.to_hex()
+            ));
+        }
+    }
+
+    #[test]
+    fn test_reassign_rejected_when_closed() {
+        let (_, mut dispute) = disputed_order();
+        let solver = Keys::generate().public_key();
+        dispute.solver_pubkey = Some(solver.to_string());
+        let new_solver = Keys::generate().public_key();
+
+        // Same solver again
+        assert!(matches!(
+            check_reassignable(&dispute, &solver),
+            Err(MostroCantDo(CantDoReason::InvalidPeer))
+        ));
+        for status in [DisputeStatus::Settled, DisputeStatus::SellerRefunded] {
+            dispute.status = status.to_string();
+            assert!(matches!(
+                check_reassignable(&dispute, &new_solver),
+                Err(MostroCantDo(CantDoReason::NotAllowedByStatus))
+            ));
+        }
+    }
+}
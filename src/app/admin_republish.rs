@@ -0,0 +1,93 @@
+//! Admin request to publish again the event of an order.
+//!
+//! Order events can get lost on relays while the order is still alive in the
+//! database, republishing the current status brings it back to the book.
+
+use crate::db::find_solver_pubkey;
+use crate::util::update_order_event;
+use mostro_core::prelude::*;
+use nostr_sdk::prelude::*;
+use sqlx::{Pool, Sqlite};
+use sqlx_crud::Crud;
+use tracing::info;
+use uuid::Uuid;
+
+/// Only Mostro itself and registered solvers can republish orders
+async fn check_republish_allowed(
+    pool: &Pool<Sqlite>,
+    sender: &PublicKey,
+    my_keys: &Keys,
+) -> Result<(), MostroError> {
+    if *sender == my_keys.public_key() {
+        return Ok(());
+    }
+    match find_solver_pubkey(pool, sender.to_string()).await {
+        Ok(solver) if solver.is_solver != 0_i64 => Ok(()),
+        _ => Err(MostroCantDo(CantDoReason::IsNotYourOrder)),
+    }
+}
+
+pub async fn admin_republish_action(
+    order_id: Uuid,
+    sender: &PublicKey,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    check_republish_allowed(pool, sender, my_keys).await?;
+    let order = Order::by_id(pool, order_id)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?
+        .ok_or(MostroCantDo(CantDoReason::NotFound))?;
+    let status = order.get_order_status().map_err(MostroInternalErr)?;
+
+    // Same status, a new replaceable event replaces the lost one
+    let order_updated = update_order_event(my_keys, status, &order)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?;
+    order_updated
+        .update(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+    info!("Order Id {}: republished by {}", order_id, sender);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::add_new_user;
+    use crate::db::test_pool;
+    use mostro_core::user::User;
+
+    #[tokio::test]
+    async fn test_only_admins_republish() {
+        let pool = test_pool().await;
+        let mostro_keys = Keys::generate();
+        let solver = Keys::generate().public_key();
+        add_new_user(
+            &pool,
+            User {
+                pubkey: solver.to_string(),
+                is_solver: 1,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let user = Keys::generate().public_key();
+        assert!(matches!(
+            check_republish_allowed(&pool, &user, &mostro_keys).await,
+            Err(MostroCantDo(CantDoReason::IsNotYourOrder))
+        ));
+        assert!(
+            check_republish_allowed(&pool, &mostro_keys.public_key(), &mostro_keys)
+                .await
+                .is_ok()
+        );
+        assert!(check_republish_allowed(&pool, &solver, &mostro_keys)
+            .await
+            .is_ok());
+    }
+}
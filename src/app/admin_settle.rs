@@ -1,8 +1,11 @@
+use crate::config::settings::Settings;
 use crate::db::{find_dispute_by_order_id, is_assigned_solver};
-use crate::lightning::LndConnector;
+use crate::lightning::LightningBackend;
 use crate::nip33::new_event;
+use crate::order_locks::lock_order;
 use crate::util::{
-    enqueue_order_msg, get_nostr_client, get_order, settle_seller_hold_invoice, update_order_event,
+    enqueue_order_msg, get_nostr_client, get_order, penalize_dispute_loser, publish_fee_receipt,
+    record_dispute_resolution, settle_seller_hold_invoice, update_order_event,
 };
 
 use mostro_core::prelude::*;
@@ -20,8 +23,10 @@ pub async fn admin_settle_action(
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-    ln_client: &mut LndConnector,
+    ln_client: &mut dyn LightningBackend,
 ) -> Result<(), MostroError> {
+    // No other handler changes the order meanwhile
+    let _order_lock = lock_order(msg.get_inner_message_kind().id).await;
     // Get request id
     let request_id = msg.get_inner_message_kind().request_id;
     // Get order
@@ -63,6 +68,7 @@ pub async fn admin_settle_action(
     settle_seller_hold_invoice(event, ln_client, Action::AdminSettled, true, &order)
         .await
         .map_err(|e| MostroInternalErr(ServiceError::LnNodeError(e.to_string())))?;
+    publish_fee_receipt(my_keys, &order).await;
     // Update order event
     let order_updated = update_order_event(my_keys, Status::SettledHoldInvoice, &order)
         .await
@@ -75,6 +81,16 @@ pub async fn admin_settle_action(
         let dispute_id = d.id;
         // we update the dispute
         d.status = DisputeStatus::Settled.to_string();
+        // Settled disputes pay the buyer
+        record_dispute_resolution(pool, &d, true).await;
+        penalize_dispute_loser(
+            pool,
+            &order,
+            &d,
+            true,
+            Settings::get_mostro().dispute_loss_penalty,
+        )
+        .await;
         d.update(pool)
             .await
             .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
@@ -0,0 +1,712 @@
+//! Dispute settlement splitting the funds between buyer and seller.
+//!
+//! A hold invoice can't be partially settled, so Mostro settles it in full to
+//! itself and then pays each party their portion through invoices for the
+//! amount of their leg. The seller invoice is given by the solver. The buyer
+//! invoice is given by the solver too, or the lightning address of the order
+//! is used, asking it for an invoice of the leg amount each attempt. The
+//! invoice of the order is for the whole trade, so it is only used when it has
+//! no amount. Each payment is a leg recorded in the database, failed legs are
+//! retried by the scheduler until they are paid or run out of attempts. A leg
+//! is claimed in the database before it is paid, and a payment whose outcome
+//! the node didn't tell is checked by its hash instead of being sent again.
+
+use crate::config::settings::Settings;
+use crate::db::{
+    add_split_legs, claim_split_leg, find_dispute_by_order_id, find_orders_with_unpaid_split_legs,
+    find_split_legs, release_split_legs, update_split_leg,
+};
+use crate::kill_switch::check_funds_movable;
+use crate::lightning::invoice::decode_invoice;
+use crate::lightning::onchain::is_onchain_address;
+use crate::lightning::LightningBackend;
+use crate::lnurl::{ln_exists, resolv_ln_address};
+use crate::models::SplitLeg;
+use crate::order_locks::lock_order;
+use crate::util::{enqueue_order_msg, record_dispute_resolution, update_order_event};
+use fedimint_tonic_lnd::lnrpc::payment::PaymentStatus;
+use lnurl::lightning_address::LightningAddress;
+use lnurl::lnurl::LnUrl;
+use mostro_core::prelude::*;
+use nostr_sdk::prelude::*;
+use sqlx::{Pool, Sqlite};
+use sqlx_crud::Crud;
+use std::str::FromStr;
+use tokio::sync::mpsc::channel;
+use tracing::{error, info};
+use uuid::Uuid;
+
+/// Basis points of a whole trade amount
+const TOTAL_BPS: u32 = 10_000;
+
+/// Portion of the trade amount, without the fee, going to the buyer. The
+/// seller gets the rest
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BuyerShare {
+    Sats(i64),
+    /// Split in basis points adding up to 10000, the seller gets the sat
+    /// left by rounding down
+    BasisPoints {
+        buyer: u32,
+        seller: u32,
+    },
+}
+
+impl BuyerShare {
+    /// Sats of `total` going to the buyer
+    fn buyer_amount(&self, total: i64) -> Result<i64, MostroError> {
+        match *self {
+            BuyerShare::Sats(amount) => Ok(amount),
+            BuyerShare::BasisPoints { buyer, seller } => {
+                if buyer.checked_add(seller) != Some(TOTAL_BPS) {
+                    return Err(MostroCantDo(CantDoReason::InvalidAmount));
+                }
+                Ok(total * buyer as i64 / TOTAL_BPS as i64)
+            }
+        }
+    }
+}
+
+/// Buyer and seller legs of a split, Mostro keeps the fee as in any trade.
+/// The buyer is paid to `buyer_invoice`, or to the invoice or address of the
+/// order when the solver gives none.
+fn split_legs(
+    order: &Order,
+    share: BuyerShare,
+    buyer_invoice: Option<&str>,
+    seller_invoice: &str,
+) -> Result<Vec<SplitLeg>, MostroError> {
+    let Some(buyer_invoice) = buyer_invoice
+        .map(str::to_string)
+        .or_else(|| order.buyer_invoice.clone())
+    else {
+        return Err(MostroCantDo(CantDoReason::InvalidInvoice));
+    };
+    let total = order.amount - order.fee;
+    let buyer_amount = share.buyer_amount(total)?;
+    // Settling or canceling the dispute already covers the full outcomes
+    if buyer_amount <= 0 || buyer_amount >= total {
+        return Err(MostroCantDo(CantDoReason::InvalidAmount));
+    }
+    let leg = |party: &str, payment_request: String, amount: i64| SplitLeg {
+        order_id: order.id,
+        party: party.to_string(),
+        payment_request,
+        amount,
+        status: "held".to_string(),
+        attempts: 0,
+        payment_hash: None,
+    };
+
+    Ok(vec![
+        leg("buyer", buyer_invoice, buyer_amount),
+        leg("seller", seller_invoice.to_string(), total - buyer_amount),
+    ])
+}
+
+/// True when the leg is paid to a lightning address or LNURL
+fn is_ln_address(payment_request: &str) -> bool {
+    LightningAddress::from_str(payment_request).is_ok() || LnUrl::from_str(payment_request).is_ok()
+}
+
+/// Checks an invoice can be paid with the amount of its leg. An invoice for
+/// another amount, like the one of the order for the whole trade, is refused.
+async fn check_leg_invoice(leg: &SplitLeg) -> Result<(), MostroError> {
+    if is_ln_address(&leg.payment_request) {
+        return ln_exists(&leg.payment_request, Some(leg.amount as u64))
+            .await
+            .map_err(|_| MostroCantDo(CantDoReason::InvalidInvoice));
+    }
+    // Legs are paid over lightning only
+    if is_onchain_address(&leg.payment_request) {
+        return Err(MostroCantDo(CantDoReason::InvalidInvoice));
+    }
+    let invoice = decode_invoice(&leg.payment_request)
+        .map_err(|_| MostroCantDo(CantDoReason::InvalidInvoice))?;
+    match invoice.amount_milli_satoshis() {
+        Some(msat) if msat != leg.amount as u64 * 1000 => {
+            Err(MostroCantDo(CantDoReason::InvalidAmount))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Outcome of the payment of a leg
+#[derive(Debug, PartialEq)]
+enum LegPayment {
+    Paid,
+    Failed,
+    /// Sent but the node didn't tell whether it succeeded, it must not be sent
+    /// again until the node reports it failed
+    InFlight,
+}
+
+/// Invoice to pay a leg with. Addresses are asked for a fresh invoice of the
+/// leg amount on each attempt.
+async fn leg_payment_request(leg: &SplitLeg) -> Option<String> {
+    if !is_ln_address(&leg.payment_request) {
+        return Some(leg.payment_request.clone());
+    }
+    match resolv_ln_address(&leg.payment_request, leg.amount as u64).await {
+        Ok(payment_request) => Some(payment_request),
+        Err(e) => {
+            info!(
+                "Order Id {}: {} split leg address not resolved: {}",
+                leg.order_id, leg.party, e
+            );
+            None
+        }
+    }
+}
+
+/// Pay one leg with `payment_request`, it is only failed once the node
+/// reports it failed or refuses to send it
+async fn pay_leg(
+    ln_client: &mut dyn LightningBackend,
+    leg: &SplitLeg,
+    payment_request: &str,
+) -> LegPayment {
+    let (tx, mut rx) = channel(100);
+    if let Err(e) = ln_client
+        .send_payment(payment_request, leg.amount, tx)
+        .await
+    {
+        info!(
+            "Order Id {}: {} split leg payment failed: {}",
+            leg.order_id, leg.party, e
+        );
+        return LegPayment::Failed;
+    }
+    while let Some(msg) = rx.recv().await {
+        match PaymentStatus::try_from(msg.payment.status) {
+            Ok(PaymentStatus::Succeeded) => return LegPayment::Paid,
+            Ok(PaymentStatus::Failed) => return LegPayment::Failed,
+            _ => {}
+        }
+    }
+    LegPayment::InFlight
+}
+
+/// Outcome of a leg whose payment may have been sent, by the status of its
+/// payment in the node. The hash is recorded before sending, a leg taken
+/// without one was never sent.
+async fn check_sent_leg(ln_client: &mut dyn LightningBackend, leg: &SplitLeg) -> LegPayment {
+    let Some(hash) = leg.payment_hash.as_ref() else {
+        return match leg.status.as_str() {
+            "paying" => LegPayment::Failed,
+            _ => LegPayment::InFlight,
+        };
+    };
+    match ln_client.sent_payment_status(hash).await {
+        Ok(PaymentStatus::Succeeded) => LegPayment::Paid,
+        Ok(PaymentStatus::Failed) => LegPayment::Failed,
+        Ok(_) => LegPayment::InFlight,
+        Err(e) => {
+            info!(
+                "Order Id {}: {} split leg payment {} not checked: {}",
+                leg.order_id, leg.party, hash, e
+            );
+            LegPayment::InFlight
+        }
+    }
+}
+
+/// Take a leg and pay it. The leg is claimed in the database first so it is
+/// never paid twice, None when it was not waiting for a payment
+async fn claim_and_pay_leg(
+    pool: &Pool<Sqlite>,
+    ln_client: &mut dyn LightningBackend,
+    leg: &mut SplitLeg,
+) -> Result<Option<LegPayment>, MostroError> {
+    if !claim_split_leg(pool, leg).await? {
+        return Ok(None);
+    }
+    leg.attempts += 1;
+    leg.status = "paying".to_string();
+    let Some(payment_request) = leg_payment_request(leg).await else {
+        return Ok(Some(LegPayment::Failed));
+    };
+    // The hash is recorded before sending so the payment can be checked later
+    leg.payment_hash = decode_invoice(&payment_request)
+        .ok()
+        .map(|invoice| invoice.payment_hash().to_string());
+    update_split_leg(pool, leg).await?;
+
+    Ok(Some(pay_leg(ln_client, leg, &payment_request).await))
+}
+
+/// Pay the legs of an order not paid yet, returns true when every leg is paid.
+/// Legs are paid independently, a failed one doesn't stop the other. Legs
+/// whose outcome was not known are checked on the node instead of paid again.
+/// Callers hold the lock of the order.
+pub async fn pay_split_legs(
+    pool: &Pool<Sqlite>,
+    ln_client: &mut dyn LightningBackend,
+    order_id: Uuid,
+) -> Result<bool, MostroError> {
+    check_funds_movable("split payment")?;
+    let mut all_paid = true;
+    for mut leg in find_split_legs(pool, order_id).await? {
+        let payment = match leg.status.as_str() {
+            "pending" | "failed" => match claim_and_pay_leg(pool, ln_client, &mut leg).await? {
+                Some(payment) => payment,
+                None => {
+                    all_paid = false;
+                    continue;
+                }
+            },
+            // Left by a payment interrupted before its outcome was known
+            "paying" | "in_flight" => check_sent_leg(ln_client, &leg).await,
+            status => {
+                all_paid &= status == "paid";
+                continue;
+            }
+        };
+        leg.status = match payment {
+            LegPayment::Paid => {
+                info!("Order Id {}: {} split leg paid", order_id, leg.party);
+                "paid"
+            }
+            LegPayment::Failed => "failed",
+            LegPayment::InFlight => {
+                info!(
+                    "Order Id {}: {} split leg payment {:?} in flight",
+                    order_id, leg.party, leg.payment_hash
+                );
+                "in_flight"
+            }
+        }
+        .to_string();
+        all_paid &= payment == LegPayment::Paid;
+        update_split_leg(pool, &leg).await?;
+    }
+
+    Ok(all_paid)
+}
+
+/// Retry the legs of every order with legs to pay or to check, each order is
+/// locked so its legs are not paid by another handler meanwhile
+pub async fn retry_split_legs(
+    pool: &Pool<Sqlite>,
+    ln_client: &mut dyn LightningBackend,
+    max_attempts: i64,
+) -> Result<(), MostroError> {
+    for order_id in find_orders_with_unpaid_split_legs(pool, max_attempts).await? {
+        let _order_lock = lock_order(Some(order_id)).await;
+        if let Err(e) = pay_split_legs(pool, ln_client, order_id).await {
+            error!("Order Id {order_id}: error retrying split legs: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Record the legs, settle the hold invoice and pay both parties
+async fn execute_split(
+    pool: &Pool<Sqlite>,
+    ln_client: &mut dyn LightningBackend,
+    order: &Order,
+    legs: &[SplitLeg],
+) -> Result<bool, MostroError> {
+    let Some(preimage) = order.preimage.as_ref() else {
+        return Err(MostroCantDo(CantDoReason::InvalidInvoice));
+    };
+    check_funds_movable("split settlement")?;
+    // Legs are recorded first so settled funds are never left untracked
+    add_split_legs(pool, legs).await?;
+    if let Err(e) = ln_client.settle_hold_invoice(preimage).await {
+        release_split_legs(pool, order.id, false).await?;
+        return Err(e);
+    }
+    info!("Order Id {}: hold invoice settled for a split", order.id);
+    release_split_legs(pool, order.id, true).await?;
+
+    pay_split_legs(pool, ln_client, order.id).await
+}
+
+/// Settle a dispute giving the `share` of the buyer to the buyer, through
+/// `buyer_invoice` or the order address, and the rest of the trade amount to
+/// the seller through `seller_invoice`
+pub async fn admin_settle_split_action(
+    order_id: Uuid,
+    share: BuyerShare,
+    buyer_invoice: Option<&str>,
+    seller_invoice: &str,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+    ln_client: &mut dyn LightningBackend,
+) -> Result<(), MostroError> {
+    if !Settings::get_mostro().split_settlements_enabled {
+        return Err(MostroCantDo(CantDoReason::NotAllowedByStatus));
+    }
+    settle_split(
+        order_id,
+        share,
+        buyer_invoice,
+        seller_invoice,
+        my_keys,
+        pool,
+        ln_client,
+    )
+    .await
+}
+
+async fn settle_split(
+    order_id: Uuid,
+    share: BuyerShare,
+    buyer_invoice: Option<&str>,
+    seller_invoice: &str,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+    ln_client: &mut dyn LightningBackend,
+) -> Result<(), MostroError> {
+    // No other handler changes the order meanwhile
+    let _order_lock = lock_order(Some(order_id)).await;
+    let order = Order::by_id(pool, order_id)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?
+        .ok_or(MostroCantDo(CantDoReason::NotFound))?;
+    order.check_status(Status::Dispute).map_err(MostroCantDo)?;
+
+    let legs = split_legs(&order, share, buyer_invoice, seller_invoice)?;
+    let buyer_amount = legs[0].amount;
+    for leg in &legs {
+        check_leg_invoice(leg).await?;
+    }
+    let all_paid = execute_split(pool, ln_client, &order, &legs).await?;
+    info!(
+        "Order Id {}: dispute split {} sats to buyer and {} sats to seller",
+        order.id, legs[0].amount, legs[1].amount
+    );
+    if !all_paid {
+        info!("Order Id {}: split legs left to retry", order.id);
+    }
+
+    let order_updated = update_order_event(my_keys, Status::SettledByAdmin, &order).await?;
+    order_updated
+        .update(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    if let Ok(mut dispute) = find_dispute_by_order_id(pool, order.id).await {
+        dispute.status = DisputeStatus::Settled.to_string();
+        // The party getting most of the funds is the one favored
+        record_dispute_resolution(pool, &dispute, buyer_amount * 2 >= order.amount - order.fee)
+            .await;
+        if let Err(e) = dispute.update(pool).await {
+            error!("Order Id {}: error updating split dispute: {}", order.id, e);
+        }
+    }
+
+    for pubkey in [&order.buyer_pubkey, &order.seller_pubkey]
+        .into_iter()
+        .flatten()
+    {
+        let pubkey = PublicKey::from_str(pubkey)
+            .map_err(|_| MostroInternalErr(ServiceError::InvalidPubkey))?;
+        enqueue_order_msg(
+            None,
+            Some(order.id),
+            Action::AdminSettled,
+            None,
+            pubkey,
+            None,
+        )
+        .await;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+    use crate::lightning::mock::MockBackend;
+
+    async fn disputed_order(pool: &Pool<Sqlite>) -> Order {
+        Order {
+            id: Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::Dispute.to_string(),
+            amount: 100_000,
+            fee: 1_000,
+            preimage: Some("cd".repeat(32)),
+            buyer_invoice: Some("lnbc-buyer".to_string()),
+            ..Default::default()
+        }
+        .create(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_split_both_legs_paid() {
+        let pool = test_pool().await;
+        let order = disputed_order(&pool).await;
+        let mut backend = MockBackend::default();
+
+        let legs = split_legs(&order, BuyerShare::Sats(60_000), None, "lnbc-seller").unwrap();
+        assert!(execute_split(&pool, &mut backend, &order, &legs)
+            .await
+            .unwrap());
+
+        // Settled in full, then each party got their portion
+        assert_eq!(backend.settled, vec!["cd".repeat(32)]);
+        assert_eq!(
+            backend.payments,
+            vec![
+                ("lnbc-buyer".to_string(), 60_000),
+                ("lnbc-seller".to_string(), 39_000)
+            ]
+        );
+        let legs = find_split_legs(&pool, order.id).await.unwrap();
+        assert!(legs
+            .iter()
+            .all(|leg| leg.status == "paid" && leg.attempts == 1));
+    }
+
+    #[tokio::test]
+    async fn test_split_failed_leg_recovers() {
+        let pool = test_pool().await;
+        let order = disputed_order(&pool).await;
+        let mut backend = MockBackend::default();
+        backend.failing_payments.insert("lnbc-seller".to_string());
+
+        let legs = split_legs(&order, BuyerShare::Sats(60_000), None, "lnbc-seller").unwrap();
+        assert!(!execute_split(&pool, &mut backend, &order, &legs)
+            .await
+            .unwrap());
+        // The buyer is paid even if the seller leg failed
+        let legs = find_split_legs(&pool, order.id).await.unwrap();
+        assert_eq!(legs[0].party, "buyer");
+        assert_eq!(legs[0].status, "paid");
+        assert_eq!(legs[1].status, "failed");
+
+        // The retry pays the seller only
+        backend.failing_payments.clear();
+        assert!(pay_split_legs(&pool, &mut backend, order.id).await.unwrap());
+        assert_eq!(backend.payments.len(), 2);
+        let legs = find_split_legs(&pool, order.id).await.unwrap();
+        assert_eq!(legs[1].status, "paid");
+        assert_eq!(legs[1].attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn test_split_leg_claimed_once() {
+        let pool = test_pool().await;
+        let order = disputed_order(&pool).await;
+        let mut backend = MockBackend::default();
+        backend.failing_payments.insert("lnbc-seller".to_string());
+        let legs = split_legs(&order, BuyerShare::Sats(60_000), None, "lnbc-seller").unwrap();
+        execute_split(&pool, &mut backend, &order, &legs)
+            .await
+            .unwrap();
+
+        // Only one payer takes the failed leg
+        let seller_leg = &find_split_legs(&pool, order.id).await.unwrap()[1];
+        assert!(claim_split_leg(&pool, seller_leg).await.unwrap());
+        assert!(!claim_split_leg(&pool, seller_leg).await.unwrap());
+        let seller_leg = &find_split_legs(&pool, order.id).await.unwrap()[1];
+        assert_eq!(seller_leg.status, "paying");
+        assert_eq!(seller_leg.attempts, 2);
+        // Nor the paid one
+        let buyer_leg = &find_split_legs(&pool, order.id).await.unwrap()[0];
+        assert!(!claim_split_leg(&pool, buyer_leg).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_split_in_flight_leg_not_paid_again() {
+        const INVOICE: &str = "lnbcrt500u1p3lzwdzpp5t9kgwgwd07y2lrwdscdnkqu4scrcgpm5pt9uwx0rxn5rxawlxlvqdqqcqzpgxqyz5vqsp5a6k7syfxeg8jy63rteywwjla5rrg2pvhedx8ajr2ltm4seydhsqq9qyyssq0n2uwlumsx4d0mtjm8tp7jw3y4da6p6z9gyyjac0d9xugf72lhh4snxpugek6n83geafue9ndgrhuhzk98xcecu2t3z56ut35mkammsqscqp0n";
+        let pool = test_pool().await;
+        let order = disputed_order(&pool).await;
+        let mut backend = MockBackend::default();
+        backend.in_flight_payments.insert(INVOICE.to_string());
+
+        let legs = split_legs(&order, BuyerShare::Sats(60_000), None, INVOICE).unwrap();
+        assert!(!execute_split(&pool, &mut backend, &order, &legs)
+            .await
+            .unwrap());
+        let seller_leg = &find_split_legs(&pool, order.id).await.unwrap()[1];
+        assert_eq!(seller_leg.status, "in_flight");
+        let hash = seller_leg.payment_hash.clone().unwrap();
+        assert_eq!(
+            hash,
+            decode_invoice(INVOICE).unwrap().payment_hash().to_string()
+        );
+
+        // Retries check the payment instead of sending it again
+        assert!(!pay_split_legs(&pool, &mut backend, order.id).await.unwrap());
+        backend
+            .payment_statuses
+            .insert(hash.clone(), PaymentStatus::InFlight);
+        assert!(!pay_split_legs(&pool, &mut backend, order.id).await.unwrap());
+        backend
+            .payment_statuses
+            .insert(hash, PaymentStatus::Succeeded);
+        assert!(pay_split_legs(&pool, &mut backend, order.id).await.unwrap());
+        assert_eq!(backend.payments.len(), 2);
+        let seller_leg = &find_split_legs(&pool, order.id).await.unwrap()[1];
+        assert_eq!(seller_leg.status, "paid");
+        assert_eq!(seller_leg.attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_split_settlement_and_retry_job_pay_legs_once() {
+        const BUYER_INVOICE: &str = "lnbcrt500u1p3lzwdzpp5t9kgwgwd07y2lrwdscdnkqu4scrcgpm5pt9uwx0rxn5rxawlxlvqdqqcqzpgxqyz5vqsp5a6k7syfxeg8jy63rteywwjla5rrg2pvhedx8ajr2ltm4seydhsqq9qyyssq0n2uwlumsx4d0mtjm8tp7jw3y4da6p6z9gyyjac0d9xugf72lhh4snxpugek6n83geafue9ndgrhuhzk98xcecu2t3z56ut35mkammsqscqp0n";
+        const SELLER_INVOICE: &str = "lnbcrt1p5gy6c6pp50h04eqruzmztz3a7x97cwea85cywsz4dl5sghzx2dkttv572e6esdqqcqzzsxqyz5vqsp5gttvmhu2mvgtnhxfvqy4t4v4vyp33n3jv23767xlcs9em2zfsq2s9qxpqysgqmhm6nhg6zrftdt8xd9yfq9zf38t65k0ff345thj5339sqnn3qqw8z63qrqdck7q5c48mmdy793u5usmx5tfc7jcydv2pmm8j2e2c3fqqpdezyf";
+        crate::config::init_test_settings();
+        let pool = test_pool().await;
+        let order = disputed_order(&pool).await;
+        let keys = Keys::generate();
+        // The seller leg fails when settling and is left to the job, which
+        // runs while the buyer is being paid
+        let mut settle_backend = MockBackend {
+            payment_delay: std::time::Duration::from_millis(100),
+            ..Default::default()
+        };
+        settle_backend
+            .failing_payments
+            .insert(SELLER_INVOICE.to_string());
+        let mut job_backend = MockBackend::default();
+
+        let (settled, retried) = tokio::join!(
+            settle_split(
+                order.id,
+                BuyerShare::Sats(50_000),
+                Some(BUYER_INVOICE),
+                SELLER_INVOICE,
+                &keys,
+                &pool,
+                &mut settle_backend,
+            ),
+            async {
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                retry_split_legs(&pool, &mut job_backend, 3).await
+            }
+        );
+        settled.unwrap();
+        retried.unwrap();
+        retry_split_legs(&pool, &mut job_backend, 3).await.unwrap();
+
+        // Each leg was paid once between the settlement and the job
+        let payments: Vec<_> = settle_backend
+            .payments
+            .iter()
+            .chain(&job_backend.payments)
+            .collect();
+        assert_eq!(
+            payments,
+            vec![
+                &(BUYER_INVOICE.to_string(), 50_000),
+                &(SELLER_INVOICE.to_string(), 49_000)
+            ]
+        );
+        let legs = find_split_legs(&pool, order.id).await.unwrap();
+        assert!(legs.iter().all(|leg| leg.status == "paid"));
+    }
+
+    #[tokio::test]
+    async fn test_split_not_settled_forgets_legs() {
+        let pool = test_pool().await;
+        let order = disputed_order(&pool).await;
+        let mut backend = MockBackend {
+            fail: true,
+            ..Default::default()
+        };
+
+        let legs = split_legs(&order, BuyerShare::Sats(60_000), None, "lnbc-seller").unwrap();
+        assert!(execute_split(&pool, &mut backend, &order, &legs)
+            .await
+            .is_err());
+        assert!(find_split_legs(&pool, order.id).await.unwrap().is_empty());
+        // Full outcomes are not splits
+        assert!(split_legs(&order, BuyerShare::Sats(99_000), None, "lnbc-seller").is_err());
+        assert!(split_legs(&order, BuyerShare::Sats(0), None, "lnbc-seller").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_split_half_in_basis_points() {
+        let pool = test_pool().await;
+        let order = disputed_order(&pool).await;
+        let share = BuyerShare::BasisPoints {
+            buyer: 5_000,
+            seller: 5_000,
+        };
+
+        let legs = split_legs(&order, share, None, "lnbc-seller").unwrap();
+        assert_eq!(legs[0].amount, 49_500);
+        assert_eq!(legs[1].amount, 49_500);
+        let mut backend = MockBackend::default();
+        assert!(execute_split(&pool, &mut backend, &order, &legs)
+            .await
+            .unwrap());
+        assert_eq!(
+            backend.payments,
+            vec![
+                ("lnbc-buyer".to_string(), 49_500),
+                ("lnbc-seller".to_string(), 49_500)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_split_basis_points_must_add_up() {
+        let pool = test_pool().await;
+        let order = disputed_order(&pool).await;
+        for (buyer, seller) in [(5_000, 4_000), (6_000, 6_000), (u32::MAX, 10_001)] {
+            let share = BuyerShare::BasisPoints { buyer, seller };
+            assert!(matches!(
+                split_legs(&order, share, None, "lnbc-seller"),
+                Err(MostroCantDo(CantDoReason::InvalidAmount))
+            ));
+        }
+        // Sats left by rounding go to the seller
+        let share = BuyerShare::BasisPoints {
+            buyer: 3_333,
+            seller: 6_667,
+        };
+        let legs = split_legs(&order, share, None, "lnbc-seller").unwrap();
+        assert_eq!((legs[0].amount, legs[1].amount), (32_996, 66_004));
+    }
+
+    #[tokio::test]
+    async fn test_split_buyer_invoice_from_solver() {
+        let pool = test_pool().await;
+        let order = disputed_order(&pool).await;
+        let mut backend = MockBackend::default();
+
+        // The invoice given by the solver replaces the one of the order
+        let legs = split_legs(
+            &order,
+            BuyerShare::Sats(60_000),
+            Some("lnbc-buyer-share"),
+            "lnbc-seller",
+        )
+        .unwrap();
+        assert!(execute_split(&pool, &mut backend, &order, &legs)
+            .await
+            .unwrap());
+        assert_eq!(
+            backend.payments[0],
+            ("lnbc-buyer-share".to_string(), 60_000)
+        );
+
+        // Orders paid onchain need an invoice from the solver
+        let onchain_order = Order {
+            buyer_invoice: Some("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".to_string()),
+            ..order
+        };
+        let legs = split_legs(
+            &onchain_order,
+            BuyerShare::Sats(60_000),
+            None,
+            "lnbc-seller",
+        )
+        .unwrap();
+        assert!(matches!(
+            check_leg_invoice(&legs[0]).await,
+            Err(MostroCantDo(CantDoReason::InvalidInvoice))
+        ));
+    }
+}
@@ -1,5 +1,5 @@
 use crate::config::MOSTRO_DB_PASSWORD;
-use crate::db::{find_solver_pubkey, is_user_present};
+use crate::db::{add_solver_dispute_taken, find_solver_pubkey, is_user_present};
 use crate::nip33::new_event;
 use crate::util::{get_dispute, get_nostr_client, send_dm};
 use mostro_core::prelude::*;
@@ -32,7 +32,7 @@ use tracing::info;
 ///
 /// This function may return errors related to invalid public keys or database access issues, which are handled
 /// by mapping them to `MostroError`.
-async fn prepare_solver_info_message(
+pub(crate) async fn prepare_solver_info_message(
     pool: &Pool<Sqlite>,
     order: &Order,
     dispute: &Dispute,
@@ -154,6 +154,11 @@ pub async fn admin_take_dispute_action(
 
     info!("Dispute {} taken by {}", dispute.id, event.sender);
 
+    // Count the dispute in the solver stats
+    if let Err(e) = add_solver_dispute_taken(pool, &event.sender.to_string()).await {
+        tracing::error!("Failed to update stats of solver {}: {}", event.sender, e);
+    }
+
     // Save it to DB
     dispute
         .clone()
@@ -1,9 +1,19 @@
+use crate::config::settings::Settings;
+use crate::config::MESSAGE_QUEUES;
 use crate::db::{
-    edit_buyer_pubkey_order, edit_master_buyer_pubkey_order, edit_master_seller_pubkey_order,
-    edit_seller_pubkey_order, update_order_to_initial_state,
+    edit_buyer_pubkey_order, edit_cancel_reason_order, edit_master_buyer_pubkey_order,
+    edit_master_seller_pubkey_order, edit_seller_pubkey_order, find_order_cancel_reason,
+    update_order_to_initial_state,
+};
+use crate::kill_switch::check_funds_movable;
+use crate::lightning::retry::{self, RetryPolicy};
+use crate::lightning::LightningBackend;
+use crate::order_locks::lock_order;
+use crate::rate_limiter::start_recreate_cooldown;
+use crate::util::{
+    cancel_republish_capped_order, get_order, republish_limit_reached, reset_api_quotes,
+    update_order_event, OrderMsg,
 };
-use crate::lightning::LndConnector;
-use crate::util::{enqueue_order_msg, get_order, update_order_event};
 use mostro_core::prelude::*;
 use nostr::nips::nip59::UnwrappedGift;
 use nostr_sdk::prelude::*;
@@ -12,31 +22,68 @@ use sqlx_crud::Crud;
 use std::str::FromStr;
 use tracing::info;
 
-/// Reset the api quotes
-fn reset_api_quotes(order: &mut Order) {
-    if order.price_from_api {
-        order.amount = 0;
-        order.fee = 0;
+/// Longest cancellation reason kept, longer ones are truncated
+const MAX_CANCEL_REASON_CHARS: usize = 280;
+
+/// Text sent along with a cancel to both parties when the initiator takes
+/// their cooperative cancel back
+const COOPERATIVE_CANCEL_WITHDRAWN: &str = "Cooperative cancel withdrawn";
+
+/// Reason sent by the party canceling as a text message payload, if any
+pub(crate) fn cancel_reason(msg: &Message) -> Option<String> {
+    match &msg.get_inner_message_kind().payload {
+        Some(Payload::TextMessage(reason)) if !reason.trim().is_empty() => Some(
+            reason
+                .trim()
+                .chars()
+                .take(MAX_CANCEL_REASON_CHARS)
+                .collect(),
+        ),
+        _ => None,
     }
 }
 
+/// Stored cancellation reason of the order, sent along with the cancel messages
+async fn cancel_reason_payload(
+    pool: &Pool<Sqlite>,
+    order_id: uuid::Uuid,
+) -> Result<Option<Payload>, MostroError> {
+    Ok(find_order_cancel_reason(pool, order_id)
+        .await?
+        .map(Payload::TextMessage))
+}
+
+/// Cancel the hold invoice of the order, if any, so funds go back to the seller
+pub(crate) async fn return_funds_to_seller(
+    ln_client: &mut dyn LightningBackend,
+    order: &Order,
+    policy: &RetryPolicy,
+) -> Result<(), MostroError> {
+    if let Some(hash) = &order.hash {
+        check_funds_movable("hold invoice cancellation")?;
+        retry::cancel_hold_invoice(ln_client, hash, policy).await?;
+        info!("Order Id {}: Funds returned to seller", &order.id);
+    }
+    Ok(())
+}
+
 /// Notify the creator that the order was cancelled
-async fn notify_creator(order: &mut Order, request_id: Option<u64>) -> Result<(), MostroError> {
+async fn notify_creator(
+    order: &mut Order,
+    request_id: Option<u64>,
+    reason: Option<Payload>,
+) -> Result<(), MostroError> {
     if order.is_buy_order().is_ok() && order.check_status(Status::WaitingBuyerInvoice).is_ok()
         || order.is_sell_order().is_ok() && order.check_status(Status::WaitingPayment).is_ok()
     {
         // Get creator pubkey
         let creator_pubkey = order.get_creator_pubkey().map_err(MostroInternalErr)?;
 
-        enqueue_order_msg(
-            request_id,
-            Some(order.id),
-            Action::Canceled,
-            None,
-            creator_pubkey,
-            None,
-        )
-        .await;
+        OrderMsg::new(order.id, Action::Canceled)
+            .request(request_id)
+            .payload(reason)
+            .send_to(creator_pubkey)
+            .await;
     }
 
     Ok(())
@@ -50,7 +97,7 @@ async fn cancel_cooperative_execution_step_2(
     mut order: Order,
     counterparty_pubkey: String,
     my_keys: &Keys,
-    ln_client: &mut LndConnector,
+    ln_client: &mut dyn LightningBackend,
 ) -> Result<(), MostroError> {
     // Validate if the initiator is the same as the event pubkey
     if let Some(initiator) = &order.cancel_initiator_pubkey {
@@ -61,14 +108,7 @@ async fn cancel_cooperative_execution_step_2(
     }
 
     // Cancel hold invoice if present
-    if let Some(hash) = &order.hash {
-        // We return funds to seller
-        ln_client.cancel_hold_invoice(hash).await?;
-        info!(
-            "Cooperative cancel: Order Id {}: Funds returned to seller",
-            &order.id
-        );
-    }
+    return_funds_to_seller(ln_client, &order, &RetryPolicy::from_settings()).await?;
     order.status = Status::CooperativelyCanceled.to_string();
     // update db
     let order = order
@@ -82,26 +122,18 @@ async fn cancel_cooperative_execution_step_2(
         .await
         .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?;
     // We create a Message for an accepted cooperative cancel and send it to both parties
-    enqueue_order_msg(
-        request_id,
-        Some(order.id),
-        Action::CooperativeCancelAccepted,
-        None,
-        event.rumor.pubkey,
-        None,
-    )
-    .await;
+    let reason = cancel_reason_payload(pool, order.id).await?;
+    OrderMsg::new(order.id, Action::CooperativeCancelAccepted)
+        .request(request_id)
+        .payload(reason.clone())
+        .send_to(event.rumor.pubkey)
+        .await;
     let counterparty_pubkey = PublicKey::from_str(&counterparty_pubkey)
         .map_err(|_| MostroInternalErr(ServiceError::InvalidPubkey))?;
-    enqueue_order_msg(
-        None,
-        Some(order.id),
-        Action::CooperativeCancelAccepted,
-        None,
-        counterparty_pubkey,
-        None,
-    )
-    .await;
+    OrderMsg::new(order.id, Action::CooperativeCancelAccepted)
+        .payload(reason)
+        .send_to(counterparty_pubkey)
+        .await;
     info!("Cancel: Order Id {} canceled cooperatively!", order.id);
 
     Ok(())
@@ -121,63 +153,76 @@ async fn cancel_cooperative_execution_step_1(
         .await
         .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
     // We create a Message to start a cooperative cancel and send it to both parties
-    enqueue_order_msg(
-        request_id,
-        Some(order.id),
-        Action::CooperativeCancelInitiatedByYou,
-        None,
-        event.rumor.pubkey,
-        None,
-    )
-    .await;
+    OrderMsg::new(order.id, Action::CooperativeCancelInitiatedByYou)
+        .request(request_id)
+        .send_to(event.rumor.pubkey)
+        .await;
     let counterparty_pubkey = PublicKey::from_str(&counterparty_pubkey)
         .map_err(|_| MostroInternalErr(ServiceError::InvalidPubkey))?;
-    enqueue_order_msg(
-        None,
-        Some(order.id),
-        Action::CooperativeCancelInitiatedByPeer,
-        None,
-        counterparty_pubkey,
-        None,
-    )
-    .await;
+    OrderMsg::new(order.id, Action::CooperativeCancelInitiatedByPeer)
+        .payload(cancel_reason_payload(pool, order.id).await?)
+        .send_to(counterparty_pubkey)
+        .await;
 
     Ok(())
 }
 
-/// Cancel an order by the taker
-async fn cancel_order_by_taker(
+/// Withdrawals are direct messages about the order, a cancel action would
+/// read as the order being canceled to clients
+fn withdrawn_msg(order_id: uuid::Uuid, request_id: Option<u64>) -> Message {
+    Message::new_dm(
+        Some(order_id),
+        request_id,
+        Action::SendDm,
+        Some(Payload::TextMessage(
+            COOPERATIVE_CANCEL_WITHDRAWN.to_string(),
+        )),
+    )
+}
+
+/// The initiator takes the cooperative cancel back before the counterparty
+/// accepts it, the trade goes on as before
+async fn withdraw_cooperative_cancel(
     pool: &Pool<Sqlite>,
     event: &UnwrappedGift,
-    order: &mut Order,
-    my_keys: &Keys,
+    mut order: Order,
+    counterparty_pubkey: PublicKey,
     request_id: Option<u64>,
-    ln_client: &mut LndConnector,
-    taker_pubkey: PublicKey,
 ) -> Result<(), MostroError> {
-    // Cancel hold invoice is present
-    if let Some(hash) = &order.hash {
-        ln_client.cancel_hold_invoice(hash).await?;
-        info!("Order Id {}: Funds returned to seller", &order.id);
+    order.cancel_initiator_pubkey = None;
+    if order.get_buyer_pubkey().ok() == Some(event.rumor.pubkey) {
+        order.buyer_cooperativecancel = false;
+    } else {
+        order.seller_cooperativecancel = false;
     }
+    let order = order
+        .update(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+    // The reason belonged to the withdrawn cancel
+    edit_cancel_reason_order(pool, order.id, None).await?;
 
-    //We notify the creator that the order was cancelled only if the taker had already done his part before
-    notify_creator(order, request_id).await?;
+    MESSAGE_QUEUES.queue_order_msg.write().await.extend([
+        (withdrawn_msg(order.id, request_id), event.rumor.pubkey),
+        (withdrawn_msg(order.id, None), counterparty_pubkey),
+    ]);
+    info!("Cancel: Order Id {} cooperative cancel withdrawn", order.id);
 
-    //We notify the taker that the order is cancelled
-    enqueue_order_msg(
-        request_id,
-        Some(order.id),
-        Action::Canceled,
-        None,
-        event.rumor.pubkey,
-        None,
-    )
-    .await;
+    Ok(())
+}
 
-    // Reset api quotes
-    reset_api_quotes(order);
+/// Role of the user who took the order, a buy order is taken by a seller
+fn taker_role(order: &Order) -> &'static str {
+    if order.is_buy_order().is_ok() {
+        "seller"
+    } else {
+        "buyer"
+    }
+}
 
+/// Forget the taker of an order going back to the book, the pubkeys of the
+/// maker are kept
+async fn clear_taker_data(pool: &Pool<Sqlite>, order: &Order) -> Result<(), MostroError> {
     if order.is_buy_order().is_ok() {
         info!("Cancel seller data from db");
         edit_seller_pubkey_order(pool, order.id, None)
@@ -196,6 +241,44 @@ async fn cancel_order_by_taker(
             .await
             .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
     }
+    Ok(())
+}
+
+/// Cancel an order by the taker
+async fn cancel_order_by_taker(
+    pool: &Pool<Sqlite>,
+    event: &UnwrappedGift,
+    order: &mut Order,
+    my_keys: &Keys,
+    request_id: Option<u64>,
+    ln_client: &mut dyn LightningBackend,
+    taker_pubkey: PublicKey,
+) -> Result<(), MostroError> {
+    // Cancel hold invoice is present
+    return_funds_to_seller(ln_client, order, &RetryPolicy::from_settings()).await?;
+
+    //We notify the creator that the order was cancelled only if the taker had already done his part before
+    let reason = cancel_reason_payload(pool, order.id).await?;
+    notify_creator(order, request_id, reason.clone()).await?;
+
+    //We notify the taker that the order is cancelled
+    OrderMsg::new(order.id, Action::Canceled)
+        .request(request_id)
+        .payload(reason)
+        .send_to(event.rumor.pubkey)
+        .await;
+
+    // The order goes back to the book, the reason only applied to this take
+    edit_cancel_reason_order(pool, order.id, None).await?;
+
+    // Reset api quotes
+    reset_api_quotes(order);
+
+    clear_taker_data(pool, order).await?;
+    // Orders abandoned too many times are canceled instead of republished
+    if republish_limit_reached(pool, order.id).await? {
+        return cancel_republish_capped_order(pool, my_keys, order, None).await;
+    }
     update_order_to_initial_state(pool, order.id, order.amount, order.fee)
         .await
         .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
@@ -204,8 +287,10 @@ async fn cancel_order_by_taker(
         .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?;
 
     info!(
-        "{}: Canceled order Id {} republishing order",
-        taker_pubkey, order.id
+        "Order Id {}: canceled by the {} {} who took it, republishing order",
+        order.id,
+        taker_role(order),
+        taker_pubkey
     );
 
     Ok(())
@@ -219,8 +304,10 @@ async fn cancel_order_by_maker(
     taker_pubkey: PublicKey,
     my_keys: &Keys,
     request_id: Option<u64>,
-    ln_client: &mut LndConnector,
+    ln_client: &mut dyn LightningBackend,
 ) -> Result<(), MostroError> {
+    // Cancel hold invoice if present, the order is only canceled once it is
+    return_funds_to_seller(ln_client, order, &RetryPolicy::from_settings()).await?;
     // We publish a new replaceable kind nostr event with the status updated
     if let Ok(order_updated) = update_order_event(my_keys, Status::Canceled, order).await {
         order_updated
@@ -228,31 +315,20 @@ async fn cancel_order_by_maker(
             .await
             .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
     }
-    // Cancel hold invoice if present
-    if let Some(hash) = &order.hash {
-        ln_client.cancel_hold_invoice(hash).await?;
-        info!("Order Id {}: Funds returned to seller", &order.id);
-    }
+    // Makers churning orders have to wait before creating a new one
+    start_recreate_cooldown(&event.sender, Settings::get_mostro().recreate_cooldown_secs);
 
-    enqueue_order_msg(
-        request_id,
-        Some(order.id),
-        Action::Canceled,
-        None,
-        event.rumor.pubkey,
-        None,
-    )
-    .await;
+    let reason = cancel_reason_payload(pool, order.id).await?;
+    OrderMsg::new(order.id, Action::Canceled)
+        .request(request_id)
+        .payload(reason.clone())
+        .send_to(event.rumor.pubkey)
+        .await;
     //We notify the taker that the order was cancelled
-    enqueue_order_msg(
-        None,
-        Some(order.id),
-        Action::Canceled,
-        None,
-        taker_pubkey,
-        None,
-    )
-    .await;
+    OrderMsg::new(order.id, Action::Canceled)
+        .payload(reason)
+        .send_to(taker_pubkey)
+        .await;
 
     Ok(())
 }
@@ -283,102 +359,193 @@ async fn cancel_pending_order_from_maker(
             )));
         }
     }
+    // Makers churning orders have to wait before creating a new one
+    start_recreate_cooldown(&event.sender, Settings::get_mostro().recreate_cooldown_secs);
     // We create a Message for cancel
-    enqueue_order_msg(
-        request_id,
-        Some(order.id),
-        Action::Canceled,
-        None,
-        event.rumor.pubkey,
-        None,
-    )
-    .await;
+    OrderMsg::new(order.id, Action::Canceled)
+        .request(request_id)
+        .payload(cancel_reason_payload(pool, order.id).await?)
+        .send_to(event.rumor.pubkey)
+        .await;
     Ok(())
 }
 
+/// Part the sender of a cancel plays in the order
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CancelSender {
+    Maker,
+    Taker,
+    /// Neither party of the order
+    Stranger,
+}
+
+/// What a cancel message does to an order
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CancelStep {
+    /// The maker takes the order out of the book
+    CancelPending,
+    /// The maker cancels the order before the trade started
+    CancelByMaker,
+    /// The taker leaves before the trade started, the order goes back to the book
+    CancelByTaker,
+    /// A party asks the counterparty for a cooperative cancel
+    StartCooperative,
+    /// The counterparty accepts the cooperative cancel
+    AcceptCooperative,
+    /// The initiator takes the cooperative cancel back
+    WithdrawCooperative,
+    /// Nothing to cancel in this status
+    Ignore,
+}
+
+fn cancel_sender(order: &Order, sender: PublicKey) -> CancelSender {
+    if order.sent_from_maker(sender).is_ok() {
+        CancelSender::Maker
+    } else if order.get_buyer_pubkey().ok() == Some(sender)
+        || order.get_seller_pubkey().ok() == Some(sender)
+    {
+        CancelSender::Taker
+    } else {
+        CancelSender::Stranger
+    }
+}
+
+/// Step of a cancel sent by `sender` in `status`. `initiated_by_sender` tells
+/// who started a pending cooperative cancel, if any
+fn cancel_step(
+    status: &Status,
+    sender: CancelSender,
+    initiated_by_sender: Option<bool>,
+) -> Result<CancelStep, MostroError> {
+    match status {
+        Status::Canceled | Status::CooperativelyCanceled | Status::CanceledByAdmin => {
+            Err(MostroCantDo(CantDoReason::OrderAlreadyCanceled))
+        }
+        Status::Pending => match sender {
+            CancelSender::Maker => Ok(CancelStep::CancelPending),
+            _ => Err(MostroCantDo(CantDoReason::IsNotYourOrder)),
+        },
+        Status::WaitingPayment | Status::WaitingBuyerInvoice => match sender {
+            CancelSender::Maker => Ok(CancelStep::CancelByMaker),
+            CancelSender::Taker => Ok(CancelStep::CancelByTaker),
+            CancelSender::Stranger => Err(MostroCantDo(CantDoReason::InvalidPubkey)),
+        },
+        Status::Active | Status::FiatSent | Status::Dispute => {
+            match (sender, initiated_by_sender) {
+                (CancelSender::Stranger, _) => Err(MostroCantDo(CantDoReason::InvalidPubkey)),
+                // The initiator sending cancel again withdraws it
+                (_, Some(true)) => Ok(CancelStep::WithdrawCooperative),
+                (_, Some(false)) => Ok(CancelStep::AcceptCooperative),
+                (_, None) => Ok(CancelStep::StartCooperative),
+            }
+        }
+        _ => Ok(CancelStep::Ignore),
+    }
+}
+
 /// Cancel an order
 pub async fn cancel_action(
     msg: Message,
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-    ln_client: &mut LndConnector,
+    ln_client: &mut dyn LightningBackend,
 ) -> Result<(), MostroError> {
+    // No other handler changes the order meanwhile
+    let _order_lock = lock_order(msg.get_inner_message_kind().id).await;
     // Get request id
     let request_id = msg.get_inner_message_kind().request_id;
     // Get order id
     let mut order = get_order(&msg, pool).await?;
 
-    if order.check_status(Status::Canceled).is_ok()
-        || order.check_status(Status::CooperativelyCanceled).is_ok()
-        || order.check_status(Status::CanceledByAdmin).is_ok()
+    let status = Status::from_str(&order.status)
+        .map_err(|_| MostroCantDo(CantDoReason::InvalidOrderStatus))?;
+    let sender = event.rumor.pubkey.to_string();
+    let initiated_by_sender = order
+        .cancel_initiator_pubkey
+        .as_ref()
+        .map(|initiator| *initiator == sender);
+    let step = cancel_step(
+        &status,
+        cancel_sender(&order, event.rumor.pubkey),
+        initiated_by_sender,
+    )?;
+    if step == CancelStep::Ignore {
+        return Ok(());
+    }
+    // Cancels giving the funds back are refused before the order changes
+    if order.hash.is_some()
+        && matches!(
+            step,
+            CancelStep::CancelByMaker | CancelStep::CancelByTaker | CancelStep::AcceptCooperative
+        )
     {
-        return Err(MostroCantDo(CantDoReason::OrderAlreadyCanceled));
+        check_funds_movable("hold invoice cancellation")?;
     }
 
-    if order.check_status(Status::Pending).is_ok() {
-        cancel_pending_order_from_maker(pool, event, &mut order, my_keys, request_id).await?;
-        return Ok(());
+    // Keep the reason given by the party canceling to share it with both parties
+    if let Some(reason) = cancel_reason(&msg) {
+        edit_cancel_reason_order(pool, order.id, Some(reason)).await?;
+    }
+
+    if step == CancelStep::CancelPending {
+        return cancel_pending_order_from_maker(pool, event, &mut order, my_keys, request_id).await;
     }
 
     // Get seller and buyer pubkey
     let seller_pubkey = order.get_seller_pubkey().map_err(MostroInternalErr)?;
     let buyer_pubkey = order.get_buyer_pubkey().map_err(MostroInternalErr)?;
+    let counterparty_pubkey = if buyer_pubkey == event.rumor.pubkey {
+        seller_pubkey
+    } else {
+        buyer_pubkey
+    };
 
-    if order.check_status(Status::WaitingPayment).is_ok()
-        || order.check_status(Status::WaitingBuyerInvoice).is_ok()
-    {
-        // Get order taker pubkey
-        let taker_pubkey = if order.creator_pubkey == seller_pubkey.to_string() {
-            buyer_pubkey
-        } else if order.creator_pubkey == buyer_pubkey.to_string() {
-            seller_pubkey
-        } else {
-            return Err(MostroInternalErr(ServiceError::InvalidPubkey));
-        };
-
-        if order.sent_from_maker(event.rumor.pubkey).is_ok() {
-            cancel_order_by_maker(
-                pool,
-                event,
-                &mut order,
-                taker_pubkey,
-                my_keys,
-                request_id,
-                ln_client,
-            )
-            .await?;
-        } else if event.rumor.pubkey == taker_pubkey {
-            cancel_order_by_taker(
-                pool,
-                event,
-                &mut order,
-                my_keys,
-                request_id,
-                ln_client,
-                taker_pubkey,
-            )
-            .await?;
-        } else {
-            return Err(MostroCantDo(CantDoReason::InvalidPubkey));
+    match step {
+        CancelStep::CancelByMaker | CancelStep::CancelByTaker => {
+            // Get order taker pubkey
+            let taker_pubkey = if order.creator_pubkey == seller_pubkey.to_string() {
+                buyer_pubkey
+            } else if order.creator_pubkey == buyer_pubkey.to_string() {
+                seller_pubkey
+            } else {
+                return Err(MostroInternalErr(ServiceError::InvalidPubkey));
+            };
+            if step == CancelStep::CancelByMaker {
+                cancel_order_by_maker(
+                    pool,
+                    event,
+                    &mut order,
+                    taker_pubkey,
+                    my_keys,
+                    request_id,
+                    ln_client,
+                )
+                .await
+            } else {
+                cancel_order_by_taker(
+                    pool,
+                    event,
+                    &mut order,
+                    my_keys,
+                    request_id,
+                    ln_client,
+                    taker_pubkey,
+                )
+                .await
+            }
         }
-    }
-
-    if order.check_status(Status::Active).is_ok()
-        || order.check_status(Status::FiatSent).is_ok()
-        || order.check_status(Status::Dispute).is_ok()
-    {
-        let counterparty_pubkey: String;
-        if buyer_pubkey == event.rumor.pubkey {
-            order.buyer_cooperativecancel = true;
-            counterparty_pubkey = seller_pubkey.to_string();
-        } else {
-            order.seller_cooperativecancel = true;
-            counterparty_pubkey = buyer_pubkey.to_string();
+        CancelStep::WithdrawCooperative => {
+            withdraw_cooperative_cancel(pool, event, order, counterparty_pubkey, request_id).await
         }
-
-        match order.cancel_initiator_pubkey {
-            Some(_) => {
+        CancelStep::StartCooperative | CancelStep::AcceptCooperative => {
+            if buyer_pubkey == event.rumor.pubkey {
+                order.buyer_cooperativecancel = true;
+            } else {
+                order.seller_cooperativecancel = true;
+            }
+            let counterparty_pubkey = counterparty_pubkey.to_string();
+            if step == CancelStep::AcceptCooperative {
                 cancel_cooperative_execution_step_2(
                     pool,
                     event,
@@ -388,9 +555,8 @@ pub async fn cancel_action(
                     my_keys,
                     ln_client,
                 )
-                .await?;
-            }
-            None => {
+                .await
+            } else {
                 cancel_cooperative_execution_step_1(
                     pool,
                     event,
@@ -398,9 +564,506 @@ pub async fn cancel_action(
                     counterparty_pubkey,
                     request_id,
                 )
-                .await?;
+                .await
             }
         }
+        CancelStep::CancelPending | CancelStep::Ignore => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::mock::MockBackend;
+
+    const TEST_RETRY_POLICY: RetryPolicy = RetryPolicy {
+        attempts: 1,
+        base_delay: std::time::Duration::ZERO,
+    };
+
+    #[tokio::test]
+    async fn test_cancel_returns_held_funds() {
+        let mut backend = MockBackend::default();
+        let order = Order {
+            hash: Some("ab".repeat(32)),
+            ..Default::default()
+        };
+        return_funds_to_seller(&mut backend, &order, &TEST_RETRY_POLICY)
+            .await
+            .unwrap();
+        assert_eq!(backend.canceled, vec!["ab".repeat(32)]);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_without_hold_invoice() {
+        let mut backend = MockBackend::default();
+        return_funds_to_seller(&mut backend, &Order::default(), &TEST_RETRY_POLICY)
+            .await
+            .unwrap();
+        assert!(backend.canceled.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_reason_round_trip() {
+        let pool = crate::db::test_pool().await;
+        let order = Order {
+            id: uuid::Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::Active.to_string(),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+        assert!(cancel_reason_payload(&pool, order.id)
+            .await
+            .unwrap()
+            .is_none());
+
+        let msg = Message::new_order(
+            Some(order.id),
+            Some(1),
+            None,
+            Action::Cancel,
+            Some(Payload::TextMessage(
+                "  Payment method unavailable ".to_string(),
+            )),
+        );
+        let reason = cancel_reason(&msg).unwrap();
+        assert_eq!(reason, "Payment method unavailable");
+        edit_cancel_reason_order(&pool, order.id, Some(reason))
+            .await
+            .unwrap();
+
+        // The order keeps the reason after other updates
+        order.clone().update(&pool).await.unwrap();
+        let payload = cancel_reason_payload(&pool, order.id).await.unwrap();
+        assert!(
+            matches!(payload, Some(Payload::TextMessage(reason)) if reason == "Payment method unavailable")
+        );
+
+        // Messages without a text payload carry no reason
+        let msg = Message::new_order(Some(order.id), Some(1), None, Action::Cancel, None);
+        assert!(cancel_reason(&msg).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_fails_when_node_fails() {
+        let mut backend = MockBackend {
+            fail: true,
+            ..Default::default()
+        };
+        let order = Order {
+            hash: Some("ab".repeat(32)),
+            ..Default::default()
+        };
+        assert!(
+            return_funds_to_seller(&mut backend, &order, &TEST_RETRY_POLICY)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_taker_cleared_on_cancel() {
+        let pool = crate::db::test_pool().await;
+        let (buyer, seller) = (
+            Keys::generate().public_key().to_string(),
+            Keys::generate().public_key().to_string(),
+        );
+        // A buy order taken by a seller who didn't pay the hold invoice
+        let order = Order {
+            id: uuid::Uuid::new_v4(),
+            kind: "buy".to_string(),
+            status: Status::WaitingPayment.to_string(),
+            amount: 1_000,
+            fee: 10,
+            hash: Some("ab".repeat(32)),
+            creator_pubkey: buyer.clone(),
+            buyer_pubkey: Some(buyer.clone()),
+            master_buyer_pubkey: Some(buyer.clone()),
+            seller_pubkey: Some(seller.clone()),
+            master_seller_pubkey: Some(seller.clone()),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+        assert_eq!(taker_role(&order), "seller");
+
+        clear_taker_data(&pool, &order).await.unwrap();
+        update_order_to_initial_state(&pool, order.id, order.amount, order.fee)
+            .await
+            .unwrap();
+        let order = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert_eq!(order.status, Status::Pending.to_string());
+        assert!(order.hash.is_none());
+        assert!(order.seller_pubkey.is_none());
+        assert!(order.master_seller_pubkey.is_none());
+        assert_eq!(order.buyer_pubkey, Some(buyer.clone()));
+        assert_eq!(order.master_buyer_pubkey, Some(buyer));
+
+        // A sell order loses its buyer instead
+        let order = Order {
+            id: uuid::Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::WaitingBuyerInvoice.to_string(),
+            creator_pubkey: seller.clone(),
+            buyer_pubkey: Some(Keys::generate().public_key().to_string()),
+            seller_pubkey: Some(seller.clone()),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+        assert_eq!(taker_role(&order), "buyer");
+        clear_taker_data(&pool, &order).await.unwrap();
+        let order = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert!(order.buyer_pubkey.is_none());
+        assert_eq!(order.seller_pubkey, Some(seller));
+    }
+
+    /// Every order status
+    const STATUSES: [Status; 15] = [
+        Status::Active,
+        Status::Canceled,
+        Status::CanceledByAdmin,
+        Status::SettledByAdmin,
+        Status::CompletedByAdmin,
+        Status::Dispute,
+        Status::Expired,
+        Status::FiatSent,
+        Status::SettledHoldInvoice,
+        Status::Pending,
+        Status::Success,
+        Status::WaitingBuyerInvoice,
+        Status::WaitingPayment,
+        Status::CooperativelyCanceled,
+        Status::InProgress,
+    ];
+    const SENDERS: [CancelSender; 3] = [
+        CancelSender::Maker,
+        CancelSender::Taker,
+        CancelSender::Stranger,
+    ];
+
+    /// Keys of the parties sending cancels in the tests
+    struct Parties {
+        maker: Keys,
+        taker: Keys,
+        stranger: Keys,
+    }
+
+    impl Parties {
+        fn keys(&self, sender: CancelSender) -> &Keys {
+            match sender {
+                CancelSender::Maker => &self.maker,
+                CancelSender::Taker => &self.taker,
+                CancelSender::Stranger => &self.stranger,
+            }
+        }
+    }
+
+    fn gift_from(keys: &Keys) -> UnwrappedGift {
+        UnwrappedGift {
+            sender: keys.public_key(),
+            rumor: UnsignedEvent::new(
+                keys.public_key(),
+                Timestamp::now(),
+                nostr_sdk::Kind::GiftWrap,
+                Vec::new(),
+                "",
+            ),
+        }
+    }
+
+    /// Order of `kind` in `status`, taken unless it is pending, with a
+    /// cooperative cancel started by `initiator` if any
+    async fn stored_order(
+        pool: &Pool<Sqlite>,
+        parties: &Parties,
+        kind: &str,
+        status: Status,
+        initiator: Option<CancelSender>,
+    ) -> Order {
+        let maker = parties.maker.public_key().to_string();
+        let taker = (status != Status::Pending).then(|| parties.taker.public_key().to_string());
+        let (buyer, seller) = if kind == "sell" {
+            (taker, Some(maker.clone()))
+        } else {
+            (Some(maker.clone()), taker)
+        };
+        let initiator = initiator.map(|sender| parties.keys(sender).public_key().to_string());
+        Order {
+            id: uuid::Uuid::new_v4(),
+            kind: kind.to_string(),
+            status: status.to_string(),
+            amount: 1_000,
+            fee: 10,
+            hash: (status != Status::Pending).then(|| "ab".repeat(32)),
+            creator_pubkey: maker,
+            buyer_cooperativecancel: initiator.is_some() && initiator == buyer,
+            seller_cooperativecancel: initiator.is_some() && initiator == seller,
+            cancel_initiator_pubkey: initiator,
+            master_buyer_pubkey: buyer.clone(),
+            buyer_pubkey: buyer,
+            master_seller_pubkey: seller.clone(),
+            seller_pubkey: seller,
+            ..Default::default()
+        }
+        .create(pool)
+        .await
+        .unwrap()
+    }
+
+    /// Every sequence of `len` cancels from any sender
+    fn sequences(len: usize) -> Vec<Vec<CancelSender>> {
+        (0..len).fold(vec![Vec::new()], |sequences, _| {
+            sequences
+                .iter()
+                .flat_map(|sequence| {
+                    SENDERS.iter().map(move |sender| {
+                        let mut sequence = sequence.clone();
+                        sequence.push(*sender);
+                        sequence
+                    })
+                })
+                .collect()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_cancel_state_machine_invariants() {
+        crate::config::init_test_settings();
+        crate::config::init_test_db_pool().await;
+        let pool = crate::db::test_pool().await;
+        let parties = Parties {
+            maker: Keys::generate(),
+            taker: Keys::generate(),
+            stranger: Keys::generate(),
+        };
+        let terminal = |status: &str| {
+            [
+                Status::Canceled,
+                Status::CooperativelyCanceled,
+                Status::CanceledByAdmin,
+            ]
+            .iter()
+            .any(|terminal| terminal.to_string() == status)
+        };
+        // Statuses a cancel can move an order out of
+        let cancelable = |status: &str| {
+            [
+                Status::Pending,
+                Status::WaitingPayment,
+                Status::WaitingBuyerInvoice,
+                Status::Active,
+                Status::FiatSent,
+                Status::Dispute,
+            ]
+            .iter()
+            .any(|cancelable| cancelable.to_string() == status)
+        };
+        let initiators = [None, Some(CancelSender::Maker), Some(CancelSender::Taker)];
+        for kind in ["sell", "buy"] {
+            for initiator in initiators {
+                for status in STATUSES {
+                    for sequence in sequences(3) {
+                        let start = stored_order(&pool, &parties, kind, status, initiator).await;
+                        let mut backend = MockBackend::default();
+                        let mut before = start.clone();
+                        for sender in sequence.iter() {
+                            let keys = parties.keys(*sender);
+                            let msg = Message::new_order(
+                                Some(start.id),
+                                None,
+                                None,
+                                Action::Cancel,
+                                None,
+                            );
+                            let result = cancel_action(
+                                msg,
+                                &gift_from(keys),
+                                &Keys::generate(),
+                                &pool,
+                                &mut backend,
+                            )
+                            .await;
+                            let after = Order::by_id(&pool, start.id).await.unwrap().unwrap();
+                            let context = format!(
+                                "{kind} {status} {initiator:?} {sequence:?}: {sender:?} {} -> {}",
+                                before.status, after.status
+                            );
+                            let unchanged = after.status == before.status
+                                && after.cancel_initiator_pubkey == before.cancel_initiator_pubkey
+                                && after.buyer_cooperativecancel == before.buyer_cooperativecancel
+                                && after.seller_cooperativecancel
+                                    == before.seller_cooperativecancel;
+                            // Refused cancels, strangers and orders out of
+                            // reach, canceled ones included, change nothing
+                            if result.is_err()
+                                || *sender == CancelSender::Stranger
+                                || !cancelable(&before.status)
+                            {
+                                assert!(unchanged, "{context}");
+                            }
+                            // The initiator can't accept their own cooperative cancel
+                            if before.cancel_initiator_pubkey == Some(keys.public_key().to_string())
+                            {
+                                assert!(
+                                    after.status == before.status
+                                        || after.status
+                                            != Status::CooperativelyCanceled.to_string(),
+                                    "{context}"
+                                );
+                            }
+                            // Only cancels and the return to the book change the status
+                            assert!(
+                                after.status == before.status
+                                    || terminal(&after.status)
+                                    || after.status == Status::Pending.to_string(),
+                                "{context}"
+                            );
+                            // Funds held go back to the seller when the trade ends
+                            if after.status != before.status && before.hash.is_some() {
+                                assert_eq!(backend.canceled, vec!["ab".repeat(32)], "{context}");
+                            }
+                            before = after;
+                        }
+                        // The hold invoice is canceled once at most
+                        assert!(backend.canceled.len() <= 1, "{start:?} {sequence:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    async fn cancel_and_reload(
+        pool: &Pool<Sqlite>,
+        event: &UnwrappedGift,
+        order_id: uuid::Uuid,
+    ) -> Order {
+        let msg = Message::new_order(Some(order_id), Some(1), None, Action::Cancel, None);
+        cancel_action(
+            msg,
+            event,
+            &Keys::generate(),
+            pool,
+            &mut MockBackend::default(),
+        )
+        .await
+        .unwrap();
+        Order::by_id(pool, order_id).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cooperative_cancel_withdrawn() {
+        let pool = crate::db::test_pool().await;
+        let (buyer, seller) = (Keys::generate(), Keys::generate());
+        let order = Order {
+            id: uuid::Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::Active.to_string(),
+            creator_pubkey: seller.public_key().to_string(),
+            buyer_pubkey: Some(buyer.public_key().to_string()),
+            seller_pubkey: Some(seller.public_key().to_string()),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+        let event = UnwrappedGift {
+            sender: buyer.public_key(),
+            rumor: UnsignedEvent::new(
+                buyer.public_key(),
+                Timestamp::now(),
+                nostr_sdk::Kind::GiftWrap,
+                Vec::new(),
+                "",
+            ),
+        };
+        // Initiated by the buyer
+        let initiated = cancel_and_reload(&pool, &event, order.id).await;
+        assert_eq!(
+            initiated.cancel_initiator_pubkey,
+            Some(buyer.public_key().to_string())
+        );
+        assert!(initiated.buyer_cooperativecancel);
+
+        // Withdrawn, both parties are told and the trade goes on
+        let withdrawn = cancel_and_reload(&pool, &event, order.id).await;
+        assert!(withdrawn.cancel_initiator_pubkey.is_none());
+        assert!(!withdrawn.buyer_cooperativecancel);
+        assert_eq!(withdrawn.status, Status::Active.to_string());
+        {
+            let queued = MESSAGE_QUEUES.queue_order_msg.read().await;
+            let notified: Vec<PublicKey> = queued
+                .iter()
+                .filter(|(msg, _)| {
+                    let kind = msg.get_inner_message_kind();
+                    // A direct message, clients don't take it for a cancel
+                    matches!(msg, Message::Dm(_))
+                        && kind.action == Action::SendDm
+                        && kind.id == Some(order.id)
+                        && matches!(
+                            &kind.payload,
+                            Some(Payload::TextMessage(text)) if text == COOPERATIVE_CANCEL_WITHDRAWN
+                        )
+                })
+                .map(|(_, pubkey)| *pubkey)
+                .collect();
+            assert_eq!(notified, vec![buyer.public_key(), seller.public_key()]);
+        }
+
+        // Initiated again
+        let initiated = cancel_and_reload(&pool, &event, order.id).await;
+        assert_eq!(
+            initiated.cancel_initiator_pubkey,
+            Some(buyer.public_key().to_string())
+        );
+        assert_eq!(initiated.status, Status::Active.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_unknown_order_answered() {
+        let pool = crate::db::test_pool().await;
+        let keys = Keys::generate();
+        let event = UnwrappedGift {
+            sender: keys.public_key(),
+            rumor: UnsignedEvent::new(
+                keys.public_key(),
+                Timestamp::now(),
+                nostr_sdk::Kind::GiftWrap,
+                Vec::new(),
+                "",
+            ),
+        };
+        let order_id = uuid::Uuid::new_v4();
+        let msg = Message::new_order(Some(order_id), Some(7), None, Action::Cancel, None);
+
+        let err = cancel_action(
+            msg.clone(),
+            &event,
+            &Keys::generate(),
+            &pool,
+            &mut MockBackend::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, MostroCantDo(CantDoReason::NotFound)));
+
+        // The sender gets a cant do instead of no answer
+        crate::app::manage_errors(err, msg, event.clone(), &Action::Cancel).await;
+        let queued = MESSAGE_QUEUES.queue_order_cantdo.read().await;
+        assert!(queued.iter().any(|(msg, pubkey)| {
+            let kind = msg.get_inner_message_kind();
+            *pubkey == keys.public_key()
+                && kind.id == Some(order_id)
+                && kind.request_id == Some(7)
+                && matches!(
+                    kind.payload,
+                    Some(Payload::CantDo(Some(CantDoReason::NotFound)))
+                )
+        }));
     }
-    Ok(())
 }
@@ -2,8 +2,13 @@
 //! It provides mechanisms for users to initiate disputes, notify counterparties,
 //! and publish dispute events to the network.
 
-use crate::db::find_dispute_by_order_id;
+use crate::config::settings::Settings;
+use crate::db::{
+    add_dispute_escalation, find_dispute_by_order_id, find_solver_pubkeys,
+    find_unescalated_disputes,
+};
 use crate::nip33::new_event;
+use crate::order_locks::{lock_and_reload, lock_order};
 use crate::util::{enqueue_order_msg, get_nostr_client, get_order};
 use mostro_core::prelude::*;
 use nostr::nips::nip59::UnwrappedGift;
@@ -11,6 +16,7 @@ use nostr_sdk::prelude::*;
 use sqlx::{Pool, Sqlite};
 use sqlx_crud::traits::Crud;
 use std::borrow::Cow;
+use std::str::FromStr;
 use uuid::Uuid;
 
 /// Publishes a dispute event to the Nostr network.
@@ -79,20 +85,68 @@ fn get_counterpart_info(sender: &str, buyer: &str, seller: &str) -> Result<bool,
     }
 }
 
+/// Order statuses allowing disputes when the operator doesn't configure them.
+/// Only these statuses have a running trade with the seller funds held, so
+/// the setting can narrow them but can't add others.
+const DEFAULT_DISPUTE_STATUSES: [Status; 2] = [Status::Active, Status::FiatSent];
+
+/// Statuses an order must be in to be disputed, from `dispute_eligible_statuses`
+/// setting or the default ones. Unknown status names and statuses without a
+/// running trade are ignored.
+fn dispute_eligible_statuses(configured: &[String]) -> Vec<Status> {
+    let statuses: Vec<Status> = configured
+        .iter()
+        .filter_map(|status| match Status::from_str(status) {
+            Ok(parsed) if DEFAULT_DISPUTE_STATUSES.contains(&parsed) => Some(parsed),
+            Ok(_) => {
+                tracing::warn!(
+                    "Ignoring dispute eligible status {}, the order has no running trade",
+                    status
+                );
+                None
+            }
+            Err(_) => {
+                tracing::warn!("Ignoring unknown dispute eligible status {}", status);
+                None
+            }
+        })
+        .collect();
+    if statuses.is_empty() {
+        DEFAULT_DISPUTE_STATUSES.to_vec()
+    } else {
+        statuses
+    }
+}
+
+/// Checks the order reached a status where a dispute makes sense, orders that
+/// never became active can't be disputed
+fn check_dispute_eligible(order: &Order, eligible: &[Status]) -> Result<(), MostroError> {
+    if eligible
+        .iter()
+        .any(|status| order.check_status(*status).is_ok())
+    {
+        Ok(())
+    } else {
+        tracing::info!(
+            "Order Id {}: dispute not allowed with status {}",
+            order.id,
+            order.status
+        );
+        Err(MostroCantDo(CantDoReason::NotAllowedByStatus))
+    }
+}
+
 /// Validates and retrieves an order from the database.
 ///
 /// Checks that:
 /// - The order exists
-/// - The order status allows disputes (Active or FiatSent)
+/// - The order status allows disputes (Active or FiatSent unless configured)
 async fn get_valid_order(pool: &Pool<Sqlite>, msg: &Message) -> Result<Order, MostroError> {
     // Try to fetch the order from the database
     let order = get_order(msg, pool).await?;
 
-    // Check if the order status is Active or FiatSent
-    if order.check_status(Status::Active).is_err() && order.check_status(Status::FiatSent).is_err()
-    {
-        return Err(MostroCantDo(CantDoReason::NotAllowedByStatus));
-    }
+    let eligible = dispute_eligible_statuses(&Settings::get_mostro().dispute_eligible_statuses);
+    check_dispute_eligible(&order, &eligible)?;
 
     Ok(order)
 }
@@ -135,6 +189,139 @@ async fn notify_dispute_to_users(
     Ok(())
 }
 
+/// Opens a dispute nobody asked for, e.g. on an order past its completion
+/// deadline, so a solver looks at it before the hold invoice expires. Both
+/// parties are notified with their token. None when the order moved since it
+/// was found or got a dispute meanwhile.
+pub async fn open_automatic_dispute(
+    pool: &Pool<Sqlite>,
+    my_keys: &Keys,
+    found: Order,
+) -> Result<Option<Dispute>, MostroError> {
+    let (_order_lock, order) = lock_and_reload(pool, found.id).await?;
+    let Some(mut order) = order.filter(|order| order.status == found.status) else {
+        return Ok(None);
+    };
+    if find_dispute_by_order_id(pool, order.id).await.is_ok() {
+        return Ok(None);
+    }
+    let buyer_pubkey = order.get_buyer_pubkey().map_err(MostroInternalErr)?;
+    let seller_pubkey = order.get_seller_pubkey().map_err(MostroInternalErr)?;
+    let mut dispute = Dispute::new(order.id, order.status.clone());
+
+    order.status = Status::Dispute.to_string();
+    let order = order
+        .update(pool)
+        .await
+        .map_err(|cause| MostroInternalErr(ServiceError::DbAccessError(cause.to_string())))?;
+
+    // No party initiated it, the buyer token takes the initiator slot
+    let (buyer_token, seller_token) = dispute.create_tokens(true);
+    let dispute = dispute
+        .create(pool)
+        .await
+        .map_err(|cause| MostroInternalErr(ServiceError::DbAccessError(cause.to_string())))?;
+
+    for (token, pubkey) in [(buyer_token, buyer_pubkey), (seller_token, seller_pubkey)] {
+        enqueue_order_msg(
+            None,
+            Some(order.id),
+            Action::DisputeInitiatedByPeer,
+            Some(Payload::Dispute(dispute.id, token, None)),
+            pubkey,
+            None,
+        )
+        .await;
+    }
+
+    // The dispute is already open, solvers can still find it in the database
+    if let Err(e) = publish_dispute_event(&dispute, my_keys).await {
+        tracing::error!("Order Id {}: {e}", order.id);
+    }
+
+    Ok(Some(dispute))
+}
+
+/// Opens a dispute on an order active for `timeout_secs` without the buyer
+/// sending fiat, the solvers are told about it
+pub async fn open_stale_trade_dispute(
+    pool: &Pool<Sqlite>,
+    my_keys: &Keys,
+    order: Order,
+    timeout_secs: u64,
+) -> Result<Option<Dispute>, MostroError> {
+    let Some(dispute) = open_automatic_dispute(pool, my_keys, order).await? else {
+        return Ok(None);
+    };
+    let text = format!(
+        "Order {} was active for {} minutes without fiat sent, a dispute was opened",
+        dispute.order_id,
+        timeout_secs / 60
+    );
+    for solver in find_solver_pubkeys(pool).await? {
+        let Ok(solver) = PublicKey::from_str(&solver) else {
+            continue;
+        };
+        enqueue_order_msg(
+            None,
+            Some(dispute.order_id),
+            Action::Dispute,
+            Some(Payload::TextMessage(text.clone())),
+            solver,
+            None,
+        )
+        .await;
+    }
+    Ok(Some(dispute))
+}
+
+/// Dispute nobody took in time, reported to the solvers
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisputeEscalation {
+    pub order_id: Uuid,
+    pub waiting_secs: i64,
+    pub solvers: Vec<PublicKey>,
+}
+
+impl DisputeEscalation {
+    pub fn text(&self) -> String {
+        format!(
+            "Dispute of order {} is waiting for a solver for {} minutes",
+            self.order_id,
+            self.waiting_secs / 60
+        )
+    }
+}
+
+/// Disputes unassigned for `escalation_secs`, each dispute is escalated once.
+/// Disputes taken by a solver before are left out.
+pub async fn escalate_unassigned_disputes(
+    pool: &Pool<Sqlite>,
+    now: i64,
+    escalation_secs: u64,
+) -> Result<Vec<DisputeEscalation>, MostroError> {
+    let disputes = find_unescalated_disputes(pool, now - escalation_secs as i64).await?;
+    if disputes.is_empty() {
+        return Ok(Vec::new());
+    }
+    let solvers: Vec<PublicKey> = find_solver_pubkeys(pool)
+        .await?
+        .iter()
+        .filter_map(|pubkey| PublicKey::from_str(pubkey).ok())
+        .collect();
+
+    let mut escalations = Vec::new();
+    for dispute in disputes {
+        add_dispute_escalation(pool, dispute.id, now).await?;
+        escalations.push(DisputeEscalation {
+            order_id: dispute.order_id,
+            waiting_secs: now - dispute.created_at,
+            solvers: solvers.clone(),
+        });
+    }
+    Ok(escalations)
+}
+
 /// Main handler for dispute actions.
 ///
 /// This function:
@@ -150,6 +337,8 @@ pub async fn dispute_action(
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
 ) -> Result<(), MostroError> {
+    // No other handler changes the order meanwhile
+    let _order_lock = lock_order(msg.get_inner_message_kind().id).await;
     let order_id = if let Some(order_id) = msg.get_inner_message_kind().id {
         order_id
     } else {
@@ -235,3 +424,237 @@ pub async fn dispute_action(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unassigned_dispute_escalated_once() {
+        let pool = crate::db::test_pool().await;
+        let solver = Keys::generate().public_key();
+        sqlx::query("INSERT INTO users (pubkey, is_solver, created_at) VALUES (?1, 1, 0)")
+            .bind(solver.to_hex())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let mut dispute = Dispute::new(Uuid::new_v4(), Status::Active.to_string());
+        dispute.create_tokens(true);
+        let dispute = dispute.create(&pool).await.unwrap();
+        let mut taken = Dispute::new(Uuid::new_v4(), Status::Active.to_string());
+        taken.create_tokens(true);
+        taken.status = DisputeStatus::InProgress.to_string();
+        taken.create(&pool).await.unwrap();
+
+        // Not waiting long enough yet
+        let opened_at = dispute.created_at;
+        let escalations = escalate_unassigned_disputes(&pool, opened_at + 60, 600)
+            .await
+            .unwrap();
+        assert!(escalations.is_empty());
+
+        // Solvers hear about the unassigned dispute only
+        let escalations = escalate_unassigned_disputes(&pool, opened_at + 601, 600)
+            .await
+            .unwrap();
+        assert_eq!(
+            escalations,
+            vec![DisputeEscalation {
+                order_id: dispute.order_id,
+                waiting_secs: 601,
+                solvers: vec![solver],
+            }]
+        );
+        // And only once
+        let escalations = escalate_unassigned_disputes(&pool, opened_at + 1200, 600)
+            .await
+            .unwrap();
+        assert!(escalations.is_empty());
+    }
+
+    fn order_with_status(status: Status) -> Order {
+        Order {
+            id: Uuid::new_v4(),
+            status: status.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_dispute_from_pending_rejected() {
+        let eligible = dispute_eligible_statuses(&[]);
+        for status in [
+            Status::Pending,
+            Status::WaitingBuyerInvoice,
+            Status::WaitingPayment,
+        ] {
+            assert!(check_dispute_eligible(&order_with_status(status), &eligible).is_err());
+        }
+    }
+
+    #[test]
+    fn test_dispute_from_active_accepted() {
+        let eligible = dispute_eligible_statuses(&[]);
+        assert!(check_dispute_eligible(&order_with_status(Status::Active), &eligible).is_ok());
+        assert!(check_dispute_eligible(&order_with_status(Status::FiatSent), &eligible).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_order_past_completion_deadline_disputed() {
+        let pool = crate::db::test_pool().await;
+        let now = 1_700_000_000;
+        let deadline = 6 * 3600;
+        let held_order = |invoice_held_at| Order {
+            id: Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::FiatSent.to_string(),
+            buyer_pubkey: Some(Keys::generate().public_key().to_string()),
+            seller_pubkey: Some(Keys::generate().public_key().to_string()),
+            invoice_held_at,
+            ..Default::default()
+        };
+        let overdue = held_order(now - deadline - 60).create(&pool).await.unwrap();
+        held_order(now - 60).create(&pool).await.unwrap();
+
+        let orders = crate::db::find_orders_past_completion_deadline(&pool, now - deadline)
+            .await
+            .unwrap();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].id, overdue.id);
+
+        let dispute = open_automatic_dispute(&pool, &Keys::generate(), orders[0].clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(dispute.order_id, overdue.id);
+        let order = Order::by_id(&pool, overdue.id).await.unwrap().unwrap();
+        assert_eq!(order.status, Status::Dispute.to_string());
+
+        // Disputed orders are not picked again
+        assert!(
+            crate::db::find_orders_past_completion_deadline(&pool, now - deadline)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_automatic_dispute_on_reloaded_order() {
+        let pool = crate::db::test_pool().await;
+        let found = Order {
+            id: Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::FiatSent.to_string(),
+            buyer_pubkey: Some(Keys::generate().public_key().to_string()),
+            seller_pubkey: Some(Keys::generate().public_key().to_string()),
+            invoice_held_at: 1_700_000_000,
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+        // Released after the sweep found it
+        let mut released = found.clone();
+        released.status = Status::SettledHoldInvoice.to_string();
+        released.update(&pool).await.unwrap();
+
+        assert!(
+            open_automatic_dispute(&pool, &Keys::generate(), found.clone())
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(find_dispute_by_order_id(&pool, found.id).await.is_err());
+        let order = Order::by_id(&pool, found.id).await.unwrap().unwrap();
+        assert_eq!(order.status, Status::SettledHoldInvoice.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_stale_active_trade_disputed() {
+        let pool = crate::db::test_pool().await;
+        let solver = Keys::generate().public_key();
+        sqlx::query("INSERT INTO users (pubkey, is_solver, created_at) VALUES (?1, 1, 0)")
+            .bind(solver.to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+        let timeout: u64 = 3 * 3600;
+        let timeout_secs = timeout as i64;
+        let now: i64 = 1_700_000_000;
+        let held_order = |status: Status| Order {
+            id: Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: status.to_string(),
+            buyer_pubkey: Some(Keys::generate().public_key().to_string()),
+            seller_pubkey: Some(Keys::generate().public_key().to_string()),
+            invoice_held_at: now,
+            ..Default::default()
+        };
+        let active = held_order(Status::Active).create(&pool).await.unwrap();
+        // The buyer sent fiat, the timer no longer applies
+        held_order(Status::FiatSent).create(&pool).await.unwrap();
+
+        // Not stale yet
+        let before_timeout = now + timeout_secs - 60;
+        assert!(
+            crate::db::find_stale_active_orders(&pool, before_timeout - timeout_secs)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        // Past the timeout only the active order is disputed
+        let after_timeout = now + timeout_secs + 60;
+        let orders = crate::db::find_stale_active_orders(&pool, after_timeout - timeout_secs)
+            .await
+            .unwrap();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].id, active.id);
+        let dispute =
+            open_stale_trade_dispute(&pool, &Keys::generate(), orders[0].clone(), timeout)
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(dispute.order_id, active.id);
+        let order = Order::by_id(&pool, active.id).await.unwrap().unwrap();
+        assert_eq!(order.status, Status::Dispute.to_string());
+        {
+            let queued = crate::config::MESSAGE_QUEUES.queue_order_msg.read().await;
+            assert!(queued.iter().any(|(msg, pubkey)| {
+                let kind = msg.get_inner_message_kind();
+                *pubkey == solver
+                    && kind.id == Some(active.id)
+                    && matches!(kind.action, Action::Dispute)
+            }));
+        }
+        assert!(
+            crate::db::find_stale_active_orders(&pool, after_timeout - timeout_secs)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_configured_dispute_statuses() {
+        let eligible = dispute_eligible_statuses(&["fiat-sent".to_string(), "bogus".to_string()]);
+        assert_eq!(eligible, vec![Status::FiatSent]);
+        assert!(check_dispute_eligible(&order_with_status(Status::Active), &eligible).is_err());
+        assert!(check_dispute_eligible(&order_with_status(Status::FiatSent), &eligible).is_ok());
+
+        // Statuses without a running trade can't be made eligible
+        let eligible = dispute_eligible_statuses(&[
+            "pending".to_string(),
+            "waiting-payment".to_string(),
+            "active".to_string(),
+        ]);
+        assert_eq!(eligible, vec![Status::Active]);
+        assert!(check_dispute_eligible(&order_with_status(Status::Pending), &eligible).is_err());
+        assert_eq!(
+            dispute_eligible_statuses(&["pending".to_string(), "success".to_string()]),
+            DEFAULT_DISPUTE_STATUSES.to_vec()
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use crate::db::transition_order_status;
+use crate::order_locks::lock_order;
 use crate::util::{enqueue_order_msg, get_order, update_order_event};
 use mostro_core::prelude::*;
 use nostr::nips::nip59::UnwrappedGift;
@@ -12,6 +14,8 @@ pub async fn fiat_sent_action(
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
 ) -> Result<(), MostroError> {
+    // No other handler changes the order meanwhile
+    let _order_lock = lock_order(msg.get_inner_message_kind().id).await;
     // Get order
     let order = get_order(&msg, pool).await?;
 
@@ -32,6 +36,12 @@ pub async fn fiat_sent_action(
         .get_next_trade_key()
         .map_err(MostroInternalErr)?;
 
+    // A repeated fiat sent message finds the order already moved, only the
+    // first one notifies the parties
+    if !transition_order_status(pool, order.id, Status::Active, Status::FiatSent).await? {
+        return Err(MostroCantDo(CantDoReason::NotAllowedByStatus));
+    }
+
     // We publish a new replaceable kind nostr event with the status updated
     // and update on local database the status and new event id
     let mut order_updated = update_order_event(my_keys, Status::FiatSent, &order)
@@ -91,3 +101,59 @@ pub async fn fiat_sent_action(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MESSAGE_QUEUES;
+    use crate::db::test_pool;
+
+    #[tokio::test]
+    async fn test_fiat_sent_twice_rejected() {
+        let pool = test_pool().await;
+        let buyer = Keys::generate();
+        let order = Order {
+            id: uuid::Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::Active.to_string(),
+            buyer_pubkey: Some(buyer.public_key().to_string()),
+            seller_pubkey: Some(Keys::generate().public_key().to_string()),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+
+        // The first message moves the order, a repeated one finds it moved
+        assert!(
+            transition_order_status(&pool, order.id, Status::Active, Status::FiatSent)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !transition_order_status(&pool, order.id, Status::Active, Status::FiatSent)
+                .await
+                .unwrap()
+        );
+
+        let msg = Message::new_order(Some(order.id), Some(1), None, Action::FiatSent, None);
+        let event = UnwrappedGift {
+            sender: buyer.public_key(),
+            rumor: UnsignedEvent::new(
+                buyer.public_key(),
+                Timestamp::now(),
+                nostr_sdk::Kind::GiftWrap,
+                Vec::new(),
+                "",
+            ),
+        };
+        let result = fiat_sent_action(msg, &event, &Keys::generate(), &pool).await;
+        assert!(matches!(result, Err(MostroCantDo(_))));
+
+        // Nobody is notified again
+        let queued = MESSAGE_QUEUES.queue_order_msg.read().await;
+        assert!(!queued
+            .iter()
+            .any(|(message, _)| message.get_inner_message_kind().id == Some(order.id)));
+    }
+}
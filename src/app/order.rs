@@ -1,6 +1,24 @@
+use crate::analytics::{record_rate_event, RateEvent};
+use crate::app::accept_terms::check_terms_accepted;
+use crate::bitcoin_price::BitcoinPriceManager;
+use crate::config::reload::hot_settings;
 use crate::config::settings::Settings;
-use crate::db::update_user_trade_index;
-use crate::util::{get_bitcoin_price, publish_order, validate_invoice};
+use crate::db::{
+    add_order_invoice_expiry, add_order_memo, add_order_min_taker_rating,
+    add_order_payment_methods, add_order_price_band, find_order_by_creation_event,
+    update_user_trade_index,
+};
+use crate::drain::check_not_draining;
+use crate::lightning::invoice::decode_invoice;
+use crate::models::{PaymentMethod, PriceBand};
+use crate::nip33::{
+    min_taker_rating_tag, payment_methods_tag, price_attestation_event, price_attestation_tag,
+};
+use crate::rate_limiter::check_recreate_cooldown;
+use crate::util::{
+    check_active_orders_limit, check_fiat_code_enabled, check_not_node_pubkey, enqueue_order_msg,
+    get_fee, get_memo, get_order_extension, publish_order, rumor_event_id, validate_invoice,
+};
 use mostro_core::prelude::*;
 use nostr::nips::nip59::UnwrappedGift;
 use nostr_sdk::prelude::*;
@@ -11,36 +29,264 @@ async fn calculate_and_check_quote(
     order: &SmallOrder,
     fiat_amount: &i64,
 ) -> Result<(), MostroError> {
-    // Get mostro settings
-    let mostro_settings = Settings::get_mostro();
-    // Calculate quote
-    let quote = match order.amount {
-        0 => match get_bitcoin_price(&order.fiat_code) {
-            Ok(price) => {
-                let quote = *fiat_amount as f64 / price;
-                (quote * 1E8) as i64
-            }
-            Err(_) => {
-                return Err(MostroInternalErr(ServiceError::NoAPIResponse));
-            }
-        },
-        _ => order.amount,
+    // Stale prices during volatility would create orders at bad amounts
+    let price = match order.amount {
+        0 => Some(BitcoinPriceManager::get_fresh_price(
+            &order.fiat_code,
+            Settings::get_mostro().max_price_age_secs,
+        )?),
+        _ => None,
     };
 
+    // Limits can change on a settings reload
+    let limits = hot_settings();
+    check_quote_limits(
+        quote_sats(order, *fiat_amount, price),
+        limits.max_order_amount,
+        limits.min_payment_amount,
+    )
+}
+
+/// Sats for `fiat_amount` of the order. Market price orders use `price`, fixed
+/// amount orders have a single fiat amount worth their amount, ranges are
+/// always priced at market
+fn quote_sats(order: &SmallOrder, fiat_amount: i64, price: Option<f64>) -> i64 {
+    match price {
+        Some(price) => (fiat_amount as f64 / price * 1E8) as i64,
+        None => order.amount,
+    }
+}
+
+/// Checks a quote in sats is inside the global order limits
+fn check_quote_limits(
+    quote: i64,
+    max_order_amount: u32,
+    min_payment_amount: u32,
+) -> Result<(), MostroError> {
     // Check amount is positive - extra safety check
     if quote < 0 {
         return Err(MostroCantDo(CantDoReason::InvalidAmount));
     }
 
-    if quote > mostro_settings.max_order_amount as i64
-        || quote < mostro_settings.min_payment_amount as i64
-    {
+    if quote > max_order_amount as i64 || quote < min_payment_amount as i64 {
         return Err(MostroCantDo(CantDoReason::OutOfRangeSatsAmount));
     }
 
     Ok(())
 }
 
+/// Checks the amount fields of a new order form one of the valid shapes and
+/// returns the fiat amounts to quote:
+/// - single amount: `fiat_amount` > 0 and no range
+/// - range: 0 < `min_amount` < `max_amount` and no `fiat_amount`
+///
+/// Either shape has a fixed `amount` in sats without premium, or a zero amount
+/// priced at market with an optional premium. A zero min or max is unset, a
+/// range with only one of them set is refused.
+fn check_order_fields(order: &SmallOrder) -> Result<Vec<i64>, MostroError> {
+    if order.amount < 0 {
+        return Err(MostroCantDo(CantDoReason::InvalidAmount));
+    }
+    // The premium only applies to market price orders
+    if order.amount != 0 && order.premium != 0 {
+        return Err(MostroCantDo(CantDoReason::InvalidParameters));
+    }
+    let min_amount = order.min_amount.filter(|amount| *amount != 0);
+    let max_amount = order.max_amount.filter(|amount| *amount != 0);
+    match (min_amount, max_amount) {
+        (None, None) => {
+            if order.fiat_amount <= 0 {
+                return Err(MostroCantDo(CantDoReason::InvalidAmount));
+            }
+            Ok(vec![order.fiat_amount])
+        }
+        (Some(min), Some(max)) => {
+            // A fiat amount would make the range ambiguous
+            if order.fiat_amount != 0 {
+                return Err(MostroCantDo(CantDoReason::InvalidParameters));
+            }
+            if order.amount != 0 || min < 0 || min >= max {
+                return Err(MostroCantDo(CantDoReason::InvalidAmount));
+            }
+            Ok(vec![min, max])
+        }
+        // Half a range, or a range starting at zero
+        _ => Err(MostroCantDo(CantDoReason::InvalidAmount)),
+    }
+}
+
+/// Hold invoice expiry `requested` by the creator of the order. Only the seller
+/// pays the hold invoice, so the expiry asked by a buyer making an order is
+/// ignored and the taker gets the global one.
+fn seller_invoice_expiry(order: &SmallOrder, requested: Option<u64>) -> Option<u64> {
+    match order.kind {
+        Some(mostro_core::order::Kind::Sell) => requested,
+        _ => None,
+    }
+}
+
+/// Checks a buyer invoice sent with a fixed amount order is for `expected_sats`,
+/// the order amount minus the buyer fee. Invoices without amount and
+/// lightning addresses are accepted.
+fn check_invoice_amount(payment_request: &str, expected_sats: u64) -> Result<(), MostroError> {
+    let Ok(invoice) = decode_invoice(payment_request) else {
+        return Ok(());
+    };
+    match invoice.amount_milli_satoshis() {
+        Some(msat) if msat / 1000 != expected_sats => {
+            tracing::info!(
+                "Invoice of {} sats for an order paying {expected_sats}",
+                msat / 1000
+            );
+            Err(MostroCantDo(CantDoReason::InvalidAmount))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks the expiration requested by the creator is inside the operator window
+fn check_order_duration(
+    expires_at: Option<i64>,
+    now: i64,
+    min_duration: u32,
+    max_duration: u32,
+) -> Result<(), MostroError> {
+    let Some(expires_at) = expires_at else {
+        return Ok(());
+    };
+    let duration = expires_at - now;
+    if min_duration > 0 && duration < min_duration as i64 {
+        tracing::info!("Order duration {duration}s is below the minimum {min_duration}s");
+        return Err(MostroCantDo(CantDoReason::InvalidParameters));
+    }
+    if max_duration > 0 && duration > max_duration as i64 {
+        tracing::info!("Order duration {duration}s is above the maximum {max_duration}s");
+        return Err(MostroCantDo(CantDoReason::InvalidParameters));
+    }
+    Ok(())
+}
+
+/// Checks the order currency is not in the operator deny-list, codes are
+/// compared ignoring case
+fn check_fiat_code(fiat_code: &str, denied_fiat_codes: &[String]) -> Result<(), MostroError> {
+    let fiat_code = fiat_code.trim();
+    if denied_fiat_codes
+        .iter()
+        .any(|denied| denied.trim().eq_ignore_ascii_case(fiat_code))
+    {
+        tracing::info!("Orders in {fiat_code} are not allowed by this Mostro");
+        return Err(MostroCantDo(CantDoReason::InvalidParameters));
+    }
+    Ok(())
+}
+
+/// Checks the order currency is supported and returns it uppercased. The
+/// operator allow-list is used when set, otherwise the currencies quoted by
+/// the price provider. Nothing is rejected before the first prices update.
+fn check_supported_fiat_code(
+    fiat_code: &str,
+    allowed_fiat_codes: &[String],
+    quoted_fiat_codes: &[String],
+) -> Result<String, MostroError> {
+    let fiat_code = fiat_code.trim().to_uppercase();
+    let supported = if allowed_fiat_codes.is_empty() {
+        quoted_fiat_codes
+    } else {
+        allowed_fiat_codes
+    };
+    if !supported.is_empty()
+        && !supported
+            .iter()
+            .any(|code| code.trim().eq_ignore_ascii_case(&fiat_code))
+    {
+        tracing::info!("Currency {fiat_code} is not supported by this Mostro");
+        return Err(MostroCantDo(CantDoReason::InvalidParameters));
+    }
+    Ok(fiat_code)
+}
+
+/// Checks the premium requested by the creator is inside the operator bounds
+fn check_premium(premium: i64, min_premium: i64, max_premium: i64) -> Result<(), MostroError> {
+    if premium < min_premium || premium > max_premium {
+        tracing::info!("Premium {premium}% is outside the {min_premium}% to {max_premium}% range");
+        return Err(MostroCantDo(CantDoReason::InvalidParameters));
+    }
+    Ok(())
+}
+
+/// Snap a fiat amount to the operator grid, in reject mode off grid amounts are
+/// refused. Fiat amounts are whole units so a grid of 0 or 1 leaves them as is.
+fn snap_fiat_amount(amount: i64, grid: u32, reject: bool) -> Result<i64, MostroError> {
+    let grid = grid as i64;
+    if grid <= 1 || amount % grid == 0 {
+        return Ok(amount);
+    }
+    if reject {
+        tracing::info!("Fiat amount {amount} is not a multiple of {grid}");
+        return Err(MostroCantDo(CantDoReason::InvalidAmount));
+    }
+    let snapped = (amount + grid / 2) / grid * grid;
+    // A grid coarser than the amount would turn it into zero
+    if snapped <= 0 {
+        return Err(MostroCantDo(CantDoReason::InvalidAmount));
+    }
+    Ok(snapped)
+}
+
+/// Snap the fiat amounts of an order to the operator grid, range limits included
+fn apply_fiat_rounding(order: &mut SmallOrder, grid: u32, reject: bool) -> Result<(), MostroError> {
+    // Range orders have no single fiat amount
+    if order.fiat_amount != 0 {
+        order.fiat_amount = snap_fiat_amount(order.fiat_amount, grid, reject)?;
+    }
+    if let Some(min_amount) = order.min_amount {
+        order.min_amount = Some(snap_fiat_amount(min_amount, grid, reject)?);
+    }
+    if let Some(max_amount) = order.max_amount {
+        order.max_amount = Some(snap_fiat_amount(max_amount, grid, reject)?);
+    }
+    Ok(())
+}
+
+/// Only one pricing mode can be set: a price band requires a market price order
+/// without premium
+fn check_price_band(order: &SmallOrder, band: &PriceBand) -> Result<(), MostroError> {
+    if !Settings::get_mostro().price_band_orders {
+        return Err(MostroCantDo(CantDoReason::InvalidParameters));
+    }
+    if order.amount != 0 || order.premium != 0 {
+        return Err(MostroCantDo(CantDoReason::InvalidParameters));
+    }
+    band.validate().map_err(MostroCantDo)
+}
+
+/// Send again the confirmation of the order created from `creation_event_id`
+async fn confirm_existing_order(
+    pool: &Pool<Sqlite>,
+    msg: &Message,
+    event: &UnwrappedGift,
+    creation_event_id: &str,
+) -> Result<(), MostroError> {
+    let Some(existing) = find_order_by_creation_event(pool, creation_event_id).await? else {
+        return Err(MostroInternalErr(ServiceError::InvalidOrderId));
+    };
+    tracing::info!(
+        "Order Id {}: already created from event {}, resending confirmation",
+        existing.id,
+        creation_event_id
+    );
+    enqueue_order_msg(
+        msg.get_inner_message_kind().request_id,
+        Some(existing.id),
+        Action::NewOrder,
+        Some(Payload::Order(existing.as_new_order())),
+        event.rumor.pubkey,
+        msg.get_inner_message_kind().trade_index,
+    )
+    .await;
+    Ok(())
+}
+
 /// Processes a trading order message by validating, updating, and publishing the order.
 ///
 /// This asynchronous function inspects the provided message for an order and, if found, proceeds to:
@@ -91,20 +337,102 @@ pub async fn order_action(
     let request_id = msg.get_inner_message_kind().request_id;
 
     if let Some(order) = msg.get_inner_message_kind().get_order() {
-        // Validate invoice
-        let _invoice = validate_invoice(&msg, &Order::from(order.clone())).await?;
+        // The node can't be the creator of an order
+        check_not_node_pubkey(my_keys, &[event.sender, event.rumor.pubkey])?;
 
-        // Default case single amount
-        let mut amount_vec = vec![order.fiat_amount];
-        // Get max and and min amount in case of range order
-        // in case of single order do like usual
-        if let Err(cause) = order.check_range_order_limits(&mut amount_vec) {
-            return Err(MostroCantDo(cause));
+        // A relay replaying the same event must not create a second order
+        let creation_event_id = rumor_event_id(event);
+        if find_order_by_creation_event(pool, &creation_event_id)
+            .await?
+            .is_some()
+        {
+            return confirm_existing_order(pool, &msg, event, &creation_event_id).await;
         }
 
-        // Check if zero amount with premium
-        if let Err(cause) = order.check_zero_amount_with_premium() {
-            return Err(MostroCantDo(cause));
+        // No new orders while the node drains for maintenance
+        check_not_draining()?;
+        // Users who just canceled their own order wait before creating another
+        check_recreate_cooldown(&event.sender)?;
+
+        // Operator terms of service must be accepted before creating orders
+        check_terms_accepted(pool, event).await?;
+
+        // Currencies excluded by the operator
+        let mostro_settings = Settings::get_mostro();
+        check_fiat_code(&order.fiat_code, &mostro_settings.denied_fiat_codes)?;
+
+        // Unknown currencies can't be priced, codes are stored uppercased
+        let mut order = order.clone();
+        order.fiat_code = check_supported_fiat_code(
+            &order.fiat_code,
+            &mostro_settings.allowed_fiat_codes,
+            &BitcoinPriceManager::supported_currencies(),
+        )?;
+        // Currencies turned off by the operator at runtime
+        check_fiat_code_enabled(pool, &order.fiat_code).await?;
+        // Each user has a limit of orders open or being traded
+        check_active_orders_limit(pool, &event.sender).await?;
+
+        // Validate invoice, with a fixed amount it must be for what the buyer gets
+        let mut new_order = Order::from(order.clone());
+        if order.amount > 0 {
+            new_order.fee = get_fee(order.amount);
+            if let Some(pr) = msg.get_inner_message_kind().get_payment_request() {
+                check_invoice_amount(&pr, (new_order.amount - new_order.fee) as u64)?;
+            }
+        }
+        let _invoice = validate_invoice(&msg, &new_order).await?;
+
+        // Snap fiat amounts to the operator grid
+        apply_fiat_rounding(
+            &mut order,
+            mostro_settings.fiat_rounding_grid,
+            mostro_settings.fiat_rounding_reject,
+        )?;
+        let order = &order;
+
+        // Fiat amount of a single order or both ends of a range
+        let amount_vec = check_order_fields(order)?;
+
+        // Check premium is inside the operator bounds
+        check_premium(
+            order.premium,
+            mostro_settings.min_premium,
+            mostro_settings.max_premium,
+        )?;
+
+        // Check expiration requested by the creator
+        check_order_duration(
+            order.expires_at,
+            Timestamp::now().as_u64() as i64,
+            mostro_settings.min_order_duration_seconds,
+            mostro_settings.max_order_duration_seconds,
+        )?;
+
+        // Price band is an alternative to premium for market price orders
+        let price_band = get_order_extension::<PriceBand>(event, "price_band");
+        if let Some(band) = &price_band {
+            check_price_band(order, band)?;
+        }
+
+        // Payment methods the maker restricts the order to, takers declare theirs
+        let payment_methods = get_order_extension::<serde_json::Value>(event, "payment_methods")
+            .map(PaymentMethod::parse_set)
+            .transpose()
+            .map_err(MostroCantDo)?;
+        if let Some(methods) = &payment_methods {
+            PaymentMethod::check_listed(methods, &order.payment_method).map_err(MostroCantDo)?;
+        }
+
+        // Memo for the taker, delivered when the order is taken
+        let memo = get_memo(event, true)?;
+
+        // Minimum rating the taker needs, on the same scale as ratings
+        let min_taker_rating = get_order_extension::<f64>(event, "min_taker_rating");
+        if min_taker_rating
+            .is_some_and(|rating| !(MIN_RATING as f64..=MAX_RATING as f64).contains(&rating))
+        {
+            return Err(MostroCantDo(CantDoReason::InvalidParameters));
         }
 
         // Check quote in sats for each amount
@@ -112,6 +440,25 @@ pub async fn order_action(
             calculate_and_check_quote(order, fiat_amount).await?;
         }
 
+        // Let takers check the market price the order was quoted at
+        let mut extra_tags = Vec::new();
+        if order.amount == 0 && mostro_settings.price_attestations {
+            let quote = BitcoinPriceManager::get_fresh_quote(
+                &order.fiat_code,
+                mostro_settings.max_price_age_secs,
+            )?;
+            let attestation = price_attestation_event(my_keys, &order.fiat_code, &quote)
+                .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?;
+            extra_tags.push(price_attestation_tag(&attestation));
+        }
+        // Takers see the accepted payment methods before taking
+        if let Some(methods) = &payment_methods {
+            extra_tags.push(payment_methods_tag(methods));
+        }
+        if let Some(min_rating) = min_taker_rating {
+            extra_tags.push(min_taker_rating_tag(min_rating));
+        }
+
         let trade_index = match msg.get_inner_message_kind().trade_index {
             Some(trade_index) => trade_index,
             None => {
@@ -129,7 +476,7 @@ pub async fn order_action(
             .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
 
         // Publish order
-        publish_order(
+        let Some(order_id) = publish_order(
             pool,
             my_keys,
             order,
@@ -138,8 +485,36 @@ pub async fn order_action(
             event.rumor.pubkey,
             request_id,
             msg.get_inner_message_kind().trade_index,
+            extra_tags,
+            &creation_event_id,
         )
         .await?
+        else {
+            // A copy of the event created the order while this one was checked
+            return confirm_existing_order(pool, &msg, event, &creation_event_id).await;
+        };
+
+        if let Some(band) = price_band {
+            add_order_price_band(pool, order_id, &band).await?;
+        }
+        if let Some(methods) = payment_methods {
+            add_order_payment_methods(pool, order_id, &methods).await?;
+        }
+        if let Some(memo) = memo {
+            add_order_memo(pool, order_id, &memo).await?;
+        }
+        if let Some(min_rating) = min_taker_rating {
+            add_order_min_taker_rating(pool, order_id, min_rating).await?;
+        }
+        // Hold invoice expiry asked by the creator, bounds are applied on take
+        if let Some(expiry_secs) = seller_invoice_expiry(
+            order,
+            get_order_extension::<u64>(event, "invoice_expiry_secs"),
+        ) {
+            add_order_invoice_expiry(pool, order_id, expiry_secs).await?;
+        }
+
+        record_rate_event(RateEvent::OrderCreated, &event.sender);
     }
     Ok(())
 }
@@ -320,4 +695,427 @@ mod tests {
             assert!(large_quote > max_order);
         }
     }
+
+    #[tokio::test]
+    async fn test_same_creation_event_creates_one_order() {
+        // Several connections to a file, so copies of the event really race
+        let path = std::env::temp_dir().join(format!("mostro-{}.db", uuid::Uuid::new_v4()));
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(4)
+            .connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        let event_id = rumor_event_id(&create_test_unwrapped_gift());
+
+        // Same event delivered by several relays at once
+        let copies = (0..8).map(|_| {
+            let (pool, event_id) = (pool.clone(), event_id.clone());
+            tokio::spawn(async move {
+                let order = Order {
+                    id: uuid::Uuid::new_v4(),
+                    kind: "sell".to_string(),
+                    status: "pending".to_string(),
+                    fiat_code: "USD".to_string(),
+                    fiat_amount: 100,
+                    ..Default::default()
+                };
+                crate::db::create_order_from_event(&pool, order, &event_id).await
+            })
+        });
+        let mut created = Vec::new();
+        for copy in copies.collect::<Vec<_>>() {
+            created.extend(copy.await.unwrap().unwrap());
+        }
+        assert_eq!(created.len(), 1);
+
+        let orders: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM orders")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(orders, 1);
+        let existing = find_order_by_creation_event(&pool, &event_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(existing.id, created[0].id);
+
+        pool.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+
+    mod order_duration_tests {
+        use super::super::check_order_duration;
+
+        const NOW: i64 = 1_700_000_000;
+        const MIN: u32 = 3600; // 1 hour
+        const MAX: u32 = 7 * 86400; // 1 week
+
+        #[test]
+        fn test_order_duration_below_min() {
+            assert!(check_order_duration(Some(NOW + 600), NOW, MIN, MAX).is_err());
+        }
+
+        #[test]
+        fn test_order_duration_within_window() {
+            assert!(check_order_duration(Some(NOW + 3600), NOW, MIN, MAX).is_ok());
+            assert!(check_order_duration(Some(NOW + 86400), NOW, MIN, MAX).is_ok());
+            assert!(check_order_duration(Some(NOW + 7 * 86400), NOW, MIN, MAX).is_ok());
+            // No expiration requested uses the default one
+            assert!(check_order_duration(None, NOW, MIN, MAX).is_ok());
+        }
+
+        #[test]
+        fn test_order_duration_above_max() {
+            assert!(check_order_duration(Some(NOW + 8 * 86400), NOW, MIN, MAX).is_err());
+            // Disabled bounds accept anything
+            assert!(check_order_duration(Some(NOW + 8 * 86400), NOW, 0, 0).is_ok());
+        }
+    }
+
+    mod fiat_code_tests {
+        use super::super::check_fiat_code;
+
+        #[test]
+        fn test_denied_fiat_code_rejected() {
+            let denied = vec!["XYZ".to_string(), "abc".to_string()];
+            assert!(check_fiat_code("XYZ", &denied).is_err());
+            assert!(check_fiat_code("xyz", &denied).is_err());
+            assert!(check_fiat_code("ABC", &denied).is_err());
+        }
+
+        #[test]
+        fn test_allowed_fiat_code_proceeds() {
+            let denied = vec!["XYZ".to_string()];
+            assert!(check_fiat_code("USD", &denied).is_ok());
+            assert!(check_fiat_code("USD", &[]).is_ok());
+        }
+    }
+
+    mod supported_fiat_code_tests {
+        use super::super::check_supported_fiat_code;
+
+        #[test]
+        fn test_supported_fiat_code() {
+            let quoted = vec!["USD".to_string(), "EUR".to_string()];
+            assert_eq!(
+                check_supported_fiat_code("USD", &[], &quoted).unwrap(),
+                "USD"
+            );
+            // Lowercase codes are normalized before the lookup
+            assert_eq!(
+                check_supported_fiat_code("usd", &[], &quoted).unwrap(),
+                "USD"
+            );
+            assert!(check_supported_fiat_code("XYZ", &[], &quoted).is_err());
+        }
+
+        #[test]
+        fn test_allow_list_overrides_provider() {
+            let allowed = vec!["ves".to_string()];
+            let quoted = vec!["USD".to_string(), "VES".to_string()];
+            assert_eq!(
+                check_supported_fiat_code("Ves", &allowed, &quoted).unwrap(),
+                "VES"
+            );
+            assert!(check_supported_fiat_code("USD", &allowed, &quoted).is_err());
+            // No prices yet and no allow-list, nothing to check against
+            assert!(check_supported_fiat_code("XYZ", &[], &[]).is_ok());
+        }
+    }
+
+    mod premium_tests {
+        use super::super::check_premium;
+
+        #[test]
+        fn test_premium_at_bounds() {
+            assert!(check_premium(100, -50, 100).is_ok());
+            assert!(check_premium(-50, -50, 100).is_ok());
+            assert!(check_premium(0, -50, 100).is_ok());
+            assert!(check_premium(101, -50, 100).is_err());
+            assert!(check_premium(-51, -50, 100).is_err());
+        }
+
+        #[test]
+        fn test_premium_far_out_of_range() {
+            assert!(check_premium(5000, -50, 100).is_err());
+            assert!(check_premium(-5000, -50, 100).is_err());
+        }
+    }
+
+    mod fiat_rounding_tests {
+        use super::super::{apply_fiat_rounding, snap_fiat_amount};
+        use mostro_core::prelude::*;
+
+        #[test]
+        fn test_off_grid_amount_snapped() {
+            assert_eq!(snap_fiat_amount(97, 10, false).unwrap(), 100);
+            assert_eq!(snap_fiat_amount(104, 10, false).unwrap(), 100);
+            assert_eq!(snap_fiat_amount(120, 10, false).unwrap(), 120);
+            // Grid coarser than the amount
+            assert!(snap_fiat_amount(4, 10, false).is_err());
+            // No grid configured
+            assert_eq!(snap_fiat_amount(97, 0, false).unwrap(), 97);
+            assert_eq!(snap_fiat_amount(97, 1, true).unwrap(), 97);
+
+            let mut order = SmallOrder {
+                min_amount: Some(52),
+                max_amount: Some(198),
+                ..Default::default()
+            };
+            apply_fiat_rounding(&mut order, 10, false).unwrap();
+            assert_eq!(order.fiat_amount, 0);
+            assert_eq!(order.min_amount, Some(50));
+            assert_eq!(order.max_amount, Some(200));
+        }
+
+        #[test]
+        fn test_off_grid_amount_rejected() {
+            assert!(snap_fiat_amount(97, 10, true).is_err());
+            assert_eq!(snap_fiat_amount(100, 10, true).unwrap(), 100);
+
+            let mut order = SmallOrder {
+                fiat_amount: 105,
+                ..Default::default()
+            };
+            assert!(apply_fiat_rounding(&mut order, 10, true).is_err());
+        }
+    }
+
+    mod price_attestation_tests {
+        use crate::bitcoin_price::PriceQuote;
+        use crate::nip33::{price_attestation_event, price_attestation_tag};
+        use nostr_sdk::{Event, JsonUtil, Keys};
+
+        #[test]
+        fn test_attestation_matches_quote() {
+            let keys = Keys::generate();
+            let quote = PriceQuote {
+                price: 50_000.0,
+                provider: "yadio".to_string(),
+                updated_at: 1_700_000_000,
+            };
+            let attestation = price_attestation_event(&keys, "USD", &quote).unwrap();
+            let tag = price_attestation_tag(&attestation);
+            assert_eq!(tag.as_slice()[0], "price_attestation");
+
+            // Takers parse the tag and check it was signed by Mostro
+            let carried = Event::from_json(&tag.as_slice()[1]).unwrap();
+            assert!(carried.verify().is_ok());
+            assert_eq!(carried.pubkey, keys.public_key());
+            let value = |name: &str| {
+                carried
+                    .tags
+                    .iter()
+                    .find(|tag| tag.as_slice()[0] == name)
+                    .map(|tag| tag.as_slice()[1].clone())
+                    .unwrap()
+            };
+            assert_eq!(value("f"), "USD");
+            assert_eq!(value("provider"), "yadio");
+            assert_eq!(value("quoted_at"), "1700000000");
+
+            // Sats of the order can be recomputed from the attested price
+            let price: f64 = value("price").parse().unwrap();
+            assert_eq!((100.0 / price * 1E8) as i64, 200_000);
+        }
+    }
+
+    mod order_fields_tests {
+        use super::super::check_order_fields;
+        use mostro_core::prelude::*;
+
+        fn order(
+            amount: i64,
+            premium: i64,
+            fiat_amount: i64,
+            range: Option<(i64, i64)>,
+        ) -> SmallOrder {
+            SmallOrder {
+                amount,
+                premium,
+                fiat_amount,
+                min_amount: range.map(|(min, _)| min),
+                max_amount: range.map(|(_, max)| max),
+                ..Default::default()
+            }
+        }
+
+        #[test]
+        fn test_valid_field_combinations() {
+            let cases = [
+                // Market single amount, with and without premium
+                (order(0, 0, 100, None), vec![100]),
+                (order(0, 5, 100, None), vec![100]),
+                (order(0, -3, 100, None), vec![100]),
+                // Fixed single amount
+                (order(50_000, 0, 100, None), vec![100]),
+                // Market range, with and without premium
+                (order(0, 0, 0, Some((10, 100))), vec![10, 100]),
+                (order(0, 2, 0, Some((10, 100))), vec![10, 100]),
+                // Zero range is no range
+                (order(0, 0, 100, Some((0, 0))), vec![100]),
+            ];
+            for (order, amounts) in cases {
+                assert_eq!(check_order_fields(&order).unwrap(), amounts, "{order:?}");
+            }
+        }
+
+        #[test]
+        fn test_invalid_field_combinations() {
+            use CantDoReason::{InvalidAmount, InvalidParameters};
+
+            let cases = [
+                // Fixed amount with premium
+                (order(50_000, 5, 100, None), InvalidParameters),
+                (order(50_000, 5, 0, Some((10, 100))), InvalidParameters),
+                // Negative sats amount
+                (order(-1, 0, 100, None), InvalidAmount),
+                // Single order without fiat amount
+                (order(0, 0, 0, None), InvalidAmount),
+                (order(0, 0, -10, None), InvalidAmount),
+                // Range with a fiat amount
+                (order(0, 0, 50, Some((10, 100))), InvalidParameters),
+                // Range with a fixed amount
+                (order(50_000, 0, 0, Some((10, 100))), InvalidAmount),
+                // Inverted, empty and negative ranges
+                (order(0, 0, 0, Some((100, 10))), InvalidAmount),
+                (order(0, 0, 0, Some((10, 10))), InvalidAmount),
+                (order(0, 0, 0, Some((-10, 10))), InvalidAmount),
+                (order(0, 0, 0, Some((-20, -10))), InvalidAmount),
+                // Range starting at zero
+                (order(0, 0, 0, Some((0, 100))), InvalidAmount),
+                // Half a range
+                (order(0, 0, 0, Some((10, 0))), InvalidAmount),
+                (
+                    SmallOrder {
+                        max_amount: Some(100),
+                        ..Default::default()
+                    },
+                    InvalidAmount,
+                ),
+                (
+                    SmallOrder {
+                        min_amount: Some(10),
+                        fiat_amount: 50,
+                        ..Default::default()
+                    },
+                    InvalidAmount,
+                ),
+            ];
+            for (order, reason) in cases {
+                match check_order_fields(&order) {
+                    Err(MostroCantDo(cause)) => assert_eq!(cause, reason, "{order:?}"),
+                    result => panic!("{order:?} gave {result:?}"),
+                }
+            }
+        }
+    }
+
+    mod invoice_amount_tests {
+        use super::super::check_invoice_amount;
+        use mostro_core::prelude::*;
+
+        // 50_000 sats
+        const INVOICE: &str = "lnbcrt500u1p3lzwdzpp5t9kgwgwd07y2lrwdscdnkqu4scrcgpm5pt9uwx0rxn5rxawlxlvqdqqcqzpgxqyz5vqsp5a6k7syfxeg8jy63rteywwjla5rrg2pvhedx8ajr2ltm4seydhsqq9qyyssq0n2uwlumsx4d0mtjm8tp7jw3y4da6p6z9gyyjac0d9xugf72lhh4snxpugek6n83geafue9ndgrhuhzk98xcecu2t3z56ut35mkammsqscqp0n";
+        const ZERO_AMOUNT_INVOICE: &str = "lnbcrt1p5gy6c6pp50h04eqruzmztz3a7x97cwea85cywsz4dl5sghzx2dkttv572e6esdqqcqzzsxqyz5vqsp5gttvmhu2mvgtnhxfvqy4t4v4vyp33n3jv23767xlcs9em2zfsq2s9qxpqysgqmhm6nhg6zrftdt8xd9yfq9zf38t65k0ff345thj5339sqnn3qqw8z63qrqdck7q5c48mmdy793u5usmx5tfc7jcydv2pmm8j2e2c3fqqpdezyf";
+
+        #[test]
+        fn test_matching_invoice_amount() {
+            assert!(check_invoice_amount(INVOICE, 50_000).is_ok());
+        }
+
+        #[test]
+        fn test_mismatched_invoice_amount() {
+            assert!(matches!(
+                check_invoice_amount(INVOICE, 49_500),
+                Err(MostroCantDo(CantDoReason::InvalidAmount))
+            ));
+        }
+
+        #[test]
+        fn test_zero_amount_invoice_accepted() {
+            assert!(check_invoice_amount(ZERO_AMOUNT_INVOICE, 49_500).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_invoice_expiry_only_from_sellers() {
+        let sell_order = SmallOrder {
+            kind: Some(mostro_core::order::Kind::Sell),
+            ..Default::default()
+        };
+        assert_eq!(seller_invoice_expiry(&sell_order, Some(1800)), Some(1800));
+        assert_eq!(seller_invoice_expiry(&sell_order, None), None);
+        let buy_order = SmallOrder {
+            kind: Some(mostro_core::order::Kind::Buy),
+            ..Default::default()
+        };
+        assert_eq!(seller_invoice_expiry(&buy_order, Some(1800)), None);
+    }
+
+    mod quote_tests {
+        use super::super::{check_quote_limits, quote_sats};
+        use mostro_core::prelude::*;
+
+        const MAX_ORDER_AMOUNT: u32 = 500_000;
+        const MIN_PAYMENT_AMOUNT: u32 = 100;
+
+        #[test]
+        fn test_market_range_endpoints_quoted() {
+            let order = SmallOrder {
+                min_amount: Some(10),
+                max_amount: Some(1000),
+                ..Default::default()
+            };
+            // 1000 fiat at 100k per BTC is 1M sats
+            assert_eq!(quote_sats(&order, 10, Some(100_000.0)), 10_000);
+            let max_quote = quote_sats(&order, 1000, Some(100_000.0));
+            assert!(check_quote_limits(max_quote, MAX_ORDER_AMOUNT, MIN_PAYMENT_AMOUNT).is_err());
+        }
+
+        #[test]
+        fn test_fixed_single_amount_unchanged() {
+            let order = SmallOrder {
+                amount: 50_000,
+                fiat_amount: 20,
+                ..Default::default()
+            };
+            assert_eq!(quote_sats(&order, 20, None), 50_000);
+        }
+
+        #[tokio::test]
+        async fn test_reloaded_limit_applied() {
+            use super::super::calculate_and_check_quote;
+            use crate::config::reload::reload_settings;
+            use crate::config::util::test_settings_file;
+
+            crate::config::init_test_settings();
+            let template = include_str!("../../settings.tpl.toml");
+            let path = test_settings_file();
+            let order = SmallOrder {
+                amount: 2_000_000,
+                fiat_amount: 100,
+                ..Default::default()
+            };
+
+            // Started with the template limit
+            assert!(matches!(
+                calculate_and_check_quote(&order, &100).await,
+                Err(MostroCantDo(CantDoReason::OutOfRangeSatsAmount))
+            ));
+
+            // The operator raises the limit and sends SIGHUP
+            let raised =
+                template.replace("max_order_amount = 1000000", "max_order_amount = 5000000");
+            std::fs::write(path, raised).unwrap();
+            let changes = reload_settings().await;
+            std::fs::remove_file(path).unwrap();
+            assert_eq!(
+                changes.unwrap(),
+                vec!["max_order_amount: 1000000 -> 5000000"]
+            );
+            assert!(calculate_and_check_quote(&order, &100).await.is_ok());
+        }
+    }
 }
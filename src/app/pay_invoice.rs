@@ -0,0 +1,103 @@
+//! Buyer request to pay the invoice stored in an order.
+//!
+//! Once the seller hold invoice is settled Mostro holds the funds until the
+//! buyer invoice is paid. The buyer can ask Mostro to pay it again, e.g. after
+//! a failed attempt, instead of waiting for the scheduler retries.
+
+use crate::app::release::do_payment;
+use crate::order_locks::lock_order;
+use crate::util::get_order;
+use mostro_core::prelude::*;
+use nostr::nips::nip59::UnwrappedGift;
+use nostr_sdk::prelude::*;
+use sqlx::{Pool, Sqlite};
+use tracing::info;
+
+/// Checks the order funds are held by Mostro and the buyer invoice can be paid
+fn check_payable(order: &Order, sender: &PublicKey) -> Result<(), MostroError> {
+    order
+        .check_status(Status::SettledHoldInvoice)
+        .map_err(MostroCantDo)?;
+    // Only the buyer can ask to be paid
+    let buyer_pubkey = order.get_buyer_pubkey().map_err(MostroInternalErr)?;
+    if buyer_pubkey != *sender {
+        return Err(MostroCantDo(CantDoReason::InvalidPeer));
+    }
+    if order.buyer_invoice.is_none() {
+        return Err(MostroCantDo(CantDoReason::InvalidInvoice));
+    }
+    Ok(())
+}
+
+pub async fn pay_invoice_action(
+    msg: Message,
+    event: &UnwrappedGift,
+    _my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    let request_id = msg.get_inner_message_kind().request_id;
+    // Not paid by the scheduler retries meanwhile
+    let _order_lock = lock_order(msg.get_inner_message_kind().id).await;
+    let order = get_order(&msg, pool).await?;
+    check_payable(&order, &event.rumor.pubkey)?;
+
+    info!("Order Id {}: buyer requested the invoice payment", order.id);
+    // Payments already attempted are not sent again by the lightning backend
+    do_payment(order, request_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+    use sqlx_crud::Crud;
+
+    #[tokio::test]
+    async fn test_pay_invoice_not_payable_order() {
+        let pool = test_pool().await;
+        let buyer = Keys::generate();
+        let order = Order {
+            id: uuid::Uuid::new_v4(),
+            kind: "buy".to_string(),
+            status: Status::Pending.to_string(),
+            buyer_pubkey: Some(buyer.public_key().to_string()),
+            buyer_invoice: Some("lnbc1".to_string()),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+
+        let msg = Message::new_order(Some(order.id), Some(1), None, Action::PayInvoice, None);
+        let event = UnwrappedGift {
+            sender: buyer.public_key(),
+            rumor: UnsignedEvent::new(
+                buyer.public_key(),
+                Timestamp::now(),
+                nostr_sdk::Kind::GiftWrap,
+                Vec::new(),
+                "",
+            ),
+        };
+
+        // Funds are not held yet, the buyer gets a cant do
+        let result = pay_invoice_action(msg, &event, &Keys::generate(), &pool).await;
+        assert!(matches!(result, Err(MostroCantDo(_))));
+    }
+
+    #[test]
+    fn test_only_buyer_can_request_payment() {
+        let buyer = Keys::generate().public_key();
+        let order = Order {
+            status: Status::SettledHoldInvoice.to_string(),
+            buyer_pubkey: Some(buyer.to_string()),
+            buyer_invoice: Some("lnbc1".to_string()),
+            ..Default::default()
+        };
+        assert!(check_payable(&order, &buyer).is_ok());
+        assert!(matches!(
+            check_payable(&order, &Keys::generate().public_key()),
+            Err(MostroCantDo(CantDoReason::InvalidPeer))
+        ));
+    }
+}
@@ -1,5 +1,8 @@
+use crate::config::settings::Settings;
 use crate::config::MOSTRO_DB_PASSWORD;
-use crate::db::{is_user_present, update_user_rating};
+use crate::db::{
+    add_user_rating, find_user_penalties, find_user_ratings, is_user_present, update_user_rating,
+};
 use crate::util::{enqueue_order_msg, get_order, update_user_rating_event};
 use mostro_core::prelude::*;
 use nostr::nips::nip59::UnwrappedGift;
@@ -39,6 +42,83 @@ pub fn prepare_variables_for_vote(
     Ok((counterpart_trade_pubkey, buyer_rating, seller_rating))
 }
 
+/// Weight of something given at `given_at`, halving every `half_life_secs`
+/// of age at `now`
+fn decay_weight(given_at: i64, now: i64, half_life_secs: i64) -> f64 {
+    let age = now.saturating_sub(given_at).max(0) as f64;
+    0.5f64.powf(age / half_life_secs as f64)
+}
+
+/// Average of `ratings` given at the paired timestamps, each weighing half as
+/// much every `half_life_secs` of age at `now`
+pub fn decayed_rating(ratings: &[(f64, i64)], now: i64, half_life_secs: i64) -> f64 {
+    let (weighted, weights) =
+        ratings
+            .iter()
+            .fold((0.0, 0.0), |(weighted, weights), (rating, given_at)| {
+                let weight = decay_weight(*given_at, now, half_life_secs);
+                (weighted + rating * weight, weights + weight)
+            });
+    if weights > 0.0 {
+        weighted / weights
+    } else {
+        0.0
+    }
+}
+
+/// Rating published in the reputation event of `user`, decayed with the
+/// configured half-life
+async fn published_rating(pool: &Pool<Sqlite>, user: &User, now: i64) -> Result<f64, MostroError> {
+    published_rating_with(
+        pool,
+        user,
+        now,
+        Settings::get_mostro().reputation_half_life_days,
+    )
+    .await
+}
+
+/// Rating of `user` decayed with a half-life of `half_life_days`. Ratings
+/// received before their time was recorded count as given when the user was
+/// created. Dispute penalties are taken from the decayed rating, fading with
+/// the same half-life.
+async fn published_rating_with(
+    pool: &Pool<Sqlite>,
+    user: &User,
+    now: i64,
+    half_life_days: u64,
+) -> Result<f64, MostroError> {
+    if half_life_days == 0 {
+        return Ok(user.total_rating);
+    }
+    let half_life_secs = (half_life_days * 24 * 60 * 60) as i64;
+    let penalties = find_user_penalties(pool, &user.pubkey).await?;
+    let mut ratings: Vec<(f64, i64)> = find_user_ratings(pool, &user.pubkey)
+        .await?
+        .into_iter()
+        .map(|(rating, given_at)| (rating as f64, given_at))
+        .collect();
+    let unrecorded = user.total_reviews - ratings.len() as i64;
+    if unrecorded > 0 {
+        let recorded_sum: f64 = ratings.iter().map(|(rating, _)| rating).sum();
+        // Penalties were taken from the total rating, they are applied below
+        let penalized: f64 = penalties.iter().map(|(penalty, _)| penalty).sum();
+        let total_rating = user.total_rating + penalized;
+        let mean = (total_rating * user.total_reviews as f64 - recorded_sum) / unrecorded as f64;
+        let mean = mean.clamp(MIN_RATING as f64, MAX_RATING as f64);
+        ratings.extend(std::iter::repeat_n(
+            (mean, user.created_at),
+            unrecorded as usize,
+        ));
+    }
+
+    let penalty: f64 = penalties
+        .iter()
+        .map(|(penalty, taken_at)| penalty * decay_weight(*taken_at, now, half_life_secs))
+        .sum();
+    Ok((decayed_rating(&ratings, now, half_life_secs) - penalty).max(0.0))
+}
+
 /// Updates a user's reputation based on a rating received from a trade counterpart.
 ///
 /// This function handles the reputation update process for users after a successful trade.
@@ -135,10 +215,15 @@ pub async fn update_user_reputation_action(
     // Calculate new rating
     user_to_vote.update_rating(new_rating);
 
+    // Keep the time of the rating for the decay of the reputation
+    let now = Timestamp::now().as_u64() as i64;
+    add_user_rating(pool, &user_to_vote.pubkey, new_rating as i64, now).await?;
+    let rating = published_rating(pool, &user_to_vote, now).await?;
+
     // Create new rating event
     let reputation_event = Rating::new(
         user_to_vote.total_reviews as u64,
-        user_to_vote.total_rating as f64,
+        rating,
         user_to_vote.last_rating as u8,
         user_to_vote.min_rating as u8,
         user_to_vote.max_rating as u8,
@@ -197,3 +282,86 @@ pub async fn update_user_reputation_action(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decayed_rating() {
+        let day = 24 * 60 * 60;
+        let half_life = 30 * day;
+        let now = 1_000 * day;
+
+        // Same age, plain average
+        let ratings = [(5.0, now - 10 * day), (3.0, now - 10 * day)];
+        assert!((decayed_rating(&ratings, now, half_life) - 4.0).abs() < 1e-9);
+
+        // A rating one half-life old weighs half, two half-lives a quarter
+        let ratings = [
+            (5.0, now),
+            (3.0, now - half_life),
+            (1.0, now - 2 * half_life),
+        ];
+        let expected = (5.0 + 3.0 * 0.5 + 1.0 * 0.25) / (1.0 + 0.5 + 0.25);
+        assert!((decayed_rating(&ratings, now, half_life) - expected).abs() < 1e-9);
+
+        // Ratings at any age in between follow the same curve
+        let age = 45 * day;
+        let weight = 0.5f64.powf(age as f64 / half_life as f64);
+        let ratings = [(2.0, now - age), (4.0, now)];
+        let expected = (2.0 * weight + 4.0) / (weight + 1.0);
+        assert!((decayed_rating(&ratings, now, half_life) - expected).abs() < 1e-9);
+
+        assert_eq!(decayed_rating(&[], now, half_life), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_published_rating_with_penalty() {
+        let pool = crate::db::test_pool().await;
+        let day = 24 * 60 * 60;
+        let now = 1_000 * day;
+        let pubkey = Keys::generate().public_key().to_string();
+        let mut user = User {
+            pubkey: pubkey.clone(),
+            total_reviews: 2,
+            total_rating: 4.0,
+            ..Default::default()
+        };
+        for rating in [5, 3] {
+            add_user_rating(&pool, &pubkey, rating, now).await.unwrap();
+        }
+        assert_eq!(
+            published_rating_with(&pool, &user, now, 30).await.unwrap(),
+            4.0
+        );
+
+        // A dispute lost now takes the whole penalty
+        sqlx::query(
+            "INSERT INTO dispute_penalties (dispute_id, pubkey, penalty, reason, created_at) VALUES (?1, ?2, 1.0, 'lost', ?3)",
+        )
+        .bind(uuid::Uuid::new_v4())
+        .bind(&pubkey)
+        .bind(now - 30 * day)
+        .execute(&pool)
+        .await
+        .unwrap();
+        user.total_rating = 3.0;
+        // One half-life old it takes half
+        let rating = published_rating_with(&pool, &user, now, 30).await.unwrap();
+        assert!((rating - 3.5).abs() < 1e-9);
+        // Without decay the total rating already has it
+        assert_eq!(
+            published_rating_with(&pool, &user, now, 0).await.unwrap(),
+            3.0
+        );
+
+        // Ratings without a time recorded are rebuilt without the penalty
+        user.total_reviews = 4;
+        let rating = published_rating_with(&pool, &user, now, 30).await.unwrap();
+        let mean = (4.0 * 4.0 - 8.0) / 2.0;
+        let old = decay_weight(0, now, 30 * day);
+        let expected = (8.0 + 2.0 * mean * old) / (2.0 + 2.0 * old) - 0.5;
+        assert!((rating - expected).abs() < 1e-9);
+    }
+}
@@ -1,11 +1,19 @@
 use crate::config;
 use crate::config::MOSTRO_DB_PASSWORD;
-use crate::db::{self};
-use crate::lightning::LndConnector;
+use crate::db::{
+    self, add_order_min_taker_rating, add_order_payment_methods, add_order_price_band,
+    find_order_min_taker_rating, find_order_payment_methods, find_order_price_band,
+};
+use crate::kill_switch::check_funds_movable;
+use crate::lightning::onchain::{is_onchain_address, pay_onchain};
+use crate::lightning::{connect_backend, LightningBackend};
 use crate::lnurl::resolv_ln_address;
+use crate::models::UserStats;
 use crate::nip33::{new_event, order_to_tags};
+use crate::order_locks::lock_order;
 use crate::util::{
-    enqueue_order_msg, get_keys, get_nostr_client, get_order, settle_seller_hold_invoice,
+    enqueue_order_msg, get_keys, get_message_extension, get_nostr_client, get_order,
+    order_restriction_tags, publish_fee_receipt, record_trade_stats, settle_seller_hold_invoice,
     update_order_event,
 };
 
@@ -13,6 +21,7 @@ use argon2::password_hash::SaltString;
 use config::settings::*;
 use fedimint_tonic_lnd::lnrpc::payment::PaymentStatus;
 use lnurl::lightning_address::LightningAddress;
+use lnurl::lnurl::LnUrl;
 use mostro_core::prelude::*;
 use nostr::nips::nip59::UnwrappedGift;
 use nostr_sdk::prelude::*;
@@ -23,7 +32,42 @@ use sqlx_crud::Crud;
 use std::cmp::Ordering;
 use std::str::FromStr;
 use tokio::sync::mpsc::channel;
-use tracing::info;
+use tracing::{error, info};
+
+/// Rebuild the range grids with a pending child after a restart and publish
+/// their current state, so partially taken range orders keep their remaining range
+pub async fn resume_range_grids(pool: &Pool<Sqlite>, my_keys: &Keys) {
+    let children = match db::find_pending_range_children(pool).await {
+        Ok(children) => children,
+        Err(e) => return tracing::error!("Error resuming range orders: {e}"),
+    };
+    for child in children {
+        let grid = match db::find_range_grid(pool, child.id).await {
+            Ok(Some(grid)) => grid,
+            Ok(None) => {
+                tracing::warn!("Order Id {}: range order without root", child.id);
+                continue;
+            }
+            Err(e) => {
+                tracing::error!("Order Id {}: error rebuilding range order: {e}", child.id);
+                continue;
+            }
+        };
+        info!(
+            "Range order {} resumed: {} filled, order {} open with range {:?}",
+            grid.root_id, grid.filled_fiat, grid.head_id, grid.remaining
+        );
+        // Republish the open child so the book shows the remaining range
+        match update_order_event(my_keys, Status::Pending, &child).await {
+            Ok(order) => {
+                if let Err(e) = order.update(pool).await {
+                    tracing::error!("Order Id {}: {e}", child.id);
+                }
+            }
+            Err(e) => tracing::error!("Order Id {}: {e}", child.id),
+        }
+    }
+}
 
 /// Check if order has failed payment retries
 pub async fn check_failure_retries(
@@ -83,6 +127,62 @@ pub async fn check_failure_retries(
     Ok(result)
 }
 
+/// Checks the order status allows the seller to release. Releasing during a
+/// dispute bypasses the solver, operators can refuse it with
+/// `block_release_in_dispute`.
+fn check_release_allowed(
+    order: &Order,
+    open_dispute: bool,
+    block_release_in_dispute: bool,
+) -> Result<(), MostroError> {
+    let in_dispute = open_dispute || order.check_status(Status::Dispute).is_ok();
+    if in_dispute && block_release_in_dispute {
+        info!(
+            "Order Id {}: release refused, the dispute must be resolved by a solver",
+            order.id
+        );
+        return Err(MostroCantDo(CantDoReason::NotAllowedByStatus));
+    }
+    if order.check_status(Status::FiatSent).is_err() && order.check_status(Status::Dispute).is_err()
+    {
+        return Err(MostroCantDo(CantDoReason::NotAllowedByStatus));
+    }
+    Ok(())
+}
+
+/// Within `grace_secs` of fiat being marked sent, a release needs the seller
+/// to confirm the fiat arrived. Orders without a recorded fiat sent time always
+/// need it, their grace period can't be told over
+async fn check_release_grace(
+    pool: &Pool<Sqlite>,
+    order: &Order,
+    fiat_received: bool,
+    grace_secs: u64,
+    now: i64,
+) -> Result<(), MostroError> {
+    if grace_secs == 0 || fiat_received || order.check_status(Status::FiatSent).is_err() {
+        return Ok(());
+    }
+    let Some(fiat_sent_at) =
+        db::find_status_reached_at(pool, order.id, &Status::FiatSent.to_string()).await?
+    else {
+        info!(
+            "Order Id {}: release refused without fiat sent time nor the seller confirming receipt",
+            order.id
+        );
+        return Err(MostroCantDo(CantDoReason::NotAllowedByStatus));
+    };
+    if now - fiat_sent_at < grace_secs as i64 {
+        info!(
+            "Order Id {}: release refused {}s after fiat sent without the seller confirming receipt",
+            order.id,
+            now - fiat_sent_at
+        );
+        return Err(MostroCantDo(CantDoReason::NotAllowedByStatus));
+    }
+    Ok(())
+}
+
 /// Handles the release action for an order, managing the release of funds and subsequent order flow.
 ///
 /// This function is responsible for processing the release of funds in a trade, which is a critical
@@ -143,8 +243,12 @@ pub async fn release_action(
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-    ln_client: &mut LndConnector,
+    ln_client: &mut dyn LightningBackend,
 ) -> Result<(), MostroError> {
+    // No funds move while the kill switch is engaged
+    check_funds_movable("release")?;
+    // No other handler changes the order meanwhile
+    let _order_lock = lock_order(msg.get_inner_message_kind().id).await;
     // Get request id
     let request_id = msg.get_inner_message_kind().request_id;
     // Get order
@@ -160,10 +264,21 @@ pub async fn release_action(
     }
 
     // Check if order is in status fiat sent or dispute
-    if order.check_status(Status::FiatSent).is_err() && order.check_status(Status::Dispute).is_err()
-    {
-        return Err(MostroCantDo(CantDoReason::NotAllowedByStatus));
-    }
+    let open_dispute = db::has_open_dispute(pool, order.id).await?;
+    check_release_allowed(
+        &order,
+        open_dispute,
+        Settings::get_mostro().block_release_in_dispute,
+    )?;
+    // Sellers get time to check their account before funds can leave
+    check_release_grace(
+        pool,
+        &order,
+        get_message_extension::<bool>(event, "fiat_received").unwrap_or(false),
+        Settings::get_mostro().release_grace_secs,
+        Timestamp::now().as_u64() as i64,
+    )
+    .await?;
 
     // Get next trade key
     let next_trade = msg
@@ -173,6 +288,7 @@ pub async fn release_action(
 
     // Settle seller hold invoice
     settle_seller_hold_invoice(event, ln_client, Action::Released, false, &order).await?;
+    publish_fee_receipt(my_keys, &order).await;
     // Update order event with status SettledHoldInvoice
     order = update_order_event(my_keys, Status::SettledHoldInvoice, &order)
         .await
@@ -402,30 +518,53 @@ async fn handle_child_order(
     }
 
     // Create the child order in database
-    child_order
+    let child_order = child_order
         .create(pool)
         .await
         .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
 
+    // Child orders keep the price band of the parent range order
+    if let Some(band) = find_order_price_band(pool, order.id).await? {
+        add_order_price_band(pool, child_order.id, &band).await?;
+    }
+    // And the payment methods accepted by the maker
+    let methods = find_order_payment_methods(pool, order.id).await?;
+    add_order_payment_methods(pool, child_order.id, &methods).await?;
+    if let Some(min_rating) = find_order_min_taker_rating(pool, order.id).await? {
+        add_order_min_taker_rating(pool, child_order.id, min_rating).await?;
+    }
+
     Ok(())
 }
 
 pub async fn do_payment(mut order: Order, request_id: Option<u64>) -> Result<(), MostroError> {
+    check_funds_movable("buyer payment")?;
+    // Buyers with an onchain payout recorded are not paid again, whatever
+    // invoice they sent after it
+    if let Some(payout) = db::find_onchain_payout(&get_db_pool(), order.id).await? {
+        return pay_buyer_onchain(order, &payout.address, payout.amount, request_id).await;
+    }
     let payment_request = match order.buyer_invoice.as_ref() {
         Some(req) => req.to_string(),
         _ => return Err(MostroInternalErr(ServiceError::InvoiceInvalidError)),
     };
 
-    let ln_addr = LightningAddress::from_str(&payment_request);
     let amount = order.amount as u64 - order.fee as u64;
-    let payment_request = if let Ok(addr) = ln_addr {
-        resolv_ln_address(&addr.to_string(), amount)
+    // Addresses are resolved now, a fresh invoice for the amount is needed
+    let payment_request = if LightningAddress::from_str(&payment_request).is_ok()
+        || LnUrl::from_str(&payment_request).is_ok()
+    {
+        resolv_ln_address(&payment_request, amount)
             .await
             .map_err(|_| MostroInternalErr(ServiceError::LnAddressParseError))?
     } else {
         payment_request
     };
-    let mut ln_client_payment = LndConnector::new().await?;
+    // Buyers paid onchain gave an address
+    if is_onchain_address(&payment_request) {
+        return pay_buyer_onchain(order, &payment_request, amount as i64, request_id).await;
+    }
+    let mut ln_client_payment = connect_backend().await?;
     let (tx, mut rx) = channel(100);
 
     let payment_task = ln_client_payment.send_payment(&payment_request, amount as i64, tx);
@@ -487,6 +626,41 @@ pub async fn do_payment(mut order: Order, request_id: Option<u64>) -> Result<(),
     Ok(())
 }
 
+/// Pay the buyer onchain, the transaction is broadcast by the node so the
+/// order succeeds once it is sent
+async fn pay_buyer_onchain(
+    mut order: Order,
+    address: &str,
+    amount: i64,
+    request_id: Option<u64>,
+) -> Result<(), MostroError> {
+    let pool = get_db_pool();
+    let mut ln_client = connect_backend().await?;
+    match pay_onchain(&pool, ln_client.as_mut(), order.id, address, amount).await {
+        Ok(Some(txid)) => {
+            info!("Order Id {}: buyer paid onchain, txid {}", order.id, txid);
+            let my_keys = get_keys()
+                .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?;
+            let buyer_pubkey = order.get_buyer_pubkey().map_err(MostroInternalErr)?;
+            payment_success(&mut order, buyer_pubkey, &my_keys, request_id)
+                .await
+                .map_err(|e| MostroInternalErr(ServiceError::LnPaymentError(e.to_string())))?;
+        }
+        // Left to the operator, retrying could pay the buyer twice
+        Ok(None) => {}
+        Err(e) => {
+            info!("Order Id {}: error paying buyer onchain: {}", order.id, e);
+            if let Ok(failed_payment) = check_failure_retries(&order, request_id).await {
+                info!(
+                    "Order id {} has {} failed payments retries",
+                    failed_payment.id, failed_payment.payment_attempts
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
 async fn payment_success(
     order: &mut Order,
     buyer_pubkey: PublicKey,
@@ -514,6 +688,9 @@ async fn payment_success(
             .update(&pool)
             .await
             .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+        if let Err(e) = record_trade_stats(&pool, &order, Status::Success).await {
+            error!("Order Id {}: error recording trade stats: {}", order.id, e);
+        }
         // Send dm to buyer to rate counterpart
         enqueue_order_msg(
             request_id,
@@ -528,6 +705,34 @@ async fn payment_success(
     Ok(())
 }
 
+/// Pending order for what is left of a partially taken range order, none
+/// when the remainder is below the minimum amount of the range
+fn remainder_order(order: &Order) -> Result<Option<Order>, MostroError> {
+    let (Some(max_amount), Some(min_amount)) = (order.max_amount, order.min_amount) else {
+        return Ok(None);
+    };
+    let Some(new_max) = max_amount.checked_sub(order.fiat_amount) else {
+        return Ok(None);
+    };
+
+    let mut new_order = create_base_order(order)?;
+    match new_max.cmp(&min_amount) {
+        // A single amount is left
+        Ordering::Equal => {
+            new_order.fiat_amount = new_max;
+            new_order.max_amount = None;
+            new_order.min_amount = None;
+        }
+        Ordering::Greater => {
+            new_order.max_amount = Some(new_max);
+            new_order.fiat_amount = 0;
+        }
+        Ordering::Less => return Ok(None),
+    }
+
+    Ok(Some(new_order))
+}
+
 /// Check if order is range type
 /// Add parent range id and update max amount
 /// publish a new replaceable kind nostr event with the status updated
@@ -536,29 +741,15 @@ pub async fn get_child_order(
     order: Order,
     my_keys: &Keys,
 ) -> Result<(Option<Order>, Option<Event>), MostroError> {
-    let (Some(max_amount), Some(min_amount)) = (order.max_amount, order.min_amount) else {
+    if !Settings::get_mostro().relist_range_remainder {
         return Ok((None, None));
-    };
-
-    if let Some(new_max) = max_amount.checked_sub(order.fiat_amount) {
-        let mut new_order = create_base_order(&order)?;
-
-        match new_max.cmp(&min_amount) {
-            Ordering::Equal => {
-                let (order, event) = order_for_equal(new_max, &mut new_order, my_keys).await?;
-                return Ok((Some(order), Some(event)));
-            }
-            Ordering::Greater => {
-                let (order, event) = order_for_greater(new_max, &mut new_order, my_keys).await?;
-                return Ok((Some(order), Some(event)));
-            }
-            Ordering::Less => {
-                return Ok((None, None));
-            }
-        }
     }
+    let Some(mut new_order) = remainder_order(&order)? else {
+        return Ok((None, None));
+    };
+    let event = create_order_event(&mut new_order, my_keys).await?;
 
-    Ok((None, None))
+    Ok((Some(new_order), Some(event)))
 }
 
 fn create_base_order(order: &Order) -> Result<Order, MostroError> {
@@ -605,13 +796,18 @@ async fn create_order_event(new_order: &mut Order, my_keys: &Keys) -> Result<Eve
 
     // If user has sent the order with his identity key means that he wants to be rate so we can just
     // check if we have identity key in db - if present we have to send reputation tags otherwise no.
-    let tags = match crate::db::is_user_present(&pool, identity_pubkey).await {
+    let tags = match crate::db::is_user_present(&pool, identity_pubkey.clone()).await {
         Ok(user) => order_to_tags(
             new_order,
             Some((user.total_rating, user.total_reviews, user.created_at)),
+            db::get_user_stats(&pool, &identity_pubkey).await?,
         )?,
-        Err(_) => order_to_tags(new_order, Some((0.0, 0, 0)))?,
+        Err(_) => order_to_tags(new_order, Some((0.0, 0, 0)), UserStats::default())?,
     };
+    // Child orders keep the restrictions of the parent order
+    let parent_id = new_order.range_parent_id.unwrap_or(new_order.id);
+    let restrictions = order_restriction_tags(&pool, parent_id).await?;
+    let tags = tags.map(|tags| Tags::from_list(tags.into_iter().chain(restrictions).collect()));
 
     // Prepare new child order event for sending
     let event = if let Some(tags) = tags {
@@ -627,27 +823,141 @@ async fn create_order_event(new_order: &mut Order, my_keys: &Keys) -> Result<Eve
     Ok(event)
 }
 
-async fn order_for_equal(
-    new_max: i64,
-    new_order: &mut Order,
-    my_keys: &Keys,
-) -> Result<(Order, Event), MostroError> {
-    new_order.fiat_amount = new_max;
-    new_order.max_amount = None;
-    new_order.min_amount = None;
-    let event = create_order_event(new_order, my_keys).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok((new_order.clone(), event))
-}
+    #[test]
+    fn test_release_during_dispute_rejected() {
+        let order = Order {
+            status: Status::Dispute.to_string(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            check_release_allowed(&order, true, true),
+            Err(MostroCantDo(CantDoReason::NotAllowedByStatus))
+        ));
+        // Sellers can still give up a dispute unless the operator refuses it
+        assert!(check_release_allowed(&order, true, false).is_ok());
+    }
 
-async fn order_for_greater(
-    new_max: i64,
-    new_order: &mut Order,
-    my_keys: &Keys,
-) -> Result<(Order, Event), MostroError> {
-    new_order.max_amount = Some(new_max);
-    new_order.fiat_amount = 0;
-    let event = create_order_event(new_order, my_keys).await?;
+    #[test]
+    fn test_range_remainder_order() {
+        let parent = Order {
+            id: uuid::Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::Success.to_string(),
+            amount: 60_000,
+            min_amount: Some(10),
+            max_amount: Some(100),
+            fiat_amount: 30,
+            buyer_pubkey: Some(Keys::generate().public_key().to_string()),
+            ..Default::default()
+        };
+
+        // Part of the range taken, the rest is listed again
+        let child = remainder_order(&parent).unwrap().unwrap();
+        assert_ne!(child.id, parent.id);
+        assert_eq!(child.range_parent_id, Some(parent.id));
+        assert_eq!(child.status, Status::Pending.to_string());
+        assert_eq!((child.min_amount, child.max_amount), (Some(10), Some(70)));
+        assert_eq!((child.fiat_amount, child.amount), (0, 0));
+        assert!(child.buyer_pubkey.is_none());
+
+        // Only the minimum is left
+        let taken = Order {
+            fiat_amount: 90,
+            ..parent.clone()
+        };
+        let child = remainder_order(&taken).unwrap().unwrap();
+        assert_eq!(child.fiat_amount, 10);
+        assert_eq!((child.min_amount, child.max_amount), (None, None));
+
+        // What is left is below the minimum
+        let taken = Order {
+            fiat_amount: 95,
+            ..parent.clone()
+        };
+        assert!(remainder_order(&taken).unwrap().is_none());
+        let fixed = Order {
+            min_amount: None,
+            max_amount: None,
+            ..parent
+        };
+        assert!(remainder_order(&fixed).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_release_grace_period() {
+        let pool = crate::db::test_pool().await;
+        let order = Order {
+            id: uuid::Uuid::new_v4(),
+            status: Status::FiatSent.to_string(),
+            ..Default::default()
+        };
+        let fiat_sent_at = 1_700_000_000;
+        db::add_order_event(
+            &pool,
+            order.id,
+            "ev",
+            &Status::FiatSent.to_string(),
+            fiat_sent_at,
+        )
+        .await
+        .unwrap();
+        let grace = 600;
+
+        // Too early
+        assert!(matches!(
+            check_release_grace(&pool, &order, false, grace, fiat_sent_at + 599).await,
+            Err(MostroCantDo(CantDoReason::NotAllowedByStatus))
+        ));
+        // Unless the seller confirms the fiat arrived
+        assert!(
+            check_release_grace(&pool, &order, true, grace, fiat_sent_at + 10)
+                .await
+                .is_ok()
+        );
+        // Window over
+        assert!(
+            check_release_grace(&pool, &order, false, grace, fiat_sent_at + 600)
+                .await
+                .is_ok()
+        );
+        // Disabled
+        assert!(check_release_grace(&pool, &order, false, 0, fiat_sent_at)
+            .await
+            .is_ok());
+        // No fiat sent time recorded, only released confirming receipt
+        let unknown = Order {
+            id: uuid::Uuid::new_v4(),
+            ..order
+        };
+        assert!(matches!(
+            check_release_grace(&pool, &unknown, false, grace, fiat_sent_at + 6000).await,
+            Err(MostroCantDo(CantDoReason::NotAllowedByStatus))
+        ));
+        assert!(
+            check_release_grace(&pool, &unknown, true, grace, fiat_sent_at)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_release_from_fiat_sent() {
+        let order = Order {
+            status: Status::FiatSent.to_string(),
+            ..Default::default()
+        };
+        assert!(check_release_allowed(&order, false, true).is_ok());
+        // A dispute opened but not reflected in the order status yet
+        assert!(check_release_allowed(&order, true, true).is_err());
 
-    Ok((new_order.clone(), event))
+        let order = Order {
+            status: Status::Active.to_string(),
+            ..Default::default()
+        };
+        assert!(check_release_allowed(&order, false, false).is_err());
+    }
 }
@@ -0,0 +1,126 @@
+//! Reputation queries.
+//!
+//! Clients can ask for the reputation of a user before trading with them. A
+//! query is a `RateUser` message with `"reputation_of": <identity pubkey>`
+//! next to its payload. It is answered with `RateUser` and a text message
+//! holding the same rating json published in the orders of the user.
+//!
+//! Queries could be used to scrape the reputation of every user, so they have
+//! their own budget per sender, stricter than the one of the other messages.
+//! Queries over it are dropped like any rate limited message.
+
+use crate::db::{get_user_stats, is_user_present};
+use crate::nip33::create_rating_tag;
+use crate::rate_limiter::{RateLimiter, REPUTATION_LIMITER};
+use crate::util::{enqueue_order_msg, get_message_extension};
+use mostro_core::prelude::*;
+use nostr::nips::nip59::UnwrappedGift;
+use nostr_sdk::prelude::*;
+use sqlx::{Pool, Sqlite};
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::info;
+
+/// Tells if a `RateUser` message asks for a reputation instead of rating
+pub fn is_reputation_query(event: &UnwrappedGift) -> bool {
+    get_message_extension::<String>(event, "reputation_of").is_some()
+}
+
+/// Takes a query from the budget of `sender`, false if it went over it
+fn query_allowed(limiter: &Mutex<RateLimiter>, sender: &PublicKey, now: Instant) -> bool {
+    match limiter.lock() {
+        Ok(mut limiter) => limiter.check(sender, now),
+        Err(_) => true,
+    }
+}
+
+/// Rating json of `identity`, users never seen are not found
+async fn reputation_of(pool: &Pool<Sqlite>, identity: &PublicKey) -> Result<String, MostroError> {
+    let user = is_user_present(pool, identity.to_string())
+        .await
+        .map_err(|_| MostroCantDo(CantDoReason::NotFound))?;
+    let trade_stats = get_user_stats(pool, &identity.to_string()).await?;
+    Ok(create_rating_tag(
+        Some((user.total_rating, user.total_reviews, user.created_at)),
+        trade_stats,
+    ))
+}
+
+pub async fn reputation_query_action(
+    msg: Message,
+    event: &UnwrappedGift,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    if !query_allowed(&REPUTATION_LIMITER, &event.sender, Instant::now()) {
+        info!("{} went over the reputation query budget", event.sender);
+        return Ok(());
+    }
+    let identity = get_message_extension::<String>(event, "reputation_of")
+        .and_then(|queried| PublicKey::parse(&queried).ok())
+        .ok_or(MostroCantDo(CantDoReason::InvalidPubkey))?;
+    let reputation = reputation_of(pool, &identity).await?;
+
+    enqueue_order_msg(
+        msg.get_inner_message_kind().request_id,
+        None,
+        Action::RateUser,
+        Some(Payload::TextMessage(reputation)),
+        event.rumor.pubkey,
+        None,
+    )
+    .await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    #[test]
+    fn test_excessive_reputation_queries_throttled() {
+        let limiter = Mutex::new(RateLimiter::new(0.1, 3));
+        let scraper = Keys::generate().public_key();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(query_allowed(&limiter, &scraper, now));
+        }
+        assert!(!query_allowed(&limiter, &scraper, now));
+        // Other users keep their budget
+        assert!(query_allowed(&limiter, &Keys::generate().public_key(), now));
+        // One query every ten seconds after that
+        let later = now + std::time::Duration::from_secs(10);
+        assert!(query_allowed(&limiter, &scraper, later));
+        assert!(!query_allowed(&limiter, &scraper, later));
+    }
+
+    #[tokio::test]
+    async fn test_reputation_of_known_user() {
+        let pool = test_pool().await;
+        let user = Keys::generate().public_key();
+        sqlx::query(
+            "INSERT INTO users (pubkey, total_rating, total_reviews, created_at) VALUES (?1, 4.5, 8, 0)",
+        )
+        .bind(user.to_string())
+        .execute(&pool)
+        .await
+        .unwrap();
+        crate::db::add_completed_trade(&pool, &user.to_string(), 50_000)
+            .await
+            .unwrap();
+
+        let reputation: serde_json::Value =
+            serde_json::from_str(&reputation_of(&pool, &user).await.unwrap()).unwrap();
+        assert_eq!(reputation[0], "rating");
+        assert_eq!(reputation[1]["total_reviews"], 8);
+        assert_eq!(reputation[1]["total_rating"], 4.5);
+        assert_eq!(reputation[1]["completed_trades"], 1);
+
+        assert!(matches!(
+            reputation_of(&pool, &Keys::generate().public_key()).await,
+            Err(MostroCantDo(CantDoReason::NotFound))
+        ));
+    }
+}
@@ -0,0 +1,151 @@
+//! Short order reservations.
+//!
+//! A taker can reserve a pending order while preparing the take, the order is
+//! shown in progress so it leaves the book and other takers can't take it until
+//! the reservation expires. Expired reservations are cleaned by the scheduler,
+//! which republishes the order as pending.
+//!
+//! A reservation is a `TakeSell` or `TakeBuy` message with `"reserve": true`
+//! next to its payload. It is answered with the same action and the request
+//! id of the message, the take is sent later without that field.
+
+use crate::config::settings::Settings;
+use crate::db::{add_order_reservation, find_order_reservation};
+use crate::order_locks::lock_order;
+use crate::util::{enqueue_order_msg, get_message_extension, get_order, update_order_event};
+use mostro_core::prelude::*;
+use nostr::nips::nip59::UnwrappedGift;
+use nostr_sdk::prelude::*;
+use sqlx::{Pool, Sqlite};
+use tracing::info;
+
+/// Checks that the order is not reserved by another taker
+pub async fn check_order_reservation(
+    pool: &Pool<Sqlite>,
+    order: &Order,
+    taker_pubkey: &PublicKey,
+) -> Result<(), MostroError> {
+    let now = Timestamp::now().as_u64() as i64;
+    match find_order_reservation(pool, order.id, now).await? {
+        Some(holder) if holder != taker_pubkey.to_string() => {
+            info!("Order Id {}: reserved by another taker", order.id);
+            Err(MostroCantDo(CantDoReason::NotAllowedByStatus))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Tells if a take message only asks to reserve the order
+pub fn is_reservation(event: &UnwrappedGift) -> bool {
+    get_message_extension::<bool>(event, "reserve").unwrap_or(false)
+}
+
+pub async fn reserve_order_action(
+    msg: Message,
+    event: &UnwrappedGift,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    let request_id = msg.get_inner_message_kind().request_id;
+    let reservation_seconds = Settings::get_mostro().order_reservation_seconds;
+    if reservation_seconds == 0 {
+        return Err(MostroCantDo(CantDoReason::InvalidParameters));
+    }
+
+    // No take or expiry changes the order meanwhile
+    let _order_lock = lock_order(msg.get_inner_message_kind().id).await;
+    let order = get_order(&msg, pool).await?;
+    // Only pending orders can be reserved
+    if let Err(cause) = order.check_status(Status::Pending) {
+        return Err(MostroCantDo(cause));
+    }
+    // Makers can't reserve their own orders
+    order
+        .not_sent_from_maker(event.rumor.pubkey)
+        .map_err(MostroCantDo)?;
+
+    // Only one reservation at a time per order
+    let now = Timestamp::now().as_u64() as i64;
+    let expires_at = now + reservation_seconds as i64;
+    if !add_order_reservation(
+        pool,
+        order.id,
+        &event.rumor.pubkey.to_string(),
+        now,
+        expires_at,
+    )
+    .await?
+    {
+        return Err(MostroCantDo(CantDoReason::NotAllowedByStatus));
+    }
+    info!(
+        "Order Id {}: reserved by {} until {}",
+        order.id, event.rumor.pubkey, expires_at
+    );
+
+    // Hide the order from the book, the status in database is still pending
+    update_order_event(my_keys, Status::InProgress, &order).await?;
+
+    enqueue_order_msg(
+        request_id,
+        Some(order.id),
+        msg.get_inner_message_kind().action.clone(),
+        None,
+        event.rumor.pubkey,
+        msg.get_inner_message_kind().trade_index,
+    )
+    .await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::test_pool;
+    use crate::db::{
+        add_order_reservation, find_order_reservation, remove_expired_order_reservations,
+    };
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_reservation_blocks_others_and_expires() {
+        let pool = test_pool().await;
+        let order_id = Uuid::new_v4();
+        let (alice, bob) = ("a".repeat(64), "b".repeat(64));
+        let now = 1_700_000_000;
+
+        // Alice reserves the order for 60 seconds
+        assert!(
+            add_order_reservation(&pool, order_id, &alice, now, now + 60)
+                .await
+                .unwrap()
+        );
+        // Bob can't reserve it meanwhile
+        assert!(
+            !add_order_reservation(&pool, order_id, &bob, now + 30, now + 90)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            find_order_reservation(&pool, order_id, now + 30)
+                .await
+                .unwrap(),
+            Some(alice.clone())
+        );
+
+        // Once expired the order is released
+        assert!(find_order_reservation(&pool, order_id, now + 60)
+            .await
+            .unwrap()
+            .is_none());
+        let expired = remove_expired_order_reservations(&pool, now + 60)
+            .await
+            .unwrap();
+        assert_eq!(expired, vec![order_id]);
+        assert!(
+            add_order_reservation(&pool, order_id, &bob, now + 61, now + 121)
+                .await
+                .unwrap()
+        );
+    }
+}
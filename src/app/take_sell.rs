@@ -1,7 +1,14 @@
+use crate::analytics::{record_rate_event, RateEvent};
+use crate::app::reserve_order::check_order_reservation;
 use crate::config::MOSTRO_DB_PASSWORD;
 use crate::db::{buyer_has_pending_order, update_user_trade_index};
+use crate::drain::check_not_draining;
+use crate::order_locks::lock_order;
 use crate::util::{
-    get_fiat_amount_requested, get_market_amount_and_fee, get_order, set_waiting_invoice_status,
+    check_active_orders_limit, check_fiat_code_enabled, check_maker_alive, check_not_node_pubkey,
+    check_not_self_trade, check_take_payment_method, check_taker_reputation,
+    check_taker_trade_limit, enqueue_memos, enqueue_settlement_estimate, get_fiat_amount_requested,
+    get_memo, get_order, recompute_take_amount_and_fee, set_waiting_invoice_status,
     show_hold_invoice, update_order_event, validate_invoice,
 };
 use mostro_core::prelude::*;
@@ -40,11 +47,23 @@ pub async fn take_sell_action(
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
 ) -> Result<(), MostroError> {
+    // No other handler changes the order meanwhile
+    let _order_lock = lock_order(msg.get_inner_message_kind().id).await;
     // Get order
     let mut order = get_order(&msg, pool).await?;
 
     // Get request id
     let request_id = msg.get_inner_message_kind().request_id;
+    // The node can't be a party of the trade
+    let mut parties = vec![event.sender, event.rumor.pubkey];
+    parties.extend(PublicKey::parse(&order.creator_pubkey).ok());
+    check_not_node_pubkey(my_keys, &parties)?;
+    // No new trades while the node drains for maintenance
+    check_not_draining()?;
+    // Currencies turned off by the operator can't start new trades
+    check_fiat_code_enabled(pool, &order.fiat_code).await?;
+    // Each user has a limit of orders open or being traded
+    check_active_orders_limit(pool, &event.sender).await?;
     // Check if the seller has a pending order
     if buyer_has_pending_order(pool, event.sender.to_string()).await? {
         return Err(MostroCantDo(CantDoReason::PendingOrderExists));
@@ -63,6 +82,19 @@ pub async fn take_sell_action(
     order
         .not_sent_from_maker(event.rumor.pubkey)
         .map_err(MostroCantDo)?;
+    // Neither from another trade key of the maker
+    check_not_self_trade(&order, event)?;
+
+    // Reserved orders can only be taken by the taker holding the reservation
+    check_order_reservation(pool, &order, &event.rumor.pubkey).await?;
+    // Makers gone silent would leave the taker waiting, the order stays pending
+    check_maker_alive(pool, &order).await?;
+    // Takers pay or get paid with one of the methods the maker accepts
+    check_take_payment_method(pool, &order, event).await?;
+    // Makers can ask for a minimum rating of the taker
+    check_taker_reputation(pool, &order, &event.sender).await?;
+    // Memo for the maker, checked before the order is taken
+    let taker_memo = get_memo(event, false)?;
 
     // Get seller pubkey
     let seller_pubkey = order.get_seller_pubkey().map_err(MostroInternalErr)?;
@@ -70,12 +102,9 @@ pub async fn take_sell_action(
     // Validate invoice and get payment request if present
     let payment_request = validate_invoice(&msg, &order).await?;
 
-    // Get amount request if user requested one for range order - fiat amount will be used below
-    if let Some(am) = get_fiat_amount_requested(&order, &msg) {
-        order.fiat_amount = am;
-    } else {
-        return Err(MostroCantDo(CantDoReason::OutOfRangeSatsAmount));
-    }
+    // Range orders are taken at the fiat amount picked by the taker, the sats
+    // amount is quoted for it below
+    order.fiat_amount = get_fiat_amount_requested(&order, &msg)?;
 
     // Add buyer pubkey to order
     order.buyer_pubkey = Some(event.rumor.pubkey.to_string());
@@ -100,22 +129,18 @@ pub async fn take_sell_action(
     // Timestamp take order time
     order.set_timestamp_now();
 
-    // Check market price value in sats - if order was with market price then calculate it and send a DM to buyer
-    if order.has_no_amount() {
-        match get_market_amount_and_fee(order.fiat_amount, &order.fiat_code, order.premium).await {
-            Ok(amount_fees) => {
-                order.amount = amount_fees.0;
-                order.fee = amount_fees.1
-            }
-            Err(_) => return Err(MostroInternalErr(ServiceError::WrongAmountError)),
-        };
-    }
+    // Calculate amount and fee for the current quote
+    recompute_take_amount_and_fee(pool, &mut order).await?;
+    // New takers can only take small amounts
+    check_taker_trade_limit(pool, &order, &event.sender).await?;
 
     // Update trade index only after all checks are done
     update_user_trade_index(pool, event.sender.to_string(), trade_index)
         .await
         .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
 
+    // Kept to send the settlement estimate after the confirmation
+    let taken_order = order.clone();
     // If payment request is not present, update order status to waiting buyer invoice
     if payment_request.is_none() {
         update_order_status(&mut order, my_keys, pool, request_id).await?;
@@ -132,6 +157,27 @@ pub async fn take_sell_action(
         )
         .await?;
     }
+    enqueue_settlement_estimate(
+        &taken_order,
+        Action::TakeSell,
+        event.rumor.pubkey,
+        taken_order.trade_index_buyer,
+        request_id,
+    )
+    .await;
+    // Each party gets the memo of the other one
+    if let Err(e) = enqueue_memos(
+        pool,
+        &taken_order,
+        event.rumor.pubkey,
+        taker_memo.as_deref(),
+        request_id,
+    )
+    .await
+    {
+        tracing::error!("Order Id {}: error sending memos: {}", taken_order.id, e);
+    }
+    record_rate_event(RateEvent::OrderTaken, &event.sender);
 
     Ok(())
 }
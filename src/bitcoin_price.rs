@@ -1,6 +1,7 @@
 use crate::config::settings::Settings;
 use crate::lnurl::HTTP_CLIENT;
 use mostro_core::prelude::*;
+use nostr_sdk::Timestamp;
 use once_cell::sync::Lazy;
 use serde::Deserialize;
 use std::collections::HashMap;
@@ -13,15 +14,36 @@ struct YadioResponse {
     btc: HashMap<String, f64>,
 }
 
-static BITCOIN_PRICES: Lazy<RwLock<HashMap<String, f64>>> =
-    Lazy::new(|| RwLock::new(HashMap::new()));
+#[derive(Debug, Deserialize)]
+struct BlockchainTicker {
+    last: f64,
+}
 
-pub struct BitcoinPriceManager;
+/// Blockchain.com ticker, used as a fallback source
+const BLOCKCHAIN_TICKER_URL: &str = "https://blockchain.info/ticker";
 
-impl BitcoinPriceManager {
-    pub async fn update_prices() -> Result<(), MostroError> {
-        let mostro_settings = Settings::get_mostro();
-        let api_url = format!("{}/exrates/BTC", mostro_settings.bitcoin_price_api_url);
+/// Source of bitcoin prices in fiat currencies
+#[tonic::async_trait]
+pub trait PriceProvider: Send + Sync {
+    /// Name used in settings and logs
+    fn name(&self) -> &str;
+
+    /// Price of one bitcoin by fiat currency code
+    async fn fetch_prices(&self) -> Result<HashMap<String, f64>, MostroError>;
+}
+
+pub struct YadioProvider {
+    api_url: String,
+}
+
+#[tonic::async_trait]
+impl PriceProvider for YadioProvider {
+    fn name(&self) -> &str {
+        "yadio"
+    }
+
+    async fn fetch_prices(&self) -> Result<HashMap<String, f64>, MostroError> {
+        let api_url = format!("{}/exrates/BTC", self.api_url);
         let response = HTTP_CLIENT
             .get(&api_url)
             .send()
@@ -31,27 +53,232 @@ impl BitcoinPriceManager {
             .json()
             .await
             .map_err(|_| MostroInternalErr(ServiceError::MessageSerializationError))?;
+        Ok(yadio_response.btc)
+    }
+}
+
+pub struct BlockchainProvider;
+
+#[tonic::async_trait]
+impl PriceProvider for BlockchainProvider {
+    fn name(&self) -> &str {
+        "blockchain"
+    }
+
+    async fn fetch_prices(&self) -> Result<HashMap<String, f64>, MostroError> {
+        let response = HTTP_CLIENT
+            .get(BLOCKCHAIN_TICKER_URL)
+            .send()
+            .await
+            .map_err(|_| MostroInternalErr(ServiceError::NoAPIResponse))?;
+        let tickers: HashMap<String, BlockchainTicker> = response
+            .json()
+            .await
+            .map_err(|_| MostroInternalErr(ServiceError::MessageSerializationError))?;
+        Ok(tickers
+            .into_iter()
+            .map(|(currency, ticker)| (currency, ticker.last))
+            .collect())
+    }
+}
+
+/// Providers named in `price_providers` in priority order, yadio when none is
+/// configured. Unknown names are skipped.
+fn configured_providers(names: &[String], yadio_api_url: &str) -> Vec<Box<dyn PriceProvider>> {
+    let yadio = || -> Box<dyn PriceProvider> {
+        Box::new(YadioProvider {
+            api_url: yadio_api_url.to_string(),
+        })
+    };
+    if names.is_empty() {
+        return vec![yadio()];
+    }
+    names
+        .iter()
+        .filter_map(|name| match name.as_str() {
+            "yadio" => Some(yadio()),
+            "blockchain" => Some(Box::new(BlockchainProvider) as Box<dyn PriceProvider>),
+            _ => {
+                tracing::warn!("Ignoring unknown price provider {}", name);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Prices from the first provider answering, in priority order
+async fn fetch_first_prices(
+    providers: &[Box<dyn PriceProvider>],
+) -> Result<(String, HashMap<String, f64>), MostroError> {
+    for provider in providers {
+        match provider.fetch_prices().await {
+            Ok(prices) if !prices.is_empty() => return Ok((provider.name().to_string(), prices)),
+            Ok(_) => tracing::warn!("Price provider {} returned no prices", provider.name()),
+            Err(e) => tracing::warn!("Price provider {} failed: {}", provider.name(), e),
+        }
+    }
+    Err(MostroInternalErr(ServiceError::NoAPIResponse))
+}
+
+/// Price of a currency and the unix time it was fetched
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CachedPrice {
+    price: f64,
+    updated_at: i64,
+}
+
+static BITCOIN_PRICES: Lazy<RwLock<HashMap<String, CachedPrice>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Provider of the cached prices
+static PRICES_PROVIDER: Lazy<RwLock<String>> = Lazy::new(|| RwLock::new(String::new()));
+
+/// Price used to quote an order, with its source
+#[derive(Debug, Clone, PartialEq)]
+pub struct PriceQuote {
+    pub price: f64,
+    pub provider: String,
+    pub updated_at: i64,
+}
+
+pub struct BitcoinPriceManager;
+
+impl BitcoinPriceManager {
+    pub async fn update_prices() -> Result<(), MostroError> {
+        let mostro_settings = Settings::get_mostro();
+        let providers = configured_providers(
+            &mostro_settings.price_providers,
+            &mostro_settings.bitcoin_price_api_url,
+        );
+        let (provider, prices) = fetch_first_prices(&providers).await?;
         info!(
-            "Bitcoin prices updated. Got BTC price in {} fiat currencies",
-            yadio_response.btc.keys().collect::<Vec<&String>>().len()
+            "Bitcoin prices updated from {}. Got BTC price in {} fiat currencies",
+            provider,
+            prices.len()
         );
+        Self::store_prices(provider, prices)
+    }
 
-        let mut prices_write = BITCOIN_PRICES
+    /// Replace the cached prices, the lock is only held to swap them
+    fn store_prices(provider: String, prices: HashMap<String, f64>) -> Result<(), MostroError> {
+        let updated_at = Timestamp::now().as_u64() as i64;
+        let prices = prices
+            .into_iter()
+            .map(|(currency, price)| (currency, CachedPrice { price, updated_at }))
+            .collect();
+        *BITCOIN_PRICES
             .write()
-            .map_err(|e| MostroInternalErr(ServiceError::IOError(e.to_string())))?;
-        *prices_write = yadio_response.btc;
+            .map_err(|e| MostroInternalErr(ServiceError::IOError(e.to_string())))? = prices;
+        if let Ok(mut provider_write) = PRICES_PROVIDER.write() {
+            *provider_write = provider;
+        }
         Ok(())
     }
 
+    /// Cached price of the currency, fetched from the configured providers
+    /// when it is not cached yet, e.g. before the first update
+    pub async fn get_price_or_fetch(currency: &str) -> Result<f64, MostroError> {
+        let mostro_settings = Settings::get_mostro();
+        let providers = configured_providers(
+            &mostro_settings.price_providers,
+            &mostro_settings.bitcoin_price_api_url,
+        );
+        Self::price_or_fetch_with(currency, &providers).await
+    }
+
+    /// No lock is held while fetching, concurrent callers fetch on their own
+    /// instead of waiting for each other
+    async fn price_or_fetch_with(
+        currency: &str,
+        providers: &[Box<dyn PriceProvider>],
+    ) -> Result<f64, MostroError> {
+        if let Ok(price) = Self::get_price(currency) {
+            return Ok(price);
+        }
+        let (provider, prices) = fetch_first_prices(providers).await?;
+        let price = prices
+            .get(currency)
+            .copied()
+            .ok_or(MostroInternalErr(ServiceError::NoCurrency))?;
+        Self::store_prices(provider, prices)?;
+        Ok(price)
+    }
+
     pub fn get_price(currency: &str) -> Result<f64, MostroError> {
-        let prices_read: std::sync::RwLockReadGuard<'_, HashMap<String, f64>> = BITCOIN_PRICES
-            .read()
-            .map_err(|e| MostroInternalErr(ServiceError::IOError(e.to_string())))?;
+        let prices_read: std::sync::RwLockReadGuard<'_, HashMap<String, CachedPrice>> =
+            BITCOIN_PRICES
+                .read()
+                .map_err(|e| MostroInternalErr(ServiceError::IOError(e.to_string())))?;
         prices_read
             .get(currency)
-            .cloned()
+            .map(|cached| cached.price)
             .ok_or(MostroInternalErr(ServiceError::NoAPIResponse))
     }
+
+    /// Price of the currency, refused when it was fetched more than
+    /// `max_age_secs` ago. A zero max age accepts any cached price.
+    pub fn get_fresh_price(currency: &str, max_age_secs: u64) -> Result<f64, MostroError> {
+        let prices_read = BITCOIN_PRICES
+            .read()
+            .map_err(|e| MostroInternalErr(ServiceError::IOError(e.to_string())))?;
+        fresh_price(
+            &prices_read,
+            currency,
+            Timestamp::now().as_u64() as i64,
+            max_age_secs,
+        )
+    }
+
+    /// Fresh price of the currency with the provider it came from and when
+    pub fn get_fresh_quote(currency: &str, max_age_secs: u64) -> Result<PriceQuote, MostroError> {
+        let prices_read = BITCOIN_PRICES
+            .read()
+            .map_err(|e| MostroInternalErr(ServiceError::IOError(e.to_string())))?;
+        let price = fresh_price(
+            &prices_read,
+            currency,
+            Timestamp::now().as_u64() as i64,
+            max_age_secs,
+        )?;
+        let updated_at = prices_read
+            .get(currency)
+            .map(|cached| cached.updated_at)
+            .unwrap_or_default();
+        let provider = PRICES_PROVIDER
+            .read()
+            .map(|provider| provider.clone())
+            .unwrap_or_default();
+        Ok(PriceQuote {
+            price,
+            provider,
+            updated_at,
+        })
+    }
+
+    /// Currency codes quoted in the last prices update, empty until the first one
+    pub fn supported_currencies() -> Vec<String> {
+        BITCOIN_PRICES
+            .read()
+            .map(|prices| prices.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+fn fresh_price(
+    prices: &HashMap<String, CachedPrice>,
+    currency: &str,
+    now: i64,
+    max_age_secs: u64,
+) -> Result<f64, MostroError> {
+    let cached = prices
+        .get(currency)
+        .ok_or(MostroInternalErr(ServiceError::NoAPIResponse))?;
+    let age = now - cached.updated_at;
+    if max_age_secs > 0 && age > max_age_secs as i64 {
+        info!("Refusing {currency} price fetched {age} seconds ago");
+        return Err(MostroCantDo(CantDoReason::InvalidAmount));
+    }
+    Ok(cached.price)
 }
 
 #[cfg(test)]
@@ -82,6 +309,144 @@ mod tests {
         assert_eq!(response.btc.len(), 3);
     }
 
+    #[test]
+    fn test_stale_price_refused() {
+        let now = 1_700_000_000;
+        let prices = HashMap::from([
+            (
+                "USD".to_string(),
+                CachedPrice {
+                    price: 50000.0,
+                    updated_at: now - 60,
+                },
+            ),
+            (
+                "EUR".to_string(),
+                CachedPrice {
+                    price: 45000.0,
+                    updated_at: now - 3600,
+                },
+            ),
+        ]);
+
+        assert_eq!(fresh_price(&prices, "USD", now, 600).unwrap(), 50000.0);
+        assert!(matches!(
+            fresh_price(&prices, "EUR", now, 600),
+            Err(MostroCantDo(CantDoReason::InvalidAmount))
+        ));
+        // Without a max age any cached price is used
+        assert_eq!(fresh_price(&prices, "EUR", now, 0).unwrap(), 45000.0);
+        assert!(fresh_price(&prices, "VES", now, 600).is_err());
+    }
+
+    struct MockProvider {
+        name: &'static str,
+        prices: Option<HashMap<String, f64>>,
+    }
+
+    #[tonic::async_trait]
+    impl PriceProvider for MockProvider {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        async fn fetch_prices(&self) -> Result<HashMap<String, f64>, MostroError> {
+            self.prices
+                .clone()
+                .ok_or(MostroInternalErr(ServiceError::NoAPIResponse))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_price_provider_failover() {
+        let providers: Vec<Box<dyn PriceProvider>> = vec![
+            Box::new(MockProvider {
+                name: "down",
+                prices: None,
+            }),
+            Box::new(MockProvider {
+                name: "backup",
+                prices: Some(HashMap::from([("USD".to_string(), 50000.0)])),
+            }),
+        ];
+        let (provider, prices) = fetch_first_prices(&providers).await.unwrap();
+        assert_eq!(provider, "backup");
+        assert_eq!(prices.get("USD"), Some(&50000.0));
+
+        // Every provider down
+        assert!(fetch_first_prices(&providers[..1]).await.is_err());
+    }
+
+    /// Provider answering after a delay, like a slow price API
+    struct SlowProvider {
+        delay: std::time::Duration,
+        prices: HashMap<String, f64>,
+    }
+
+    #[tonic::async_trait]
+    impl PriceProvider for SlowProvider {
+        fn name(&self) -> &str {
+            "slow"
+        }
+
+        async fn fetch_prices(&self) -> Result<HashMap<String, f64>, MostroError> {
+            tokio::time::sleep(self.delay).await;
+            Ok(self.prices.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_price_fetches() {
+        let delay = std::time::Duration::from_millis(200);
+        // A currency no other test caches, so both calls have to fetch
+        let providers: Vec<Box<dyn PriceProvider>> = vec![Box::new(SlowProvider {
+            delay,
+            prices: HashMap::from([("XTS".to_string(), 123.0)]),
+        })];
+
+        // Both fetches wait at once on the single threaded runtime, they
+        // would take twice the delay if one blocked the other
+        let started = std::time::Instant::now();
+        let (first, second) = tokio::join!(
+            BitcoinPriceManager::price_or_fetch_with("XTS", &providers),
+            BitcoinPriceManager::price_or_fetch_with("XTS", &providers),
+        );
+        assert_eq!(first.unwrap(), 123.0);
+        assert_eq!(second.unwrap(), 123.0);
+        assert!(started.elapsed() < delay * 2);
+    }
+
+    #[test]
+    fn test_configured_providers_order() {
+        let names = |providers: Vec<Box<dyn PriceProvider>>| {
+            providers
+                .iter()
+                .map(|provider| provider.name().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(configured_providers(&[], "https://api.yadio.io")),
+            vec!["yadio"]
+        );
+        let configured = vec![
+            "blockchain".to_string(),
+            "bogus".to_string(),
+            "yadio".to_string(),
+        ];
+        assert_eq!(
+            names(configured_providers(&configured, "https://api.yadio.io")),
+            vec!["blockchain", "yadio"]
+        );
+    }
+
+    #[test]
+    fn test_blockchain_ticker_deserialization() {
+        let json_response = r#"{"USD": {"15m": 50010.0, "last": 50000.0, "buy": 50000.0, "sell": 50000.0, "symbol": "$"}}"#;
+        let tickers: HashMap<String, BlockchainTicker> =
+            serde_json::from_str(json_response).unwrap();
+        assert_eq!(tickers["USD"].last, 50000.0);
+    }
+
     #[test]
     fn test_yadio_response_invalid_json() {
         // Test deserialization with invalid JSON
@@ -2,8 +2,16 @@
 // / Initialize the default directory for the settings file
 //! CLI
 
-use crate::config::util::init_configuration_file;
-use clap::Parser;
+use crate::config::util::{check_configuration_file, init_configuration_file, settings_summary};
+use crate::db;
+use crate::lightning::dry_run::set_dry_run;
+use crate::models::Backup;
+use clap::{Parser, Subcommand};
+use mostro_core::prelude::*;
+use std::path::PathBuf;
+
+/// Environment variable with the settings folder, used when `-d` is not given
+pub const SETTINGS_DIR_ENV: &str = "MOSTRO_SETTINGS_DIR";
 
 #[derive(Parser)]
 #[command(
@@ -24,30 +32,124 @@ use clap::Parser;
 #[command(propagate_version = true)]
 #[command(arg_required_else_help(false))]
 pub struct Cli {
-    /// Set folder for Mostro settings file - default is HOME/.mostro
+    /// Set folder for Mostro settings file - default is MOSTRO_SETTINGS_DIR or HOME/.mostro
     #[arg(short, long)]
     dirsettings: Option<String>,
+    /// Log the lightning calls instead of sending them to the node
+    #[arg(long)]
+    dry_run: bool,
+    /// Validate the settings file, print a summary and exit
+    #[arg(long)]
+    config_check: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Maintenance commands, Mostro exits once they are done
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Export the order book to a JSON file
+    Export {
+        /// File the backup is written to
+        #[arg(long)]
+        out: PathBuf,
+        /// Include the users with their ratings
+        #[arg(long)]
+        with_ratings: bool,
+        /// Include the disputes
+        #[arg(long)]
+        with_disputes: bool,
+    },
+    /// Import a backup into an empty database, it has to use the password of
+    /// the exported one to read the encrypted fields. Admin passwords are not
+    /// in backups and are set again
+    Import {
+        /// Backup written by the export command
+        #[arg(long = "in")]
+        input: PathBuf,
+    },
+}
+
+/// Settings folder from the CLI flag, else from the environment variable,
+/// None is the default HOME/.mostro
+fn settings_dir(cli_dir: Option<String>, env_dir: Option<String>) -> Option<String> {
+    cli_dir.or(env_dir.filter(|dir| !dir.is_empty()))
 }
 
 /// Initialize the settings file and create the global config variable for Mostro settings
 /// Default folder is HOME but user can specify a custom folder with dirsettings (-d ) parameter from CLI
+/// or with the MOSTRO_SETTINGS_DIR environment variable
 /// Example: mostro p2p -d /user_folder/mostro
 pub fn settings_init() -> Result<(), Box<dyn std::error::Error>> {
     // Parse CLI arguments
     let cli = Cli::parse();
 
-    // Select config file from CLI or default to HOME/.mostro
+    let dir = settings_dir(cli.dirsettings, std::env::var(SETTINGS_DIR_ENV).ok());
+    if cli.config_check {
+        match check_configuration_file(dir) {
+            Ok(settings) => {
+                println!("Settings are valid\n{}", settings_summary(&settings));
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Invalid settings: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if cli.dry_run {
+        set_dry_run(true);
+    }
+
+    // Select config file from CLI, environment or default to HOME/.mostro
     // create config file if it doesn't exist
-    if let Some(path) = cli.dirsettings.as_deref() {
-        init_configuration_file(Some(path.to_string()))?
-    } else {
-        init_configuration_file(None)?
-    };
+    init_configuration_file(dir)?;
 
     // Mostro settings are initialized
     Ok(())
 }
 
+/// Maintenance command given in the command line, if any
+pub fn command() -> Option<Command> {
+    Cli::parse().command
+}
+
+/// Run a maintenance command against the database of the settings
+pub async fn run_command(command: Command) -> Result<(), MostroError> {
+    let pool = db::connect().await?;
+    let io_err = |e: std::io::Error| MostroInternalErr(ServiceError::IOError(e.to_string()));
+    match command {
+        Command::Export {
+            out,
+            with_ratings,
+            with_disputes,
+        } => {
+            let backup = db::export_backup(&pool, with_ratings, with_disputes).await?;
+            let json = serde_json::to_string_pretty(&backup)
+                .map_err(|_| MostroInternalErr(ServiceError::MessageSerializationError))?;
+            std::fs::write(&out, json).map_err(io_err)?;
+            println!(
+                "Exported {} orders to {}",
+                backup.rows("orders"),
+                out.display()
+            );
+        }
+        Command::Import { input } => {
+            let json = std::fs::read_to_string(&input).map_err(io_err)?;
+            let backup: Backup = serde_json::from_str(&json)
+                .map_err(|e| MostroInternalErr(ServiceError::IOError(e.to_string())))?;
+            db::import_backup(&pool, &backup).await?;
+            println!(
+                "Imported {} orders from {}",
+                backup.rows("orders"),
+                input.display()
+            );
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -56,11 +158,19 @@ mod tests {
     #[test]
     fn test_cli_parser_creation() {
         // Test that CLI struct can be created
-        let cli = Cli { dirsettings: None };
+        let cli = Cli {
+            dirsettings: None,
+            dry_run: false,
+            config_check: false,
+            command: None,
+        };
         assert!(cli.dirsettings.is_none());
 
         let cli_with_path = Cli {
             dirsettings: Some("/custom/path".to_string()),
+            dry_run: false,
+            config_check: false,
+            command: None,
         };
         assert_eq!(cli_with_path.dirsettings.unwrap(), "/custom/path");
     }
@@ -79,6 +189,59 @@ mod tests {
         assert!(result.is_err()); // Version exits with error code
     }
 
+    #[test]
+    fn test_cli_parsing_dry_run() {
+        let cli = Cli::try_parse_from(["mostro", "--dry-run"]).unwrap();
+        assert!(cli.dry_run);
+        let cli = Cli::try_parse_from(["mostro"]).unwrap();
+        assert!(!cli.dry_run);
+    }
+
+    #[test]
+    fn test_settings_dir_precedence() {
+        let flag = Some("/from/flag".to_string());
+        let env = Some("/from/env".to_string());
+        // Flag over environment
+        assert_eq!(settings_dir(flag.clone(), env.clone()), flag);
+        assert_eq!(settings_dir(flag.clone(), None), flag);
+        // Environment when the flag is absent
+        assert_eq!(settings_dir(None, env.clone()), env);
+        // Default HOME folder otherwise
+        assert_eq!(settings_dir(None, None), None);
+        assert_eq!(settings_dir(None, Some(String::new())), None);
+    }
+
+    #[test]
+    fn test_cli_parsing_config_check() {
+        let cli = Cli::try_parse_from(["mostro", "--config-check", "-d", "/test/path"]).unwrap();
+        assert!(cli.config_check);
+        assert_eq!(cli.dirsettings.unwrap(), "/test/path");
+    }
+
+    #[test]
+    fn test_cli_parsing_export_import() {
+        let cli =
+            Cli::try_parse_from(["mostro", "export", "--out", "orders.json", "--with-ratings"])
+                .unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::Export {
+                out: PathBuf::from("orders.json"),
+                with_ratings: true,
+                with_disputes: false,
+            })
+        );
+        let cli = Cli::try_parse_from(["mostro", "-d", "/test/path", "import", "--in", "b.json"])
+            .unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::Import {
+                input: PathBuf::from("b.json"),
+            })
+        );
+        assert!(Cli::try_parse_from(["mostro", "export"]).is_err());
+    }
+
     #[test]
     fn test_cli_parsing_no_args() {
         // Test parsing with no arguments (should succeed)
@@ -134,6 +297,9 @@ mod tests {
             let custom_path = Some("/custom/path".to_string());
             let cli = Cli {
                 dirsettings: custom_path.clone(),
+                dry_run: false,
+                config_check: false,
+                command: None,
             };
 
             if let Some(path) = cli.dirsettings.as_deref() {
@@ -146,7 +312,12 @@ mod tests {
         #[test]
         fn test_default_path_handling() {
             // Test the logical flow of default path handling
-            let cli = Cli { dirsettings: None };
+            let cli = Cli {
+                dirsettings: None,
+                dry_run: false,
+                config_check: false,
+                command: None,
+            };
 
             if cli.dirsettings.is_none() {
                 // This is the expected path for default settings
@@ -1,4 +1,5 @@
 // Mostro module for configurataion settings
+pub mod reload;
 pub mod settings;
 /// This module provides functionality to manage and initialize settings for the Mostro application.
 /// It includes structures for database, lightning, Nostr, and Mostro settings, as well as functions to initialize and access these settings.
@@ -45,6 +46,45 @@ pub struct MessageQueues {
 
 pub static MESSAGE_QUEUES: LazyLock<MessageQueues> = LazyLock::new(MessageQueues::default);
 
+/// Set the global settings to the ones of the template, for tests of code
+/// reading them. Settings are only set once per test run
+#[cfg(test)]
+pub fn init_test_settings() {
+    MOSTRO_CONFIG.get_or_init(|| {
+        toml::from_str(include_str!("../../settings.tpl.toml"))
+            .expect("Failed to parse the settings template")
+    });
+}
+
+/// Set the global database pool to a migrated database of this test run, for
+/// tests of code reading it
+#[cfg(test)]
+pub async fn init_test_db_pool() {
+    use sqlx::Connection;
+    use std::str::FromStr;
+
+    static INIT: tokio::sync::OnceCell<()> = tokio::sync::OnceCell::const_new();
+    INIT.get_or_init(|| async {
+        let options = sqlx::sqlite::SqliteConnectOptions::from_str("sqlite::memory:")
+            .expect("Invalid test database url");
+        // The database goes away with its last connection, pool connections
+        // can be dropped with the runtime of the test that used them last
+        let keeper = sqlx::SqliteConnection::connect_with(&options)
+            .await
+            .expect("Failed to open the test database");
+        Box::leak(Box::new(keeper));
+        let pool = sqlx::SqlitePool::connect_with(options)
+            .await
+            .expect("Failed to open the test database");
+        sqlx::migrate!()
+            .run(&pool)
+            .await
+            .expect("Failed to run the migrations");
+        let _ = DB_POOL.set(Arc::new(pool));
+    })
+    .await;
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -174,4 +214,32 @@ mod tests {
             "https://api.yadio.io"
         );
     }
+
+    #[test]
+    fn test_order_templates_published_as_configured() {
+        let settings = format!(
+            r#"{MOSTRO_SETTINGS}
+            order_templates = [
+              {{ name = 'Bank transfer', payment_methods = ['SEPA', 'Wise'], fiat_codes = ['EUR'], expiration_seconds = 3600 }},
+              {{ name = 'Cash' }},
+            ]"#
+        );
+        let mostro_settings: StubSettingsMostro =
+            toml::from_str(&settings).expect("Failed to deserialize");
+        let templates = mostro_settings.mostro.order_templates;
+        assert_eq!(templates.len(), 2);
+        assert_eq!(templates[0].payment_methods, vec!["SEPA", "Wise"]);
+        assert!(templates[1].fiat_codes.is_empty());
+
+        // Clients get the same templates from the signed event
+        let keys = nostr_sdk::Keys::generate();
+        let event = crate::nip33::order_templates_event(&keys, &templates).unwrap();
+        assert!(event.verify().is_ok());
+        assert_eq!(
+            event.tags.identifier(),
+            Some(crate::nip33::ORDER_TEMPLATES_IDENTIFIER)
+        );
+        let published: Vec<types::OrderTemplate> = serde_json::from_str(&event.content).unwrap();
+        assert_eq!(published, templates);
+    }
 }
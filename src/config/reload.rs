@@ -0,0 +1,262 @@
+//! Settings reloaded on SIGHUP.
+//!
+//! Operators change relays, order limits, the proof of work or the allowlist
+//! without a restart dropping the connections: the settings file is read again
+//! on SIGHUP and those settings replace the running ones at once. Changes to
+//! other settings are logged and only applied on the next restart.
+
+use crate::allowlist::set_allowlist;
+use crate::config::util::reread_settings;
+use crate::config::{Settings, MOSTRO_CONFIG, NOSTR_CLIENT};
+use crate::relay_monitor::{gift_wrap_filter, RelayLink};
+use crate::util::get_keys;
+use mostro_core::prelude::*;
+use nostr_sdk::Client;
+use std::sync::RwLock;
+use tracing::{error, info, warn};
+
+/// Settings applied without a restart
+#[derive(Debug, Clone, PartialEq)]
+pub struct HotSettings {
+    pub max_order_amount: u32,
+    pub min_payment_amount: u32,
+    pub pow: u8,
+    pub relays: Vec<String>,
+    pub allowlist: Vec<String>,
+}
+
+impl From<&Settings> for HotSettings {
+    fn from(settings: &Settings) -> Self {
+        Self {
+            max_order_amount: settings.mostro.max_order_amount,
+            min_payment_amount: settings.mostro.min_payment_amount,
+            pow: settings.mostro.pow,
+            relays: settings.nostr.relays.clone(),
+            allowlist: settings.mostro.allowlist.clone(),
+        }
+    }
+}
+
+/// Reloaded settings, unset until the first reload
+#[cfg(not(test))]
+static HOT_SETTINGS: std::sync::LazyLock<RwLock<Option<HotSettings>>> =
+    std::sync::LazyLock::new(RwLock::default);
+
+#[cfg(test)]
+thread_local! {
+    /// Reloaded settings of the test running on this thread, tests reloading
+    /// them don't change the limits of the ones running next to them
+    static TEST_HOT_SETTINGS: &'static RwLock<Option<HotSettings>> = Box::leak(Box::default());
+}
+
+#[cfg(not(test))]
+fn hot_settings_lock() -> &'static RwLock<Option<HotSettings>> {
+    &HOT_SETTINGS
+}
+
+#[cfg(test)]
+fn hot_settings_lock() -> &'static RwLock<Option<HotSettings>> {
+    TEST_HOT_SETTINGS.with(|hot| *hot)
+}
+
+/// Running settings that can change without a restart, the ones Mostro was
+/// started with until the first reload
+pub fn hot_settings() -> HotSettings {
+    let reloaded = match hot_settings_lock().read() {
+        Ok(hot) => hot.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    };
+    reloaded.unwrap_or_else(|| {
+        HotSettings::from(MOSTRO_CONFIG.get().expect("No Mostro settings found"))
+    })
+}
+
+/// Description of each setting changing from `current` to `new`
+fn hot_changes(current: &HotSettings, new: &HotSettings) -> Vec<String> {
+    let mut changes = Vec::new();
+    let mut compare = |name: &str, current: String, new: String| {
+        if current != new {
+            changes.push(format!("{name}: {current} -> {new}"));
+        }
+    };
+    compare(
+        "max_order_amount",
+        current.max_order_amount.to_string(),
+        new.max_order_amount.to_string(),
+    );
+    compare(
+        "min_payment_amount",
+        current.min_payment_amount.to_string(),
+        new.min_payment_amount.to_string(),
+    );
+    compare("pow", current.pow.to_string(), new.pow.to_string());
+    compare("relays", current.relays.join(", "), new.relays.join(", "));
+    compare(
+        "allowlist",
+        format!("{} pubkeys", current.allowlist.len()),
+        format!("{} pubkeys", new.allowlist.len()),
+    );
+    changes
+}
+
+/// Sections of the settings changed in `new` that need a restart
+fn restart_changes(current: &Settings, new: &Settings) -> Vec<&'static str> {
+    // Hot settings are left out of the comparison
+    let mut mostro = new.mostro.clone();
+    mostro.max_order_amount = current.mostro.max_order_amount;
+    mostro.min_payment_amount = current.mostro.min_payment_amount;
+    mostro.pow = current.mostro.pow;
+    mostro.allowlist = current.mostro.allowlist.clone();
+
+    let mut changed = Vec::new();
+    if format!("{:?}", current.database) != format!("{:?}", new.database) {
+        changed.push("database");
+    }
+    if current.nostr.nsec_privkey != new.nostr.nsec_privkey {
+        changed.push("nostr");
+    }
+    if format!("{:?}", current.mostro) != format!("{mostro:?}") {
+        changed.push("mostro");
+    }
+    if format!("{:?}", current.lightning) != format!("{:?}", new.lightning) {
+        changed.push("lightning");
+    }
+    if format!("{:?}", current.rpc) != format!("{:?}", new.rpc) {
+        changed.push("rpc");
+    }
+    changed
+}
+
+/// Replace the running hot settings with the ones of `settings`, returns
+/// the changes
+pub fn apply_settings(settings: &Settings) -> Vec<String> {
+    let new = HotSettings::from(settings);
+    let mut hot = match hot_settings_lock().write() {
+        Ok(hot) => hot,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let current = match hot.as_ref() {
+        Some(current) => current.clone(),
+        None => match MOSTRO_CONFIG.get() {
+            Some(started) => HotSettings::from(started),
+            None => new.clone(),
+        },
+    };
+    let changes = hot_changes(&current, &new);
+    if current.allowlist != new.allowlist {
+        set_allowlist(&new.allowlist);
+    }
+    *hot = Some(new);
+
+    if let Some(started) = MOSTRO_CONFIG.get() {
+        for section in restart_changes(started, settings) {
+            warn!("Settings of [{section}] changed, restart Mostro to apply them");
+        }
+    }
+    changes
+}
+
+/// Connect to the relays added and drop the ones removed. When a relay can't
+/// be added or dropped the ones changed before are restored, so the client
+/// keeps the `current` relays.
+async fn update_relays(
+    client: &Client,
+    current: &[String],
+    new: &[String],
+) -> Result<(), MostroError> {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut result = Ok(());
+    for relay in new.iter().filter(|relay| !current.contains(relay)) {
+        added.push(relay);
+        result = match client.add_relay(relay).await {
+            Ok(_) => client.connect_relay(relay).await,
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+    if result.is_ok() {
+        for relay in current.iter().filter(|relay| !new.contains(relay)) {
+            result = client.remove_relay(relay).await;
+            if result.is_err() {
+                break;
+            }
+            removed.push(relay);
+        }
+    }
+    if let Err(e) = result {
+        for relay in added {
+            let _ = client.remove_relay(relay).await;
+        }
+        for relay in removed {
+            if client.add_relay(relay).await.is_ok() {
+                let _ = client.connect_relay(relay).await;
+            }
+        }
+        return Err(MostroInternalErr(ServiceError::NostrError(e.to_string())));
+    }
+    // New relays get the subscription to the messages sent to Mostro
+    client
+        .resubscribe(gift_wrap_filter(get_keys()?.public_key()))
+        .await
+}
+
+/// Read the settings file again and apply the settings that can change
+/// without a restart, returns the changes. The relays are changed first,
+/// nothing is applied if that fails.
+pub async fn reload_settings() -> Result<Vec<String>, MostroError> {
+    let settings = reread_settings()?;
+    let current_relays = hot_settings().relays;
+    if current_relays != settings.nostr.relays {
+        if let Some(client) = NOSTR_CLIENT.get() {
+            update_relays(client, &current_relays, &settings.nostr.relays).await?;
+        }
+    }
+    Ok(apply_settings(&settings))
+}
+
+/// Reload the settings each time the process gets SIGHUP
+#[cfg(unix)]
+pub async fn reload_on_hangup() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Error listening for SIGHUP: {e}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match reload_settings().await {
+            Ok(changes) if changes.is_empty() => info!("Settings reloaded, nothing changed"),
+            Ok(changes) => info!("Settings reloaded: {}", changes.join("; ")),
+            // The running settings are kept
+            Err(e) => error!("Error reloading the settings: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_failed_relay_update_rolled_back() {
+        let client = Client::default();
+        let current = vec!["wss://relay.one".to_string()];
+        client.add_relay(&current[0]).await.unwrap();
+
+        let new = vec!["wss://relay.two".to_string(), "not a relay".to_string()];
+        assert!(update_relays(&client, &current, &new).await.is_err());
+        let relays: Vec<String> = client
+            .relays()
+            .await
+            .keys()
+            .map(|relay| relay.to_string())
+            .collect();
+        assert_eq!(relays.len(), 1);
+        assert!(relays[0].starts_with("wss://relay.one"));
+    }
+}
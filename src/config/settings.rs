@@ -79,4 +79,96 @@ impl Settings {
     pub fn get_rpc() -> &'static RpcSettings {
         &MOSTRO_CONFIG.get().expect("No RPC settings found").rpc
     }
+
+    /// Checks the settings make sense before Mostro runs with them, the error
+    /// names the first wrong field. Called on startup so bad settings stop
+    /// Mostro instead of failing later in the handlers
+    pub fn validate(&self) -> Result<(), MostroError> {
+        let invalid =
+            |reason: &str| Err(MostroInternalErr(ServiceError::IOError(reason.to_string())));
+        let (ln, nostr, mostro) = (&self.lightning, &self.nostr, &self.mostro);
+
+        if nostr.nsec_privkey.is_empty() {
+            return invalid("nsec_privkey must be set");
+        }
+        if nostr.relays.iter().all(|relay| relay.trim().is_empty()) {
+            return invalid("relays must list at least one relay");
+        }
+
+        // The node is not contacted in dry run mode
+        if !ln.dry_run {
+            match ln.backend.as_str() {
+                "" | "lnd" => {
+                    if ln.lnd_cert_file.is_empty()
+                        || ln.lnd_macaroon_file.is_empty()
+                        || ln.lnd_grpc_host.is_empty()
+                    {
+                        return invalid(
+                            "lnd_cert_file, lnd_macaroon_file and lnd_grpc_host must be set",
+                        );
+                    }
+                }
+                "cln" => {
+                    if ln.cln_rpc_path.is_empty() {
+                        return invalid("cln_rpc_path must be set with the cln backend");
+                    }
+                }
+                _ => return invalid("backend must be 'lnd' or 'cln'"),
+            }
+        }
+        if ln.max_hold_invoice_expiry_secs > 0
+            && ln.min_hold_invoice_expiry_secs > ln.max_hold_invoice_expiry_secs
+        {
+            return invalid("min_hold_invoice_expiry_secs is above max_hold_invoice_expiry_secs");
+        }
+
+        if !(0.0..1.0).contains(&mostro.fee) {
+            return invalid("fee must be between 0 and 1");
+        }
+        if !(0.0..1.0).contains(&mostro.max_routing_fee) {
+            return invalid("max_routing_fee must be between 0 and 1");
+        }
+        if mostro.max_order_amount == 0 {
+            return invalid("max_order_amount must be greater than zero");
+        }
+        if mostro.min_payment_amount > mostro.max_order_amount {
+            return invalid("min_payment_amount is above max_order_amount");
+        }
+        if mostro.max_order_duration_seconds > 0
+            && mostro.min_order_duration_seconds > mostro.max_order_duration_seconds
+        {
+            return invalid("min_order_duration_seconds is above max_order_duration_seconds");
+        }
+        if mostro.min_premium > mostro.max_premium {
+            return invalid("min_premium is above max_premium");
+        }
+        if mostro.fee_max_sats > 0 && mostro.fee_min_sats > mostro.fee_max_sats {
+            return invalid("fee_min_sats is above fee_max_sats");
+        }
+        // Replay protection needs a positive window
+        if mostro.max_message_age_secs == 0 {
+            return invalid("max_message_age_secs must be greater than zero");
+        }
+        // Only orders with a running trade and the seller funds held can be disputed
+        if mostro.dispute_eligible_statuses.iter().any(|status| {
+            !matches!(
+                status.parse::<Status>(),
+                Ok(Status::Active | Status::FiatSent)
+            )
+        }) {
+            return invalid("dispute_eligible_statuses can only have active and fiat-sent");
+        }
+        if !(0.0..=5.0).contains(&mostro.dispute_loss_penalty) {
+            return invalid("dispute_loss_penalty must be between 0 and 5");
+        }
+        if mostro
+            .allowlist
+            .iter()
+            .any(|pubkey| PublicKey::parse(pubkey).is_err())
+        {
+            return invalid("allowlist must only have hex or npub pubkeys");
+        }
+
+        Ok(())
+    }
 }
@@ -1,7 +1,9 @@
 // File with the types for the configuration settings
 // Initialize the types for the configuration settings
 use crate::config::MOSTRO_CONFIG;
-use serde::Deserialize;
+use crate::logging::LogFormat;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 // / Implement the TryFrom trait for each of the structs in Settings
 // / This allows you to convert from Settings to each of the structs directly.
@@ -27,6 +29,12 @@ pub struct DatabaseSettings {
 /// Lightning configuration settings
 #[derive(Debug, Deserialize, Default, Clone)]
 pub struct LightningSettings {
+    /// Lightning node backend, `lnd` (default) or `cln`
+    #[serde(default)]
+    pub backend: String,
+    /// Core Lightning rpc socket path, used with the `cln` backend
+    #[serde(default)]
+    pub cln_rpc_path: String,
     /// LND certificate file path
     pub lnd_cert_file: String,
     /// LND macaroon file path
@@ -43,6 +51,32 @@ pub struct LightningSettings {
     pub payment_attempts: u32,
     /// Payment retries interval in seconds
     pub payment_retries_interval: u32,
+    /// Seconds until a hold invoice expires, 0 uses the node default
+    #[serde(default)]
+    pub hold_invoice_expiry_secs: u64,
+    /// Shortest hold invoice expiry sellers can ask for, 0 disables the bound
+    #[serde(default)]
+    pub min_hold_invoice_expiry_secs: u64,
+    /// Longest hold invoice expiry sellers can ask for, 0 disables the bound
+    #[serde(default)]
+    pub max_hold_invoice_expiry_secs: u64,
+    /// Shortest expiry accepted in buyer invoices, the expiration window is
+    /// used if it is larger
+    #[serde(default)]
+    pub min_invoice_expiry_secs: u64,
+    /// Longest expiry accepted in buyer invoices, 0 disables the bound
+    #[serde(default)]
+    pub max_invoice_expiry_secs: u64,
+    /// Log the lightning calls instead of sending them to the node, same as
+    /// the `--dry-run` flag
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Attempts of a node call failing with a connection error, 1 disables retries
+    #[serde(default = "default_ln_call_attempts")]
+    pub call_attempts: u32,
+    /// Milliseconds before the first retry of a node call, doubled on each retry
+    #[serde(default = "default_ln_call_retry_base_ms")]
+    pub call_retry_base_ms: u64,
 }
 /// Nostr configuration settings
 #[derive(Debug, Deserialize, Default, Clone)]
@@ -101,6 +135,279 @@ pub struct MostroSettings {
     pub publish_mostro_info_interval: u32,
     /// Bitcoin price API base URL
     pub bitcoin_price_api_url: String,
+    /// Price sources in priority order, `yadio` and `blockchain`, yadio alone when empty
+    #[serde(default)]
+    pub price_providers: Vec<String>,
+    /// Require users to accept the operator terms of service before creating orders
+    #[serde(default)]
+    pub terms_of_service_enabled: bool,
+    /// Terms of service text, its sha256 hash identifies the version users accept
+    #[serde(default)]
+    pub terms_of_service: String,
+    /// Allow orders priced with an absolute price band instead of a premium
+    #[serde(default)]
+    pub price_band_orders: bool,
+    /// Maximum times an abandoned order is republished before canceling it, 0 means no limit
+    #[serde(default)]
+    pub max_republish_cycles: u32,
+    /// Minimum lifetime in seconds of an order expiration set by its creator, 0 means no limit
+    #[serde(default)]
+    pub min_order_duration_seconds: u32,
+    /// Maximum lifetime in seconds of an order expiration set by its creator, 0 means no limit
+    #[serde(default)]
+    pub max_order_duration_seconds: u32,
+    /// Seconds a solver has to resolve a dispute once taken, 0 means no SLA
+    #[serde(default)]
+    pub dispute_sla_seconds: u32,
+    /// Seconds a taker can reserve a pending order before taking it, 0 disables reservations
+    #[serde(default)]
+    pub order_reservation_seconds: u32,
+    /// Always recompute amount and fee when a pending order is (re)taken
+    #[serde(default)]
+    pub enforce_fee_recompute: bool,
+    /// Rolling window in seconds for order creation and take rates, 0 disables rate alerts
+    #[serde(default)]
+    pub rate_alert_window_seconds: u32,
+    /// Expected number of orders created or taken in a window
+    #[serde(default)]
+    pub rate_alert_baseline: u32,
+    /// Alert when the rate goes over the baseline multiplied by this value
+    #[serde(default)]
+    pub rate_alert_threshold: f64,
+    /// Webhook receiving rate alerts as json, empty only logs them
+    #[serde(default)]
+    pub rate_alert_webhook_url: String,
+    /// Fiat amounts of new orders must be multiples of this value, 0 disables the grid
+    #[serde(default)]
+    pub fiat_rounding_grid: u32,
+    /// Refuse off grid fiat amounts instead of snapping them to the grid
+    #[serde(default)]
+    pub fiat_rounding_reject: bool,
+    /// Order statuses allowing disputes among active and fiat-sent, empty means both
+    #[serde(default)]
+    pub dispute_eligible_statuses: Vec<String>,
+    /// Messages older than this many seconds are discarded to prevent replay attacks
+    #[serde(default = "default_max_message_age_secs")]
+    pub max_message_age_secs: u64,
+    /// Publish a public receipt with the bucketed fee each time a fee is collected
+    #[serde(default)]
+    pub fee_receipts_enabled: bool,
+    /// Rebuild partially taken range orders at startup and republish their open child
+    #[serde(default = "default_resume_range_grids")]
+    pub resume_range_grids: bool,
+    /// Highest premium allowed in new orders, in percent
+    #[serde(default = "default_max_premium")]
+    pub max_premium: i64,
+    /// Lowest premium allowed in new orders, in percent, usually negative
+    #[serde(default = "default_min_premium")]
+    pub min_premium: i64,
+    /// Fiat currency codes not allowed in new orders, case insensitive
+    #[serde(default)]
+    pub denied_fiat_codes: Vec<String>,
+    /// Fiat currency codes accepted in new orders, when empty the currencies
+    /// quoted by the price provider are accepted
+    #[serde(default)]
+    pub allowed_fiat_codes: Vec<String>,
+    /// Send takers the estimated fiat settlement time along with the take confirmation
+    #[serde(default)]
+    pub settlement_estimates: bool,
+    /// Average minutes to settle the fiat payment by payment method
+    #[serde(default)]
+    pub payment_method_settlement_minutes: HashMap<String, u32>,
+    /// Seconds a pending order can stay in the book before it is canceled, 0 means no limit
+    #[serde(default)]
+    pub pending_order_ttl_secs: u64,
+    /// Days of finished trades checked by the admin reconciliation report, 0 disables it
+    #[serde(default)]
+    pub reconciliation_report_days: u32,
+    /// Graduated trust for takers, `(min completed trades, max sats)` steps, empty disables it
+    #[serde(default)]
+    pub taker_trade_limits: Vec<(u32, u64)>,
+    /// Messages per second each pubkey can send on average, 0 disables rate limiting
+    #[serde(default)]
+    pub rate_limit_per_second: f64,
+    /// Messages a pubkey can send in a burst before being rate limited
+    #[serde(default)]
+    pub rate_limit_burst: u32,
+    /// Reputation queries per second each pubkey can send on average, 0 disables the limit
+    #[serde(default)]
+    pub reputation_query_per_second: f64,
+    /// Reputation queries a pubkey can send in a burst
+    #[serde(default)]
+    pub reputation_query_burst: u32,
+    /// Push metrics with `statsd` or `otlp`, empty disables pushing them
+    #[serde(default)]
+    pub metrics_exporter: String,
+    /// StatsD `host:port` or OTLP/HTTP metrics url receiving the metrics
+    #[serde(default)]
+    pub metrics_endpoint: String,
+    /// Seconds between metrics pushes
+    #[serde(default = "default_metrics_push_interval_seconds")]
+    pub metrics_push_interval_seconds: u32,
+    /// Refuse seller releases while the order has an open dispute, so it can
+    /// only be closed by a solver
+    #[serde(default)]
+    pub block_release_in_dispute: bool,
+    /// Seconds a cached price is trusted to create market orders, 0 means no limit
+    #[serde(default)]
+    pub max_price_age_secs: u64,
+    /// Lower the proof of work asked from pubkeys by how long they have been seen
+    #[serde(default)]
+    pub pow_by_account_age: bool,
+    /// `(min account age in days, difficulty)` steps used with `pow_by_account_age`
+    #[serde(default)]
+    pub pow_account_age_tiers: Vec<(u64, u8)>,
+    /// Seconds an order has to complete once funds are held before a dispute
+    /// is opened automatically, 0 disables it
+    #[serde(default)]
+    pub completion_deadline_secs: u64,
+    /// Takes are refused when the maker wasn't seen in this many seconds, by a
+    /// message or the order being published, 0 disables the check
+    #[serde(default)]
+    pub maker_liveness_window_secs: u64,
+    /// Name of the operator attached to outgoing messages, up to 32 characters
+    #[serde(default)]
+    pub operator_name: String,
+    /// `host:port` serving Prometheus metrics on `/metrics`, empty disables it.
+    /// Needs the `metrics` feature
+    #[serde(default)]
+    pub metrics_listen_address: String,
+    /// Attach a signed attestation of the price and provider to market price orders
+    #[serde(default)]
+    pub price_attestations: bool,
+    /// Seconds a user who canceled their own order has to wait to create a
+    /// new one, 0 disables it
+    #[serde(default)]
+    pub recreate_cooldown_secs: u64,
+    /// Allow solvers to settle disputes splitting the funds between buyer and seller
+    #[serde(default)]
+    pub split_settlements_enabled: bool,
+    /// Order templates published for clients to pre-fill order forms
+    #[serde(default)]
+    pub order_templates: Vec<OrderTemplate>,
+    /// Start with fund movements halted, see the SetKillSwitch admin RPC
+    #[serde(default)]
+    pub funds_kill_switch: bool,
+    /// Seconds a dispute can wait for a solver before every solver is
+    /// notified, 0 disables it
+    #[serde(default)]
+    pub dispute_escalation_secs: u64,
+    /// Sats added to the `fee` percentage of each trade
+    #[serde(default)]
+    pub fee_flat_sats: u64,
+    /// Lowest fee of a trade in sats, 0 disables it
+    #[serde(default)]
+    pub fee_min_sats: u64,
+    /// Highest fee of a trade in sats, 0 disables it
+    #[serde(default)]
+    pub fee_max_sats: u64,
+    /// Publish a non replaceable event on each order status change
+    #[serde(default)]
+    pub status_change_events: bool,
+    /// Orders a user can have open or being traded at once, as maker or
+    /// taker, 0 disables the limit
+    #[serde(default)]
+    pub max_active_orders_per_user: u32,
+    /// Messages handled at the same time, messages of the same order are
+    /// always handled one after another
+    #[serde(default = "default_message_workers")]
+    pub message_workers: usize,
+    /// `host:port` serving the `/health` check on its own, empty serves it
+    /// along with the metrics. Needs the `metrics` feature
+    #[serde(default)]
+    pub health_listen_address: String,
+    /// Refuse takers without ratings on orders asking for a minimum taker
+    /// rating, they are let through by default
+    #[serde(default)]
+    pub deny_unrated_takers: bool,
+    /// Let buyers get paid onchain giving a bitcoin address instead of an
+    /// invoice, the onchain fee is taken from their amount
+    #[serde(default)]
+    pub onchain_payouts: bool,
+    /// List what is left of a range order as a new pending order once part of
+    /// it is traded, the remainder is dropped when off
+    #[serde(default = "default_relist_range_remainder")]
+    pub relist_range_remainder: bool,
+    /// `pretty` lines for people or `json` lines for log collectors
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Days after which a rating weighs half in the published reputation,
+    /// 0 weighs every rating the same
+    #[serde(default)]
+    pub reputation_half_life_days: u64,
+    /// Pubkeys allowed to create and take orders, hex or npub. Empty keeps
+    /// the instance public. Reloaded from the settings file on SIGHUP
+    #[serde(default)]
+    pub allowlist: Vec<String>,
+    /// Points taken from the rating of the party a dispute is resolved
+    /// against, 0 leaves ratings untouched
+    #[serde(default)]
+    pub dispute_loss_penalty: f64,
+    /// Seconds after fiat is marked sent during which the seller can only
+    /// release confirming the fiat arrived, 0 lets them release right away
+    #[serde(default)]
+    pub release_grace_secs: u64,
+    /// Seconds an order can stay active without the buyer sending fiat before
+    /// a dispute is opened automatically, 0 disables it
+    #[serde(default)]
+    pub active_trade_timeout_secs: u64,
+    /// Relays that should accept each order event, fewer are logged as a
+    /// warning. 0 disables the check
+    #[serde(default)]
+    pub publish_quorum: usize,
+    /// Times relays rejecting an order event are tried again while the
+    /// quorum is not reached
+    #[serde(default)]
+    pub publish_retries: u32,
+}
+
+/// Operator defaults for a kind of order, published in the order templates event
+#[derive(Debug, Deserialize, Serialize, Default, Clone, PartialEq)]
+pub struct OrderTemplate {
+    pub name: String,
+    #[serde(default)]
+    pub payment_methods: Vec<String>,
+    #[serde(default)]
+    pub fiat_codes: Vec<String>,
+    /// Seconds the order stays pending, 0 uses the default expiration
+    #[serde(default)]
+    pub expiration_seconds: u64,
+}
+
+fn default_max_message_age_secs() -> u64 {
+    10
+}
+
+fn default_metrics_push_interval_seconds() -> u32 {
+    10
+}
+
+fn default_message_workers() -> usize {
+    8
+}
+
+fn default_ln_call_attempts() -> u32 {
+    3
+}
+
+fn default_ln_call_retry_base_ms() -> u64 {
+    500
+}
+
+fn default_relist_range_remainder() -> bool {
+    true
+}
+
+fn default_resume_range_grids() -> bool {
+    true
+}
+
+fn default_max_premium() -> i64 {
+    100
+}
+
+fn default_min_premium() -> i64 {
+    -100
 }
 
 // Macro call here to implement the TryFrom trait for each of the structs in Settings
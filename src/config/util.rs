@@ -3,34 +3,136 @@
 /// It includes functions to initialize the default settings directory and create a settings file from the template if it doesn't exist.
 /// It also includes functions to add a trailing slash to a path if it doesn't already have one.
 use crate::config::{init_mostro_settings, Settings};
+use crate::lightning::dry_run::is_dry_run;
 use mostro_core::error::MostroError::{self, *};
 use mostro_core::error::ServiceError;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 const DB_FILENAME: &str = "mostro.db";
 
+/// Settings file Mostro was started with
+static SETTINGS_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Settings file read again by [`reread_settings`] in tests, shared by them
+#[cfg(test)]
+pub fn test_settings_file() -> &'static Path {
+    SETTINGS_FILE.get_or_init(|| {
+        std::env::temp_dir().join(format!("mostro-settings-{}.toml", std::process::id()))
+    })
+}
+
+/// Checks the settings directory is a writable directory, creating it if missing
+fn check_settings_dir(settings_dir: &Path) -> Result<(), MostroError> {
+    let dir_error = |reason: String| {
+        MostroInternalErr(ServiceError::IOError(format!(
+            "Settings directory {} {reason}",
+            settings_dir.display()
+        )))
+    };
+    // Created on the first run
+    if !settings_dir.exists() {
+        fs::create_dir_all(settings_dir)
+            .map_err(|e| dir_error(format!("does not exist and can't be created: {e}")))?;
+    }
+    if !settings_dir.is_dir() {
+        return Err(dir_error("is not a directory".to_string()));
+    }
+    // The database lives next to the settings, write a probe file to be sure
+    let probe = settings_dir.join(".write-check");
+    fs::write(&probe, b"").map_err(|e| dir_error(format!("is not writable: {e}")))?;
+    let _ = fs::remove_file(&probe);
+
+    Ok(())
+}
+
+/// Settings directory given by the user, or the default `~/.mostro`
+fn settings_dir_path(config_path: Option<String>) -> Result<PathBuf, MostroError> {
+    if let Some(user_path) = config_path {
+        return Ok(PathBuf::from(user_path));
+    }
+    let home_dir = dirs::home_dir().ok_or_else(|| {
+        MostroInternalErr(ServiceError::IOError(
+            "Could not find home directory".to_string(),
+        ))
+    })?;
+    let package_name = env!("CARGO_PKG_NAME");
+    Ok(home_dir.join(format!(".{}", package_name)))
+}
+
+/// Read and parse the settings file
+fn read_settings(config_file_path: &Path) -> Result<Settings, MostroError> {
+    // Read the file content
+    let contents = fs::read_to_string(config_file_path)
+        .map_err(|e| MostroInternalErr(ServiceError::IOError(e.to_string())))?;
+
+    // Parse TOML content
+    toml::from_str(&contents).map_err(|e| MostroInternalErr(ServiceError::IOError(e.to_string())))
+}
+
+/// Short description of the settings printed by `--config-check`
+pub fn settings_summary(settings: &Settings) -> String {
+    let ln = &settings.lightning;
+    let backend = if ln.dry_run {
+        "dry run"
+    } else if ln.backend.is_empty() {
+        "lnd"
+    } else {
+        ln.backend.as_str()
+    };
+    let rpc = if settings.rpc.enabled {
+        format!("{}:{}", settings.rpc.listen_address, settings.rpc.port)
+    } else {
+        "disabled".to_string()
+    };
+    format!(
+        "relays: {}\nlightning backend: {backend}\nfee: {}\norder amount: {} - {} sats\npow: {}\nrpc: {rpc}",
+        settings.nostr.relays.join(", "),
+        settings.mostro.fee,
+        settings.mostro.min_payment_amount,
+        settings.mostro.max_order_amount,
+        settings.mostro.pow,
+    )
+}
+
+/// Load and validate the settings file without starting Mostro, the file is
+/// not created from the template when missing
+pub fn check_configuration_file(config_path: Option<String>) -> Result<Settings, MostroError> {
+    let config_file_path = settings_dir_path(config_path)?.join("settings.toml");
+    if !config_file_path.exists() {
+        return Err(MostroInternalErr(ServiceError::IOError(format!(
+            "Settings file {} not found",
+            config_file_path.display()
+        ))));
+    }
+    let settings = read_settings(&config_file_path)?;
+    settings.validate()?;
+
+    Ok(settings)
+}
+
+/// Read again the settings file Mostro was started with, for the settings
+/// that can change without a restart
+pub fn reread_settings() -> Result<Settings, MostroError> {
+    let config_file_path = SETTINGS_FILE.get().ok_or_else(|| {
+        MostroInternalErr(ServiceError::IOError(
+            "Settings file not loaded yet".to_string(),
+        ))
+    })?;
+    let settings = read_settings(config_file_path)?;
+    settings.validate()?;
+
+    Ok(settings)
+}
+
 /// Initialize the default settings directory and create a settings file from the template if it doesn't exist.
 /// Checks if the directory already exists, and if not, creates it and writes the template file.
 /// If a custom config path is provided, it uses that instead of the default `~/.mostro` directory.
 pub fn init_configuration_file(config_path: Option<String>) -> Result<(), MostroError> {
-    let settings_dir = if let Some(user_path) = config_path {
-        PathBuf::from(user_path)
-    } else {
-        let home_dir = dirs::home_dir().ok_or_else(|| {
-            MostroInternalErr(ServiceError::IOError(
-                "Could not find home directory".to_string(),
-            ))
-        })?;
-        let package_name = env!("CARGO_PKG_NAME");
-        home_dir.join(format!(".{}", package_name))
-    };
+    let settings_dir = settings_dir_path(config_path)?;
 
-    // Check if /.mostro directory exists
-    if !settings_dir.exists() {
-        std::fs::create_dir_all(&settings_dir)
-            .map_err(|e| MostroInternalErr(ServiceError::IOError(e.to_string())))?;
-    }
+    check_settings_dir(&settings_dir)?;
     let config_file_path = settings_dir.join("settings.toml");
     // Check if settings.toml file exists
     if !config_file_path.exists() {
@@ -43,21 +145,154 @@ pub fn init_configuration_file(config_path: Option<String>) -> Result<(), Mostro
         );
         std::process::exit(0);
     }
-    // Read the file content
-    let contents = fs::read_to_string(&config_file_path)
-        .map_err(|e| MostroInternalErr(ServiceError::IOError(e.to_string())))?;
-
-    // Parse TOML content
-    let mut settings: Settings = toml::from_str(&contents)
-        .map_err(|e| MostroInternalErr(ServiceError::IOError(e.to_string())))?;
+    let mut settings = read_settings(&config_file_path)?;
+    // The --dry-run flag needs no lightning credentials either
+    settings.lightning.dry_run |= is_dry_run();
+    settings.validate()?;
 
     // Override database URL
     settings.database.url = format!("sqlite://{}", settings_dir.join(DB_FILENAME).display());
 
     // Initialize the global settings variable
     init_mostro_settings(settings);
+    let _ = SETTINGS_FILE.set(config_file_path);
 
     tracing::info!("Settings correctly loaded!");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn test_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("mostro-settings-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn template_settings() -> Settings {
+        toml::from_str(include_str!("../../settings.tpl.toml")).unwrap()
+    }
+
+    fn invalid_reason(settings: &Settings) -> String {
+        match settings.validate() {
+            Err(MostroInternalErr(ServiceError::IOError(reason))) => reason,
+            other => panic!("Expected a validation error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_template_settings_valid() {
+        let settings = template_settings();
+        assert!(settings.validate().is_ok());
+        let summary = settings_summary(&settings);
+        assert!(summary.contains("relays: ws://localhost:7000"));
+        assert!(summary.contains("lightning backend: lnd"));
+    }
+
+    #[test]
+    fn test_invalid_settings_rejected() {
+        let mut settings = template_settings();
+        settings.nostr.relays.clear();
+        assert!(invalid_reason(&settings).starts_with("relays"));
+
+        let mut settings = template_settings();
+        settings.lightning.lnd_macaroon_file.clear();
+        assert!(invalid_reason(&settings).contains("lnd_macaroon_file"));
+        // No credentials needed when nothing reaches the node
+        settings.lightning.dry_run = true;
+        assert!(settings.validate().is_ok());
+
+        let mut settings = template_settings();
+        settings.lightning.backend = "cln".to_string();
+        settings.lightning.cln_rpc_path.clear();
+        assert!(invalid_reason(&settings).starts_with("cln_rpc_path"));
+
+        let mut settings = template_settings();
+        settings.mostro.min_payment_amount = settings.mostro.max_order_amount + 1;
+        assert!(invalid_reason(&settings).starts_with("min_payment_amount"));
+
+        let mut settings = template_settings();
+        settings.mostro.fee = 1.5;
+        assert!(invalid_reason(&settings).starts_with("fee"));
+
+        let mut settings = template_settings();
+        settings.mostro.max_message_age_secs = 0;
+        assert!(invalid_reason(&settings).starts_with("max_message_age_secs"));
+
+        let mut settings = template_settings();
+        settings.mostro.allowlist = vec!["not a pubkey".to_string()];
+        assert!(invalid_reason(&settings).starts_with("allowlist"));
+
+        let mut settings = template_settings();
+        settings.mostro.dispute_eligible_statuses =
+            vec!["active".to_string(), "pending".to_string()];
+        assert!(invalid_reason(&settings).starts_with("dispute_eligible_statuses"));
+        settings.mostro.dispute_eligible_statuses = vec!["fiat-sent".to_string()];
+        assert!(settings.validate().is_ok());
+
+        let mut settings = template_settings();
+        settings.mostro.dispute_loss_penalty = -1.0;
+        assert!(invalid_reason(&settings).starts_with("dispute_loss_penalty"));
+
+        let mut settings = template_settings();
+        settings.nostr.nsec_privkey.clear();
+        assert!(invalid_reason(&settings).starts_with("nsec_privkey"));
+
+        let mut settings = template_settings();
+        settings.mostro.max_order_amount = 0;
+        assert!(invalid_reason(&settings).starts_with("max_order_amount"));
+
+        let mut settings = template_settings();
+        settings.mostro.min_premium = 10;
+        settings.mostro.max_premium = 5;
+        assert!(invalid_reason(&settings).starts_with("min_premium"));
+
+        let mut settings = template_settings();
+        settings.lightning.min_hold_invoice_expiry_secs = 7_200;
+        settings.lightning.max_hold_invoice_expiry_secs = 3_600;
+        assert!(invalid_reason(&settings).starts_with("min_hold_invoice_expiry_secs"));
+    }
+
+    #[test]
+    fn test_settings_dir_created_when_missing() {
+        let dir = test_dir();
+        check_settings_dir(&dir).unwrap();
+        assert!(dir.is_dir());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_settings_dir_not_writable() {
+        let dir = test_dir();
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+
+        // Root writes anywhere, nothing to check
+        let writable = fs::write(dir.join("probe"), b"").is_ok();
+        let result = check_settings_dir(&dir);
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        if writable {
+            return;
+        }
+
+        assert!(matches!(
+            result,
+            Err(MostroInternalErr(ServiceError::IOError(msg))) if msg.contains("is not writable")
+        ));
+    }
+
+    #[test]
+    fn test_settings_path_is_a_file() {
+        let file = test_dir();
+        fs::write(&file, b"").unwrap();
+        let result = check_settings_dir(&file);
+        fs::remove_file(&file).unwrap();
+        assert!(matches!(
+            result,
+            Err(MostroInternalErr(ServiceError::IOError(msg))) if msg.contains("is not a directory")
+        ));
+    }
+}
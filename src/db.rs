@@ -1,8 +1,13 @@
 use crate::config::settings::Settings;
 use crate::config::MOSTRO_DB_PASSWORD;
+use crate::models::{
+    Backup, BackupRow, OnchainPayout, OrderEventRecord, PaymentMethod, PriceBand, RangeGrid,
+    SolverStats, SplitLeg, UserStats,
+};
 use argon2::password_hash::rand_core::OsRng;
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use mostro_core::prelude::*;
+use nostr_sdk::nostr::hashes::hex::FromHex;
 use nostr_sdk::prelude::*;
 use rpassword::read_password;
 use secrecy::zeroize::Zeroize;
@@ -310,11 +315,32 @@ pub async fn connect() -> Result<Arc<Pool<Sqlite>>, MostroError> {
             }
         }
 
+        // Apply migrations added after the database was created
+        sqlx::migrate!()
+            .run(&conn)
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
         conn
     };
     Ok(Arc::new(conn))
 }
 
+/// In-memory database with every migration, shared by the tests
+#[cfg(test)]
+pub async fn test_pool() -> SqlitePool {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("Failed to open the test database");
+    sqlx::migrate!()
+        .run(&pool)
+        .await
+        .expect("Failed to run the migrations");
+    pool
+}
+
 // You'll need to implement these functions to store and verify the password hash
 async fn store_password_hash(
     password: &SecretString,
@@ -397,6 +423,19 @@ pub async fn edit_seller_pubkey_order(
     Ok(rows_affected > 0)
 }
 
+/// Hash of the hold invoice of an order, none until the order has one
+pub async fn find_order_hash(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<Option<String>, MostroError> {
+    sqlx::query_scalar::<_, Option<String>>("SELECT hash FROM orders WHERE id = ?1")
+        .bind(order_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?
+        .ok_or(MostroCantDo(CantDoReason::NotFound))
+}
+
 pub async fn find_order_by_hash(pool: &SqlitePool, hash: &str) -> Result<Order, MostroError> {
     let order = sqlx::query_as::<_, Order>(
         r#"
@@ -449,6 +488,73 @@ pub async fn find_order_by_seconds(pool: &SqlitePool) -> Result<Vec<Order>, Most
     Ok(order)
 }
 
+/// Condition of the orders pending since before `?1`. They are pending since
+/// the last pending event recorded, an order taken and abandoned gets a new
+/// one, or since they were created when no event was recorded
+const STALE_PENDING_CONDITION: &str = r#"
+    status == 'pending'
+      AND COALESCE(
+        (SELECT MAX(created_at) FROM order_events
+          WHERE order_events.order_id = orders.id AND order_events.status = 'pending'),
+        created_at
+      ) < ?1
+"#;
+
+/// Orders pending since before `pending_before`
+pub async fn find_stale_pending_orders(
+    pool: &SqlitePool,
+    pending_before: i64,
+) -> Result<Vec<Uuid>, MostroError> {
+    sqlx::query_scalar::<_, Uuid>(&format!(
+        "SELECT id FROM orders WHERE {STALE_PENDING_CONDITION}"
+    ))
+    .bind(pending_before)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))
+}
+
+/// Cancel the order if it is still pending since before `pending_before` and
+/// return it. Done in a single statement so an order taken meanwhile is left
+/// untouched.
+pub async fn cancel_stale_pending_order(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    pending_before: i64,
+) -> Result<Option<Order>, MostroError> {
+    sqlx::query_as::<_, Order>(&format!(
+        "UPDATE orders SET status = 'canceled' WHERE id = ?2 AND {STALE_PENDING_CONDITION} RETURNING *"
+    ))
+    .bind(pending_before)
+    .bind(order_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))
+}
+
+/// Orders with a hold invoice that are still running or were finished after
+/// `finished_since`, used to reconcile them with the lightning node
+pub async fn find_orders_with_hold_invoice(
+    pool: &SqlitePool,
+    finished_since: i64,
+) -> Result<Vec<Order>, MostroError> {
+    let orders = sqlx::query_as::<_, Order>(
+        r#"
+          SELECT *
+          FROM orders
+          WHERE hash IS NOT NULL
+            AND ( status IN ('waiting-payment', 'active', 'fiat-sent', 'dispute', 'settled-hold-invoice')
+                  OR taken_at >= ?1 )
+        "#,
+    )
+    .bind(finished_since)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(orders)
+}
+
 pub async fn find_dispute_by_order_id(
     pool: &SqlitePool,
     order_id: Uuid,
@@ -468,6 +574,148 @@ pub async fn find_dispute_by_order_id(
     Ok(dispute)
 }
 
+/// Disputes still waiting for a solver since `created_before` which were not
+/// escalated yet
+pub async fn find_unescalated_disputes(
+    pool: &SqlitePool,
+    created_before: i64,
+) -> Result<Vec<Dispute>, MostroError> {
+    let disputes = sqlx::query_as::<_, Dispute>(
+        r#"
+          SELECT *
+          FROM disputes
+          WHERE status = ?1 AND created_at <= ?2
+            AND id NOT IN (SELECT dispute_id FROM dispute_escalations)
+        "#,
+    )
+    .bind(DisputeStatus::Initiated.to_string())
+    .bind(created_before)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(disputes)
+}
+
+pub async fn add_dispute_escalation(
+    pool: &SqlitePool,
+    dispute_id: Uuid,
+    escalated_at: i64,
+) -> Result<(), MostroError> {
+    sqlx::query(
+        "INSERT OR IGNORE INTO dispute_escalations (dispute_id, escalated_at) VALUES (?1, ?2)",
+    )
+    .bind(dispute_id)
+    .bind(escalated_at)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(())
+}
+
+/// Highest trade index seen from an identity, none if it never sent one
+pub async fn last_seen_trade_index(
+    pool: &SqlitePool,
+    identity_pubkey: &str,
+) -> Result<Option<i64>, MostroError> {
+    sqlx::query_scalar::<_, Option<i64>>(
+        "SELECT MAX(trade_index) FROM trade_key_bindings WHERE identity_pubkey = ?1",
+    )
+    .bind(identity_pubkey)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))
+}
+
+/// Tells if a trade key was already bound to a trade index
+pub async fn is_trade_key_bound(
+    pool: &SqlitePool,
+    trade_pubkey: &str,
+) -> Result<bool, MostroError> {
+    sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM trade_key_bindings WHERE trade_pubkey = ?1)",
+    )
+    .bind(trade_pubkey)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))
+}
+
+/// Bind a trade key to the identity and trade index it was used with, false
+/// when the trade key or the index of the identity were already used
+pub async fn add_trade_key_binding(
+    pool: &SqlitePool,
+    identity_pubkey: &str,
+    trade_index: i64,
+    trade_pubkey: &str,
+    created_at: i64,
+) -> Result<bool, MostroError> {
+    let result = sqlx::query(
+        r#"
+          INSERT OR IGNORE INTO trade_key_bindings (trade_pubkey, identity_pubkey, trade_index, created_at)
+          VALUES (?1, ?2, ?3, ?4)
+        "#,
+    )
+    .bind(trade_pubkey)
+    .bind(identity_pubkey)
+    .bind(trade_index)
+    .bind(created_at)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Pubkeys of the solvers who are not banned
+pub async fn find_solver_pubkeys(pool: &SqlitePool) -> Result<Vec<String>, MostroError> {
+    let pubkeys = sqlx::query_scalar::<_, String>(
+        "SELECT pubkey FROM users WHERE is_solver != 0 AND is_banned = 0",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(pubkeys)
+}
+
+/// Count a completed trade of `amount` sats for `pubkey`
+pub async fn add_completed_trade(
+    pool: &SqlitePool,
+    pubkey: &str,
+    amount: i64,
+) -> Result<(), MostroError> {
+    sqlx::query(
+        r#"
+          INSERT INTO user_trade_stats (pubkey, completed_trades, volume_sats) VALUES (?1, 1, ?2)
+          ON CONFLICT(pubkey) DO UPDATE SET
+            completed_trades = completed_trades + 1,
+            volume_sats = volume_sats + excluded.volume_sats
+        "#,
+    )
+    .bind(pubkey)
+    .bind(amount)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(())
+}
+
+/// Completed trades of a user, zero for users without trades
+pub async fn get_user_stats(pool: &SqlitePool, pubkey: &str) -> Result<UserStats, MostroError> {
+    let stats = sqlx::query_as::<_, UserStats>(
+        "SELECT completed_trades, volume_sats FROM user_trade_stats WHERE pubkey = ?1",
+    )
+    .bind(pubkey)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(stats.unwrap_or_default())
+}
+
 pub async fn update_order_to_initial_state(
     pool: &SqlitePool,
     order_id: Uuid,
@@ -507,6 +755,133 @@ pub async fn update_order_to_initial_state(
     Ok(rows_affected > 0)
 }
 
+/// Move an order from `from` to `to` only if it is still in `from`, false when
+/// another message already changed its status
+pub async fn transition_order_status(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    from: Status,
+    to: Status,
+) -> Result<bool, MostroError> {
+    let result = sqlx::query("UPDATE orders SET status = ?1 WHERE id = ?2 AND status = ?3")
+        .bind(to.to_string())
+        .bind(order_id)
+        .bind(from.to_string())
+        .execute(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Orders waiting for the seller to pay the hold invoice
+pub async fn find_waiting_payment_orders(pool: &SqlitePool) -> Result<Vec<Order>, MostroError> {
+    let orders = sqlx::query_as::<_, Order>(
+        r#"
+          SELECT *
+          FROM orders
+          WHERE status == 'waiting-payment' AND hash IS NOT NULL
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(orders)
+}
+
+/// Active or fiat sent orders with funds held since before `held_before`
+/// and no dispute opened yet
+pub async fn find_orders_past_completion_deadline(
+    pool: &SqlitePool,
+    held_before: i64,
+) -> Result<Vec<Order>, MostroError> {
+    let orders = sqlx::query_as::<_, Order>(
+        r#"
+          SELECT *
+          FROM orders
+          WHERE status IN ('active', 'fiat-sent') AND invoice_held_at > 0
+            AND invoice_held_at < ?1
+            AND NOT EXISTS (SELECT 1 FROM disputes WHERE disputes.order_id = orders.id)
+        "#,
+    )
+    .bind(held_before)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(orders)
+}
+
+/// Orders active since before `active_before` with no dispute opened yet.
+/// They are active since the last active event recorded, or since the
+/// funds were held when no event was recorded
+pub async fn find_stale_active_orders(
+    pool: &SqlitePool,
+    active_before: i64,
+) -> Result<Vec<Order>, MostroError> {
+    let orders = sqlx::query_as::<_, Order>(
+        r#"
+          SELECT *
+          FROM orders
+          WHERE status = 'active' AND invoice_held_at > 0
+            AND COALESCE(
+              (SELECT MAX(created_at) FROM order_events
+                WHERE order_events.order_id = orders.id AND order_events.status = 'active'),
+              invoice_held_at
+            ) < ?1
+            AND NOT EXISTS (SELECT 1 FROM disputes WHERE disputes.order_id = orders.id)
+        "#,
+    )
+    .bind(active_before)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(orders)
+}
+
+/// Put back to pending an order whose hold invoice `hash` expired before being
+/// paid, the taker data and any cancel request are cleared. The update only
+/// applies while the order still waits for that invoice, returns if it did.
+pub async fn reset_expired_hold_invoice_order(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    hash: &str,
+    amount: i64,
+    fee: i64,
+) -> Result<bool, MostroError> {
+    let result = sqlx::query(
+        r#"
+            UPDATE orders
+            SET
+            status = 'pending',
+            amount = ?1,
+            fee = ?2,
+            hash = NULL,
+            preimage = NULL,
+            taken_at = 0,
+            invoice_held_at = 0,
+            cancel_initiator_pubkey = NULL,
+            buyer_pubkey = CASE WHEN kind = 'sell' THEN NULL ELSE buyer_pubkey END,
+            master_buyer_pubkey = CASE WHEN kind = 'sell' THEN NULL ELSE master_buyer_pubkey END,
+            buyer_invoice = CASE WHEN kind = 'sell' THEN NULL ELSE buyer_invoice END,
+            seller_pubkey = CASE WHEN kind = 'buy' THEN NULL ELSE seller_pubkey END,
+            master_seller_pubkey = CASE WHEN kind = 'buy' THEN NULL ELSE master_seller_pubkey END
+            WHERE id = ?3 AND status = 'waiting-payment' AND hash = ?4
+        "#,
+    )
+    .bind(amount)
+    .bind(fee)
+    .bind(order_id)
+    .bind(hash)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(result.rows_affected() > 0)
+}
+
 pub async fn edit_master_buyer_pubkey_order(
     pool: &SqlitePool,
     order_id: Uuid,
@@ -530,6 +905,36 @@ pub async fn edit_master_buyer_pubkey_order(
     Ok(rows_affected > 0)
 }
 
+/// Set or clear the reason given by the party canceling the order
+pub async fn edit_cancel_reason_order(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    cancel_reason: Option<String>,
+) -> Result<bool, MostroError> {
+    let result = sqlx::query("UPDATE orders SET cancel_reason = ?1 WHERE id = ?2")
+        .bind(cancel_reason)
+        .bind(order_id)
+        .execute(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn find_order_cancel_reason(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<Option<String>, MostroError> {
+    let reason =
+        sqlx::query_scalar::<_, Option<String>>("SELECT cancel_reason FROM orders WHERE id = ?1")
+            .bind(order_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(reason.flatten())
+}
+
 pub async fn edit_master_seller_pubkey_order(
     pool: &SqlitePool,
     order_id: Uuid,
@@ -614,90 +1019,367 @@ pub async fn find_held_invoices(pool: &SqlitePool) -> Result<Vec<Order>, MostroE
     Ok(order)
 }
 
-pub async fn find_failed_payment(pool: &SqlitePool) -> Result<Vec<Order>, MostroError> {
-    let order = sqlx::query_as::<_, Order>(
+/// Page of the orders in a status, newest first
+pub async fn find_orders_by_status(
+    pool: &SqlitePool,
+    status: Status,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<Order>, MostroError> {
+    let orders = sqlx::query_as::<_, Order>(
         r#"
           SELECT *
           FROM orders
-          WHERE failed_payment == true AND  status == 'settled-hold-invoice'
+          WHERE status = ?1
+          ORDER BY created_at DESC, id
+          LIMIT ?2 OFFSET ?3
         "#,
     )
+    .bind(status.to_string())
+    .bind(limit)
+    .bind(offset)
     .fetch_all(pool)
     .await
     .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
 
-    Ok(order)
+    Ok(orders)
 }
 
-pub async fn get_admin_password(pool: &SqlitePool) -> Result<Option<String>, MostroError> {
-    if let Some(user) = sqlx::query_as::<_, User>(
+/// Orders whose trade started and didn't reach a final status yet
+pub async fn count_in_flight_orders(pool: &SqlitePool) -> Result<i64, MostroError> {
+    let count = sqlx::query_scalar::<_, i64>(
         r#"
-          SELECT *
-          FROM users
-          WHERE is_admin == 1
-          LIMIT 1
+          SELECT COUNT(*)
+          FROM orders
+          WHERE status IN ('waiting-buyer-invoice', 'waiting-payment', 'active', 'fiat-sent',
+            'dispute', 'settled-hold-invoice')
         "#,
     )
-    .fetch_optional(pool)
+    .fetch_one(pool)
     .await
-    .map_err(|_| {
-        MostroInternalErr(ServiceError::DbAccessError(
-            "Failed to get admin password".to_string(),
-        ))
-    })? {
-        Ok(user.admin_password)
-    } else {
-        Ok(None)
-    }
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(count)
 }
 
-pub async fn find_solver_pubkey(
-    pool: &SqlitePool,
-    solver_npub: String,
-) -> Result<User, MostroError> {
-    let user = sqlx::query_as::<_, User>(
-        r#"
-          SELECT *
-          FROM users
-          WHERE pubkey == ?1 AND  is_solver == true
-          LIMIT 1
-        "#,
+/// Number of orders in each status
+#[cfg(feature = "metrics")]
+pub async fn count_orders_by_status(pool: &SqlitePool) -> Result<Vec<(String, i64)>, MostroError> {
+    let counts = sqlx::query_as::<_, (String, i64)>(
+        "SELECT status, COUNT(*) FROM orders GROUP BY status ORDER BY status",
     )
-    .bind(solver_npub)
-    .fetch_one(pool)
+    .fetch_all(pool)
     .await
     .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
 
-    Ok(user)
+    Ok(counts)
 }
 
-pub async fn is_user_present(pool: &SqlitePool, public_key: String) -> Result<User, MostroError> {
-    let user = sqlx::query_as::<_, User>(
+/// Record an onchain payout about to be sent, false if the order already has one
+pub async fn add_onchain_payout(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    address: &str,
+    amount: i64,
+) -> Result<bool, MostroError> {
+    let result = sqlx::query(
         r#"
-            SELECT *
-            FROM users
-            WHERE pubkey == ?1
-            LIMIT 1
+            INSERT OR IGNORE INTO onchain_payouts (order_id, address, amount, created_at)
+            VALUES (?1, ?2, ?3, ?4)
         "#,
     )
-    .bind(public_key)
-    .fetch_one(pool)
+    .bind(order_id)
+    .bind(address)
+    .bind(amount)
+    .bind(Timestamp::now().as_u64() as i64)
+    .execute(pool)
     .await
     .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
 
-    Ok(user)
+    Ok(result.rows_affected() > 0)
 }
 
-pub async fn add_new_user(pool: &SqlitePool, new_user: User) -> Result<String, MostroError> {
-    let created_at: Timestamp = Timestamp::now();
-    let _result = sqlx::query(
-        "
-            INSERT INTO users (pubkey, is_admin,admin_password, is_solver, is_banned, category, last_trade_index, total_reviews, total_rating, last_rating, max_rating, min_rating, created_at) 
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
-        ",
-    )
-    .bind(new_user.pubkey.clone())
-    .bind(new_user.is_admin)
+pub async fn set_onchain_payout_txid(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    txid: &str,
+) -> Result<(), MostroError> {
+    sqlx::query("UPDATE onchain_payouts SET txid = ?1 WHERE order_id = ?2")
+        .bind(txid)
+        .bind(order_id)
+        .execute(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(())
+}
+
+pub async fn find_onchain_payout(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<Option<OnchainPayout>, MostroError> {
+    sqlx::query_as::<_, OnchainPayout>("SELECT * FROM onchain_payouts WHERE order_id = ?1")
+        .bind(order_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))
+}
+
+/// Record the legs of a split settlement, legs already recorded are kept
+pub async fn add_split_legs(pool: &SqlitePool, legs: &[SplitLeg]) -> Result<(), MostroError> {
+    for leg in legs {
+        sqlx::query(
+            r#"
+                INSERT OR IGNORE INTO split_settlement_legs
+                  (order_id, party, payment_request, amount, status, attempts)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            "#,
+        )
+        .bind(leg.order_id)
+        .bind(&leg.party)
+        .bind(&leg.payment_request)
+        .bind(leg.amount)
+        .bind(&leg.status)
+        .bind(leg.attempts)
+        .execute(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+    }
+
+    Ok(())
+}
+
+pub async fn find_split_legs(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<Vec<SplitLeg>, MostroError> {
+    let legs = sqlx::query_as::<_, SplitLeg>(
+        "SELECT * FROM split_settlement_legs WHERE order_id = ?1 ORDER BY party",
+    )
+    .bind(order_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(legs)
+}
+
+/// Orders with split legs waiting to be paid and attempts left, or whose
+/// payment outcome is still to be checked
+pub async fn find_orders_with_unpaid_split_legs(
+    pool: &SqlitePool,
+    max_attempts: i64,
+) -> Result<Vec<Uuid>, MostroError> {
+    let order_ids = sqlx::query_scalar::<_, Uuid>(
+        r#"
+          SELECT DISTINCT order_id
+          FROM split_settlement_legs
+          WHERE (status IN ('pending', 'failed') AND attempts < ?1)
+            OR status IN ('paying', 'in_flight')
+        "#,
+    )
+    .bind(max_attempts)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(order_ids)
+}
+
+/// Take a pending or failed leg to pay it, false when another payer already
+/// took it. The attempt is counted as the leg is taken
+pub async fn claim_split_leg(pool: &SqlitePool, leg: &SplitLeg) -> Result<bool, MostroError> {
+    let result = sqlx::query(
+        r#"
+            UPDATE split_settlement_legs SET status = 'paying', attempts = attempts + 1
+            WHERE order_id = ?1 AND party = ?2 AND status IN ('pending', 'failed')
+        "#,
+    )
+    .bind(leg.order_id)
+    .bind(&leg.party)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn update_split_leg(pool: &SqlitePool, leg: &SplitLeg) -> Result<(), MostroError> {
+    sqlx::query(
+        r#"
+            UPDATE split_settlement_legs SET status = ?1, attempts = ?2, payment_hash = ?3
+            WHERE order_id = ?4 AND party = ?5
+        "#,
+    )
+    .bind(&leg.status)
+    .bind(leg.attempts)
+    .bind(&leg.payment_hash)
+    .bind(leg.order_id)
+    .bind(&leg.party)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(())
+}
+
+/// Move the `held` legs of an order to `pending` once its funds are settled,
+/// or forget them when the hold invoice could not be settled
+pub async fn release_split_legs(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    settled: bool,
+) -> Result<(), MostroError> {
+    let query = if settled {
+        "UPDATE split_settlement_legs SET status = 'pending' WHERE order_id = ?1 AND status = 'held'"
+    } else {
+        "DELETE FROM split_settlement_legs WHERE order_id = ?1 AND status = 'held'"
+    };
+    sqlx::query(query)
+        .bind(order_id)
+        .execute(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(())
+}
+
+/// Turn trading in a currency on or off, codes are stored uppercased
+pub async fn set_fiat_code_enabled(
+    pool: &SqlitePool,
+    fiat_code: &str,
+    enabled: bool,
+    now: i64,
+) -> Result<(), MostroError> {
+    let fiat_code = fiat_code.trim().to_uppercase();
+    let query = if enabled {
+        sqlx::query("DELETE FROM disabled_fiat_codes WHERE fiat_code = ?1")
+    } else {
+        sqlx::query(
+            "INSERT OR IGNORE INTO disabled_fiat_codes (fiat_code, disabled_at) VALUES (?1, ?2)",
+        )
+    };
+    query
+        .bind(fiat_code)
+        .bind(now)
+        .execute(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(())
+}
+
+/// Currencies with trading turned off by the operator
+pub async fn find_disabled_fiat_codes(pool: &SqlitePool) -> Result<Vec<String>, MostroError> {
+    let fiat_codes = sqlx::query_scalar::<_, String>(
+        "SELECT fiat_code FROM disabled_fiat_codes ORDER BY fiat_code",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(fiat_codes)
+}
+
+pub async fn is_fiat_code_disabled(
+    pool: &SqlitePool,
+    fiat_code: &str,
+) -> Result<bool, MostroError> {
+    let disabled = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM disabled_fiat_codes WHERE fiat_code = ?1",
+    )
+    .bind(fiat_code.trim().to_uppercase())
+    .fetch_one(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(disabled > 0)
+}
+
+pub async fn find_failed_payment(pool: &SqlitePool) -> Result<Vec<Order>, MostroError> {
+    let order = sqlx::query_as::<_, Order>(
+        r#"
+          SELECT *
+          FROM orders
+          WHERE failed_payment == true AND  status == 'settled-hold-invoice'
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(order)
+}
+
+pub async fn get_admin_password(pool: &SqlitePool) -> Result<Option<String>, MostroError> {
+    if let Some(user) = sqlx::query_as::<_, User>(
+        r#"
+          SELECT *
+          FROM users
+          WHERE is_admin == 1
+          LIMIT 1
+        "#,
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|_| {
+        MostroInternalErr(ServiceError::DbAccessError(
+            "Failed to get admin password".to_string(),
+        ))
+    })? {
+        Ok(user.admin_password)
+    } else {
+        Ok(None)
+    }
+}
+
+pub async fn find_solver_pubkey(
+    pool: &SqlitePool,
+    solver_npub: String,
+) -> Result<User, MostroError> {
+    let user = sqlx::query_as::<_, User>(
+        r#"
+          SELECT *
+          FROM users
+          WHERE pubkey == ?1 AND  is_solver == true
+          LIMIT 1
+        "#,
+    )
+    .bind(solver_npub)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(user)
+}
+
+pub async fn is_user_present(pool: &SqlitePool, public_key: String) -> Result<User, MostroError> {
+    let user = sqlx::query_as::<_, User>(
+        r#"
+            SELECT *
+            FROM users
+            WHERE pubkey == ?1
+            LIMIT 1
+        "#,
+    )
+    .bind(public_key)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(user)
+}
+
+pub async fn add_new_user(pool: &SqlitePool, new_user: User) -> Result<String, MostroError> {
+    let created_at: Timestamp = Timestamp::now();
+    let _result = sqlx::query(
+        "
+            INSERT INTO users (pubkey, is_admin,admin_password, is_solver, is_banned, category, last_trade_index, total_reviews, total_rating, last_rating, max_rating, min_rating, created_at) 
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+        ",
+    )
+    .bind(new_user.pubkey.clone())
+    .bind(new_user.is_admin)
     .bind(new_user.admin_password)
     .bind(new_user.is_solver)
     .bind(new_user.is_banned)
@@ -810,6 +1492,51 @@ async fn has_pending_order_with_status(
     }
 }
 
+/// Statuses of orders open or being traded, they all can lock node liquidity
+const ACTIVE_ORDER_STATUSES: &str = "'pending', 'waiting-buyer-invoice', 'waiting-payment', \
+     'active', 'fiat-sent', 'settled-hold-invoice', 'dispute'";
+
+/// Orders open or being traded where `pubkey` is the identity of the buyer or
+/// the seller, so both maker and taker roles count
+pub async fn count_active_orders(pool: &SqlitePool, pubkey: &str) -> Result<i64, MostroError> {
+    // Check if database is encrypted
+    if MOSTRO_DB_PASSWORD.get().is_some() {
+        let rows = sqlx::query(&format!(
+            "SELECT master_buyer_pubkey, master_seller_pubkey FROM orders WHERE status IN ({})",
+            ACTIVE_ORDER_STATUSES
+        ))
+        .fetch_all(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+        let is_pubkey = |master_key: Option<String>| {
+            master_key
+                .and_then(|key| CryptoUtils::decrypt_data(key, MOSTRO_DB_PASSWORD.get()).ok())
+                .is_some_and(|key| key == pubkey)
+        };
+        let count = rows
+            .into_iter()
+            .filter(|row| {
+                is_pubkey(row.get("master_buyer_pubkey"))
+                    || is_pubkey(row.get("master_seller_pubkey"))
+            })
+            .count();
+        Ok(count as i64)
+    }
+    // if not encrypted, use the default search
+    else {
+        sqlx::query_scalar::<_, i64>(&format!(
+            "SELECT COUNT(*) FROM orders WHERE status IN ({}) \
+             AND (master_buyer_pubkey = ?1 OR master_seller_pubkey = ?1)",
+            ACTIVE_ORDER_STATUSES
+        ))
+        .bind(pubkey)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))
+    }
+}
+
 pub async fn update_user_rating(
     pool: &SqlitePool,
     public_key: String,
@@ -905,41 +1632,944 @@ pub async fn find_order_by_id(
     Ok(order)
 }
 
-// Add this cfg attribute if the code is *only* for testing
-#[cfg(test)]
-mod tests {
-    use argon2::password_hash::SaltString;
-    use mostro_core::prelude::*;
-    use secrecy::SecretString;
-    use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
-    use sqlx::Error;
-    use std::collections::HashSet; // Import HashSet for the test
-    use tokio::time::Instant; // Use sqlx::Error for the Result return type
-
-    const TEST_DB_URL: &str = "sqlite::memory:"; // In-memory database for tests
-    const SECRET_PASSWORD: &str = "test_password"; // Example password for encryption
+/// Tells if the order has a dispute waiting for or being handled by a solver
+pub async fn has_open_dispute(pool: &SqlitePool, order_id: Uuid) -> Result<bool, MostroError> {
+    let result = sqlx::query(
+        "SELECT EXISTS(SELECT 1 FROM disputes WHERE order_id = ? AND status IN (?, ?))",
+    )
+    .bind(order_id)
+    .bind(DisputeStatus::Initiated.to_string())
+    .bind(DisputeStatus::InProgress.to_string())
+    .map(|row: SqliteRow| row.get(0))
+    .fetch_one(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
 
-    // Helper function to set up the database and pool
-    async fn setup_db() -> Result<SqlitePool, Error> {
-        let pool = SqlitePoolOptions::new()
-            .max_connections(1) // Usually fine for simple tests
-            .connect(TEST_DB_URL)
-            .await?;
+    Ok(result)
+}
 
-        // Create the table
-        sqlx::query(
-            r#"
-            CREATE TABLE items (
-                id INTEGER PRIMARY KEY,
-                value TEXT NOT NULL
-            )
-            "#,
-        )
-        .execute(&pool)
-        .await?;
+/// Record a message of a pubkey and return when it was first seen
+pub async fn record_seen(pool: &SqlitePool, pubkey: &str, now: i64) -> Result<i64, MostroError> {
+    sqlx::query(
+        r#"
+            INSERT INTO pubkey_first_seen (pubkey, first_seen, last_seen) VALUES (?1, ?2, ?2)
+            ON CONFLICT(pubkey) DO UPDATE SET last_seen = excluded.last_seen
+        "#,
+    )
+    .bind(pubkey)
+    .bind(now)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+    let first_seen =
+        sqlx::query_scalar::<_, i64>("SELECT first_seen FROM pubkey_first_seen WHERE pubkey = ?1")
+            .bind(pubkey)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
 
-        Ok(pool)
-    }
+    Ok(first_seen)
+}
+
+/// Time of the first message of a pubkey, if it was ever recorded
+pub async fn find_first_seen(pool: &SqlitePool, pubkey: &str) -> Result<Option<i64>, MostroError> {
+    let first_seen =
+        sqlx::query_scalar::<_, i64>("SELECT first_seen FROM pubkey_first_seen WHERE pubkey = ?1")
+            .bind(pubkey)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(first_seen)
+}
+
+/// Time of the last message of a pubkey, if it was ever recorded
+pub async fn find_last_seen(pool: &SqlitePool, pubkey: &str) -> Result<Option<i64>, MostroError> {
+    let last_seen =
+        sqlx::query_scalar::<_, i64>("SELECT last_seen FROM pubkey_first_seen WHERE pubkey = ?1")
+            .bind(pubkey)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(last_seen)
+}
+
+pub async fn has_accepted_terms(
+    pool: &SqlitePool,
+    pubkey: &str,
+    terms_hash: &str,
+) -> Result<bool, MostroError> {
+    let accepted = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS (SELECT 1 FROM terms_acceptances WHERE pubkey = ? AND terms_hash = ?)",
+    )
+    .bind(pubkey)
+    .bind(terms_hash)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(accepted)
+}
+
+pub async fn add_terms_acceptance(
+    pool: &SqlitePool,
+    pubkey: &str,
+    terms_hash: &str,
+) -> Result<(), MostroError> {
+    sqlx::query(
+        r#"
+            INSERT OR IGNORE INTO terms_acceptances (pubkey, terms_hash, accepted_at)
+            VALUES (?1, ?2, ?3)
+        "#,
+    )
+    .bind(pubkey)
+    .bind(terms_hash)
+    .bind(Timestamp::now().as_u64() as i64)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(())
+}
+
+/// Keep the hold invoice expiry requested by the creator of an order
+pub async fn add_order_invoice_expiry(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    expiry_secs: u64,
+) -> Result<(), MostroError> {
+    sqlx::query(
+        "INSERT OR REPLACE INTO order_invoice_expiry (order_id, expiry_secs) VALUES (?1, ?2)",
+    )
+    .bind(order_id)
+    .bind(expiry_secs as i64)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(())
+}
+
+pub async fn find_order_invoice_expiry(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<Option<u64>, MostroError> {
+    let expiry = sqlx::query_scalar::<_, i64>(
+        "SELECT expiry_secs FROM order_invoice_expiry WHERE order_id = ?1",
+    )
+    .bind(order_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(expiry.map(|expiry| expiry as u64))
+}
+
+pub async fn add_order_price_band(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    band: &PriceBand,
+) -> Result<(), MostroError> {
+    sqlx::query(
+        r#"
+            INSERT OR REPLACE INTO order_price_bands (order_id, min_price, max_price)
+            VALUES (?1, ?2, ?3)
+        "#,
+    )
+    .bind(order_id)
+    .bind(band.min_price)
+    .bind(band.max_price)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(())
+}
+
+pub async fn find_order_price_band(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<Option<PriceBand>, MostroError> {
+    let band = sqlx::query_as::<_, (f64, f64)>(
+        "SELECT min_price, max_price FROM order_price_bands WHERE order_id = ?",
+    )
+    .bind(order_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(band.map(|(min_price, max_price)| PriceBand {
+        min_price,
+        max_price,
+    }))
+}
+
+/// Keep the payment methods the maker accepts for an order
+pub async fn add_order_payment_methods(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    methods: &[PaymentMethod],
+) -> Result<(), MostroError> {
+    for method in methods {
+        sqlx::query(
+            "INSERT OR IGNORE INTO order_payment_methods (order_id, method) VALUES (?1, ?2)",
+        )
+        .bind(order_id)
+        .bind(method.as_str())
+        .execute(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+    }
+
+    Ok(())
+}
+
+/// Payment methods accepted for an order, empty if the maker set none
+pub async fn find_order_payment_methods(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<Vec<PaymentMethod>, MostroError> {
+    let methods = sqlx::query_scalar::<_, String>(
+        "SELECT method FROM order_payment_methods WHERE order_id = ?1 ORDER BY rowid",
+    )
+    .bind(order_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(methods
+        .iter()
+        .filter_map(|method| method.parse().ok())
+        .collect())
+}
+
+/// Keep the minimum rating the maker asks of the taker of an order
+pub async fn add_order_min_taker_rating(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    min_rating: f64,
+) -> Result<(), MostroError> {
+    sqlx::query(
+        "INSERT OR REPLACE INTO order_min_taker_ratings (order_id, min_rating) VALUES (?1, ?2)",
+    )
+    .bind(order_id)
+    .bind(min_rating)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(())
+}
+
+pub async fn find_order_min_taker_rating(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<Option<f64>, MostroError> {
+    sqlx::query_scalar::<_, f64>(
+        "SELECT min_rating FROM order_min_taker_ratings WHERE order_id = ?1",
+    )
+    .bind(order_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))
+}
+
+/// Append the event of an order status change to its history, rows are never
+/// updated nor deleted
+pub async fn add_order_event(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    event_id: &str,
+    status: &str,
+    created_at: i64,
+) -> Result<(), MostroError> {
+    sqlx::query(
+        r#"
+            INSERT INTO order_events (order_id, event_id, status, created_at)
+            VALUES (?1, ?2, ?3, ?4)
+        "#,
+    )
+    .bind(order_id)
+    .bind(event_id)
+    .bind(status)
+    .bind(created_at)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(())
+}
+
+/// Events published for an order, oldest first
+pub async fn get_order_event_history(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<Vec<OrderEventRecord>, MostroError> {
+    sqlx::query_as::<_, OrderEventRecord>(
+        r#"
+            SELECT order_id, event_id, status, created_at
+            FROM order_events
+            WHERE order_id = ?1
+            ORDER BY id ASC
+        "#,
+    )
+    .bind(order_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))
+}
+
+/// Last time the order got to `status`, none if the history doesn't have it
+pub async fn find_status_reached_at(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    status: &str,
+) -> Result<Option<i64>, MostroError> {
+    sqlx::query_scalar::<_, Option<i64>>(
+        "SELECT MAX(created_at) FROM order_events WHERE order_id = ?1 AND status = ?2",
+    )
+    .bind(order_id)
+    .bind(status)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))
+}
+
+/// Keep the memo of the maker of an order, encrypted with the database password
+pub async fn add_order_memo(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    memo: &str,
+) -> Result<(), MostroError> {
+    let memo = CryptoUtils::store_encrypted(memo, MOSTRO_DB_PASSWORD.get(), None)
+        .map_err(|e| MostroInternalErr(ServiceError::EncryptionError(e.to_string())))?;
+    sqlx::query("INSERT OR REPLACE INTO order_memos (order_id, memo) VALUES (?1, ?2)")
+        .bind(order_id)
+        .bind(memo)
+        .execute(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(())
+}
+
+pub async fn find_order_memo(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<Option<String>, MostroError> {
+    let memo = sqlx::query_scalar::<_, String>("SELECT memo FROM order_memos WHERE order_id = ?1")
+        .bind(order_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    memo.map(|memo| CryptoUtils::decrypt_data(memo, MOSTRO_DB_PASSWORD.get()))
+        .transpose()
+        .map_err(MostroInternalErr)
+}
+
+pub async fn add_user_rating(
+    pool: &SqlitePool,
+    pubkey: &str,
+    rating: i64,
+    created_at: i64,
+) -> Result<(), MostroError> {
+    sqlx::query("INSERT INTO user_ratings (pubkey, rating, created_at) VALUES (?1, ?2, ?3)")
+        .bind(pubkey)
+        .bind(rating)
+        .bind(created_at)
+        .execute(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(())
+}
+
+/// Ratings received by a user with the time they were given
+pub async fn find_user_ratings(
+    pool: &SqlitePool,
+    pubkey: &str,
+) -> Result<Vec<(i64, i64)>, MostroError> {
+    sqlx::query_as::<_, (i64, i64)>(
+        "SELECT rating, created_at FROM user_ratings WHERE pubkey = ?1 ORDER BY id",
+    )
+    .bind(pubkey)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))
+}
+
+/// Dispute penalties of a user with the time they were taken
+pub async fn find_user_penalties(
+    pool: &SqlitePool,
+    pubkey: &str,
+) -> Result<Vec<(f64, i64)>, MostroError> {
+    sqlx::query_as::<_, (f64, i64)>(
+        "SELECT penalty, created_at FROM dispute_penalties WHERE pubkey = ?1",
+    )
+    .bind(pubkey)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))
+}
+
+/// Take `penalty` points from the rating of the party a dispute was resolved
+/// against, recording why. A dispute penalizes once, false when it already did
+pub async fn add_dispute_penalty(
+    pool: &SqlitePool,
+    dispute_id: Uuid,
+    pubkey: &str,
+    penalty: f64,
+    reason: &str,
+) -> Result<bool, MostroError> {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+    let recorded = sqlx::query(
+        r#"
+            INSERT OR IGNORE INTO dispute_penalties (dispute_id, pubkey, penalty, reason, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+        "#,
+    )
+    .bind(dispute_id)
+    .bind(pubkey)
+    .bind(penalty)
+    .bind(reason)
+    .bind(Timestamp::now().as_u64() as i64)
+    .execute(&mut tx)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?
+    .rows_affected()
+        > 0;
+    if !recorded {
+        return Ok(false);
+    }
+    sqlx::query("UPDATE users SET total_rating = MAX(total_rating - ?1, 0) WHERE pubkey = ?2")
+        .bind(penalty)
+        .bind(pubkey)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+    tx.commit()
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(true)
+}
+
+/// Version of the backups written by [`export_backup`]
+pub const BACKUP_VERSION: u32 = 2;
+
+/// Order book tables, always in a backup
+const BACKUP_ORDER_TABLES: [&str; 10] = [
+    "orders",
+    "order_price_bands",
+    "order_republish_counts",
+    "order_creation_events",
+    "order_invoice_expiry",
+    "order_payment_methods",
+    "order_min_taker_ratings",
+    "order_events",
+    "order_memos",
+    "split_settlement_legs",
+];
+
+/// Users with their ratings and trade keys
+const BACKUP_USER_TABLES: [&str; 6] = [
+    "users",
+    "user_ratings",
+    "user_trade_stats",
+    "terms_acceptances",
+    "trade_key_bindings",
+    "pubkey_first_seen",
+];
+
+/// Disputes with their outcome
+const BACKUP_DISPUTE_TABLES: [&str; 4] = [
+    "disputes",
+    "dispute_penalties",
+    "dispute_escalations",
+    "solver_stats",
+];
+
+/// Columns of `table` in their order
+async fn table_columns<'e, E>(executor: E, table: &str) -> Result<Vec<String>, MostroError>
+where
+    E: sqlx::Executor<'e, Database = Sqlite>,
+{
+    sqlx::query_scalar::<_, String>("SELECT name FROM pragma_table_info(?1) ORDER BY cid")
+        .bind(table)
+        .fetch_all(executor)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))
+}
+
+/// Every row of `table` in insertion order
+async fn export_table(pool: &SqlitePool, table: &str) -> Result<Vec<BackupRow>, MostroError> {
+    let columns = table_columns(pool, table).await?;
+    let values = columns
+        .iter()
+        .map(|column| {
+            format!(
+                "'{column}', CASE WHEN typeof(\"{column}\") = 'blob' \
+                 THEN json_object('blob', hex(\"{column}\")) ELSE \"{column}\" END"
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let rows = sqlx::query_scalar::<_, String>(&format!(
+        "SELECT json_object({values}) FROM \"{table}\" ORDER BY rowid"
+    ))
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+    rows.iter()
+        .map(|row| {
+            serde_json::from_str(row)
+                .map_err(|_| MostroInternalErr(ServiceError::MessageSerializationError))
+        })
+        .collect()
+}
+
+/// Read the order book, and the users with their ratings and the disputes if
+/// asked. Admin passwords are left out, they are set again after an import.
+/// Order reservations, lasting minutes, and the fiat codes disabled by the
+/// operator are not exported.
+pub async fn export_backup(
+    pool: &SqlitePool,
+    with_users: bool,
+    with_disputes: bool,
+) -> Result<Backup, MostroError> {
+    let mut tables: Vec<&str> = BACKUP_ORDER_TABLES.to_vec();
+    if with_users {
+        tables.extend(BACKUP_USER_TABLES);
+    }
+    if with_disputes {
+        tables.extend(BACKUP_DISPUTE_TABLES);
+    }
+
+    let mut backup = Backup {
+        version: BACKUP_VERSION,
+        ..Default::default()
+    };
+    for table in tables {
+        let mut rows = export_table(pool, table).await?;
+        if table == "users" {
+            for row in rows.iter_mut() {
+                row.insert("admin_password".to_string(), serde_json::Value::Null);
+            }
+        }
+        backup.tables.insert(table.to_string(), rows);
+    }
+    Ok(backup)
+}
+
+/// Insert `rows` in `table`, the columns must exist in the table
+async fn import_table(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    table: &str,
+    rows: &[BackupRow],
+) -> Result<(), MostroError> {
+    let db_err = |e: sqlx::Error| MostroInternalErr(ServiceError::DbAccessError(e.to_string()));
+    let invalid = |reason: String| MostroInternalErr(ServiceError::IOError(reason));
+    let columns = table_columns(&mut *tx, table).await?;
+    for row in rows {
+        if let Some(column) = row.keys().find(|column| !columns.contains(column)) {
+            return Err(invalid(format!("Unknown column {column} in table {table}")));
+        }
+        let names = row
+            .keys()
+            .map(|column| format!("\"{column}\""))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let placeholders = (1..=row.len())
+            .map(|i| format!("?{i}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!("INSERT INTO \"{table}\" ({names}) VALUES ({placeholders})");
+        let mut query = sqlx::query(&sql);
+        for value in row.values() {
+            query = match value {
+                serde_json::Value::Null => query.bind(None::<String>),
+                serde_json::Value::Bool(value) => query.bind(*value),
+                serde_json::Value::Number(number) => match number.as_i64() {
+                    Some(value) => query.bind(value),
+                    None => query.bind(number.as_f64()),
+                },
+                serde_json::Value::String(value) => query.bind(value.clone()),
+                serde_json::Value::Object(object) => {
+                    let blob = object
+                        .get("blob")
+                        .and_then(|hex| hex.as_str())
+                        .and_then(|hex| Vec::<u8>::from_hex(hex).ok())
+                        .ok_or_else(|| invalid(format!("Invalid value in table {table}")))?;
+                    query.bind(blob)
+                }
+                serde_json::Value::Array(_) => {
+                    return Err(invalid(format!("Invalid value in table {table}")))
+                }
+            };
+        }
+        query.execute(&mut *tx).await.map_err(db_err)?;
+    }
+    Ok(())
+}
+
+/// Write a backup in a database without data in the backed up tables.
+/// Nothing is written if any row fails
+pub async fn import_backup(pool: &SqlitePool, backup: &Backup) -> Result<(), MostroError> {
+    let db_err = |e: sqlx::Error| MostroInternalErr(ServiceError::DbAccessError(e.to_string()));
+    if backup.version != BACKUP_VERSION {
+        return Err(MostroInternalErr(ServiceError::IOError(format!(
+            "Unsupported backup version {}",
+            backup.version
+        ))));
+    }
+    // Table names are checked before they are used in queries
+    let known = BACKUP_ORDER_TABLES
+        .iter()
+        .chain(&BACKUP_USER_TABLES)
+        .chain(&BACKUP_DISPUTE_TABLES);
+    if let Some(table) = backup
+        .tables
+        .keys()
+        .find(|table| !known.clone().any(|known| known == table))
+    {
+        return Err(MostroInternalErr(ServiceError::IOError(format!(
+            "Unknown table {table} in the backup"
+        ))));
+    }
+    for table in backup.tables.keys() {
+        let existing: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM \"{table}\""))
+            .fetch_one(pool)
+            .await
+            .map_err(db_err)?;
+        if existing > 0 {
+            return Err(MostroInternalErr(ServiceError::IOError(
+                "The database already has data, backups are only imported in an empty one"
+                    .to_string(),
+            )));
+        }
+    }
+
+    let mut tx = pool.begin().await.map_err(db_err)?;
+    for (table, rows) in &backup.tables {
+        import_table(&mut tx, table, rows).await?;
+    }
+    tx.commit().await.map_err(db_err)?;
+
+    Ok(())
+}
+
+/// Adds one to the times the order was republished and returns the new count
+pub async fn increment_order_republish_count(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<i64, MostroError> {
+    sqlx::query(
+        r#"
+            INSERT INTO order_republish_counts (order_id, republish_count) VALUES (?1, 1)
+            ON CONFLICT(order_id) DO UPDATE SET republish_count = republish_count + 1
+        "#,
+    )
+    .bind(order_id)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    let count = sqlx::query_scalar::<_, i64>(
+        "SELECT republish_count FROM order_republish_counts WHERE order_id = ?",
+    )
+    .bind(order_id)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(count)
+}
+
+/// Insert an order created from the event `creation_event_id`, the event is
+/// recorded in the same transaction. The event id is unique, so when another
+/// order was already created from it nothing is inserted and None is returned
+pub async fn create_order_from_event(
+    pool: &SqlitePool,
+    order: Order,
+    creation_event_id: &str,
+) -> Result<Option<Order>, MostroError> {
+    use sqlx_crud::{Crud, Schema};
+
+    let db_err = |e: sqlx::Error| MostroInternalErr(ServiceError::DbAccessError(e.to_string()));
+    let mut tx = pool.begin().await.map_err(db_err)?;
+    let claimed = sqlx::query(
+        "INSERT OR IGNORE INTO order_creation_events (event_id, order_id) VALUES (?1, ?2)",
+    )
+    .bind(creation_event_id)
+    .bind(order.id)
+    .execute(&mut tx)
+    .await
+    .map_err(db_err)?
+    .rows_affected()
+        > 0;
+    if !claimed {
+        return Ok(None);
+    }
+    sqlx::query_with(
+        <Order as Schema>::insert_sql(),
+        <Order as Crud<&SqlitePool>>::insert_args(order.clone()),
+    )
+    .execute(&mut tx)
+    .await
+    .map_err(db_err)?;
+    tx.commit().await.map_err(db_err)?;
+
+    Ok(Some(order))
+}
+
+pub async fn find_order_by_creation_event(
+    pool: &SqlitePool,
+    event_id: &str,
+) -> Result<Option<Order>, MostroError> {
+    let order = sqlx::query_as::<_, Order>(
+        r#"
+          SELECT orders.*
+          FROM orders
+          JOIN order_creation_events ON order_creation_events.order_id = orders.id
+          WHERE order_creation_events.event_id = ?1
+        "#,
+    )
+    .bind(event_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(order)
+}
+
+pub async fn add_solver_dispute_taken(
+    pool: &SqlitePool,
+    solver_pubkey: &str,
+) -> Result<(), MostroError> {
+    sqlx::query(
+        r#"
+            INSERT INTO solver_stats (solver_pubkey, disputes_taken) VALUES (?1, 1)
+            ON CONFLICT(solver_pubkey) DO UPDATE SET disputes_taken = disputes_taken + 1
+        "#,
+    )
+    .bind(solver_pubkey)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(())
+}
+
+pub async fn add_solver_dispute_resolved(
+    pool: &SqlitePool,
+    solver_pubkey: &str,
+    resolution_seconds: i64,
+    buyer_favor: bool,
+    sla_breached: bool,
+) -> Result<(), MostroError> {
+    sqlx::query(
+        r#"
+            INSERT INTO solver_stats (solver_pubkey, disputes_resolved, total_resolution_seconds, buyer_favor, seller_favor, sla_breaches)
+            VALUES (?1, 1, ?2, ?3, ?4, ?5)
+            ON CONFLICT(solver_pubkey) DO UPDATE SET
+            disputes_resolved = disputes_resolved + 1,
+            total_resolution_seconds = total_resolution_seconds + excluded.total_resolution_seconds,
+            buyer_favor = buyer_favor + excluded.buyer_favor,
+            seller_favor = seller_favor + excluded.seller_favor,
+            sla_breaches = sla_breaches + excluded.sla_breaches
+        "#,
+    )
+    .bind(solver_pubkey)
+    .bind(resolution_seconds)
+    .bind(buyer_favor as i64)
+    .bind(!buyer_favor as i64)
+    .bind(sla_breached as i64)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(())
+}
+
+pub async fn find_solver_stats(
+    pool: &SqlitePool,
+    solver_pubkey: Option<&str>,
+) -> Result<Vec<SolverStats>, MostroError> {
+    let stats = match solver_pubkey {
+        Some(pubkey) => {
+            sqlx::query_as::<_, SolverStats>("SELECT * FROM solver_stats WHERE solver_pubkey = ?")
+                .bind(pubkey)
+                .fetch_all(pool)
+                .await
+        }
+        None => {
+            sqlx::query_as::<_, SolverStats>("SELECT * FROM solver_stats")
+                .fetch_all(pool)
+                .await
+        }
+    }
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(stats)
+}
+
+/// Reserves an order for a taker, returns false if another reservation is still active
+pub async fn add_order_reservation(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    taker_pubkey: &str,
+    now: i64,
+    expires_at: i64,
+) -> Result<bool, MostroError> {
+    let result = sqlx::query(
+        r#"
+            INSERT INTO order_reservations (order_id, taker_pubkey, expires_at) VALUES (?1, ?2, ?3)
+            ON CONFLICT(order_id) DO UPDATE SET
+            taker_pubkey = excluded.taker_pubkey,
+            expires_at = excluded.expires_at
+            WHERE order_reservations.expires_at <= ?4
+        "#,
+    )
+    .bind(order_id)
+    .bind(taker_pubkey)
+    .bind(expires_at)
+    .bind(now)
+    .execute(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Returns the taker holding an active reservation of the order
+pub async fn find_order_reservation(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    now: i64,
+) -> Result<Option<String>, MostroError> {
+    let taker = sqlx::query_scalar::<_, String>(
+        "SELECT taker_pubkey FROM order_reservations WHERE order_id = ? AND expires_at > ?",
+    )
+    .bind(order_id)
+    .bind(now)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(taker)
+}
+
+/// Removes expired reservations and returns the ids of their orders
+pub async fn remove_expired_order_reservations(
+    pool: &SqlitePool,
+    now: i64,
+) -> Result<Vec<Uuid>, MostroError> {
+    let order_ids = sqlx::query_scalar::<_, Uuid>(
+        "DELETE FROM order_reservations WHERE expires_at <= ? RETURNING order_id",
+    )
+    .bind(now)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(order_ids)
+}
+
+// Add this cfg attribute if the code is *only* for testing
+/// Orders of the range grid the order belongs to, parents and children included
+pub async fn find_range_grid_orders(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<Vec<Order>, MostroError> {
+    let orders = sqlx::query_as::<_, Order>(
+        r#"
+          WITH RECURSIVE ancestors(id, range_parent_id) AS (
+            SELECT id, range_parent_id FROM orders WHERE id = ?1
+            UNION ALL
+            SELECT orders.id, orders.range_parent_id
+            FROM orders JOIN ancestors ON orders.id = ancestors.range_parent_id
+          ),
+          grid(id) AS (
+            SELECT id FROM ancestors WHERE range_parent_id IS NULL
+            UNION ALL
+            SELECT orders.id FROM orders JOIN grid ON orders.range_parent_id = grid.id
+          )
+          SELECT * FROM orders WHERE id IN (SELECT id FROM grid)
+        "#,
+    )
+    .bind(order_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(orders)
+}
+
+/// Rebuild the range grid the order belongs to
+pub async fn find_range_grid(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<Option<RangeGrid>, MostroError> {
+    let orders = find_range_grid_orders(pool, order_id).await?;
+    Ok(RangeGrid::from_orders(&orders))
+}
+
+/// Pending child orders, the open heads of partially taken range orders
+pub async fn find_pending_range_children(pool: &SqlitePool) -> Result<Vec<Order>, MostroError> {
+    let orders = sqlx::query_as::<_, Order>(
+        r#"
+          SELECT *
+          FROM orders
+          WHERE range_parent_id IS NOT NULL AND status == 'pending'
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    Ok(orders)
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::password_hash::SaltString;
+    use mostro_core::prelude::*;
+    use nostr_sdk::Keys;
+    use secrecy::SecretString;
+    use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+    use sqlx::Error;
+    use std::collections::HashSet; // Import HashSet for the test
+    use tokio::time::Instant; // Use sqlx::Error for the Result return type
+
+    const TEST_DB_URL: &str = "sqlite::memory:"; // In-memory database for tests
+    const SECRET_PASSWORD: &str = "test_password"; // Example password for encryption
+
+    // Helper function to set up the database and pool
+    async fn setup_db() -> Result<SqlitePool, Error> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1) // Usually fine for simple tests
+            .connect(TEST_DB_URL)
+            .await?;
+
+        // Create the table
+        sqlx::query(
+            r#"
+            CREATE TABLE items (
+                id INTEGER PRIMARY KEY,
+                value TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        Ok(pool)
+    }
 
     #[tokio::test]
     async fn test_fetch_string_column_scalar() {
@@ -1032,4 +2662,358 @@ mod tests {
             "Should have exactly 5 unique entries"
         );
     }
+
+    #[tokio::test]
+    async fn test_solver_stats_after_disputes() {
+        let pool = super::test_pool().await;
+        let solver = "f".repeat(64);
+
+        // Three disputes taken, two resolved
+        for _ in 0..3 {
+            super::add_solver_dispute_taken(&pool, &solver)
+                .await
+                .unwrap();
+        }
+        super::add_solver_dispute_resolved(&pool, &solver, 600, true, false)
+            .await
+            .unwrap();
+        super::add_solver_dispute_resolved(&pool, &solver, 7800, false, true)
+            .await
+            .unwrap();
+
+        let stats = super::find_solver_stats(&pool, Some(&solver))
+            .await
+            .unwrap();
+        assert_eq!(stats.len(), 1);
+        let stats = &stats[0];
+        assert_eq!(stats.disputes_taken, 3);
+        assert_eq!(stats.disputes_resolved, 2);
+        assert_eq!(stats.average_resolution_seconds(), 4200);
+        assert_eq!(stats.buyer_favor, 1);
+        assert_eq!(stats.seller_favor, 1);
+        assert_eq!(stats.sla_breaches, 1);
+    }
+
+    #[tokio::test]
+    async fn test_range_grid_survives_restart() {
+        use sqlx_crud::Crud;
+
+        let db_path = std::env::temp_dir().join(format!("mostro-grid-{}.db", uuid::Uuid::new_v4()));
+        let db_url = format!("sqlite://{}?mode=rwc", db_path.display());
+        let range_order = |id, parent, status: Status, fiat_amount, max_amount| Order {
+            id,
+            range_parent_id: parent,
+            kind: "sell".to_string(),
+            status: status.to_string(),
+            fiat_code: "USD".to_string(),
+            fiat_amount,
+            min_amount: Some(10),
+            max_amount,
+            ..Default::default()
+        };
+        let (root, child, head) = (
+            uuid::Uuid::new_v4(),
+            uuid::Uuid::new_v4(),
+            uuid::Uuid::new_v4(),
+        );
+
+        // A 10-100 range taken for 30 and then for 20
+        let pool = SqlitePool::connect(&db_url).await.unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        for order in [
+            range_order(root, None, Status::Success, 30, Some(100)),
+            range_order(child, Some(root), Status::Success, 20, Some(70)),
+            range_order(head, Some(child), Status::Pending, 0, Some(50)),
+        ] {
+            order.create(&pool).await.unwrap();
+        }
+        let before = super::find_range_grid(&pool, head).await.unwrap().unwrap();
+        pool.close().await;
+
+        // Node restarts
+        let pool = SqlitePool::connect(&db_url).await.unwrap();
+        let pending = super::find_pending_range_children(&pool).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, head);
+
+        let grid = super::find_range_grid(&pool, root).await.unwrap().unwrap();
+        assert_eq!(grid, before);
+        assert_eq!(grid.root_id, root);
+        assert_eq!(grid.order_ids, vec![root, child, head]);
+        assert_eq!(grid.filled_fiat, 50);
+        assert_eq!(grid.head_id, head);
+        assert_eq!(grid.remaining, Some((10, 50)));
+
+        pool.close().await;
+        let _ = std::fs::remove_file(db_path);
+    }
+
+    #[tokio::test]
+    async fn test_stale_pending_orders_canceled() {
+        use sqlx_crud::Crud;
+
+        let pool = super::test_pool().await;
+        let now = 1_700_000_000;
+        let ttl = 86400;
+        let pending_order = |created_at| Order {
+            id: uuid::Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::Pending.to_string(),
+            fiat_code: "USD".to_string(),
+            fiat_amount: 100,
+            created_at,
+            ..Default::default()
+        };
+        let expired = pending_order(now - ttl - 60).create(&pool).await.unwrap();
+        let fresh = pending_order(now - 60).create(&pool).await.unwrap();
+        // Created long ago, but taken and back to pending a minute ago
+        let republished = pending_order(now - 2 * ttl).create(&pool).await.unwrap();
+        super::add_order_event(&pool, republished.id, "a", "pending", now - 2 * ttl)
+            .await
+            .unwrap();
+        super::add_order_event(&pool, republished.id, "b", "waiting-payment", now - 120)
+            .await
+            .unwrap();
+        super::add_order_event(&pool, republished.id, "c", "pending", now - 60)
+            .await
+            .unwrap();
+
+        let stale = super::find_stale_pending_orders(&pool, now - ttl)
+            .await
+            .unwrap();
+        assert_eq!(stale, vec![expired.id]);
+        for order_id in [expired.id, fresh.id, republished.id] {
+            let canceled = super::cancel_stale_pending_order(&pool, order_id, now - ttl)
+                .await
+                .unwrap();
+            assert_eq!(canceled.is_some(), order_id == expired.id);
+        }
+
+        let expired = Order::by_id(&pool, expired.id).await.unwrap().unwrap();
+        assert_eq!(expired.status, Status::Canceled.to_string());
+        for order_id in [fresh.id, republished.id] {
+            let order = Order::by_id(&pool, order_id).await.unwrap().unwrap();
+            assert_eq!(order.status, Status::Pending.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_find_orders_by_status_pages() {
+        use sqlx_crud::Crud;
+
+        let pool = super::test_pool().await;
+        for (i, status) in [
+            Status::Pending,
+            Status::Active,
+            Status::Pending,
+            Status::Canceled,
+            Status::Pending,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            Order {
+                id: uuid::Uuid::new_v4(),
+                kind: "sell".to_string(),
+                status: status.to_string(),
+                fiat_code: "USD".to_string(),
+                created_at: 1_700_000_000 + i as i64,
+                ..Default::default()
+            }
+            .create(&pool)
+            .await
+            .unwrap();
+        }
+
+        let pending = super::find_orders_by_status(&pool, Status::Pending, 10, 0)
+            .await
+            .unwrap();
+        assert_eq!(pending.len(), 3);
+        assert!(pending
+            .iter()
+            .all(|o| o.status == Status::Pending.to_string()));
+        // Newest first
+        assert_eq!(pending[0].created_at, 1_700_000_004);
+
+        let page = super::find_orders_by_status(&pool, Status::Pending, 2, 2)
+            .await
+            .unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].created_at, 1_700_000_000);
+        let active = super::find_orders_by_status(&pool, Status::Active, 10, 0)
+            .await
+            .unwrap();
+        assert_eq!(active.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_has_open_dispute() {
+        use sqlx_crud::Crud;
+
+        let pool = super::test_pool().await;
+        let order_id = uuid::Uuid::new_v4();
+        assert!(!super::has_open_dispute(&pool, order_id).await.unwrap());
+
+        let mut dispute = Dispute::new(order_id, Status::FiatSent.to_string());
+        dispute.create_tokens(true);
+        let mut dispute = dispute.create(&pool).await.unwrap();
+        assert!(super::has_open_dispute(&pool, order_id).await.unwrap());
+
+        dispute.status = DisputeStatus::Settled.to_string();
+        dispute.update(&pool).await.unwrap();
+        assert!(!super::has_open_dispute(&pool, order_id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_first_seen_kept() {
+        let pool = super::test_pool().await;
+        let pubkey = Keys::generate().public_key().to_string();
+
+        assert_eq!(super::find_first_seen(&pool, &pubkey).await.unwrap(), None);
+        let first_seen = super::record_seen(&pool, &pubkey, 1_700_000_000)
+            .await
+            .unwrap();
+        assert_eq!(first_seen, 1_700_000_000);
+        // Later messages don't move the first seen time
+        let first_seen = super::record_seen(&pool, &pubkey, 1_800_000_000)
+            .await
+            .unwrap();
+        assert_eq!(first_seen, 1_700_000_000);
+        assert_eq!(
+            super::find_first_seen(&pool, &pubkey).await.unwrap(),
+            Some(1_700_000_000)
+        );
+        assert_eq!(
+            super::find_last_seen(&pool, &pubkey).await.unwrap(),
+            Some(1_800_000_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_order_event_history() {
+        let pool = super::test_pool().await;
+        let order_id = uuid::Uuid::new_v4();
+
+        // Two transitions in the same second keep their order
+        let transitions = [
+            (Status::Pending, 1_700_000_000),
+            (Status::WaitingBuyerInvoice, 1_700_000_060),
+            (Status::WaitingPayment, 1_700_000_060),
+            (Status::Active, 1_700_000_120),
+            (Status::FiatSent, 1_700_000_600),
+            (Status::Success, 1_700_000_700),
+        ];
+        for (i, (status, created_at)) in transitions.iter().enumerate() {
+            let event_id = format!("{i:064x}");
+            super::add_order_event(&pool, order_id, &event_id, &status.to_string(), *created_at)
+                .await
+                .unwrap();
+        }
+        super::add_order_event(&pool, uuid::Uuid::new_v4(), &"f".repeat(64), "pending", 0)
+            .await
+            .unwrap();
+
+        let history = super::get_order_event_history(&pool, order_id)
+            .await
+            .unwrap();
+        assert_eq!(history.len(), transitions.len());
+        let statuses: Vec<String> = history.iter().map(|e| e.status.clone()).collect();
+        let expected: Vec<String> = transitions.iter().map(|(s, _)| s.to_string()).collect();
+        assert_eq!(statuses, expected);
+        assert_eq!(history[0].event_id, format!("{:064x}", 0));
+        // The final event is the last one
+        assert_eq!(history[5].event_id, format!("{:064x}", 5));
+        assert!(history.iter().all(|e| e.order_id == order_id));
+    }
+
+    #[tokio::test]
+    async fn test_backup_round_trip() {
+        use sqlx_crud::Crud;
+
+        let source = super::test_pool().await;
+        for (status, created_at) in [
+            (Status::Pending, 1_700_000_000),
+            (Status::Success, 1_700_000_100),
+        ] {
+            Order {
+                id: uuid::Uuid::new_v4(),
+                kind: "sell".to_string(),
+                status: status.to_string(),
+                fiat_code: "VES".to_string(),
+                fiat_amount: 100,
+                created_at,
+                ..Default::default()
+            }
+            .create(&source)
+            .await
+            .unwrap();
+        }
+        let user = User {
+            pubkey: Keys::generate().public_key().to_string(),
+            is_admin: 1,
+            admin_password: Some("secret".to_string()),
+            total_reviews: 3,
+            total_rating: 4.5,
+            ..Default::default()
+        };
+        super::add_new_user(&source, user.clone()).await.unwrap();
+        let mut dispute = Dispute::new(uuid::Uuid::new_v4(), Status::FiatSent.to_string());
+        dispute.create_tokens(true);
+        dispute.create(&source).await.unwrap();
+        // Tables around orders, users and disputes
+        let order_id = uuid::Uuid::new_v4();
+        for sql in [
+            "INSERT INTO order_memos (order_id, memo) VALUES (?1, 'Pay from a Bank X account')",
+            "INSERT INTO order_payment_methods (order_id, method) VALUES (?1, 'SEPA')",
+            "INSERT INTO order_min_taker_ratings (order_id, min_rating) VALUES (?1, 3.5)",
+            "INSERT INTO order_events (order_id, event_id, status, created_at) VALUES (?1, 'abc', 'pending', 1)",
+            "INSERT INTO user_ratings (pubkey, rating, created_at) VALUES (?1, 5, 1)",
+            "INSERT INTO dispute_penalties (dispute_id, pubkey, penalty, reason, created_at) VALUES (?1, 'pk', 0.5, 'lost', 1)",
+        ] {
+            sqlx::query(sql)
+                .bind(order_id)
+                .execute(&source)
+                .await
+                .unwrap();
+        }
+
+        let backup = super::export_backup(&source, true, true).await.unwrap();
+        assert_eq!(backup.rows("orders"), 2);
+        for table in [
+            "order_memos",
+            "order_payment_methods",
+            "order_min_taker_ratings",
+            "order_events",
+            "user_ratings",
+            "dispute_penalties",
+        ] {
+            assert_eq!(backup.rows(table), 1, "{table}");
+        }
+        let json = serde_json::to_string(&backup).unwrap();
+        // Admin passwords are left out
+        assert!(!json.contains("secret"));
+        assert_eq!(
+            backup.tables["users"][0]["admin_password"],
+            serde_json::Value::Null
+        );
+
+        // Restored in a fresh database
+        let target = super::test_pool().await;
+        let restored: crate::models::Backup = serde_json::from_str(&json).unwrap();
+        super::import_backup(&target, &restored).await.unwrap();
+        let exported_again = super::export_backup(&target, true, true).await.unwrap();
+        assert_eq!(exported_again, backup);
+        let memo = super::find_order_memo(&target, order_id).await.unwrap();
+        assert_eq!(memo.as_deref(), Some("Pay from a Bank X account"));
+
+        // Data already there is never overwritten
+        assert!(super::import_backup(&target, &restored).await.is_err());
+        assert_eq!(
+            super::export_backup(&target, false, false)
+                .await
+                .unwrap()
+                .rows("orders"),
+            2
+        );
+    }
 }
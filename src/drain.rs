@@ -0,0 +1,103 @@
+//! Drain mode for maintenance.
+//!
+//! While draining new orders and takes are refused, trades already running
+//! go on until they finish. Operators can stop the daemon once no order is
+//! left in flight.
+
+use crate::db::count_in_flight_orders;
+use mostro_core::prelude::*;
+use sqlx::SqlitePool;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::info;
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Drain state and trades still running
+#[derive(Debug, Clone, PartialEq)]
+pub struct DrainStatus {
+    pub draining: bool,
+    pub in_flight_orders: i64,
+}
+
+impl DrainStatus {
+    /// Nothing is left running, the daemon can be stopped
+    pub fn safe_to_stop(&self) -> bool {
+        self.draining && self.in_flight_orders == 0
+    }
+}
+
+pub fn set_draining(draining: bool) {
+    DRAINING.store(draining, Ordering::Relaxed);
+    if draining {
+        info!("Drain mode on, new orders and takes are refused");
+    } else {
+        info!("Drain mode off");
+    }
+}
+
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+fn check_accepting_trades(draining: bool) -> Result<(), MostroError> {
+    if draining {
+        info!("Mostro is draining, request refused");
+        return Err(MostroCantDo(CantDoReason::NotAllowedByStatus));
+    }
+    Ok(())
+}
+
+/// Checks new orders and takes are accepted, they are refused while draining
+pub fn check_not_draining() -> Result<(), MostroError> {
+    check_accepting_trades(is_draining())
+}
+
+async fn drain_status_with(pool: &SqlitePool, draining: bool) -> Result<DrainStatus, MostroError> {
+    Ok(DrainStatus {
+        draining,
+        in_flight_orders: count_in_flight_orders(pool).await?,
+    })
+}
+
+pub async fn drain_status(pool: &SqlitePool) -> Result<DrainStatus, MostroError> {
+    drain_status_with(pool, is_draining()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+    use sqlx_crud::Crud;
+
+    #[tokio::test]
+    async fn test_drain_blocks_new_trades_until_done() {
+        let pool = test_pool().await;
+        let mut order = Order {
+            id: uuid::Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::FiatSent.to_string(),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+
+        // New orders and takes are refused
+        assert!(check_accepting_trades(false).is_ok());
+        assert!(matches!(
+            check_accepting_trades(true),
+            Err(MostroCantDo(CantDoReason::NotAllowedByStatus))
+        ));
+
+        // The running trade keeps the node busy until it finishes
+        let status = drain_status_with(&pool, true).await.unwrap();
+        assert_eq!(status.in_flight_orders, 1);
+        assert!(!status.safe_to_stop());
+
+        order.status = Status::Success.to_string();
+        order.update(&pool).await.unwrap();
+        let status = drain_status_with(&pool, true).await.unwrap();
+        assert_eq!(status.in_flight_orders, 0);
+        assert!(status.safe_to_stop());
+    }
+}
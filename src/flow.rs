@@ -1,9 +1,12 @@
-use crate::util::{enqueue_order_msg, notify_taker_reputation};
+use crate::lightning::LightningBackend;
+use crate::order_locks::lock_and_reload;
+use crate::util::{enqueue_order_msg, notify_taker_reputation, reset_api_quotes};
+use fedimint_tonic_lnd::lnrpc::invoice::InvoiceState;
 use mostro_core::prelude::*;
 use nostr_sdk::prelude::*;
 use sqlx::SqlitePool;
 use sqlx_crud::Crud;
-use tracing::info;
+use tracing::{error, info};
 
 pub async fn hold_invoice_paid(
     hash: &str,
@@ -142,6 +145,100 @@ pub async fn hold_invoice_canceled(hash: &str, pool: &SqlitePool) -> Result<()>
     Ok(())
 }
 
+/// Orders waiting for the seller payment whose hold invoice expired or was
+/// canceled in the node
+pub async fn find_expired_hold_invoice_orders(
+    pool: &SqlitePool,
+    ln_client: &mut dyn LightningBackend,
+) -> Result<Vec<Order>, MostroError> {
+    let mut expired = Vec::new();
+    for order in crate::db::find_waiting_payment_orders(pool).await? {
+        let Some(hash) = &order.hash else {
+            continue;
+        };
+        match ln_client.hold_invoice_state(hash).await {
+            Ok(InvoiceState::Canceled) => expired.push(order),
+            Ok(_) => {}
+            Err(e) => error!("Order Id {}: {e}", order.id),
+        }
+    }
+    Ok(expired)
+}
+
+/// Put back in the book an order whose hold invoice expired before the seller
+/// paid it, amount and fee are reset like in any republish. Returns false if
+/// the order was already handled, the seller never paid so no funds are held.
+pub async fn reset_expired_hold_invoice_order(
+    pool: &SqlitePool,
+    order: &mut Order,
+) -> Result<bool, MostroError> {
+    let Some(hash) = order.hash.clone() else {
+        return Ok(false);
+    };
+    reset_api_quotes(order);
+    crate::db::reset_expired_hold_invoice_order(pool, order.id, &hash, order.amount, order.fee)
+        .await
+}
+
+/// Republish an order whose hold invoice expired and tell both parties the
+/// trade was canceled, orders paid meanwhile are left as they are
+pub async fn hold_invoice_expired(
+    pool: &SqlitePool,
+    my_keys: &Keys,
+    found: Order,
+) -> Result<(), MostroError> {
+    let (_order_lock, order) = lock_and_reload(pool, found.id).await?;
+    let Some(order) = order.filter(|order| {
+        order.status == Status::WaitingPayment.to_string() && order.hash == found.hash
+    }) else {
+        return Ok(());
+    };
+    let parties = [
+        (order.get_buyer_pubkey(), order.trade_index_buyer),
+        (order.get_seller_pubkey(), order.trade_index_seller),
+    ];
+    let mut order = order;
+    if !reset_expired_hold_invoice_order(pool, &mut order).await? {
+        return Ok(());
+    }
+    info!(
+        "Order Id {}: hold invoice expired before the seller paid it, republishing",
+        order.id
+    );
+
+    for (pubkey, trade_index) in parties {
+        if let Ok(pubkey) = pubkey {
+            enqueue_order_msg(
+                None,
+                Some(order.id),
+                Action::Canceled,
+                None,
+                pubkey,
+                trade_index,
+            )
+            .await;
+        }
+    }
+
+    let Some(order) = Order::by_id(pool, order.id)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?
+    else {
+        return Err(MostroInternalErr(ServiceError::InvalidOrderId));
+    };
+    // Orders abandoned too many times are canceled instead of republished
+    if crate::util::republish_limit_reached(pool, order.id).await? {
+        return crate::util::cancel_republish_capped_order(pool, my_keys, &order, None).await;
+    }
+    if let Ok(order_updated) =
+        crate::util::update_order_event(my_keys, Status::Pending, &order).await
+    {
+        let _ = order_updated.update(pool).await;
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -466,4 +563,65 @@ mod tests {
             assert_eq!(timestamp_u64, timestamp_i64 as u64);
         }
     }
+
+    #[tokio::test]
+    async fn test_expired_hold_invoice_republishes_order() {
+        use crate::lightning::mock::MockBackend;
+
+        let pool = crate::db::test_pool().await;
+        let hash = "ab".repeat(32);
+        let buyer = create_test_keys().public_key().to_string();
+        let seller = create_test_keys().public_key().to_string();
+        // Market price buy order taken by a seller who never paid
+        let order = Order {
+            id: uuid::Uuid::new_v4(),
+            kind: OrderKind::Buy.to_string(),
+            status: Status::WaitingPayment.to_string(),
+            amount: 10_000,
+            fee: 100,
+            price_from_api: true,
+            hash: Some(hash.clone()),
+            buyer_pubkey: Some(buyer.clone()),
+            seller_pubkey: Some(seller),
+            cancel_initiator_pubkey: Some(buyer.clone()),
+            taken_at: Timestamp::now().as_u64() as i64,
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+
+        // The node reports the hold invoice expired
+        let mut backend = MockBackend::default();
+        backend
+            .invoice_states
+            .insert(hash.clone(), InvoiceState::Canceled);
+        let mut expired = find_expired_hold_invoice_orders(&pool, &mut backend)
+            .await
+            .unwrap();
+        assert_eq!(expired.len(), 1);
+
+        let mut first = expired.remove(0);
+        let mut second = first.clone();
+        assert!(reset_expired_hold_invoice_order(&pool, &mut first)
+            .await
+            .unwrap());
+        // The same expiration seen again doesn't reset the order twice
+        assert!(!reset_expired_hold_invoice_order(&pool, &mut second)
+            .await
+            .unwrap());
+
+        let order = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert_eq!(order.status, Status::Pending.to_string());
+        assert_eq!(order.amount, 0);
+        assert_eq!(order.fee, 0);
+        assert!(order.hash.is_none());
+        assert!(order.seller_pubkey.is_none());
+        assert!(order.cancel_initiator_pubkey.is_none());
+        assert_eq!(order.buyer_pubkey, Some(buyer));
+        assert!(find_expired_hold_invoice_orders(&pool, &mut backend)
+            .await
+            .unwrap()
+            .is_empty());
+    }
 }
@@ -0,0 +1,214 @@
+//! Emergency stop of fund movements.
+//!
+//! With the kill switch engaged hold invoices are not settled nor canceled and
+//! no payment is sent, every request moving funds is refused. Everything else
+//! keeps working so the node can be diagnosed. It starts from the
+//! `funds_kill_switch` setting and is toggled at runtime through the admin RPC.
+//! Its state is logged on each change, exported as the `mostro_kill_switch`
+//! gauge and shown on `/health`, which is only served by builds with the
+//! `metrics` feature.
+
+use mostro_core::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{info, warn};
+
+static ENGAGED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(test)]
+thread_local! {
+    /// Kill switch of the test running on this thread, tests engaging it
+    /// don't halt the ones running next to them
+    static TEST_ENGAGED: std::cell::Cell<Option<bool>> = const { std::cell::Cell::new(None) };
+}
+
+/// Engage the kill switch for the test running on this thread only
+#[cfg(test)]
+pub fn set_test_kill_switch(engaged: bool) {
+    TEST_ENGAGED.with(|test| test.set(Some(engaged)));
+}
+
+pub fn set_kill_switch(engaged: bool) {
+    ENGAGED.store(engaged, Ordering::Relaxed);
+    if engaged {
+        warn!("Kill switch engaged, fund movements are halted");
+    } else {
+        info!("Kill switch released, fund movements resumed");
+    }
+}
+
+pub fn is_kill_switch_engaged() -> bool {
+    #[cfg(test)]
+    if let Some(engaged) = TEST_ENGAGED.with(|test| test.get()) {
+        return engaged;
+    }
+    ENGAGED.load(Ordering::Relaxed)
+}
+
+fn check_movement_allowed(engaged: bool, what: &str) -> Result<(), MostroError> {
+    if engaged {
+        warn!("Kill switch engaged, {what} refused");
+        return Err(MostroCantDo(CantDoReason::NotAllowedByStatus));
+    }
+    Ok(())
+}
+
+/// Checks funds can be moved, `what` names the refused movement in the logs
+pub fn check_funds_movable(what: &str) -> Result<(), MostroError> {
+    check_movement_allowed(is_kill_switch_engaged(), what)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::admin_settle_split::pay_split_legs;
+    use crate::app::cancel::cancel_action;
+    use crate::app::release::release_action;
+    use crate::db::{
+        add_split_legs, find_order_cancel_reason, find_orders_by_status, find_split_legs, test_pool,
+    };
+    use crate::lightning::mock::MockBackend;
+    use crate::models::SplitLeg;
+    use crate::util::settle_seller_hold_invoice;
+    use nostr::nips::nip59::UnwrappedGift;
+    use nostr_sdk::prelude::*;
+    use sqlx_crud::Crud;
+
+    #[test]
+    fn test_kill_switch_refuses_movements() {
+        assert!(check_movement_allowed(false, "release").is_ok());
+        assert!(matches!(
+            check_movement_allowed(true, "release"),
+            Err(MostroCantDo(CantDoReason::NotAllowedByStatus))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_kill_switch_blocks_release_not_queries() {
+        crate::config::init_test_settings();
+        let pool = test_pool().await;
+        let seller = Keys::generate();
+        let order = Order {
+            id: uuid::Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::FiatSent.to_string(),
+            seller_pubkey: Some(seller.public_key().to_string()),
+            preimage: Some("cd".repeat(32)),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+        let release = Message::new_order(Some(order.id), None, None, Action::Release, None);
+        let event = UnwrappedGift {
+            sender: seller.public_key(),
+            rumor: EventBuilder::text_note("").build(seller.public_key()),
+        };
+        let mut backend = MockBackend::default();
+
+        set_test_kill_switch(true);
+        assert!(matches!(
+            release_action(release, &event, &Keys::generate(), &pool, &mut backend).await,
+            Err(MostroCantDo(CantDoReason::NotAllowedByStatus))
+        ));
+        assert!(matches!(
+            settle_seller_hold_invoice(&event, &mut backend, Action::Release, false, &order).await,
+            Err(MostroCantDo(CantDoReason::NotAllowedByStatus))
+        ));
+        // Nothing reached the node and the order is unchanged
+        assert!(backend.settled.is_empty());
+        let stored = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert_eq!(stored.status, Status::FiatSent.to_string());
+        // Orders can still be looked up
+        let orders = find_orders_by_status(&pool, Status::FiatSent, 10, 0)
+            .await
+            .unwrap();
+        assert_eq!(orders.len(), 1);
+
+        // Released, the hold invoice is settled again
+        set_test_kill_switch(false);
+        settle_seller_hold_invoice(&event, &mut backend, Action::Release, false, &order)
+            .await
+            .unwrap();
+        assert_eq!(backend.settled, vec!["cd".repeat(32)]);
+    }
+
+    #[tokio::test]
+    async fn test_kill_switch_blocks_payouts() {
+        let pool = test_pool().await;
+        let order_id = uuid::Uuid::new_v4();
+        let leg = |party: &str, amount| SplitLeg {
+            order_id,
+            party: party.to_string(),
+            payment_request: format!("lnbc-{party}"),
+            amount,
+            status: "pending".to_string(),
+            attempts: 0,
+            payment_hash: None,
+        };
+        add_split_legs(&pool, &[leg("buyer", 60_000), leg("seller", 39_000)])
+            .await
+            .unwrap();
+        let mut backend = MockBackend::default();
+
+        set_test_kill_switch(true);
+        assert!(pay_split_legs(&pool, &mut backend, order_id).await.is_err());
+        assert!(backend.payments.is_empty());
+        assert!(find_split_legs(&pool, order_id)
+            .await
+            .unwrap()
+            .iter()
+            .all(|leg| leg.status == "pending" && leg.attempts == 0));
+
+        set_test_kill_switch(false);
+        assert!(pay_split_legs(&pool, &mut backend, order_id).await.unwrap());
+        assert_eq!(backend.payments.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_kill_switch_blocks_maker_cancel() {
+        crate::config::init_test_settings();
+        let pool = test_pool().await;
+        let (buyer, seller) = (Keys::generate(), Keys::generate());
+        let order = Order {
+            id: uuid::Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::WaitingPayment.to_string(),
+            creator_pubkey: seller.public_key().to_string(),
+            buyer_pubkey: Some(buyer.public_key().to_string()),
+            seller_pubkey: Some(seller.public_key().to_string()),
+            hash: Some("ef".repeat(32)),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+        let cancel = Message::new_order(
+            Some(order.id),
+            None,
+            None,
+            Action::Cancel,
+            Some(Payload::TextMessage("changed my mind".to_string())),
+        );
+        let event = UnwrappedGift {
+            sender: seller.public_key(),
+            rumor: EventBuilder::text_note("").build(seller.public_key()),
+        };
+        let mut backend = MockBackend::default();
+
+        set_test_kill_switch(true);
+        assert!(matches!(
+            cancel_action(cancel, &event, &Keys::generate(), &pool, &mut backend).await,
+            Err(MostroCantDo(CantDoReason::NotAllowedByStatus))
+        ));
+        // The hold invoice is kept and the order is unchanged
+        assert!(backend.canceled.is_empty());
+        let stored = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert_eq!(stored.status, Status::WaitingPayment.to_string());
+        assert_eq!(stored.event_id, order.event_id);
+        assert!(find_order_cancel_reason(&pool, order.id)
+            .await
+            .unwrap()
+            .is_none());
+        set_test_kill_switch(false);
+    }
+}
@@ -0,0 +1,287 @@
+//! Lightning node backends.
+//!
+//! Action handlers only talk to the node through [`LightningBackend`], the
+//! implementation is selected with the `backend` option of the `[lightning]`
+//! settings: `lnd` (default) or `cln` when built with the `cln` feature. In
+//! dry run mode no node is used, see [`crate::lightning::dry_run`].
+
+use crate::config::settings::Settings;
+#[cfg(feature = "cln")]
+use crate::lightning::cln::ClnConnector;
+use crate::lightning::dry_run::{is_dry_run, DryRunBackend};
+use crate::lightning::{InvoiceMessage, LnStatus, LndConnector, PaymentMessage};
+use fedimint_tonic_lnd::lnrpc::{invoice::InvoiceState, payment::PaymentStatus};
+use mostro_core::prelude::*;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// State of a hold invoice payment, as reported to operators
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaymentState {
+    /// Waiting for the payer
+    Open,
+    /// Paid and held by the node
+    Accepted,
+    /// Funds taken by the node
+    Settled,
+    /// Funds back to the payer
+    Canceled,
+}
+
+impl PaymentState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentState::Open => "open",
+            PaymentState::Accepted => "accepted",
+            PaymentState::Settled => "settled",
+            PaymentState::Canceled => "canceled",
+        }
+    }
+}
+
+impl From<InvoiceState> for PaymentState {
+    fn from(state: InvoiceState) -> Self {
+        match state {
+            InvoiceState::Open => PaymentState::Open,
+            InvoiceState::Accepted => PaymentState::Accepted,
+            InvoiceState::Settled => PaymentState::Settled,
+            InvoiceState::Canceled => PaymentState::Canceled,
+        }
+    }
+}
+
+#[tonic::async_trait]
+pub trait LightningBackend: Send {
+    /// Create a hold invoice expiring in `expiry_secs`, 0 uses the node default.
+    /// Returns the payment request, the preimage and its hash
+    async fn create_hold_invoice(
+        &mut self,
+        description: &str,
+        amount: i64,
+        expiry_secs: u64,
+    ) -> Result<(String, Vec<u8>, Vec<u8>), MostroError>;
+
+    /// Send the state changes of a hold invoice to the listener
+    async fn subscribe_invoice(
+        &mut self,
+        r_hash: Vec<u8>,
+        listener: Sender<InvoiceMessage>,
+    ) -> Result<(), MostroError>;
+
+    /// Settle a hold invoice with its hex encoded preimage
+    async fn settle_hold_invoice(&mut self, preimage: &str) -> Result<(), MostroError>;
+
+    /// Cancel a hold invoice by its hex encoded hash, funds go back to the payer
+    async fn cancel_hold_invoice(&mut self, hash: &str) -> Result<(), MostroError>;
+
+    /// Current state of a hold invoice by its hex encoded hash
+    async fn hold_invoice_state(&mut self, hash: &str) -> Result<InvoiceState, MostroError>;
+
+    /// Current state of the payment of a hold invoice by its hex encoded hash
+    async fn payment_state(&mut self, hash: &str) -> Result<PaymentState, MostroError> {
+        Ok(self.hold_invoice_state(hash).await?.into())
+    }
+
+    /// Pay an invoice, the payment updates are sent to the listener
+    async fn send_payment(
+        &mut self,
+        payment_request: &str,
+        amount: i64,
+        listener: Sender<PaymentMessage>,
+    ) -> Result<(), MostroError>;
+
+    /// Status of a payment sent before, by the hex encoded hash of its invoice
+    async fn sent_payment_status(&mut self, hash: &str) -> Result<PaymentStatus, MostroError>;
+
+    /// Node information shown at startup and in the info event
+    async fn get_node_status(&mut self) -> Result<LnStatus, MostroError>;
+
+    /// Fee in sats of sending `amount` sats onchain to `address`
+    async fn estimate_onchain_fee(
+        &mut self,
+        _address: &str,
+        _amount: i64,
+    ) -> Result<i64, MostroError> {
+        Err(onchain_unsupported())
+    }
+
+    /// Send `amount` sats onchain to `address`, returns the transaction id
+    async fn send_onchain(&mut self, _address: &str, _amount: i64) -> Result<String, MostroError> {
+        Err(onchain_unsupported())
+    }
+}
+
+fn onchain_unsupported() -> MostroError {
+    MostroInternalErr(ServiceError::LnPaymentError(
+        "Onchain payouts are not supported by this backend".to_string(),
+    ))
+}
+
+#[tonic::async_trait]
+impl LightningBackend for LndConnector {
+    async fn create_hold_invoice(
+        &mut self,
+        description: &str,
+        amount: i64,
+        expiry_secs: u64,
+    ) -> Result<(String, Vec<u8>, Vec<u8>), MostroError> {
+        let (invoice, preimage, hash) =
+            LndConnector::create_hold_invoice(self, description, amount, expiry_secs).await?;
+        Ok((invoice.payment_request, preimage, hash))
+    }
+
+    async fn subscribe_invoice(
+        &mut self,
+        r_hash: Vec<u8>,
+        listener: Sender<InvoiceMessage>,
+    ) -> Result<(), MostroError> {
+        LndConnector::subscribe_invoice(self, r_hash, listener).await
+    }
+
+    async fn settle_hold_invoice(&mut self, preimage: &str) -> Result<(), MostroError> {
+        LndConnector::settle_hold_invoice(self, preimage).await?;
+        Ok(())
+    }
+
+    async fn cancel_hold_invoice(&mut self, hash: &str) -> Result<(), MostroError> {
+        LndConnector::cancel_hold_invoice(self, hash).await?;
+        Ok(())
+    }
+
+    async fn hold_invoice_state(&mut self, hash: &str) -> Result<InvoiceState, MostroError> {
+        self.lookup_hold_invoice_state(hash).await
+    }
+
+    async fn send_payment(
+        &mut self,
+        payment_request: &str,
+        amount: i64,
+        listener: Sender<PaymentMessage>,
+    ) -> Result<(), MostroError> {
+        LndConnector::send_payment(self, payment_request, amount, listener).await
+    }
+
+    async fn sent_payment_status(&mut self, hash: &str) -> Result<PaymentStatus, MostroError> {
+        self.track_payment_status(hash).await
+    }
+
+    async fn get_node_status(&mut self) -> Result<LnStatus, MostroError> {
+        let info = self.get_node_info().await?;
+        Ok(LnStatus::from_get_info_response(info))
+    }
+
+    async fn estimate_onchain_fee(
+        &mut self,
+        address: &str,
+        amount: i64,
+    ) -> Result<i64, MostroError> {
+        LndConnector::estimate_onchain_fee(self, address, amount).await
+    }
+
+    async fn send_onchain(&mut self, address: &str, amount: i64) -> Result<String, MostroError> {
+        LndConnector::send_coins(self, address, amount).await
+    }
+}
+
+/// Connect to the lightning node configured in settings
+pub async fn connect_backend() -> Result<Box<dyn LightningBackend>, MostroError> {
+    if is_dry_run() {
+        return Ok(Box::new(DryRunBackend));
+    }
+    match Settings::get_ln().backend.as_str() {
+        "" | "lnd" => Ok(Box::new(LndConnector::new().await?)),
+        #[cfg(feature = "cln")]
+        "cln" => Ok(Box::new(ClnConnector::new().await?)),
+        backend => Err(MostroInternalErr(ServiceError::LnNodeError(format!(
+            "Unsupported lightning backend {backend}"
+        )))),
+    }
+}
+
+/// Connectors shared by the message workers. Each handler takes one for
+/// itself, so a slow node call only delays the order it is handling.
+pub struct BackendPool {
+    permits: Semaphore,
+    backends: Mutex<Vec<Box<dyn LightningBackend>>>,
+}
+
+impl BackendPool {
+    pub fn new(backends: Vec<Box<dyn LightningBackend>>) -> Self {
+        Self {
+            permits: Semaphore::new(backends.len()),
+            backends: Mutex::new(backends),
+        }
+    }
+
+    /// Pool of `size` connectors to the node, `first` being one of them
+    pub async fn connect(
+        first: Box<dyn LightningBackend>,
+        size: usize,
+    ) -> Result<Self, MostroError> {
+        let mut backends = vec![first];
+        while backends.len() < size {
+            backends.push(connect_backend().await?);
+        }
+        Ok(Self::new(backends))
+    }
+
+    /// Take a free connector, waiting for one when all of them are in use
+    pub async fn get(&self) -> PooledBackend<'_> {
+        // The semaphore is never closed
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("Lightning backend pool closed");
+        // There is a connector left for each permit
+        let backend = lock_backends(&self.backends)
+            .pop()
+            .expect("No lightning backend left");
+        PooledBackend {
+            pool: self,
+            backend: Some(backend),
+            _permit: permit,
+        }
+    }
+}
+
+fn lock_backends(
+    backends: &Mutex<Vec<Box<dyn LightningBackend>>>,
+) -> MutexGuard<'_, Vec<Box<dyn LightningBackend>>> {
+    match backends.lock() {
+        Ok(backends) => backends,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Connector taken from a [`BackendPool`], given back when dropped
+pub struct PooledBackend<'a> {
+    pool: &'a BackendPool,
+    backend: Option<Box<dyn LightningBackend>>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Deref for PooledBackend<'_> {
+    type Target = Box<dyn LightningBackend>;
+
+    fn deref(&self) -> &Self::Target {
+        self.backend.as_ref().expect("Lightning backend given back")
+    }
+}
+
+impl DerefMut for PooledBackend<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.backend.as_mut().expect("Lightning backend given back")
+    }
+}
+
+impl Drop for PooledBackend<'_> {
+    fn drop(&mut self) {
+        // Given back before the permit is released
+        if let Some(backend) = self.backend.take() {
+            lock_backends(&self.pool.backends).push(backend);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Core Lightning backend.
+//!
+//! Talks to lightningd through its unix socket. CLN has no native hold
+//! invoices, the node must run the `hold` plugin which provides the
+//! `holdinvoice`, `settleholdinvoice`, `cancelholdinvoice` and
+//! `listholdinvoices` methods.
+
+use crate::config::settings::Settings;
+use crate::lightning::backend::LightningBackend;
+use crate::lightning::invoice::decode_invoice;
+use crate::lightning::{InvoiceMessage, LnStatus, PaymentMessage};
+use crate::util::bytes_to_string;
+use cln_rpc::ClnRpc;
+use easy_hasher::easy_hasher::*;
+use fedimint_tonic_lnd::lnrpc::{invoice::InvoiceState, payment::PaymentStatus, Payment};
+use mostro_core::prelude::*;
+use nostr_sdk::nostr::secp256k1::rand::{self, RngCore};
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use tokio::sync::mpsc::Sender;
+use tracing::info;
+
+/// Seconds between hold invoice state checks
+const INVOICE_POLL_SECONDS: u64 = 2;
+
+pub struct ClnConnector {
+    client: ClnRpc,
+}
+
+/// Map a hold plugin invoice state to the invoice state used by the flows
+fn invoice_state(state: &str) -> Option<InvoiceState> {
+    match state {
+        "unpaid" => Some(InvoiceState::Open),
+        "accepted" => Some(InvoiceState::Accepted),
+        "paid" => Some(InvoiceState::Settled),
+        "cancelled" => Some(InvoiceState::Canceled),
+        _ => None,
+    }
+}
+
+impl ClnConnector {
+    pub async fn new() -> Result<Self, MostroError> {
+        let ln_settings = Settings::get_ln();
+        let client = ClnRpc::new(&ln_settings.cln_rpc_path)
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::LnNodeError(e.to_string())))?;
+
+        Ok(Self { client })
+    }
+
+    async fn call(&mut self, method: &str, params: Value) -> Result<Value, MostroError> {
+        self.client
+            .call_raw(method, &params)
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::LnNodeError(format!("{method}: {e:?}"))))
+    }
+}
+
+#[tonic::async_trait]
+impl LightningBackend for ClnConnector {
+    async fn create_hold_invoice(
+        &mut self,
+        description: &str,
+        amount: i64,
+        expiry_secs: u64,
+    ) -> Result<(String, Vec<u8>, Vec<u8>), MostroError> {
+        let mut preimage = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut preimage);
+        let hash = raw_sha256(preimage.to_vec());
+        let ln_settings = Settings::get_ln();
+
+        let mut params = json!({
+            "payment_hash": hash.to_hex_string(),
+            "amount": amount * 1000,
+            "description": description,
+            "min_final_cltv_expiry": ln_settings.hold_invoice_cltv_delta,
+        });
+        if expiry_secs > 0 {
+            params["expiry"] = json!(expiry_secs);
+        }
+        let invoice = self.call("holdinvoice", params).await?;
+        let Some(payment_request) = invoice["bolt11"].as_str() else {
+            return Err(MostroInternalErr(ServiceError::HoldInvoiceError(
+                "Missing bolt11 in holdinvoice response".to_string(),
+            )));
+        };
+
+        Ok((
+            payment_request.to_string(),
+            preimage.to_vec(),
+            hash.to_vec(),
+        ))
+    }
+
+    async fn subscribe_invoice(
+        &mut self,
+        r_hash: Vec<u8>,
+        listener: Sender<InvoiceMessage>,
+    ) -> Result<(), MostroError> {
+        let payment_hash = bytes_to_string(&r_hash);
+        let mut last_state = None;
+
+        // The hold plugin has no subscriptions, state changes are polled
+        loop {
+            let invoices = self
+                .call("listholdinvoices", json!({ "payment_hash": payment_hash }))
+                .await?;
+            let state = invoices["holdinvoices"][0]["state"]
+                .as_str()
+                .and_then(invoice_state);
+
+            if let Some(state) = state {
+                if last_state != Some(state) {
+                    last_state = Some(state);
+                    listener
+                        .send(InvoiceMessage {
+                            hash: r_hash.clone(),
+                            state,
+                        })
+                        .await
+                        .map_err(|e| MostroInternalErr(ServiceError::LnNodeError(e.to_string())))?;
+                }
+                if matches!(state, InvoiceState::Settled | InvoiceState::Canceled) {
+                    return Ok(());
+                }
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(INVOICE_POLL_SECONDS)).await;
+        }
+    }
+
+    async fn settle_hold_invoice(&mut self, preimage: &str) -> Result<(), MostroError> {
+        self.call("settleholdinvoice", json!({ "preimage": preimage }))
+            .await?;
+        Ok(())
+    }
+
+    async fn cancel_hold_invoice(&mut self, hash: &str) -> Result<(), MostroError> {
+        self.call("cancelholdinvoice", json!({ "payment_hash": hash }))
+            .await?;
+        Ok(())
+    }
+
+    async fn hold_invoice_state(&mut self, hash: &str) -> Result<InvoiceState, MostroError> {
+        let invoices = self
+            .call("listholdinvoices", json!({ "payment_hash": hash }))
+            .await?;
+        invoices["holdinvoices"][0]["state"]
+            .as_str()
+            .and_then(invoice_state)
+            .ok_or_else(|| {
+                MostroInternalErr(ServiceError::LnNodeError(format!(
+                    "Hold invoice {hash} not found"
+                )))
+            })
+    }
+
+    async fn send_payment(
+        &mut self,
+        payment_request: &str,
+        amount: i64,
+        listener: Sender<PaymentMessage>,
+    ) -> Result<(), MostroError> {
+        let invoice = decode_invoice(payment_request)?;
+        let hash = bytes_to_string(invoice.signable_hash().as_ref());
+        let mostro_settings = Settings::get_mostro();
+
+        // Same routing fee limits as the lnd backend
+        let max_fee = match amount.cmp(&1000) {
+            Ordering::Less | Ordering::Equal => amount as f64 * 0.01,
+            Ordering::Greater => amount as f64 * mostro_settings.max_routing_fee,
+        };
+
+        // We only send the payment if it wasn't attempted before
+        let pays = self
+            .call("listpays", json!({ "bolt11": payment_request }))
+            .await?;
+        if pays["pays"].as_array().is_some_and(|pays| !pays.is_empty()) {
+            info!("Aborting paying invoice with hash {} to buyer", hash);
+            return Err(MostroInternalErr(ServiceError::LnPaymentError(
+                "Track error".to_string(),
+            )));
+        }
+
+        let mut params = json!({
+            "bolt11": payment_request,
+            "maxfee": (max_fee * 1000.0) as i64,
+            "retry_for": 60,
+        });
+        match invoice.amount_milli_satoshis() {
+            Some(amt) => {
+                if amt != amount as u64 * 1000 {
+                    info!(
+                        "Aborting paying invoice with wrong amount to buyer, hash: {}",
+                        hash
+                    );
+                    return Err(MostroInternalErr(ServiceError::LnPaymentError(
+                        "Wrong amount".to_string(),
+                    )));
+                }
+            }
+            // We add amount to the request only if the invoice doesn't have amount
+            None => params["amount_msat"] = json!(amount * 1000),
+        }
+
+        let status = match self.call("pay", params).await {
+            Ok(pay) if pay["status"] == "complete" => PaymentStatus::Succeeded,
+            Ok(_) => PaymentStatus::InFlight,
+            Err(e) => {
+                info!("Payment of invoice with hash {} failed: {}", hash, e);
+                PaymentStatus::Failed
+            }
+        };
+        let payment = Payment {
+            payment_hash: hash,
+            status: status as i32,
+            ..Default::default()
+        };
+        listener
+            .send(PaymentMessage { payment })
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::LnNodeError(e.to_string())))
+    }
+
+    async fn sent_payment_status(&mut self, hash: &str) -> Result<PaymentStatus, MostroError> {
+        let pays = self
+            .call("listpays", json!({ "payment_hash": hash }))
+            .await?;
+        match pays["pays"][0]["status"].as_str() {
+            Some("complete") => Ok(PaymentStatus::Succeeded),
+            Some("failed") => Ok(PaymentStatus::Failed),
+            Some(_) => Ok(PaymentStatus::InFlight),
+            None => Err(MostroInternalErr(ServiceError::LnPaymentError(format!(
+                "Payment {hash} not found"
+            )))),
+        }
+    }
+
+    async fn get_node_status(&mut self) -> Result<LnStatus, MostroError> {
+        let info = self.call("getinfo", json!({})).await?;
+        let text = |value: &Value| value.as_str().unwrap_or_default().to_string();
+        let node_pubkey = text(&info["id"]);
+        let uris = info["address"]
+            .as_array()
+            .map(|addresses| {
+                addresses
+                    .iter()
+                    .map(|address| {
+                        format!(
+                            "{}@{}:{}",
+                            node_pubkey,
+                            text(&address["address"]),
+                            address["port"]
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(LnStatus {
+            version: text(&info["version"]),
+            commit_hash: String::new(),
+            node_alias: text(&info["alias"]),
+            chains: vec!["bitcoin".to_string()],
+            networks: vec![text(&info["network"])],
+            uris,
+            node_pubkey,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hold_plugin_states() {
+        assert_eq!(invoice_state("unpaid"), Some(InvoiceState::Open));
+        assert_eq!(invoice_state("accepted"), Some(InvoiceState::Accepted));
+        assert_eq!(invoice_state("paid"), Some(InvoiceState::Settled));
+        assert_eq!(invoice_state("cancelled"), Some(InvoiceState::Canceled));
+        assert_eq!(invoice_state("unknown"), None);
+    }
+}
@@ -0,0 +1,255 @@
+//! Lightning backend of the dry run mode.
+//!
+//! Nothing reaches a lightning node: the calls made by the flows are logged
+//! and recorded, hold invoices are accepted right away as if the seller paid
+//! them and every payment succeeds. The database and Nostr work as usual, so
+//! operators can go through whole trades against a test relay.
+
+use crate::lightning::{InvoiceMessage, LightningBackend, LnStatus, PaymentMessage};
+use crate::util::bytes_to_string;
+use easy_hasher::easy_hasher::*;
+use fedimint_tonic_lnd::lnrpc::{invoice::InvoiceState, payment::PaymentStatus, Payment};
+use mostro_core::prelude::*;
+use nostr_sdk::nostr::hashes::hex::FromHex;
+use nostr_sdk::nostr::secp256k1::rand::{self, RngCore};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
+use tokio::sync::mpsc::Sender;
+use tracing::info;
+
+/// Seconds between hold invoice state checks
+const INVOICE_POLL_SECONDS: u64 = 1;
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Calls made to the backend, oldest first
+static CALLS: LazyLock<Mutex<Vec<String>>> = LazyLock::new(Mutex::default);
+
+/// Hold invoice states by hex encoded hash, shared by every backend instance
+static INVOICES: LazyLock<Mutex<HashMap<String, InvoiceState>>> = LazyLock::new(Mutex::default);
+
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+    if dry_run {
+        info!("Dry run mode on, no call reaches the lightning node");
+    }
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// Calls the flows made to the backend in dry run mode
+pub fn recorded_calls() -> Vec<String> {
+    CALLS.lock().map(|calls| calls.clone()).unwrap_or_default()
+}
+
+fn record(call: String) {
+    info!("Dry run: {call}");
+    if let Ok(mut calls) = CALLS.lock() {
+        calls.push(call);
+    }
+}
+
+fn invoice_state(hash: &str) -> Option<InvoiceState> {
+    INVOICES.lock().ok()?.get(hash).copied()
+}
+
+fn set_invoice_state(hash: &str, state: InvoiceState) {
+    if let Ok(mut invoices) = INVOICES.lock() {
+        invoices.insert(hash.to_string(), state);
+    }
+}
+
+#[derive(Default)]
+pub struct DryRunBackend;
+
+#[tonic::async_trait]
+impl LightningBackend for DryRunBackend {
+    async fn create_hold_invoice(
+        &mut self,
+        description: &str,
+        amount: i64,
+        _expiry_secs: u64,
+    ) -> Result<(String, Vec<u8>, Vec<u8>), MostroError> {
+        let mut preimage = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut preimage);
+        let hash = raw_sha256(preimage.to_vec());
+        record(format!(
+            "create_hold_invoice {} {amount} {description}",
+            hash.to_hex_string()
+        ));
+        set_invoice_state(&hash.to_hex_string(), InvoiceState::Open);
+
+        Ok((
+            format!("dry-run-hold-invoice-{}", hash.to_hex_string()),
+            preimage.to_vec(),
+            hash.to_vec(),
+        ))
+    }
+
+    async fn subscribe_invoice(
+        &mut self,
+        r_hash: Vec<u8>,
+        listener: Sender<InvoiceMessage>,
+    ) -> Result<(), MostroError> {
+        let hash = bytes_to_string(&r_hash);
+        record(format!("subscribe_invoice {hash}"));
+        // The seller pays right away
+        if invoice_state(&hash) == Some(InvoiceState::Open) {
+            set_invoice_state(&hash, InvoiceState::Accepted);
+        }
+
+        let mut last_state = None;
+        loop {
+            let Some(state) = invoice_state(&hash) else {
+                return Ok(());
+            };
+            if last_state != Some(state) {
+                last_state = Some(state);
+                listener
+                    .send(InvoiceMessage {
+                        hash: r_hash.clone(),
+                        state,
+                    })
+                    .await
+                    .map_err(|e| MostroInternalErr(ServiceError::LnNodeError(e.to_string())))?;
+            }
+            if matches!(state, InvoiceState::Settled | InvoiceState::Canceled) {
+                return Ok(());
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(INVOICE_POLL_SECONDS)).await;
+        }
+    }
+
+    async fn settle_hold_invoice(&mut self, preimage: &str) -> Result<(), MostroError> {
+        let preimage_bytes = Vec::<u8>::from_hex(preimage)
+            .map_err(|e| MostroInternalErr(ServiceError::HoldInvoiceError(e.to_string())))?;
+        let hash = raw_sha256(preimage_bytes).to_hex_string();
+        record(format!("settle_hold_invoice {hash}"));
+        set_invoice_state(&hash, InvoiceState::Settled);
+        Ok(())
+    }
+
+    async fn cancel_hold_invoice(&mut self, hash: &str) -> Result<(), MostroError> {
+        record(format!("cancel_hold_invoice {hash}"));
+        set_invoice_state(hash, InvoiceState::Canceled);
+        Ok(())
+    }
+
+    async fn hold_invoice_state(&mut self, hash: &str) -> Result<InvoiceState, MostroError> {
+        invoice_state(hash).ok_or_else(|| {
+            MostroInternalErr(ServiceError::LnNodeError(format!(
+                "Hold invoice {hash} not found"
+            )))
+        })
+    }
+
+    async fn send_payment(
+        &mut self,
+        payment_request: &str,
+        amount: i64,
+        listener: Sender<PaymentMessage>,
+    ) -> Result<(), MostroError> {
+        record(format!("send_payment {amount} {payment_request}"));
+        let payment = Payment {
+            status: PaymentStatus::Succeeded as i32,
+            ..Default::default()
+        };
+        listener
+            .send(PaymentMessage { payment })
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::LnNodeError(e.to_string())))
+    }
+
+    async fn sent_payment_status(&mut self, _hash: &str) -> Result<PaymentStatus, MostroError> {
+        // Every payment succeeds in dry run mode
+        Ok(PaymentStatus::Succeeded)
+    }
+
+    async fn get_node_status(&mut self) -> Result<LnStatus, MostroError> {
+        Ok(LnStatus {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            node_pubkey: String::new(),
+            commit_hash: String::new(),
+            node_alias: "dry-run".to_string(),
+            chains: vec!["bitcoin".to_string()],
+            networks: vec!["regtest".to_string()],
+            uris: Vec::new(),
+        })
+    }
+
+    async fn estimate_onchain_fee(
+        &mut self,
+        address: &str,
+        amount: i64,
+    ) -> Result<i64, MostroError> {
+        record(format!("estimate_onchain_fee {amount} {address}"));
+        Ok(0)
+    }
+
+    async fn send_onchain(&mut self, address: &str, amount: i64) -> Result<String, MostroError> {
+        record(format!("send_onchain {amount} {address}"));
+        let mut txid = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut txid);
+        Ok(bytes_to_string(&txid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+
+    #[tokio::test]
+    async fn test_trade_calls_recorded() {
+        let mut backend = DryRunBackend;
+
+        // Take: the seller gets a hold invoice, paying it is simulated
+        let (_, preimage, hash) = backend
+            .create_hold_invoice("Dry run trade", 100_000, 0)
+            .await
+            .unwrap();
+        let hash_hex = bytes_to_string(&hash);
+        let (tx, mut rx) = channel(10);
+        let subscription = tokio::spawn({
+            let hash = hash.clone();
+            async move { DryRunBackend.subscribe_invoice(hash, tx).await }
+        });
+        assert_eq!(rx.recv().await.unwrap().state, InvoiceState::Accepted);
+
+        // Fiat sent needs no lightning call, release settles and pays the buyer
+        backend
+            .settle_hold_invoice(&bytes_to_string(&preimage))
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await.unwrap().state, InvoiceState::Settled);
+        subscription.await.unwrap().unwrap();
+        let (tx, mut rx) = channel(1);
+        backend
+            .send_payment("lnbcrt-buyer", 99_400, tx)
+            .await
+            .unwrap();
+        let payment = rx.recv().await.unwrap().payment;
+        assert_eq!(payment.status, PaymentStatus::Succeeded as i32);
+
+        let calls: Vec<String> = recorded_calls()
+            .into_iter()
+            .filter(|call| call.contains(&hash_hex) || call.contains("lnbcrt-buyer"))
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                format!("create_hold_invoice {hash_hex} 100000 Dry run trade"),
+                format!("subscribe_invoice {hash_hex}"),
+                format!("settle_hold_invoice {hash_hex}"),
+                "send_payment 99400 lnbcrt-buyer".to_string(),
+            ]
+        );
+        assert_eq!(
+            backend.hold_invoice_state(&hash_hex).await.unwrap(),
+            InvoiceState::Settled
+        );
+    }
+}
@@ -1,8 +1,8 @@
 use crate::config::settings::Settings;
+use crate::lightning::onchain::{is_onchain_address, validate_onchain_address};
 use crate::lnurl::ln_exists;
 
 use chrono::prelude::*;
-use chrono::TimeDelta;
 use lightning_invoice::{Bolt11Invoice, SignedRawBolt11Invoice};
 use lnurl::lightning_address::LightningAddress;
 use lnurl::lnurl::LnUrl;
@@ -46,6 +46,7 @@ pub fn decode_invoice(payment_request: &str) -> Result<Bolt11Invoice, MostroErro
 /// # Arguments
 ///
 /// * `payment_request` - A string slice containing the Lightning Address (e.g., "user@domain.com")
+/// * `amount` - Optional sats the address must accept, checked against its sendable range
 ///
 /// # Returns
 ///
@@ -56,8 +57,11 @@ pub fn decode_invoice(payment_request: &str) -> Result<Bolt11Invoice, MostroErro
 ///
 /// This function performs a network request to validate the address, so it may
 /// fail due to network issues even if the address format is correct.
-async fn validate_lightning_address(payment_request: &str) -> Result<(), MostroError> {
-    if ln_exists(payment_request).await.is_err() {
+async fn validate_lightning_address(
+    payment_request: &str,
+    amount: Option<u64>,
+) -> Result<(), MostroError> {
+    if ln_exists(payment_request, amount).await.is_err() {
         return Err(MostroInternalErr(ServiceError::InvoiceInvalidError));
     }
     Ok(())
@@ -87,7 +91,8 @@ async fn validate_lightning_address(payment_request: &str) -> Result<(), MostroE
 /// - If `amount` is provided, the invoice amount must match `amount - fee`
 /// - Invoice amount must meet minimum payment threshold (if non-zero)
 /// - Invoice must not be expired
-/// - Invoice expiration must be within acceptable time window
+/// - Invoice expiry must be between `min_invoice_expiry_secs` (at least the
+///   expiration window) and `max_invoice_expiry_secs`
 ///
 /// # Notes
 ///
@@ -122,11 +127,6 @@ async fn validate_bolt11_invoice(
         return Err(MostroInternalErr(ServiceError::InvoiceInvalidError));
     }
 
-    // Check if invoice is expired
-    if invoice.is_expired() {
-        return Err(MostroInternalErr(ServiceError::InvoiceInvalidError));
-    }
-
     // Check expiration window
     let parsed = payment_request
         .parse::<SignedRawBolt11Invoice>()
@@ -134,14 +134,34 @@ async fn validate_bolt11_invoice(
 
     let (parsed_invoice, _, _) = parsed.into_parts();
 
-    let expiration_window = ln_settings.invoice_expiration_window as i64;
-    let latest_date = Utc::now()
-        + TimeDelta::try_seconds(expiration_window).expect("wrong seconds timeout value");
-    let latest_date = latest_date.timestamp() as u64;
     let expires_at =
         invoice.expiry_time().as_secs() + parsed_invoice.data.timestamp.as_unix_timestamp();
+    // The old expiration window is the minimum unless a larger one is set
+    let min_expiry_secs = ln_settings
+        .min_invoice_expiry_secs
+        .max(ln_settings.invoice_expiration_window as u64);
+
+    check_invoice_expiry(
+        expires_at,
+        Utc::now().timestamp() as u64,
+        min_expiry_secs,
+        ln_settings.max_invoice_expiry_secs,
+    )
+}
 
-    if expires_at < latest_date {
+/// Checks an invoice expiring at `expires_at` is not expired and stays payable
+/// for at least `min_secs`, but no longer than `max_secs` when it is not 0
+fn check_invoice_expiry(
+    expires_at: u64,
+    now: u64,
+    min_secs: u64,
+    max_secs: u64,
+) -> Result<(), MostroError> {
+    if expires_at <= now {
+        return Err(MostroInternalErr(ServiceError::InvoiceInvalidError));
+    }
+    let expiry_secs = expires_at - now;
+    if expiry_secs < min_secs || (max_secs > 0 && expiry_secs > max_secs) {
         return Err(MostroInternalErr(ServiceError::InvoiceInvalidError));
     }
 
@@ -154,6 +174,7 @@ async fn validate_bolt11_invoice(
 /// and routes them to the appropriate validation logic. It supports:
 /// - Lightning Addresses (user@domain.com format)
 /// - LNURL-pay requests (lnurl1... format)
+/// - Bitcoin addresses, when onchain payouts are enabled
 /// - BOLT11 invoices (lnbc... format)
 ///
 /// # Arguments
@@ -172,7 +193,9 @@ async fn validate_bolt11_invoice(
 /// The function tries to parse the payment request in the following order:
 /// 1. Lightning Address - if it matches email-like format
 /// 2. LNURL - if it can be parsed as a valid LNURL
-/// 3. BOLT11 - falls back to BOLT11 invoice validation
+/// 3. Bitcoin address - if it is an address of any network, it must be for the
+///    network of the node
+/// 4. BOLT11 - falls back to BOLT11 invoice validation
 ///
 /// # Usage
 ///
@@ -187,7 +210,13 @@ pub async fn is_valid_invoice(
     if LightningAddress::from_str(&payment_request).is_ok()
         || LnUrl::from_str(&payment_request).is_ok()
     {
-        return validate_lightning_address(&payment_request).await;
+        let amount = amount.map(|amount| amount.saturating_sub(fee.unwrap_or(0)));
+        return validate_lightning_address(&payment_request, amount).await;
+    }
+
+    // Buyers paid onchain send an address
+    if is_onchain_address(&payment_request) {
+        return validate_onchain_address(&payment_request);
     }
 
     // Fall back to BOLT11 invoice
@@ -290,6 +319,29 @@ mod tests {
         assert_eq!(Ok(()), zero_amount_err.await);
     }
 
+    #[test]
+    fn test_expired_invoice_rejected() {
+        let now = 1_700_000_000;
+        assert!(check_invoice_expiry(now - 1, now, 0, 0).is_err());
+        assert!(check_invoice_expiry(now, now, 0, 0).is_err());
+    }
+
+    #[test]
+    fn test_near_expiry_invoice_rejected() {
+        let now = 1_700_000_000;
+        assert!(check_invoice_expiry(now + 600, now, 3600, 0).is_err());
+    }
+
+    #[test]
+    fn test_invoice_expiry_inside_bounds() {
+        let now = 1_700_000_000;
+        assert!(check_invoice_expiry(now + 7200, now, 3600, 86_400).is_ok());
+        // No max bound
+        assert!(check_invoice_expiry(now + 30 * 86_400, now, 3600, 0).is_ok());
+        // Too far in the future
+        assert!(check_invoice_expiry(now + 2 * 86_400, now, 3600, 86_400).is_err());
+    }
+
     #[tokio::test]
     async fn test_min_amount_invoice() {
         init_settings_test();
@@ -319,6 +371,13 @@ mod tests {
             "LNURL validation with valid amount should succeed"
         );
 
+        // Amount over the max sendable of the endpoint
+        let result = is_valid_invoice(lnurl.clone(), Some(20_000), None).await;
+        assert!(
+            result.is_err(),
+            "LNURL validation with an out of range amount should fail"
+        );
+
         // Lightning address validation
         // Test with a valid Lightning address that matches our test server
         let valid_address = "MostroP2P@localhost".to_string();
@@ -0,0 +1,168 @@
+//! Lightning backend used in tests, it records the calls made by the flows
+//! and answers with the configured hold invoice states.
+
+use crate::lightning::{InvoiceMessage, LightningBackend, LnStatus, PaymentMessage};
+use fedimint_tonic_lnd::lnrpc::{invoice::InvoiceState, payment::PaymentStatus, Payment};
+use mostro_core::prelude::*;
+use std::collections::{HashMap, HashSet};
+use tokio::sync::mpsc::Sender;
+
+#[derive(Default)]
+pub struct MockBackend {
+    /// Hashes of the hold invoices canceled
+    pub canceled: Vec<String>,
+    /// Every call fails as if the node was down
+    pub fail: bool,
+    /// Hold invoice states by hash, unknown hashes are not found
+    pub invoice_states: HashMap<String, InvoiceState>,
+    /// Preimages of the hold invoices settled
+    pub settled: Vec<String>,
+    /// Payment requests paid, with their amount
+    pub payments: Vec<(String, i64)>,
+    /// Payment requests whose payment fails, onchain sends to these addresses
+    /// are broadcast but answered with an error
+    pub failing_payments: HashSet<String>,
+    /// Payment requests whose payment is sent without the node telling its
+    /// outcome
+    pub in_flight_payments: HashSet<String>,
+    /// Status of the payments sent by hash, unknown hashes are not found
+    pub payment_statuses: HashMap<String, PaymentStatus>,
+    /// Time the node takes to answer a payment
+    pub payment_delay: std::time::Duration,
+    /// Calls failing with a connection error before the node answers
+    pub transient_failures: u32,
+    /// Fee estimated for onchain sends
+    pub onchain_fee: i64,
+    /// Addresses sent to onchain, with their amount
+    pub onchain_sends: Vec<(String, i64)>,
+}
+
+impl MockBackend {
+    fn check_node(&mut self) -> Result<(), MostroError> {
+        if self.fail {
+            return Err(MostroInternalErr(ServiceError::LnNodeError(
+                "node down".to_string(),
+            )));
+        }
+        if self.transient_failures > 0 {
+            self.transient_failures -= 1;
+            return Err(MostroInternalErr(ServiceError::LnNodeError(
+                "status: Unavailable, message: \"transport error\"".to_string(),
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl LightningBackend for MockBackend {
+    async fn create_hold_invoice(
+        &mut self,
+        _description: &str,
+        _amount: i64,
+        _expiry_secs: u64,
+    ) -> Result<(String, Vec<u8>, Vec<u8>), MostroError> {
+        unimplemented!()
+    }
+
+    async fn subscribe_invoice(
+        &mut self,
+        _r_hash: Vec<u8>,
+        _listener: Sender<InvoiceMessage>,
+    ) -> Result<(), MostroError> {
+        unimplemented!()
+    }
+
+    async fn settle_hold_invoice(&mut self, preimage: &str) -> Result<(), MostroError> {
+        self.check_node()?;
+        self.settled.push(preimage.to_string());
+        Ok(())
+    }
+
+    async fn cancel_hold_invoice(&mut self, hash: &str) -> Result<(), MostroError> {
+        self.check_node()?;
+        self.canceled.push(hash.to_string());
+        self.invoice_states
+            .insert(hash.to_string(), InvoiceState::Canceled);
+        Ok(())
+    }
+
+    async fn hold_invoice_state(&mut self, hash: &str) -> Result<InvoiceState, MostroError> {
+        self.check_node()?;
+        self.invoice_states.get(hash).copied().ok_or_else(|| {
+            MostroInternalErr(ServiceError::LnNodeError(format!(
+                "Hold invoice {hash} not found"
+            )))
+        })
+    }
+
+    async fn send_payment(
+        &mut self,
+        payment_request: &str,
+        amount: i64,
+        listener: Sender<PaymentMessage>,
+    ) -> Result<(), MostroError> {
+        self.check_node()?;
+        tokio::time::sleep(self.payment_delay).await;
+        let status = if self.failing_payments.contains(payment_request) {
+            PaymentStatus::Failed
+        } else if self.in_flight_payments.contains(payment_request) {
+            // The payment is on its way but the updates end before its outcome
+            self.payments.push((payment_request.to_string(), amount));
+            return Ok(());
+        } else {
+            self.payments.push((payment_request.to_string(), amount));
+            PaymentStatus::Succeeded
+        };
+        let payment = Payment {
+            status: status as i32,
+            ..Default::default()
+        };
+        listener
+            .send(PaymentMessage { payment })
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::LnNodeError(e.to_string())))
+    }
+
+    async fn sent_payment_status(&mut self, hash: &str) -> Result<PaymentStatus, MostroError> {
+        self.check_node()?;
+        self.payment_statuses.get(hash).copied().ok_or_else(|| {
+            MostroInternalErr(ServiceError::LnPaymentError(format!(
+                "Payment {hash} not found"
+            )))
+        })
+    }
+
+    async fn get_node_status(&mut self) -> Result<LnStatus, MostroError> {
+        self.check_node()?;
+        Ok(LnStatus {
+            version: "mock".to_string(),
+            node_pubkey: String::new(),
+            commit_hash: String::new(),
+            node_alias: "mock".to_string(),
+            chains: vec!["bitcoin".to_string()],
+            networks: vec!["regtest".to_string()],
+            uris: Vec::new(),
+        })
+    }
+
+    async fn estimate_onchain_fee(
+        &mut self,
+        _address: &str,
+        _amount: i64,
+    ) -> Result<i64, MostroError> {
+        self.check_node()?;
+        Ok(self.onchain_fee)
+    }
+
+    async fn send_onchain(&mut self, address: &str, amount: i64) -> Result<String, MostroError> {
+        self.check_node()?;
+        self.onchain_sends.push((address.to_string(), amount));
+        if self.failing_payments.contains(address) {
+            return Err(MostroInternalErr(ServiceError::LnPaymentError(
+                "send failed".to_string(),
+            )));
+        }
+        Ok(format!("{:064x}", self.onchain_sends.len()))
+    }
+}
@@ -1,14 +1,28 @@
+pub mod backend;
+#[cfg(feature = "cln")]
+pub mod cln;
+pub mod dry_run;
 pub mod invoice;
+#[cfg(test)]
+pub mod mock;
+pub mod onchain;
+pub mod retry;
+
+pub use backend::{connect_backend, BackendPool, LightningBackend, PaymentState};
 
 use crate::config::settings::Settings;
 use crate::lightning::invoice::decode_invoice;
+use crate::lightning::onchain::ONCHAIN_TARGET_CONF;
 use crate::util::bytes_to_string;
 use easy_hasher::easy_hasher::*;
 use fedimint_tonic_lnd::invoicesrpc::{
     AddHoldInvoiceRequest, AddHoldInvoiceResp, CancelInvoiceMsg, CancelInvoiceResp,
     SettleInvoiceMsg, SettleInvoiceResp,
 };
-use fedimint_tonic_lnd::lnrpc::{invoice::InvoiceState, GetInfoRequest, GetInfoResponse, Payment};
+use fedimint_tonic_lnd::lnrpc::{
+    invoice::InvoiceState, payment::PaymentStatus, EstimateFeeRequest, GetInfoRequest,
+    GetInfoResponse, Payment, PaymentHash, SendCoinsRequest,
+};
 use fedimint_tonic_lnd::routerrpc::{SendPaymentRequest, TrackPaymentRequest};
 use fedimint_tonic_lnd::Client;
 use mostro_core::prelude::*;
@@ -55,6 +69,7 @@ impl LndConnector {
         &mut self,
         description: &str,
         amount: i64,
+        expiry_secs: u64,
     ) -> Result<(AddHoldInvoiceResp, Vec<u8>, Vec<u8>), MostroError> {
         let mut preimage = [0u8; 32];
         rand::thread_rng().fill_bytes(&mut preimage);
@@ -67,6 +82,8 @@ impl LndConnector {
             memo: description.to_string(),
             value: amount,
             cltv_expiry,
+            // lnd uses its default expiry for 0
+            expiry: expiry_secs as i64,
             ..Default::default()
         };
         let holdinvoice = self
@@ -162,6 +179,27 @@ impl LndConnector {
         }
     }
 
+    pub async fn lookup_hold_invoice_state(
+        &mut self,
+        hash: &str,
+    ) -> Result<InvoiceState, MostroError> {
+        let r_hash = FromHex::from_hex(hash)
+            .map_err(|e| MostroInternalErr(ServiceError::LnNodeError(format!("{e}"))))?;
+        let invoice = self
+            .client
+            .lightning()
+            .lookup_invoice(PaymentHash {
+                r_hash,
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::LnNodeError(e.to_string())))?
+            .into_inner();
+
+        InvoiceState::try_from(invoice.state)
+            .map_err(|e| MostroInternalErr(ServiceError::LnNodeError(e.to_string())))
+    }
+
     pub async fn send_payment(
         &mut self,
         payment_request: &str,
@@ -257,6 +295,74 @@ impl LndConnector {
         Ok(())
     }
 
+    /// Current status of a payment sent before, by its hex encoded hash
+    pub async fn track_payment_status(&mut self, hash: &str) -> Result<PaymentStatus, MostroError> {
+        let payment_hash = FromHex::from_hex(hash)
+            .map_err(|e| MostroInternalErr(ServiceError::LnNodeError(format!("{e}"))))?;
+        let mut stream = self
+            .client
+            .router()
+            .track_payment_v2(TrackPaymentRequest {
+                payment_hash,
+                no_inflight_updates: false,
+            })
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::LnPaymentError(e.to_string())))?
+            .into_inner();
+        // The first update is the current status of the payment
+        let payment = stream
+            .message()
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::LnPaymentError(e.to_string())))?
+            .ok_or_else(|| {
+                MostroInternalErr(ServiceError::LnPaymentError(format!(
+                    "Payment {hash} not found"
+                )))
+            })?;
+
+        PaymentStatus::try_from(payment.status)
+            .map_err(|e| MostroInternalErr(ServiceError::LnPaymentError(e.to_string())))
+    }
+
+    pub async fn estimate_onchain_fee(
+        &mut self,
+        address: &str,
+        amount: i64,
+    ) -> Result<i64, MostroError> {
+        let request = EstimateFeeRequest {
+            addr_to_amount: [(address.to_string(), amount)].into_iter().collect(),
+            target_conf: ONCHAIN_TARGET_CONF,
+            ..Default::default()
+        };
+        let fee = self
+            .client
+            .lightning()
+            .estimate_fee(request)
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::LnPaymentError(e.to_string())))?
+            .into_inner();
+
+        Ok(fee.fee_sat)
+    }
+
+    pub async fn send_coins(&mut self, address: &str, amount: i64) -> Result<String, MostroError> {
+        let request = SendCoinsRequest {
+            addr: address.to_string(),
+            amount,
+            target_conf: ONCHAIN_TARGET_CONF,
+            ..Default::default()
+        };
+        let sent = self
+            .client
+            .lightning()
+            .send_coins(request)
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::LnPaymentError(e.to_string())))?
+            .into_inner();
+
+        Ok(sent.txid)
+    }
+
     pub async fn get_node_info(&mut self) -> Result<GetInfoResponse, MostroError> {
         let info = self.client.lightning().get_info(GetInfoRequest {}).await;
 
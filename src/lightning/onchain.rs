@@ -0,0 +1,225 @@
+//! Onchain payouts to buyers.
+//!
+//! Buyers can send a bitcoin address instead of an invoice when the operator
+//! turns on `onchain_payouts`. The address has to be for the network of the
+//! node, on release the node sends the amount of the buyer minus the onchain
+//! fee estimated for the transaction.
+//!
+//! Unlike invoices, a transaction has no payment hash the node could refuse
+//! to pay twice, and a send failing after the broadcast can't be told from
+//! one failing before it. Each payout is recorded before it is sent and an
+//! order with a payout recorded is never paid again: the ones without a
+//! transaction id have to be checked in the node wallet by the operator.
+
+use crate::config::settings::Settings;
+use crate::db::{add_onchain_payout, find_onchain_payout, set_onchain_payout_txid};
+use crate::lightning::{LightningBackend, LnStatus};
+use crate::LN_STATUS;
+use bitcoin::{Address, Network};
+use mostro_core::prelude::*;
+use sqlx::{Pool, Sqlite};
+use std::str::FromStr;
+use tracing::{error, info};
+use uuid::Uuid;
+
+/// Blocks the onchain payout should confirm in, used to estimate its fee
+pub const ONCHAIN_TARGET_CONF: i32 = 6;
+
+/// Network of the chain the node runs on
+pub fn node_network(status: &LnStatus) -> Option<Network> {
+    match status.networks.first()?.as_str() {
+        "mainnet" | "bitcoin" => Some(Network::Bitcoin),
+        "testnet" => Some(Network::Testnet),
+        "signet" => Some(Network::Signet),
+        "regtest" => Some(Network::Regtest),
+        _ => None,
+    }
+}
+
+/// Tells if the payment request is a bitcoin address of any network
+pub fn is_onchain_address(payment_request: &str) -> bool {
+    Address::from_str(payment_request).is_ok()
+}
+
+/// Parse a buyer address, refused if it is not for `network`
+pub fn parse_address(address: &str, network: Network) -> Result<Address, MostroError> {
+    Address::from_str(address)
+        .map_err(|_| MostroCantDo(CantDoReason::InvalidInvoice))?
+        .require_network(network)
+        .map_err(|_| MostroCantDo(CantDoReason::InvalidInvoice))
+}
+
+/// Check an address sent by a buyer can be paid by this node
+pub fn validate_onchain_address(address: &str) -> Result<(), MostroError> {
+    if !Settings::get_mostro().onchain_payouts {
+        return Err(MostroCantDo(CantDoReason::InvalidInvoice));
+    }
+    let network = LN_STATUS
+        .get()
+        .and_then(node_network)
+        .ok_or(MostroInternalErr(ServiceError::LnNodeError(
+            "Unknown node network".to_string(),
+        )))?;
+    parse_address(address, network)?;
+    Ok(())
+}
+
+/// Send `amount` sats minus the estimated fee to `address` for the buyer of
+/// an order, returns the id of the transaction.
+///
+/// Orders paid before get the id of their transaction without sending again,
+/// and none when the send before didn't return it. Errors mean nothing was
+/// sent and the payout can be retried.
+pub async fn pay_onchain(
+    pool: &Pool<Sqlite>,
+    ln_client: &mut dyn LightningBackend,
+    order_id: Uuid,
+    address: &str,
+    amount: i64,
+) -> Result<Option<String>, MostroError> {
+    if let Some(payout) = find_onchain_payout(pool, order_id).await? {
+        info!("Order Id {order_id}: buyer already paid onchain, not sent again");
+        return Ok(payout.txid);
+    }
+    let fee = ln_client.estimate_onchain_fee(address, amount).await?;
+    let payout = amount - fee;
+    if fee < 0 || payout <= 0 {
+        return Err(MostroInternalErr(ServiceError::LnPaymentError(format!(
+            "Onchain fee of {fee} sats leaves nothing to pay out of {amount} sats"
+        ))));
+    }
+    // Another attempt recorded its payout meanwhile
+    if !add_onchain_payout(pool, order_id, address, payout).await? {
+        return Ok(find_onchain_payout(pool, order_id)
+            .await?
+            .and_then(|payout| payout.txid));
+    }
+    match ln_client.send_onchain(address, payout).await {
+        Ok(txid) => {
+            set_onchain_payout_txid(pool, order_id, &txid).await?;
+            info!("Sent {payout} sats onchain to {address} with {fee} sats of fee, txid {txid}");
+            Ok(Some(txid))
+        }
+        // The transaction may be broadcast anyway, so the payout is kept
+        Err(e) => {
+            error!(
+                "Order Id {order_id}: onchain send of {payout} sats to {address} failed: {e}. \
+                 Check the node wallet, the order won't be paid again"
+            );
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::mock::MockBackend;
+
+    const MAINNET_ADDRESS: &str = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+    const TESTNET_ADDRESS: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+
+    #[test]
+    fn test_parse_address() {
+        assert!(parse_address(MAINNET_ADDRESS, Network::Bitcoin).is_ok());
+        assert!(parse_address(TESTNET_ADDRESS, Network::Testnet).is_ok());
+        // Address of another network than the node one
+        assert!(matches!(
+            parse_address(TESTNET_ADDRESS, Network::Bitcoin),
+            Err(MostroCantDo(CantDoReason::InvalidInvoice))
+        ));
+        assert!(matches!(
+            parse_address("bc1qnotanaddress", Network::Bitcoin),
+            Err(MostroCantDo(CantDoReason::InvalidInvoice))
+        ));
+
+        assert!(is_onchain_address(MAINNET_ADDRESS));
+        assert!(!is_onchain_address("lnbcrt500u1p3l8zyapp5"));
+        assert!(!is_onchain_address("mostro@mostro.network"));
+    }
+
+    #[test]
+    fn test_node_network() {
+        let status = |network: &str| LnStatus {
+            version: String::new(),
+            node_pubkey: String::new(),
+            commit_hash: String::new(),
+            node_alias: String::new(),
+            chains: vec!["bitcoin".to_string()],
+            networks: vec![network.to_string()],
+            uris: Vec::new(),
+        };
+        assert_eq!(node_network(&status("mainnet")), Some(Network::Bitcoin));
+        assert_eq!(node_network(&status("regtest")), Some(Network::Regtest));
+        assert_eq!(node_network(&status("litecoin")), None);
+    }
+
+    #[tokio::test]
+    async fn test_pay_onchain_subtracts_fee() {
+        let pool = crate::db::test_pool().await;
+        let mut backend = MockBackend {
+            onchain_fee: 1_500,
+            ..Default::default()
+        };
+        let order_id = Uuid::new_v4();
+        let txid = pay_onchain(&pool, &mut backend, order_id, MAINNET_ADDRESS, 99_400)
+            .await
+            .unwrap();
+        assert!(txid.is_some());
+        assert_eq!(
+            backend.onchain_sends,
+            vec![(MAINNET_ADDRESS.to_string(), 97_900)]
+        );
+        let payout = find_onchain_payout(&pool, order_id).await.unwrap().unwrap();
+        assert_eq!(payout.amount, 97_900);
+        assert_eq!(payout.txid, txid);
+
+        // The fee can't take the whole amount, nothing is sent nor recorded
+        let mut backend = MockBackend {
+            onchain_fee: 5_000,
+            ..Default::default()
+        };
+        let order_id = Uuid::new_v4();
+        assert!(
+            pay_onchain(&pool, &mut backend, order_id, MAINNET_ADDRESS, 5_000)
+                .await
+                .is_err()
+        );
+        assert!(backend.onchain_sends.is_empty());
+        assert!(find_onchain_payout(&pool, order_id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_onchain_payout_sent_once() {
+        let pool = crate::db::test_pool().await;
+        let order_id = Uuid::new_v4();
+        let mut backend = MockBackend::default();
+
+        // Retried after it was paid
+        let txid = pay_onchain(&pool, &mut backend, order_id, MAINNET_ADDRESS, 50_000)
+            .await
+            .unwrap();
+        let again = pay_onchain(&pool, &mut backend, order_id, MAINNET_ADDRESS, 50_000)
+            .await
+            .unwrap();
+        assert_eq!(again, txid);
+        assert_eq!(backend.onchain_sends.len(), 1);
+
+        // The node broadcast the transaction but answered with an error
+        let order_id = Uuid::new_v4();
+        let mut backend = MockBackend {
+            failing_payments: [MAINNET_ADDRESS.to_string()].into(),
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            let txid = pay_onchain(&pool, &mut backend, order_id, MAINNET_ADDRESS, 50_000)
+                .await
+                .unwrap();
+            assert!(txid.is_none());
+        }
+        assert_eq!(backend.onchain_sends.len(), 1);
+    }
+}
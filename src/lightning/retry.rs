@@ -0,0 +1,170 @@
+//! Retries of node calls failing with a connection error.
+//!
+//! A dropped gRPC connection or a node restarting shouldn't abort a cancel or
+//! a release halfway. Calls are retried with exponential backoff while the
+//! error looks like a connection problem, errors from the node rejecting the
+//! call are returned right away. Before each retry the hold invoice state is
+//! checked, a call that reached the node before the connection dropped is not
+//! sent twice.
+
+use crate::config::settings::Settings;
+use crate::lightning::LightningBackend;
+use easy_hasher::easy_hasher::*;
+use fedimint_tonic_lnd::lnrpc::invoice::InvoiceState;
+use mostro_core::prelude::*;
+use nostr_sdk::nostr::hashes::hex::FromHex;
+use std::time::Duration;
+use tracing::warn;
+
+/// Parts of node errors telling the node was not reached
+const TRANSIENT_ERRORS: [&str; 6] = [
+    "unavailable",
+    "deadline",
+    "transport error",
+    "connection",
+    "timed out",
+    "broken pipe",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts of a call, the first one included
+    pub attempts: u32,
+    /// Wait before the first retry, doubled on each retry
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_settings() -> Self {
+        let ln_settings = Settings::get_ln();
+        Self {
+            attempts: ln_settings.call_attempts.max(1),
+            base_delay: Duration::from_millis(ln_settings.call_retry_base_ms),
+        }
+    }
+
+    /// Wait before retrying `attempt` when it failed with a transient error
+    /// and attempts are left, false if the error has to be returned
+    pub async fn backoff(&self, attempt: u32, err: &MostroError) -> bool {
+        if attempt >= self.attempts || !is_transient(err) {
+            return false;
+        }
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1));
+        warn!("Lightning call attempt {attempt} failed: {err}, retrying in {delay:?}");
+        tokio::time::sleep(delay).await;
+        true
+    }
+}
+
+/// Tells if the node was likely not reached, so the call can be sent again
+pub fn is_transient(err: &MostroError) -> bool {
+    match err {
+        MostroInternalErr(ServiceError::LnNodeError(msg)) => {
+            let msg = msg.to_lowercase();
+            TRANSIENT_ERRORS.iter().any(|part| msg.contains(part))
+        }
+        _ => false,
+    }
+}
+
+/// True if the hold invoice already got to `state`
+async fn reached(ln_client: &mut dyn LightningBackend, hash: &str, state: InvoiceState) -> bool {
+    ln_client
+        .hold_invoice_state(hash)
+        .await
+        .is_ok_and(|current| current == state)
+}
+
+/// Cancel a hold invoice, retrying on connection errors
+pub async fn cancel_hold_invoice(
+    ln_client: &mut dyn LightningBackend,
+    hash: &str,
+    policy: &RetryPolicy,
+) -> Result<(), MostroError> {
+    let mut attempt = 1;
+    loop {
+        match ln_client.cancel_hold_invoice(hash).await {
+            Err(e) if policy.backoff(attempt, &e).await => {
+                if reached(ln_client, hash, InvoiceState::Canceled).await {
+                    return Ok(());
+                }
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Settle a hold invoice, retrying on connection errors
+pub async fn settle_hold_invoice(
+    ln_client: &mut dyn LightningBackend,
+    preimage: &str,
+    policy: &RetryPolicy,
+) -> Result<(), MostroError> {
+    let hash = Vec::<u8>::from_hex(preimage)
+        .map(|preimage| raw_sha256(preimage).to_hex_string())
+        .map_err(|e| MostroInternalErr(ServiceError::HoldInvoiceError(e.to_string())))?;
+    let mut attempt = 1;
+    loop {
+        match ln_client.settle_hold_invoice(preimage).await {
+            Err(e) if policy.backoff(attempt, &e).await => {
+                if reached(ln_client, &hash, InvoiceState::Settled).await {
+                    return Ok(());
+                }
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::mock::MockBackend;
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        attempts: 3,
+        base_delay: Duration::from_millis(1),
+    };
+
+    #[tokio::test]
+    async fn test_transient_failures_retried() {
+        // The connection drops twice, then the node answers
+        let hash = "ab".repeat(32);
+        let mut backend = MockBackend {
+            transient_failures: 2,
+            ..Default::default()
+        };
+        cancel_hold_invoice(&mut backend, &hash, &POLICY)
+            .await
+            .unwrap();
+        assert_eq!(backend.canceled, vec![hash.clone()]);
+
+        // Out of attempts
+        let mut backend = MockBackend {
+            transient_failures: 10,
+            ..Default::default()
+        };
+        let result = cancel_hold_invoice(&mut backend, &hash, &POLICY).await;
+        assert!(result.as_ref().is_err_and(is_transient));
+        assert!(backend.canceled.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rejections_not_retried() {
+        let mut backend = MockBackend {
+            fail: true,
+            ..Default::default()
+        };
+        assert!(cancel_hold_invoice(&mut backend, &"ab".repeat(32), &POLICY)
+            .await
+            .is_err());
+        assert!(settle_hold_invoice(&mut backend, &"cd".repeat(32), &POLICY)
+            .await
+            .is_err());
+        assert!(backend.settled.is_empty());
+    }
+}
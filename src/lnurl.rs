@@ -1,3 +1,4 @@
+use crate::lightning::invoice::decode_invoice;
 use lnurl::lnurl::LnUrl;
 use mostro_core::prelude::*;
 use once_cell::sync::Lazy;
@@ -42,7 +43,8 @@ async fn extract_lnurl(address: &str) -> Result<String, MostroError> {
     Ok(url)
 }
 
-pub async fn ln_exists(address: &str) -> Result<(), MostroError> {
+/// Fetch the LUD-06 pay request behind a lightning address or LNURL
+async fn fetch_pay_request(address: &str) -> Result<Value, MostroError> {
     // Get the url from the str - could be a LNURL or a Lightning Address
     let url = extract_lnurl(address).await?;
     // Make the request to the LNURL
@@ -51,74 +53,125 @@ pub async fn ln_exists(address: &str) -> Result<(), MostroError> {
         .send()
         .await
         .map_err(|_| MostroInternalErr(ServiceError::NoAPIResponse))?;
-    let status = res.status();
-    if status.is_success() {
-        let body = res
-            .text()
-            .await
-            .map_err(|_| MostroInternalErr(ServiceError::NoAPIResponse))?;
-        let body: Value = serde_json::from_str(&body)
-            .map_err(|_| MostroInternalErr(ServiceError::MalformedAPIRes))?;
-        let tag = body["tag"].as_str().unwrap_or("");
-        if tag == "payRequest" {
-            return Ok(());
-        }
-        Err(MostroInternalErr(ServiceError::LnAddressParseError))
-    } else {
-        Err(MostroInternalErr(ServiceError::LnAddressParseError))
+    if !res.status().is_success() {
+        return Err(MostroInternalErr(ServiceError::LnAddressParseError));
+    }
+    let body = res
+        .text()
+        .await
+        .map_err(|_| MostroInternalErr(ServiceError::NoAPIResponse))?;
+    serde_json::from_str(&body).map_err(|_| MostroInternalErr(ServiceError::MalformedAPIRes))
+}
+
+/// Callback url of a pay request asking for an invoice of `amount_msat`,
+/// the amount must be inside the sendable range of the pay request
+fn pay_request_callback(pay_request: &Value, amount_msat: u64) -> Result<String, MostroError> {
+    if pay_request["tag"].as_str() != Some("payRequest") {
+        return Err(MostroInternalErr(ServiceError::LnAddressParseError));
+    }
+    let min = pay_request["minSendable"].as_u64().unwrap_or(0);
+    let max = pay_request["maxSendable"].as_u64().unwrap_or(0);
+    if amount_msat < min || amount_msat > max {
+        return Err(MostroCantDo(CantDoReason::InvalidAmount));
+    }
+    let Some(callback) = pay_request["callback"].as_str() else {
+        return Err(MostroInternalErr(ServiceError::MalformedAPIRes));
+    };
+    // The callback may already carry query parameters
+    let separator = if callback.contains('?') { '&' } else { '?' };
+    Ok(format!("{callback}{separator}amount={amount_msat}"))
+}
+
+/// Checks the invoice returned by a pay request callback is for `amount_msat`
+fn check_callback_invoice(payment_request: &str, amount_msat: u64) -> Result<(), MostroError> {
+    let invoice = decode_invoice(payment_request)?;
+    if invoice.amount_milli_satoshis() != Some(amount_msat) {
+        return Err(MostroCantDo(CantDoReason::InvalidAmount));
     }
+    Ok(())
 }
 
+/// Checks a lightning address or LNURL can be paid, and that it accepts
+/// `amount` sats when it is known
+pub async fn ln_exists(address: &str, amount: Option<u64>) -> Result<(), MostroError> {
+    let pay_request = fetch_pay_request(address).await?;
+    match amount {
+        Some(amount) if amount > 0 => pay_request_callback(&pay_request, amount * 1000).map(|_| ()),
+        _ if pay_request["tag"].as_str() == Some("payRequest") => Ok(()),
+        _ => Err(MostroInternalErr(ServiceError::LnAddressParseError)),
+    }
+}
+
+/// Get an invoice of `amount` sats from a lightning address or LNURL. The
+/// invoice is requested when the payment is due since invoices expire.
 pub async fn resolv_ln_address(address: &str, amount: u64) -> Result<String, MostroError> {
-    // Get the url from the str - could be a LNURL or a Lightning Address
-    let url = extract_lnurl(address).await?;
+    let pay_request = fetch_pay_request(address).await?;
     // Convert the amount to msat
     let amount_msat = amount * 1000;
+    let callback = pay_request_callback(&pay_request, amount_msat)?;
 
-    // Make the request to the LNURL
     let res = HTTP_CLIENT
-        .get(url)
+        .get(callback)
         .send()
         .await
-        .map_err(|_| MostroInternalErr(ServiceError::MalformedAPIRes))?;
-    let status = res.status();
-    if status.is_success() {
-        let body = res
-            .text()
-            .await
-            .map_err(|_| MostroInternalErr(ServiceError::MessageSerializationError))?;
-        let body: Value = serde_json::from_str(&body)
-            .map_err(|_| MostroInternalErr(ServiceError::MessageSerializationError))?;
-        let tag = body["tag"].as_str().unwrap_or("");
-        if tag != "payRequest" {
-            return Ok("".to_string());
-        }
-        let min = body["minSendable"].as_u64().unwrap_or(0);
-        let max = body["maxSendable"].as_u64().unwrap_or(0);
-        if min > amount_msat || max < amount_msat {
-            return Ok("".to_string());
-        }
-        let callback = body["callback"].as_str().unwrap_or("");
-        let callback = format!("{callback}?amount={amount_msat}");
-        let res = HTTP_CLIENT
-            .get(callback)
-            .send()
-            .await
-            .map_err(|_| MostroInternalErr(ServiceError::MalformedAPIRes))?;
-        let status = res.status();
-        if status.is_success() {
-            let body = res
-                .text()
-                .await
-                .map_err(|_| MostroInternalErr(ServiceError::MessageSerializationError))?;
-            let body: Value = serde_json::from_str(&body)
-                .map_err(|_| MostroInternalErr(ServiceError::MessageSerializationError))?;
-            let pr = body["pr"].as_str().unwrap_or("");
+        .map_err(|_| MostroInternalErr(ServiceError::NoAPIResponse))?;
+    if !res.status().is_success() {
+        return Err(MostroInternalErr(ServiceError::LnAddressParseError));
+    }
+    let body = res
+        .text()
+        .await
+        .map_err(|_| MostroInternalErr(ServiceError::MessageSerializationError))?;
+    let body: Value = serde_json::from_str(&body)
+        .map_err(|_| MostroInternalErr(ServiceError::MessageSerializationError))?;
+    let Some(payment_request) = body["pr"].as_str() else {
+        return Err(MostroInternalErr(ServiceError::MalformedAPIRes));
+    };
+    check_callback_invoice(payment_request, amount_msat)?;
+
+    Ok(payment_request.to_string())
+}
 
-            return Ok(pr.to_string());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn pay_request(min_sendable: u64, max_sendable: u64) -> Value {
+        json!({
+            "tag": "payRequest",
+            "callback": "https://example.com/lnurlp/callback",
+            "minSendable": min_sendable,
+            "maxSendable": max_sendable,
+            "metadata": "[[\"text/plain\",\"Test payment\"]]",
+        })
+    }
+
+    #[test]
+    fn test_callback_for_amount_in_range() {
+        let callback = pay_request_callback(&pay_request(1000, 10_000_000), 5_000_000).unwrap();
+        assert_eq!(
+            callback,
+            "https://example.com/lnurlp/callback?amount=5000000"
+        );
+    }
+
+    #[test]
+    fn test_amount_out_of_sendable_range() {
+        let pay_request = pay_request(1_000_000, 10_000_000);
+        for amount_msat in [999_000, 20_000_000] {
+            assert!(matches!(
+                pay_request_callback(&pay_request, amount_msat),
+                Err(MostroCantDo(CantDoReason::InvalidAmount))
+            ));
         }
-        Ok("".to_string())
-    } else {
-        Ok("".to_string())
+    }
+
+    #[test]
+    fn test_callback_invoice_amount_checked() {
+        // 50_000 sats
+        let invoice = "lnbcrt500u1p3lzwdzpp5t9kgwgwd07y2lrwdscdnkqu4scrcgpm5pt9uwx0rxn5rxawlxlvqdqqcqzpgxqyz5vqsp5a6k7syfxeg8jy63rteywwjla5rrg2pvhedx8ajr2ltm4seydhsqq9qyyssq0n2uwlumsx4d0mtjm8tp7jw3y4da6p6z9gyyjac0d9xugf72lhh4snxpugek6n83geafue9ndgrhuhzk98xcecu2t3z56ut35mkammsqscqp0n";
+        assert!(check_callback_invoice(invoice, 50_000_000).is_ok());
+        assert!(check_callback_invoice(invoice, 40_000_000).is_err());
     }
 }
@@ -0,0 +1,105 @@
+//! Log output.
+//!
+//! Logs are written as human readable lines by default. With `log_format =
+//! 'json'` each line is a JSON object, so log collectors can index them. The
+//! handling of each message runs in a span with the action, the order id and
+//! the sender pubkey, JSON lines carry those fields.
+
+use mostro_core::prelude::*;
+use nostr_sdk::prelude::PublicKey;
+use serde::{Deserialize, Serialize};
+use tracing::field::{display, Empty};
+use tracing::Span;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter, Layer, Registry};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+/// JSON lines with the fields of the current span
+fn json_layer<W>(writer: W) -> impl Layer<Registry>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    fmt::layer()
+        .json()
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_writer(writer)
+}
+
+/// Install the subscriber writing logs filtered by `RUST_LOG`
+pub fn init_tracing(format: LogFormat) {
+    let layer = match format {
+        LogFormat::Pretty => fmt::layer().boxed(),
+        LogFormat::Json => json_layer(std::io::stdout).boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(EnvFilter::from_default_env())
+        .init();
+}
+
+/// Span of the handling of a message
+pub fn message_span(action: &Action, order_id: Option<Uuid>, pubkey: &PublicKey) -> Span {
+    let span = tracing::info_span!(
+        "message",
+        action = %action,
+        order_id = Empty,
+        pubkey = %pubkey
+    );
+    if let Some(order_id) = order_id {
+        span.record("order_id", display(order_id));
+    }
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::Keys;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_lines_carry_message_fields() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::registry().with(json_layer(move || writer.clone()));
+        let order_id = Uuid::new_v4();
+        let pubkey = Keys::generate().public_key();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = message_span(&Action::TakeSell, Some(order_id), &pubkey);
+            let _entered = span.enter();
+            tracing::info!("Order taken");
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(line["fields"]["message"], "Order taken");
+        assert_eq!(line["span"]["action"], Action::TakeSell.to_string());
+        assert_eq!(line["span"]["order_id"], order_id.to_string());
+        assert_eq!(line["span"]["pubkey"], pubkey.to_string());
+    }
+}
@@ -1,31 +1,44 @@
+pub mod allowlist;
+pub mod analytics;
 pub mod app;
 mod bitcoin_price;
 pub mod cli;
 pub mod config;
 pub mod db;
+pub mod drain;
 pub mod flow;
+pub mod kill_switch;
 pub mod lightning;
 pub mod lnurl;
+pub mod logging;
 pub mod messages;
+pub mod metrics;
 pub mod models;
 pub mod nip33;
+pub mod order_locks;
+pub mod rate_limiter;
+pub mod reconciliation;
+pub mod relay_monitor;
+pub mod relay_publish;
 pub mod rpc;
 pub mod scheduler;
+pub mod seen_events;
 pub mod util;
+pub mod worker_pool;
 
+use crate::app::release::resume_range_grids;
 use crate::app::run;
 use crate::cli::settings_init;
+use crate::config::settings::Settings;
 use crate::config::{get_db_pool, DB_POOL, LN_STATUS, NOSTR_CLIENT};
 use crate::db::find_held_invoices;
-use crate::lightning::LnStatus;
-use crate::lightning::LndConnector;
+use crate::lightning::connect_backend;
 use crate::rpc::RpcServer;
 use nostr_sdk::prelude::*;
-use scheduler::start_scheduler;
+use scheduler::{drain_messages_queue, job_cancel_stale_pending_orders, start_scheduler};
 use std::env;
 use std::process::exit;
 use std::sync::Arc;
-use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 use util::{get_nostr_client, invoice_subscribe};
 
 #[tokio::main]
@@ -41,15 +54,23 @@ async fn main() -> Result<()> {
         env::set_var("RUST_LOG", "none,mostro=info");
     }
 
-    // Tracing using RUST_LOG
-    tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(EnvFilter::from_default_env())
-        .init();
-
     // Init MOSTRO_SETTINGS oncelock with all settings variables from TOML file
     settings_init()?;
 
+    // Tracing using RUST_LOG, in the format set in the settings
+    logging::init_tracing(Settings::get_mostro().log_format);
+
+    // Maintenance commands run against the database and exit
+    if let Some(command) = cli::command() {
+        cli::run_command(command).await?;
+        return Ok(());
+    }
+
+    if let Err(e) = util::operator_tag(&Settings::get_mostro().operator_name) {
+        tracing::error!("Invalid operator name: {e}");
+        exit(1);
+    }
+
     // Connect to database
     if DB_POOL.set(db::connect().await?).is_err() {
         tracing::error!("No connection to database - closing Mostro!");
@@ -65,10 +86,7 @@ async fn main() -> Result<()> {
     // Get mostro keys
     let mostro_keys = util::get_keys()?;
 
-    let subscription = Filter::new()
-        .pubkey(mostro_keys.public_key())
-        .kind(Kind::GiftWrap)
-        .limit(0);
+    let subscription = relay_monitor::gift_wrap_filter(mostro_keys.public_key());
 
     let client = match get_nostr_client() {
         Ok(client) => client,
@@ -80,13 +98,21 @@ async fn main() -> Result<()> {
     };
 
     // Client subscription
-    client.subscribe(subscription, None).await?;
+    client
+        .subscribe_with_id(
+            SubscriptionId::new(relay_monitor::GIFT_WRAP_SUBSCRIPTION),
+            subscription,
+            None,
+        )
+        .await?;
 
-    let mut ln_client = LndConnector::new().await?;
-    let ln_status = ln_client.get_node_info().await?;
-    let ln_status = LnStatus::from_get_info_response(ln_status);
+    if Settings::get_ln().dry_run {
+        lightning::dry_run::set_dry_run(true);
+    }
+    let mut ln_client = connect_backend().await?;
+    let ln_status = ln_client.get_node_status().await?;
     if LN_STATUS.set(ln_status).is_err() {
-        panic!("No connection to LND node - shutting down Mostro!");
+        panic!("No connection to lightning node - shutting down Mostro!");
     };
 
     if let Ok(held_invoices) = find_held_invoices(get_db_pool().as_ref()).await {
@@ -100,12 +126,27 @@ async fn main() -> Result<()> {
         }
     }
 
+    // Private instances only serve the pubkeys of their allowlist
+    allowlist::set_allowlist(&Settings::get_mostro().allowlist);
+    #[cfg(unix)]
+    tokio::spawn(config::reload::reload_on_hangup());
+
+    // Fund movements stay halted across restarts while the setting is on
+    if Settings::get_mostro().funds_kill_switch {
+        kill_switch::set_kill_switch(true);
+    }
+
+    // Partially taken range orders go back to the book with their remaining range
+    if Settings::get_mostro().resume_range_grids {
+        resume_range_grids(get_db_pool().as_ref(), &mostro_keys).await;
+    }
+
     // Start RPC server if enabled
     if RpcServer::is_enabled() {
         let rpc_server = RpcServer::new();
         let rpc_keys = mostro_keys.clone();
         let rpc_pool = get_db_pool();
-        let rpc_ln_client = Arc::new(tokio::sync::Mutex::new(ln_client.clone()));
+        let rpc_ln_client = Arc::new(tokio::sync::Mutex::new(connect_backend().await?));
 
         tokio::spawn(async move {
             match rpc_server.start(rpc_keys, rpc_pool, rpc_ln_client).await {
@@ -118,8 +159,62 @@ async fn main() -> Result<()> {
     // Start scheduler for tasks
     start_scheduler().await;
 
+    // Background tasks running alongside the main loop stop on this signal
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    tokio::spawn(job_cancel_stale_pending_orders(shutdown_rx.clone()));
+    let shutdown_tx = Arc::new(shutdown_tx);
+    let signal_tx = shutdown_tx.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!("Shutdown signal received, stopping Mostro");
+        let _ = signal_tx.send(true);
+    });
+
     // Run the Mostro and be happy!!
-    run(mostro_keys, client, &mut ln_client).await
+    // Each message worker has its own connector
+    let ln_client = Arc::new(
+        lightning::BackendPool::connect(ln_client, Settings::get_mostro().message_workers).await?,
+    );
+    let result = run(mostro_keys, client, ln_client.clone(), shutdown_rx).await;
+    let _ = shutdown_tx.send(true);
+
+    // Let the answers to the last messages go out before closing connections
+    drain_messages_queue(std::time::Duration::from_secs(10)).await;
+    client.disconnect().await;
+    drop(ln_client);
+    get_db_pool().close().await;
+    tracing::info!("Mostro stopped");
+    result
+}
+
+/// Resolves on Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Error listening for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Error listening for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
 
 #[cfg(test)]
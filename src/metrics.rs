@@ -0,0 +1,714 @@
+//! Order lifecycle and message handling metrics.
+//!
+//! Every order status change and the time spent handling each message are
+//! counted here. When `metrics_exporter` is set they are pushed at an interval
+//! to a StatsD daemon over UDP or to an OpenTelemetry collector over OTLP/HTTP.
+//! Built with the `metrics` feature they can also be scraped by Prometheus on
+//! `metrics_listen_address`, along with a `/health` check of the database, the
+//! lightning node and the relays.
+
+use crate::config::settings::Settings;
+#[cfg(any(feature = "metrics", test))]
+use crate::lightning::LightningBackend;
+use crate::lnurl::HTTP_CLIENT;
+use mostro_core::prelude::*;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+#[cfg(any(feature = "metrics", test))]
+use sqlx::{Pool, Sqlite};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tracing::{error, info};
+
+/// Prefix of every metric name
+const METRIC_PREFIX: &str = "mostro";
+
+/// Seconds the lightning node has to answer the health check
+#[cfg(any(feature = "metrics", test))]
+const HEALTH_LN_TIMEOUT_SECS: u64 = 5;
+/// Seconds a health report is answered before the services are checked again,
+/// probes polling often don't reach the node each time
+#[cfg(any(feature = "metrics", test))]
+const HEALTH_CACHE_SECS: u64 = 10;
+
+/// Counters since the node started
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Orders moved to each status
+    pub status_changes: BTreeMap<String, u64>,
+    /// Messages dispatched by action
+    pub actions: BTreeMap<String, u64>,
+    /// Messages handled by the event loop
+    pub messages_handled: u64,
+    /// Total time spent handling those messages
+    pub handling_ms: u64,
+}
+
+static METRICS: Lazy<Mutex<MetricsSnapshot>> = Lazy::new(|| Mutex::new(MetricsSnapshot::default()));
+
+/// Count an order moving to `status`
+pub fn record_status_change(status: Status) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        *metrics
+            .status_changes
+            .entry(status.to_string())
+            .or_default() += 1;
+    }
+}
+
+/// Count a message dispatched to the `action` handler
+pub fn record_action(action: &Action) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        *metrics.actions.entry(action.to_string()).or_default() += 1;
+    }
+}
+
+/// Count a message handled in `elapsed`
+pub fn record_message_handled(elapsed: Duration) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        metrics.messages_handled += 1;
+        metrics.handling_ms += elapsed.as_millis() as u64;
+    }
+}
+
+pub fn snapshot() -> MetricsSnapshot {
+    METRICS.lock().map(|m| m.clone()).unwrap_or_default()
+}
+
+/// StatsD lines with what changed since the `previous` push, counters are
+/// sent as deltas and the handling latency as the average of the new messages
+fn statsd_payload(current: &MetricsSnapshot, previous: &MetricsSnapshot) -> String {
+    let mut lines = Vec::new();
+    for (status, count) in &current.status_changes {
+        let delta = count
+            - previous
+                .status_changes
+                .get(status)
+                .copied()
+                .unwrap_or_default();
+        if delta > 0 {
+            lines.push(format!("{METRIC_PREFIX}.order_status.{status}:{delta}|c"));
+        }
+    }
+    let messages = current.messages_handled - previous.messages_handled;
+    if let Some(latency) = (current.handling_ms - previous.handling_ms).checked_div(messages) {
+        lines.push(format!("{METRIC_PREFIX}.messages_handled:{messages}|c"));
+        lines.push(format!("{METRIC_PREFIX}.message_latency_ms:{latency}|ms"));
+    }
+    lines.join("\n")
+}
+
+/// OTLP/HTTP json request with the cumulative counters
+fn otlp_payload(current: &MetricsSnapshot, time_unix_nano: u128) -> Value {
+    let time = time_unix_nano.to_string();
+    let sum = |name: &str, data_points: Vec<Value>| {
+        json!({
+            "name": format!("{METRIC_PREFIX}.{name}"),
+            "sum": {
+                "aggregationTemporality": 2,
+                "isMonotonic": true,
+                "dataPoints": data_points,
+            }
+        })
+    };
+    let point = |value: u64| json!({ "asInt": value.to_string(), "timeUnixNano": time });
+    let status_points = current
+        .status_changes
+        .iter()
+        .map(|(status, count)| {
+            let mut point = point(*count);
+            point["attributes"] = json!([{ "key": "status", "value": { "stringValue": status } }]);
+            point
+        })
+        .collect();
+
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": METRIC_PREFIX } }]
+            },
+            "scopeMetrics": [{
+                "scope": { "name": METRIC_PREFIX },
+                "metrics": [
+                    sum("order_status_changes", status_points),
+                    sum("messages_handled", vec![point(current.messages_handled)]),
+                    sum("message_handling_ms", vec![point(current.handling_ms)]),
+                ]
+            }]
+        }]
+    })
+}
+
+/// Prometheus text exposition of the counters and of the `orders` by status
+#[cfg(any(feature = "metrics", test))]
+fn prometheus_payload(
+    current: &MetricsSnapshot,
+    orders: &[(String, i64)],
+    kill_switch: bool,
+) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        out.push_str(&format!(
+            "# HELP {METRIC_PREFIX}_{name} {help}\n# TYPE {METRIC_PREFIX}_{name} {kind}\n"
+        ));
+        for (labels, value) in samples {
+            out.push_str(&format!("{METRIC_PREFIX}_{name}{labels} {value}\n"));
+        }
+    };
+    let status_count = |statuses: &[Status]| -> u64 {
+        statuses
+            .iter()
+            .filter_map(|status| current.status_changes.get(&status.to_string()))
+            .sum()
+    };
+
+    metric(
+        "kill_switch",
+        "gauge",
+        "Fund movements halted by the kill switch",
+        vec![(String::new(), (kill_switch as u8).to_string())],
+    );
+    metric(
+        "orders",
+        "gauge",
+        "Orders in each status",
+        orders
+            .iter()
+            .map(|(status, count)| (format!("{{status=\"{status}\"}}"), count.to_string()))
+            .collect(),
+    );
+    metric(
+        "order_status_changes_total",
+        "counter",
+        "Orders moved to each status",
+        current
+            .status_changes
+            .iter()
+            .map(|(status, count)| (format!("{{status=\"{status}\"}}"), count.to_string()))
+            .collect(),
+    );
+    metric(
+        "actions_total",
+        "counter",
+        "Messages dispatched by action",
+        current
+            .actions
+            .iter()
+            .map(|(action, count)| (format!("{{action=\"{action}\"}}"), count.to_string()))
+            .collect(),
+    );
+    metric(
+        "cancels_total",
+        "counter",
+        "Orders canceled",
+        vec![(
+            String::new(),
+            status_count(&[
+                Status::Canceled,
+                Status::CooperativelyCanceled,
+                Status::CanceledByAdmin,
+            ])
+            .to_string(),
+        )],
+    );
+    metric(
+        "disputes_opened_total",
+        "counter",
+        "Disputes opened",
+        vec![(String::new(), status_count(&[Status::Dispute]).to_string())],
+    );
+    metric(
+        "disputes_settled_total",
+        "counter",
+        "Disputes closed by a solver",
+        vec![(
+            String::new(),
+            status_count(&[
+                Status::SettledByAdmin,
+                Status::CompletedByAdmin,
+                Status::CanceledByAdmin,
+            ])
+            .to_string(),
+        )],
+    );
+    metric(
+        "messages_handled_total",
+        "counter",
+        "Messages handled by the event loop",
+        vec![(String::new(), current.messages_handled.to_string())],
+    );
+    metric(
+        "message_handling_ms_total",
+        "counter",
+        "Time spent handling messages in milliseconds",
+        vec![(String::new(), current.handling_ms.to_string())],
+    );
+    out
+}
+
+/// State of the services Mostro depends on
+#[cfg(any(feature = "metrics", test))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthReport {
+    /// The database answered a trivial query
+    pub database: bool,
+    /// The lightning node answered a getinfo
+    pub lightning: bool,
+    /// Relays the Nostr client is connected to
+    pub relays_connected: usize,
+    pub kill_switch: bool,
+}
+
+#[cfg(any(feature = "metrics", test))]
+impl HealthReport {
+    /// Degraded when the database or the node is unreachable or no relay is
+    /// connected. An engaged kill switch is on purpose and still healthy
+    pub fn is_healthy(&self) -> bool {
+        self.database && self.lightning && self.relays_connected > 0
+    }
+
+    pub fn to_json(&self) -> Value {
+        let status = if !self.is_healthy() {
+            "degraded"
+        } else if self.kill_switch {
+            "funds_halted"
+        } else {
+            "ok"
+        };
+        json!({
+            "status": status,
+            "database": self.database,
+            "lightning": self.lightning,
+            "relays_connected": self.relays_connected,
+            "kill_switch": self.kill_switch,
+        })
+    }
+}
+
+/// Check the database and the lightning node
+#[cfg(any(feature = "metrics", test))]
+pub async fn check_health(
+    pool: &Pool<Sqlite>,
+    ln_client: &mut dyn LightningBackend,
+    relays_connected: usize,
+) -> HealthReport {
+    let database = sqlx::query("SELECT 1").execute(pool).await.is_ok();
+    let lightning = tokio::time::timeout(
+        Duration::from_secs(HEALTH_LN_TIMEOUT_SECS),
+        ln_client.get_node_status(),
+    )
+    .await
+    .is_ok_and(|status| status.is_ok());
+
+    HealthReport {
+        database,
+        lightning,
+        relays_connected,
+        kill_switch: crate::kill_switch::is_kill_switch_engaged(),
+    }
+}
+
+/// Health checks of the `/health` route. The connection to the lightning node
+/// is kept between checks and made again only after it failed, and the last
+/// report is answered for `HEALTH_CACHE_SECS`.
+#[cfg(any(feature = "metrics", test))]
+#[derive(Default)]
+struct HealthState {
+    ln_client: Option<Box<dyn LightningBackend>>,
+    /// Last report and when it was made
+    last: Option<(std::time::Instant, HealthReport)>,
+}
+
+#[cfg(any(feature = "metrics", test))]
+impl HealthState {
+    /// Report of the services, `connect` opens a connection to the node when
+    /// there is none
+    async fn report<F, Fut>(
+        &mut self,
+        pool: &Pool<Sqlite>,
+        relays_connected: usize,
+        now: std::time::Instant,
+        connect: F,
+    ) -> HealthReport
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<Box<dyn LightningBackend>, MostroError>>,
+    {
+        if let Some((checked_at, report)) = &self.last {
+            if now.duration_since(*checked_at) < Duration::from_secs(HEALTH_CACHE_SECS) {
+                return report.clone();
+            }
+        }
+        if self.ln_client.is_none() {
+            match connect().await {
+                Ok(ln_client) => self.ln_client = Some(ln_client),
+                Err(e) => error!("Health check can't connect to the lightning node: {e}"),
+            }
+        }
+        let report = match self.ln_client.as_mut() {
+            Some(ln_client) => check_health(pool, ln_client.as_mut(), relays_connected).await,
+            None => HealthReport {
+                database: sqlx::query("SELECT 1").execute(pool).await.is_ok(),
+                lightning: false,
+                relays_connected,
+                kill_switch: crate::kill_switch::is_kill_switch_engaged(),
+            },
+        };
+        // A connection that stopped answering is opened again next time
+        if !report.lightning {
+            self.ln_client = None;
+        }
+        self.last = Some((now, report.clone()));
+        report
+    }
+}
+
+/// `/health` answer, 503 when degraded
+#[cfg(any(feature = "metrics", test))]
+fn health_response(report: HealthReport) -> (axum::http::StatusCode, axum::Json<Value>) {
+    use axum::http::StatusCode;
+
+    if !report.is_healthy() {
+        error!("Health check degraded: {}", report.to_json());
+    }
+    let code = if report.is_healthy() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, axum::Json(report.to_json()))
+}
+
+/// Health checks shared by the metrics and health endpoints
+#[cfg(feature = "metrics")]
+static HEALTH_STATE: Lazy<tokio::sync::Mutex<HealthState>> = Lazy::new(Default::default);
+
+/// Router with the `/health` check of the running node
+#[cfg(feature = "metrics")]
+fn health_router() -> axum::Router {
+    use axum::{routing::get, Router};
+
+    Router::new().route(
+        "/health",
+        get(|| async {
+            let pool = crate::config::settings::get_db_pool();
+            let relays_connected = crate::util::get_nostr_relays()
+                .await
+                .map(|relays| relays.values().filter(|r| r.is_connected()).count())
+                .unwrap_or_default();
+            let report = HEALTH_STATE
+                .lock()
+                .await
+                .report(
+                    &pool,
+                    relays_connected,
+                    std::time::Instant::now(),
+                    crate::lightning::connect_backend,
+                )
+                .await;
+            health_response(report)
+        }),
+    )
+}
+
+async fn send_statsd(endpoint: &str, payload: &str) -> Result<(), MostroError> {
+    if payload.is_empty() {
+        return Ok(());
+    }
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::IOError(e.to_string())))?;
+    socket
+        .send_to(payload.as_bytes(), endpoint)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::IOError(e.to_string())))?;
+    Ok(())
+}
+
+async fn send_otlp(endpoint: &str, payload: &Value) -> Result<(), MostroError> {
+    HTTP_CLIENT
+        .post(endpoint)
+        .json(payload)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| MostroInternalErr(ServiceError::IOError(e.to_string())))?;
+    Ok(())
+}
+
+/// Push the metrics to the configured collector at the configured interval
+pub async fn job_push_metrics() {
+    let mostro_settings = Settings::get_mostro();
+    let exporter = mostro_settings.metrics_exporter.clone();
+    let endpoint = mostro_settings.metrics_endpoint.clone();
+    let interval = mostro_settings.metrics_push_interval_seconds.max(1) as u64;
+    if !matches!(exporter.as_str(), "statsd" | "otlp") {
+        if !exporter.is_empty() {
+            error!("Unsupported metrics exporter {exporter}, metrics are not pushed");
+        }
+        return;
+    }
+    info!("Pushing metrics to {exporter} collector at {endpoint}");
+
+    tokio::spawn(async move {
+        let mut previous = MetricsSnapshot::default();
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            let current = snapshot();
+            let result = if exporter == "statsd" {
+                send_statsd(&endpoint, &statsd_payload(&current, &previous)).await
+            } else {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos();
+                send_otlp(&endpoint, &otlp_payload(&current, now)).await
+            };
+            match result {
+                Ok(()) => previous = current,
+                Err(e) => error!("Error pushing metrics: {e}"),
+            }
+        }
+    });
+}
+
+/// Serve the metrics for Prometheus on `metrics_listen_address`, along with
+/// the `/health` check unless it has its own `health_listen_address`
+#[cfg(feature = "metrics")]
+pub async fn job_serve_metrics() {
+    use axum::{http::StatusCode, routing::get, Router};
+
+    let mostro_settings = Settings::get_mostro();
+    let address = mostro_settings.metrics_listen_address.clone();
+    if address.is_empty() {
+        return;
+    }
+    let listener = match tokio::net::TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => return error!("Error binding metrics endpoint to {address}: {e}"),
+    };
+    info!("Serving metrics on http://{address}/metrics");
+
+    let mut app = Router::new().route(
+        "/metrics",
+        get(|| async {
+            let pool = crate::config::settings::get_db_pool();
+            match crate::db::count_orders_by_status(&pool).await {
+                Ok(orders) => (
+                    StatusCode::OK,
+                    prometheus_payload(
+                        &snapshot(),
+                        &orders,
+                        crate::kill_switch::is_kill_switch_engaged(),
+                    ),
+                ),
+                Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            }
+        }),
+    );
+    if mostro_settings.health_listen_address.is_empty() {
+        app = app.merge(health_router());
+    }
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Metrics endpoint stopped: {e}");
+        }
+    });
+}
+
+/// Serve the `/health` check on `health_listen_address`
+#[cfg(feature = "metrics")]
+pub async fn job_serve_health() {
+    let address = Settings::get_mostro().health_listen_address.clone();
+    if address.is_empty() {
+        return;
+    }
+    let listener = match tokio::net::TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => return error!("Error binding health endpoint to {address}: {e}"),
+    };
+    info!("Serving health check on http://{address}/health");
+
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, health_router()).await {
+            error!("Health endpoint stopped: {e}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_snapshot(active: u64, messages_handled: u64, handling_ms: u64) -> MetricsSnapshot {
+        MetricsSnapshot {
+            status_changes: BTreeMap::from([
+                ("active".to_string(), active),
+                ("success".to_string(), 1),
+            ]),
+            actions: BTreeMap::new(),
+            messages_handled,
+            handling_ms,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_statsd_push() {
+        // Mock StatsD daemon
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = sink.local_addr().unwrap().to_string();
+
+        let previous = test_snapshot(1, 10, 100);
+        let current = test_snapshot(3, 14, 180);
+        send_statsd(&endpoint, &statsd_payload(&current, &previous))
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 1024];
+        let (len, _) = sink.recv_from(&mut buf).await.unwrap();
+        let received = String::from_utf8_lossy(&buf[..len]);
+        // Unchanged counters are not sent
+        assert_eq!(
+            received,
+            "mostro.order_status.active:2|c\nmostro.messages_handled:4|c\nmostro.message_latency_ms:20|ms"
+        );
+    }
+
+    #[test]
+    fn test_otlp_payload() {
+        let payload = otlp_payload(&test_snapshot(3, 14, 180), 1_700_000_000_000_000_000);
+        let metrics = &payload["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[0]["name"], "mostro.order_status_changes");
+        let point = &metrics[0]["sum"]["dataPoints"][0];
+        assert_eq!(point["asInt"], "3");
+        assert_eq!(point["attributes"][0]["value"]["stringValue"], "active");
+        assert_eq!(metrics[1]["sum"]["dataPoints"][0]["asInt"], "14");
+    }
+
+    #[test]
+    fn test_prometheus_scrape_after_actions() {
+        let before = snapshot();
+        record_action(&Action::NewOrder);
+        record_action(&Action::NewOrder);
+        record_action(&Action::Cancel);
+        record_status_change(Status::Canceled);
+        let after = snapshot();
+
+        let count = |snapshot: &MetricsSnapshot, action: &Action| {
+            snapshot
+                .actions
+                .get(&action.to_string())
+                .copied()
+                .unwrap_or_default()
+        };
+        assert_eq!(
+            count(&after, &Action::NewOrder) - count(&before, &Action::NewOrder),
+            2
+        );
+        assert_eq!(
+            count(&after, &Action::Cancel) - count(&before, &Action::Cancel),
+            1
+        );
+
+        let orders = vec![("pending".to_string(), 4), ("dispute".to_string(), 1)];
+        let scrape = prometheus_payload(&after, &orders, true);
+        assert!(scrape.contains("mostro_kill_switch 1\n"));
+        assert!(scrape.contains("# TYPE mostro_orders gauge\n"));
+        assert!(scrape.contains("mostro_orders{status=\"pending\"} 4\n"));
+        assert!(scrape.contains(&format!(
+            "mostro_actions_total{{action=\"{}\"}} {}\n",
+            Action::NewOrder,
+            count(&after, &Action::NewOrder)
+        )));
+        let cancels = scrape
+            .lines()
+            .find_map(|line| line.strip_prefix("mostro_cancels_total "))
+            .unwrap();
+        assert!(cancels.parse::<u64>().unwrap() >= 1);
+    }
+
+    #[tokio::test]
+    async fn test_health_degraded_returns_503() {
+        use crate::lightning::mock::MockBackend;
+        use axum::http::StatusCode;
+
+        let pool = crate::db::test_pool().await;
+
+        let mut node = MockBackend::default();
+        let (code, body) = health_response(check_health(&pool, &mut node, 2).await);
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["database"], true);
+        assert_eq!(body["lightning"], true);
+        assert_eq!(body["relays_connected"], 2);
+
+        // Node down and the database gone
+        let mut node = MockBackend {
+            fail: true,
+            ..Default::default()
+        };
+        pool.close().await;
+        let (code, body) = health_response(check_health(&pool, &mut node, 2).await);
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "degraded");
+        assert_eq!(body["database"], false);
+        assert_eq!(body["lightning"], false);
+    }
+
+    #[tokio::test]
+    async fn test_health_reuses_connection_and_report() {
+        use crate::lightning::mock::MockBackend;
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::time::Instant;
+
+        let pool = crate::db::test_pool().await;
+        let connections = AtomicU32::new(0);
+        let connect = |fail: bool| {
+            connections.fetch_add(1, Ordering::Relaxed);
+            async move {
+                let node: Box<dyn LightningBackend> = Box::new(MockBackend {
+                    fail,
+                    ..Default::default()
+                });
+                Ok(node)
+            }
+        };
+        let mut health = HealthState::default();
+        let start = Instant::now();
+
+        let report = health.report(&pool, 2, start, || connect(false)).await;
+        assert!(report.is_healthy());
+        // Probes inside the cache window get the same report
+        let later = start + Duration::from_secs(1);
+        let cached = health.report(&pool, 0, later, || connect(false)).await;
+        assert_eq!(cached, report);
+        // After it the services are checked again with the same connection
+        let later = start + Duration::from_secs(HEALTH_CACHE_SECS);
+        let report = health.report(&pool, 0, later, || connect(false)).await;
+        assert_eq!(report.relays_connected, 0);
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+
+        // A node that stopped answering is connected again on the next check
+        health.ln_client = Some(Box::new(MockBackend {
+            fail: true,
+            ..Default::default()
+        }));
+        let later = start + Duration::from_secs(2 * HEALTH_CACHE_SECS);
+        assert!(
+            !health
+                .report(&pool, 2, later, || connect(false))
+                .await
+                .lightning
+        );
+        assert!(health.ln_client.is_none());
+        let later = start + Duration::from_secs(3 * HEALTH_CACHE_SECS);
+        assert!(
+            health
+                .report(&pool, 2, later, || connect(false))
+                .await
+                .lightning
+        );
+        assert_eq!(connections.load(Ordering::Relaxed), 2);
+    }
+}
@@ -1,4 +1,8 @@
+use mostro_core::error::CantDoReason;
+use mostro_core::order::{Order, Status};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Yadio {
@@ -14,3 +18,332 @@ pub struct Request {
     from: String,
     to: String,
 }
+
+/// Absolute price band in fiat per BTC, an alternative to the premium.
+/// Orders with a band are taken at market price only if the price is inside it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PriceBand {
+    pub min_price: f64,
+    pub max_price: f64,
+}
+
+impl PriceBand {
+    /// Check the band limits are sane
+    pub fn validate(&self) -> Result<(), CantDoReason> {
+        if !self.min_price.is_finite()
+            || !self.max_price.is_finite()
+            || self.min_price <= 0.0
+            || self.min_price > self.max_price
+        {
+            return Err(CantDoReason::InvalidParameters);
+        }
+        Ok(())
+    }
+
+    /// Sats for a fiat amount at the market price, refused if the market is outside the band
+    pub fn sats_for(&self, fiat_amount: i64, market_price: f64) -> Result<i64, CantDoReason> {
+        if market_price < self.min_price || market_price > self.max_price {
+            return Err(CantDoReason::InvalidAmount);
+        }
+        Ok((fiat_amount as f64 / market_price * 1E8) as i64)
+    }
+}
+
+/// Payment methods a maker can restrict an order to. Orders with a set of
+/// methods can only be taken declaring one of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaymentMethod {
+    Sepa,
+    Cash,
+    PayPal,
+    OnChain,
+}
+
+impl PaymentMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentMethod::Sepa => "sepa",
+            PaymentMethod::Cash => "cash",
+            PaymentMethod::PayPal => "paypal",
+            PaymentMethod::OnChain => "onchain",
+        }
+    }
+
+    /// Parse a set of methods sent by a maker, unknown or repeated methods and
+    /// empty sets are refused
+    pub fn parse_set(value: serde_json::Value) -> Result<Vec<Self>, CantDoReason> {
+        let names: Vec<String> =
+            serde_json::from_value(value).map_err(|_| CantDoReason::InvalidParameters)?;
+        let methods = names
+            .iter()
+            .map(|name| name.parse())
+            .collect::<Result<Vec<Self>, _>>()?;
+        let unique: std::collections::HashSet<&Self> = methods.iter().collect();
+        if methods.is_empty() || unique.len() != methods.len() {
+            return Err(CantDoReason::InvalidParameters);
+        }
+        Ok(methods)
+    }
+
+    /// Checks `methods` are the same ones listed in the free text payment
+    /// method of the order, published in the `pm` tag, so takers don't pick
+    /// a listed method the maker refuses
+    pub fn check_listed(methods: &[Self], payment_method: &str) -> Result<(), CantDoReason> {
+        let listed = payment_method
+            .split(',')
+            .filter(|name| !name.trim().is_empty())
+            .map(|name| name.parse())
+            .collect::<Result<std::collections::HashSet<Self>, _>>()?;
+        let methods: std::collections::HashSet<Self> = methods.iter().copied().collect();
+        if listed != methods {
+            return Err(CantDoReason::InvalidParameters);
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for PaymentMethod {
+    type Err = CantDoReason;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "sepa" => Ok(PaymentMethod::Sepa),
+            "cash" => Ok(PaymentMethod::Cash),
+            "paypal" => Ok(PaymentMethod::PayPal),
+            "onchain" => Ok(PaymentMethod::OnChain),
+            _ => Err(CantDoReason::InvalidParameters),
+        }
+    }
+}
+
+/// Event published for an order as its status changed, oldest first in the
+/// history of an order
+#[derive(Debug, Clone, PartialEq, Serialize, sqlx::FromRow)]
+pub struct OrderEventRecord {
+    pub order_id: Uuid,
+    pub event_id: String,
+    pub status: String,
+    pub created_at: i64,
+}
+
+/// Row of a table in a backup, values by column name. Blobs are written as
+/// `{"blob": "<hex>"}`
+pub type BackupRow = serde_json::Map<String, serde_json::Value>;
+
+/// Order book exported to move a node, rows of each table by table name.
+/// Ratings and disputes are optional
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Backup {
+    pub version: u32,
+    pub tables: BTreeMap<String, Vec<BackupRow>>,
+}
+
+impl Backup {
+    /// Rows of `table` in the backup
+    pub fn rows(&self, table: &str) -> usize {
+        self.tables.get(table).map_or(0, Vec::len)
+    }
+}
+
+/// Dispute handling stats of a solver, updated as disputes are taken and resolved
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SolverStats {
+    pub solver_pubkey: String,
+    pub disputes_taken: i64,
+    pub disputes_resolved: i64,
+    pub total_resolution_seconds: i64,
+    /// Disputes resolved paying the buyer
+    pub buyer_favor: i64,
+    /// Disputes resolved refunding the seller
+    pub seller_favor: i64,
+    /// Disputes resolved after the configured SLA
+    pub sla_breaches: i64,
+}
+
+impl SolverStats {
+    /// Average seconds from taking a dispute to resolving it
+    pub fn average_resolution_seconds(&self) -> i64 {
+        if self.disputes_resolved == 0 {
+            return 0;
+        }
+        self.total_resolution_seconds / self.disputes_resolved
+    }
+}
+
+/// Trades a user completed, published next to their rating
+#[derive(Debug, Clone, Copy, Default, PartialEq, sqlx::FromRow)]
+pub struct UserStats {
+    pub completed_trades: i64,
+    /// Sats traded in those trades
+    pub volume_sats: i64,
+}
+
+/// Payment of the portion of one party when a dispute is settled with a split
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow)]
+pub struct SplitLeg {
+    pub order_id: Uuid,
+    /// `buyer` or `seller`
+    pub party: String,
+    pub payment_request: String,
+    pub amount: i64,
+    /// `held` until the hold invoice is settled, then `pending`. A payer takes
+    /// it as `paying`, it ends `paid`, `failed` or `in_flight` when the node
+    /// didn't tell the outcome
+    pub status: String,
+    pub attempts: i64,
+    /// Hash of the invoice paid, recorded before sending the payment
+    pub payment_hash: Option<String>,
+}
+
+/// Onchain payout of the buyer of an order, recorded before the transaction
+/// is sent so the buyer is never paid twice
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow)]
+pub struct OnchainPayout {
+    pub order_id: Uuid,
+    pub address: String,
+    /// Sats sent, the onchain fee is already taken out
+    pub amount: i64,
+    /// Id of the transaction, none if the node didn't return it
+    pub txid: Option<String>,
+    pub created_at: i64,
+}
+
+/// Range order split in child orders as it gets partially taken, rebuilt from
+/// the `range_parent_id` links stored with each order
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RangeGrid {
+    pub root_id: Uuid,
+    /// Orders of the grid from the root to the most recent child
+    pub order_ids: Vec<Uuid>,
+    /// Fiat amount already taken
+    pub filled_fiat: i64,
+    /// Most recent order of the grid
+    pub head_id: Uuid,
+    pub head_status: String,
+    /// Fiat range still available, `None` when the head is not pending
+    pub remaining: Option<(i64, i64)>,
+}
+
+impl RangeGrid {
+    /// Rebuild the grid from its orders, in any order
+    pub fn from_orders(orders: &[Order]) -> Option<Self> {
+        let ids: Vec<Uuid> = orders.iter().map(|o| o.id).collect();
+        let root = orders.iter().find(|o| match o.range_parent_id {
+            Some(parent_id) => !ids.contains(&parent_id),
+            None => true,
+        })?;
+
+        // Each order has at most one child, the one created when it was taken
+        let mut chain = vec![root];
+        while let Some(child) = orders
+            .iter()
+            .find(|o| o.range_parent_id == Some(chain[chain.len() - 1].id))
+        {
+            chain.push(child);
+        }
+        let head = chain[chain.len() - 1];
+        let pending = head.status == Status::Pending.to_string();
+
+        // Every order but a pending head was traded for its fiat amount
+        let filled_fiat = chain
+            .iter()
+            .filter(|o| !(pending && o.id == head.id))
+            .map(|o| o.fiat_amount)
+            .sum();
+        let remaining = match (pending, head.min_amount, head.max_amount) {
+            (false, _, _) => None,
+            (true, Some(min), Some(max)) => Some((min, max)),
+            (true, _, _) => Some((head.fiat_amount, head.fiat_amount)),
+        };
+
+        Some(Self {
+            root_id: root.id,
+            order_ids: chain.iter().map(|o| o.id).collect(),
+            filled_fiat,
+            head_id: head.id,
+            head_status: head.status.clone(),
+            remaining,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_price_band_validate() {
+        let band = PriceBand {
+            min_price: 90_000.0,
+            max_price: 100_000.0,
+        };
+        assert!(band.validate().is_ok());
+
+        let inverted = PriceBand {
+            min_price: 100_000.0,
+            max_price: 90_000.0,
+        };
+        assert_eq!(inverted.validate(), Err(CantDoReason::InvalidParameters));
+    }
+
+    #[test]
+    fn test_payment_method_set() {
+        let methods = PaymentMethod::parse_set(serde_json::json!(["SEPA", "cash"])).unwrap();
+        assert_eq!(methods, vec![PaymentMethod::Sepa, PaymentMethod::Cash]);
+
+        for invalid in [
+            serde_json::json!([]),
+            serde_json::json!(["sepa", "Sepa"]),
+            serde_json::json!(["wire"]),
+            serde_json::json!("sepa"),
+        ] {
+            assert_eq!(
+                PaymentMethod::parse_set(invalid),
+                Err(CantDoReason::InvalidParameters)
+            );
+        }
+    }
+
+    #[test]
+    fn test_payment_methods_listed() {
+        let methods = [PaymentMethod::Sepa, PaymentMethod::Cash];
+        assert!(PaymentMethod::check_listed(&methods, "cash,SEPA").is_ok());
+        assert!(PaymentMethod::check_listed(&methods, "sepa, cash").is_ok());
+
+        // Listed methods missing from the set, or the other way around
+        for listed in ["sepa", "sepa,cash,paypal", "sepa,cash,face to face", ""] {
+            assert_eq!(
+                PaymentMethod::check_listed(&methods, listed),
+                Err(CantDoReason::InvalidParameters)
+            );
+        }
+    }
+
+    #[test]
+    fn test_price_band_take_inside_band() {
+        let band = PriceBand {
+            min_price: 90_000.0,
+            max_price: 100_000.0,
+        };
+        // 100 fiat at 100k per BTC is 100_000 sats
+        assert_eq!(band.sats_for(100, 100_000.0), Ok(100_000));
+        assert_eq!(band.sats_for(90, 90_000.0), Ok(100_000));
+    }
+
+    #[test]
+    fn test_price_band_take_outside_band() {
+        let band = PriceBand {
+            min_price: 90_000.0,
+            max_price: 100_000.0,
+        };
+        assert_eq!(
+            band.sats_for(100, 89_999.0),
+            Err(CantDoReason::InvalidAmount)
+        );
+        assert_eq!(
+            band.sats_for(100, 100_001.0),
+            Err(CantDoReason::InvalidAmount)
+        );
+    }
+}
@@ -1,5 +1,8 @@
+use crate::bitcoin_price::PriceQuote;
 use crate::config::settings::Settings;
+use crate::config::types::OrderTemplate;
 use crate::lightning::LnStatus;
+use crate::models::{PaymentMethod, UserStats};
 use crate::LN_STATUS;
 use chrono::Duration;
 use mostro_core::prelude::*;
@@ -8,6 +11,7 @@ use nostr_sdk::prelude::*;
 use serde_json::json;
 use std::borrow::Cow;
 use std::vec;
+use uuid::Uuid;
 
 /// Creates a new mostro nip33 event
 ///
@@ -37,14 +41,257 @@ pub fn new_event(
         .sign_with_keys(keys)
 }
 
+/// Kind of the public fee receipt events
+pub const FEE_RECEIPT_EVENT_KIND: u16 = 8383;
+
+/// Bounds of the bucket a collected fee falls in, powers of ten keep the
+/// published amounts coarse enough to not identify trades
+fn fee_bucket(fee: i64) -> (i64, i64) {
+    let mut lower = 1;
+    while lower * 10 <= fee {
+        lower *= 10;
+    }
+    (lower, lower * 10)
+}
+
+/// Creates a public receipt of a collected fee, in the spirit of NIP-57 zap
+/// receipts but without any information about the parties. The amount is
+/// bucketed and the timestamp rounded down to the hour.
+///
+/// # Arguments
+///
+/// * `keys` - The keys used to sign the event
+/// * `fee` - Total fee collected in sats
+///
+/// # Returns
+/// Returns a new event
+///
+pub fn fee_receipt_event(keys: &Keys, fee: i64) -> Result<Event, Error> {
+    const SECONDS_IN_HOUR: u64 = 3600;
+    let (lower, upper) = fee_bucket(fee);
+    let created_at = Timestamp::now().as_u64() / SECONDS_IN_HOUR * SECONDS_IN_HOUR;
+    let tags = Tags::from_list(vec![
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("amount_bucket")),
+            vec![lower.to_string(), upper.to_string()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("y")),
+            vec!["mostro".to_string()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("z")),
+            vec!["fee-receipt".to_string()],
+        ),
+    ]);
+
+    EventBuilder::new(nostr::Kind::Custom(FEE_RECEIPT_EVENT_KIND), "")
+        .tags(tags)
+        .custom_created_at(Timestamp::from(created_at))
+        .sign_with_keys(keys)
+}
+
+/// Kind of the price attestation events carried by market price orders
+pub const PRICE_ATTESTATION_EVENT_KIND: u16 = 8384;
+
+/// Creates an attestation of the price used to quote a market price order,
+/// takers can check its signature against the Mostro pubkey
+///
+/// # Arguments
+///
+/// * `keys` - The Mostro keys used to sign the event
+/// * `fiat_code` - Currency of the order
+/// * `quote` - Price, provider and time the price was fetched
+///
+/// # Returns
+/// Returns a new event
+///
+pub fn price_attestation_event(
+    keys: &Keys,
+    fiat_code: &str,
+    quote: &PriceQuote,
+) -> Result<Event, Error> {
+    let tags = Tags::from_list(vec![
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("f")),
+            vec![fiat_code.to_string()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("price")),
+            vec![quote.price.to_string()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("provider")),
+            vec![quote.provider.clone()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("quoted_at")),
+            vec![quote.updated_at.to_string()],
+        ),
+    ]);
+
+    EventBuilder::new(nostr::Kind::Custom(PRICE_ATTESTATION_EVENT_KIND), "")
+        .tags(tags)
+        .sign_with_keys(keys)
+}
+
+/// Kind of the order status change events, not replaceable so subscribers
+/// get every transition
+pub const STATUS_CHANGE_EVENT_KIND: u16 = 8385;
+
+/// Creates the audit event of an order moving from `old_status` to
+/// `new_status`, the event timestamp is the time of the change
+///
+/// # Arguments
+///
+/// * `keys` - The Mostro keys used to sign the event
+/// * `order_id` - Id of the order
+/// * `old_status` - Status the order leaves
+/// * `new_status` - Status the order enters
+///
+/// # Returns
+/// Returns a new event
+///
+pub fn status_change_event(
+    keys: &Keys,
+    order_id: Uuid,
+    old_status: &str,
+    new_status: &str,
+) -> Result<Event, Error> {
+    let tags = Tags::from_list(vec![
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("order")),
+            vec![order_id.to_string()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("from")),
+            vec![old_status.to_string()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("s")),
+            vec![new_status.to_string()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("y")),
+            vec!["mostro".to_string()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("z")),
+            vec!["status-change".to_string()],
+        ),
+    ]);
+
+    EventBuilder::new(nostr::Kind::Custom(STATUS_CHANGE_EVENT_KIND), "")
+        .tags(tags)
+        .sign_with_keys(keys)
+}
+
+/// Kind of the operator announcement events
+pub const ANNOUNCEMENT_EVENT_KIND: u16 = 8386;
+
+/// Creates an announcement of the operator to every user, like a maintenance
+/// window, clients subscribe to the kind from the Mostro pubkey
+///
+/// # Arguments
+///
+/// * `keys` - The Mostro keys used to sign the event
+/// * `message` - Text of the announcement
+/// * `severity` - How important the announcement is
+///
+/// # Returns
+/// Returns a new event
+///
+pub fn announcement_event(keys: &Keys, message: &str, severity: &str) -> Result<Event, Error> {
+    let tags = Tags::from_list(vec![
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("severity")),
+            vec![severity.to_string()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("y")),
+            vec!["mostro".to_string()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("z")),
+            vec!["announcement".to_string()],
+        ),
+    ]);
+
+    EventBuilder::new(nostr::Kind::Custom(ANNOUNCEMENT_EVENT_KIND), message)
+        .tags(tags)
+        .sign_with_keys(keys)
+}
+
+/// Tag carrying a price attestation event in an order event
+pub fn price_attestation_tag(attestation: &Event) -> Tag {
+    Tag::custom(
+        TagKind::Custom(Cow::Borrowed("price_attestation")),
+        vec![attestation.as_json()],
+    )
+}
+
+/// Tag with the payment methods an order can be taken with, the `pm` tag has
+/// the same methods as free text
+pub fn payment_methods_tag(methods: &[PaymentMethod]) -> Tag {
+    Tag::custom(
+        TagKind::Custom(Cow::Borrowed("payment_methods")),
+        methods.iter().map(|method| method.as_str().to_string()),
+    )
+}
+
+/// Tag with the minimum rating a taker needs to take an order
+pub fn min_taker_rating_tag(min_rating: f64) -> Tag {
+    Tag::custom(
+        TagKind::Custom(Cow::Borrowed("min_taker_rating")),
+        vec![min_rating.to_string()],
+    )
+}
+
+/// Identifier of the order templates event
+pub const ORDER_TEMPLATES_IDENTIFIER: &str = "order-templates";
+
+/// Creates the event with the operator order templates as json content
+///
+/// # Arguments
+///
+/// * `keys` - The Mostro keys used to sign the event
+/// * `templates` - The order templates in settings
+///
+/// # Returns
+/// Returns a new event
+///
+pub fn order_templates_event(keys: &Keys, templates: &[OrderTemplate]) -> Result<Event, Error> {
+    let tags = Tags::from_list(vec![
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("y")),
+            vec!["mostro".to_string()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("z")),
+            vec![ORDER_TEMPLATES_IDENTIFIER.to_string()],
+        ),
+    ]);
+
+    new_event(
+        keys,
+        &json!(templates).to_string(),
+        ORDER_TEMPLATES_IDENTIFIER.to_string(),
+        tags,
+    )
+}
+
 /// Create a rating tag
 ///
 /// # Arguments
 ///
 /// * `reputation_data` - The reputation data of the user
+/// * `trade_stats` - The trades the user completed
 ///
 /// # Returns a json string
-fn create_rating_tag(reputation_data: Option<(f64, i64, i64)>) -> String {
+pub fn create_rating_tag(
+    reputation_data: Option<(f64, i64, i64)>,
+    trade_stats: UserStats,
+) -> String {
     if let Some(data) = reputation_data {
         const SECONDS_IN_DAY: u64 = 86400;
         // If operating day is 0, it means the user is new and we don't have a valid reputation data
@@ -58,7 +305,13 @@ fn create_rating_tag(reputation_data: Option<(f64, i64, i64)>) -> String {
         // Create the json string
         let json_data = json!([
         "rating",
-            {"total_reviews": data.1, "total_rating": data.0, "days": days}
+            {
+                "total_reviews": data.1,
+                "total_rating": data.0,
+                "days": days,
+                "completed_trades": trade_stats.completed_trades,
+                "volume_sats": trade_stats.volume_sats
+            }
         ]);
         json_data.to_string()
     } else {
@@ -101,6 +354,8 @@ fn create_status_tags(order: &Order) -> Result<(bool, Status), MostroError> {
         | Status::Expired => Ok((true, Status::Canceled)),
         Status::Success | Status::CompletedByAdmin => Ok((true, status)),
         Status::Pending => Ok((true, status)),
+        // Reserved pending orders are shown in progress to hide them from the book
+        Status::InProgress => Ok((true, status)),
         _ => Ok((false, status)),
     }
 }
@@ -159,6 +414,7 @@ fn create_source_tag(
 ///   - `f64`: Total rating score
 ///   - `i64`: Total number of reviews
 ///   - `i64`: Unix timestamp of first operation (used to calculate operating days)
+/// * `trade_stats` - Completed trades and volume of the user, added to the rating
 ///
 /// # Returns
 ///
@@ -187,6 +443,7 @@ fn create_source_tag(
 pub fn order_to_tags(
     order: &Order,
     reputation_data: Option<(f64, i64, i64)>,
+    trade_stats: UserStats,
 ) -> Result<Option<Tags>, MostroError> {
     // Position of the tags in the list
     const RATING_TAG_INDEX: usize = 7;
@@ -260,7 +517,7 @@ pub fn order_to_tags(
                 RATING_TAG_INDEX,
                 Tag::custom(
                     TagKind::Custom(Cow::Borrowed("rating")),
-                    vec![create_rating_tag(reputation_data)],
+                    vec![create_rating_tag(reputation_data, trade_stats)],
                 ),
             );
         }
@@ -286,7 +543,7 @@ pub fn info_to_tags(ln_status: &LnStatus) -> Tags {
     let mostro_settings = Settings::get_mostro();
     let ln_settings = Settings::get_ln();
 
-    let tags: Tags = Tags::from_list(vec![
+    let mut tags: Vec<Tag> = vec![
         Tag::custom(
             TagKind::Custom(Cow::Borrowed("mostro_version")),
             vec![env!("CARGO_PKG_VERSION").to_string()],
@@ -315,6 +572,18 @@ pub fn info_to_tags(ln_status: &LnStatus) -> Tags {
             TagKind::Custom(Cow::Borrowed("fee")),
             vec![mostro_settings.fee.to_string()],
         ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("fee_flat_sats")),
+            vec![mostro_settings.fee_flat_sats.to_string()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("fee_min_sats")),
+            vec![mostro_settings.fee_min_sats.to_string()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("fee_max_sats")),
+            vec![mostro_settings.fee_max_sats.to_string()],
+        ),
         Tag::custom(
             TagKind::Custom(Cow::Borrowed("pow")),
             vec![mostro_settings.pow.to_string()],
@@ -367,7 +636,18 @@ pub fn info_to_tags(ln_status: &LnStatus) -> Tags {
             TagKind::Custom(Cow::Borrowed("z")),
             vec!["info".to_string()],
         ),
-    ]);
+    ];
+
+    // Publish the terms users have to accept before creating orders
+    if mostro_settings.terms_of_service_enabled {
+        tags.push(Tag::custom(
+            TagKind::Custom(Cow::Borrowed("terms_of_service")),
+            vec![
+                mostro_settings.terms_of_service.clone(),
+                crate::app::accept_terms::terms_hash(&mostro_settings.terms_of_service),
+            ],
+        ));
+    }
 
-    tags
+    Tags::from_list(tags)
 }
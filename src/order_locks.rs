@@ -0,0 +1,151 @@
+//! Per order locks held by the handlers changing an order.
+//!
+//! A handler reads the order, decides and then writes it. Without a lock two
+//! handlers of the same order, like a cancel and a release, or an admin RPC
+//! and a user message, could both decide on the same stale state. A lock is
+//! dropped from the map once no handler holds or waits for it, so finished
+//! orders don't keep theirs. Scheduler jobs find their orders before locking
+//! them, they read the order again under the lock and decide on that one.
+
+use mostro_core::prelude::*;
+use sqlx::SqlitePool;
+use sqlx_crud::Crud;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use uuid::Uuid;
+
+type Locks = Mutex<HashMap<Uuid, Arc<AsyncMutex<()>>>>;
+
+static ORDER_LOCKS: LazyLock<Locks> = LazyLock::new(Mutex::default);
+
+/// Held while a handler changes an order
+pub struct OrderLockGuard {
+    order_id: Uuid,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for OrderLockGuard {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        let mut locks = match ORDER_LOCKS.lock() {
+            Ok(locks) => locks,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        // Handlers waiting for the lock hold a clone of it
+        if locks
+            .get(&self.order_id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.order_id);
+        }
+    }
+}
+
+/// Waits until no other handler changes the order, messages without an order
+/// id get no lock
+pub async fn lock_order(order_id: Option<Uuid>) -> Option<OrderLockGuard> {
+    Some(lock(order_id?).await)
+}
+
+async fn lock(order_id: Uuid) -> OrderLockGuard {
+    let lock = {
+        let mut locks = match ORDER_LOCKS.lock() {
+            Ok(locks) => locks,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        locks.entry(order_id).or_default().clone()
+    };
+
+    OrderLockGuard {
+        order_id,
+        guard: Some(lock.lock_owned().await),
+    }
+}
+
+/// Lock an order found by a job and read it again, None once it is gone
+pub async fn lock_and_reload(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<(OrderLockGuard, Option<Order>), MostroError> {
+    let guard = lock(order_id).await;
+    let order = Order::by_id(pool, order_id)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+    Ok((guard, order))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+    use std::time::Duration;
+
+    fn is_locked(order_id: Uuid) -> bool {
+        ORDER_LOCKS.lock().unwrap().contains_key(&order_id)
+    }
+
+    #[tokio::test]
+    async fn test_cancel_and_release_race() {
+        let pool = test_pool().await;
+        let order = Order {
+            id: Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::FiatSent.to_string(),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+        let order_id = order.id;
+
+        // Both read the order, wait so the other one reads it too, then write
+        // what they decided from what they read
+        let handler = |next: Status| {
+            let pool = pool.clone();
+            async move {
+                let _lock = lock_order(Some(order_id)).await;
+                let current = Order::by_id(&pool, order_id).await.unwrap().unwrap();
+                if current.status != Status::FiatSent.to_string() {
+                    return false;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let mut current = current;
+                current.status = next.to_string();
+                current.update(&pool).await.unwrap();
+                true
+            }
+        };
+        let (canceled, released) = tokio::join!(
+            tokio::spawn(handler(Status::CooperativelyCanceled)),
+            tokio::spawn(handler(Status::SettledHoldInvoice)),
+        );
+
+        // Only one of them acted on the order, the other one saw it moved
+        let (canceled, released) = (canceled.unwrap(), released.unwrap());
+        assert!(canceled ^ released);
+        let order = Order::by_id(&pool, order_id).await.unwrap().unwrap();
+        let expected = if canceled {
+            Status::CooperativelyCanceled
+        } else {
+            Status::SettledHoldInvoice
+        };
+        assert_eq!(order.status, expected.to_string());
+        assert!(!is_locked(order_id));
+    }
+
+    #[tokio::test]
+    async fn test_lock_evicted_after_waiters() {
+        let order_id = Uuid::new_v4();
+        let first = lock_order(Some(order_id)).await.unwrap();
+        let waiter = tokio::spawn(lock_order(Some(order_id)));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // Kept for the handler waiting
+        drop(first);
+        assert!(is_locked(order_id));
+        drop(waiter.await.unwrap());
+        assert!(!is_locked(order_id));
+        assert!(lock_order(None).await.is_none());
+    }
+}
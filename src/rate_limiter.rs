@@ -0,0 +1,215 @@
+//! Per pubkey rate limiting of incoming messages.
+//!
+//! Each pubkey gets a token bucket holding up to `burst` messages which refills
+//! at `refill_per_second`. Messages arriving with an empty bucket are dropped.
+//! The limiter also keeps the cooldown of users who canceled their own order,
+//! they can't create a new one until it is over.
+
+use mostro_core::prelude::*;
+use nostr_sdk::PublicKey;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Seconds between removals of idle buckets
+const PRUNE_INTERVAL_SECS: u64 = 60;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: HashMap<PublicKey, Bucket>,
+    /// End of the order recreate cooldown of each pubkey
+    cooldowns: HashMap<PublicKey, Instant>,
+    refill_per_second: f64,
+    burst: f64,
+    last_prune: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(refill_per_second: f64, burst: u32) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            cooldowns: HashMap::new(),
+            refill_per_second,
+            burst: burst.max(1) as f64,
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// Change the message budget, cooldowns already started are kept
+    pub fn configure(&mut self, refill_per_second: f64, burst: u32) {
+        self.refill_per_second = refill_per_second;
+        self.burst = burst.max(1) as f64;
+    }
+
+    /// Keep `pubkey` from creating orders until `until`
+    pub fn start_cooldown(&mut self, pubkey: &PublicKey, until: Instant) {
+        self.cooldowns.insert(*pubkey, until);
+    }
+
+    /// Tells if `pubkey` is still cooling down, expired cooldowns are removed
+    pub fn in_cooldown(&mut self, pubkey: &PublicKey, now: Instant) -> bool {
+        match self.cooldowns.get(pubkey) {
+            Some(until) if now < *until => true,
+            Some(_) => {
+                self.cooldowns.remove(pubkey);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Take a token for a message of `pubkey`, false if it went over its budget.
+    /// A refill rate of 0 disables the limiter.
+    pub fn check(&mut self, pubkey: &PublicKey, now: Instant) -> bool {
+        if self.refill_per_second <= 0.0 {
+            return true;
+        }
+        if now.duration_since(self.last_prune) >= Duration::from_secs(PRUNE_INTERVAL_SECS) {
+            self.prune(now);
+        }
+
+        let (refill_per_second, burst) = (self.refill_per_second, self.burst);
+        let bucket = self.buckets.entry(*pubkey).or_insert(Bucket {
+            tokens: burst,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_per_second).min(burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Remove buckets refilled since their last message, a new bucket starts
+    /// full so dropping them changes nothing and bounds memory
+    fn prune(&mut self, now: Instant) {
+        let (refill_per_second, burst) = (self.refill_per_second, self.burst);
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens + elapsed * refill_per_second < burst
+        });
+        self.cooldowns.retain(|_, until| now < *until);
+        self.last_prune = now;
+    }
+}
+
+/// Limiter shared by the event loop and the order handlers, disabled until
+/// the event loop configures it
+pub static RATE_LIMITER: Lazy<Mutex<RateLimiter>> =
+    Lazy::new(|| Mutex::new(RateLimiter::new(0.0, 1)));
+
+/// Budget of the reputation queries of each pubkey, disabled until the event
+/// loop configures it
+pub static REPUTATION_LIMITER: Lazy<Mutex<RateLimiter>> =
+    Lazy::new(|| Mutex::new(RateLimiter::new(0.0, 1)));
+
+/// Start the recreate cooldown of a user who canceled their own order,
+/// 0 seconds disables it
+pub fn start_recreate_cooldown(pubkey: &PublicKey, cooldown_secs: u64) {
+    if cooldown_secs == 0 {
+        return;
+    }
+    if let Ok(mut limiter) = RATE_LIMITER.lock() {
+        limiter.start_cooldown(pubkey, Instant::now() + Duration::from_secs(cooldown_secs));
+    }
+}
+
+fn check_cooldown_with(
+    limiter: &mut RateLimiter,
+    pubkey: &PublicKey,
+    now: Instant,
+) -> Result<(), MostroError> {
+    if limiter.in_cooldown(pubkey, now) {
+        info!("{pubkey} canceled an order recently, new order refused");
+        return Err(MostroCantDo(CantDoReason::NotAllowedByStatus));
+    }
+    Ok(())
+}
+
+/// Checks the user is not cooling down after canceling an order
+pub fn check_recreate_cooldown(pubkey: &PublicKey) -> Result<(), MostroError> {
+    match RATE_LIMITER.lock() {
+        Ok(mut limiter) => check_cooldown_with(&mut limiter, pubkey, Instant::now()),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::Keys;
+
+    #[test]
+    fn test_message_over_burst_rejected() {
+        let burst = 5;
+        let mut limiter = RateLimiter::new(1.0, burst);
+        let pubkey = Keys::generate().public_key();
+        let now = Instant::now();
+
+        for _ in 0..burst {
+            assert!(limiter.check(&pubkey, now));
+        }
+        assert!(!limiter.check(&pubkey, now));
+        // Other pubkeys have their own budget
+        assert!(limiter.check(&Keys::generate().public_key(), now));
+        // One token is back after a second
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.check(&pubkey, later));
+        assert!(!limiter.check(&pubkey, later));
+    }
+
+    #[test]
+    fn test_idle_buckets_pruned() {
+        let mut limiter = RateLimiter::new(1.0, 2);
+        let now = Instant::now();
+        for _ in 0..10 {
+            limiter.check(&Keys::generate().public_key(), now);
+        }
+        assert_eq!(limiter.buckets.len(), 10);
+
+        let later = now + Duration::from_secs(PRUNE_INTERVAL_SECS);
+        limiter.check(&Keys::generate().public_key(), later);
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+
+    #[test]
+    fn test_recreate_inside_cooldown_rejected() {
+        let mut limiter = RateLimiter::new(1.0, 5);
+        let pubkey = Keys::generate().public_key();
+        let now = Instant::now();
+
+        // The user cancels an order and creates a new one right away
+        limiter.start_cooldown(&pubkey, now + Duration::from_secs(60));
+        assert!(matches!(
+            check_cooldown_with(&mut limiter, &pubkey, now + Duration::from_secs(1)),
+            Err(MostroCantDo(CantDoReason::NotAllowedByStatus))
+        ));
+        // Other users are not affected
+        assert!(check_cooldown_with(&mut limiter, &Keys::generate().public_key(), now).is_ok());
+        // Over once the window ends
+        assert!(check_cooldown_with(&mut limiter, &pubkey, now + Duration::from_secs(60)).is_ok());
+        assert!(limiter.cooldowns.is_empty());
+    }
+
+    #[test]
+    fn test_disabled_limiter() {
+        let mut limiter = RateLimiter::new(0.0, 1);
+        let pubkey = Keys::generate().public_key();
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(limiter.check(&pubkey, now));
+        }
+    }
+}
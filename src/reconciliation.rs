@@ -0,0 +1,201 @@
+//! Order states against the lightning node.
+//!
+//! Compares the status of orders holding a hold invoice with the state of the
+//! invoice in the node and reports the drift, e.g. an active order whose hold
+//! invoice was canceled. Nothing is fixed here, the operator decides. The
+//! payment state of a single order can be looked up too, for stuck releases.
+
+use crate::db::{find_order_hash, find_orders_with_hold_invoice};
+use crate::lightning::{LightningBackend, PaymentState};
+use fedimint_tonic_lnd::lnrpc::invoice::InvoiceState;
+use mostro_core::prelude::*;
+use sqlx::SqlitePool;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Order whose status doesn't match its hold invoice
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderDiscrepancy {
+    pub order_id: Uuid,
+    pub status: String,
+    pub hold_invoice_state: String,
+    pub description: String,
+}
+
+/// Describe the drift between an order status and its hold invoice state, if any
+fn hold_invoice_drift(status: Status, state: InvoiceState) -> Option<&'static str> {
+    match (status, state) {
+        (Status::WaitingPayment, InvoiceState::Settled | InvoiceState::Canceled) => {
+            Some("hold invoice closed while waiting for the seller payment")
+        }
+        (Status::Active | Status::FiatSent | Status::Dispute, InvoiceState::Open) => {
+            Some("trade running but the seller funds are not held")
+        }
+        (Status::Active | Status::FiatSent | Status::Dispute, InvoiceState::Canceled) => {
+            Some("trade running but the hold invoice was canceled")
+        }
+        (Status::Active | Status::FiatSent | Status::Dispute, InvoiceState::Settled) => {
+            Some("hold invoice settled before the funds were released")
+        }
+        (
+            Status::SettledHoldInvoice
+            | Status::Success
+            | Status::SettledByAdmin
+            | Status::CompletedByAdmin,
+            InvoiceState::Open | InvoiceState::Accepted | InvoiceState::Canceled,
+        ) => Some("funds released but the hold invoice is not settled"),
+        (
+            Status::Canceled
+            | Status::CanceledByAdmin
+            | Status::CooperativelyCanceled
+            | Status::Expired,
+            InvoiceState::Accepted,
+        ) => Some("order canceled but the seller funds are still held"),
+        _ => None,
+    }
+}
+
+/// Cross-check running trades, and those finished after `finished_since`, with
+/// the lightning node
+pub async fn reconciliation_report(
+    pool: &SqlitePool,
+    ln_client: &mut dyn LightningBackend,
+    finished_since: i64,
+) -> Result<Vec<OrderDiscrepancy>, MostroError> {
+    let mut report = Vec::new();
+
+    for order in find_orders_with_hold_invoice(pool, finished_since).await? {
+        let (Some(hash), Ok(status)) = (&order.hash, Status::from_str(&order.status)) else {
+            continue;
+        };
+        let discrepancy = |hold_invoice_state: String, description: String| OrderDiscrepancy {
+            order_id: order.id,
+            status: order.status.clone(),
+            hold_invoice_state,
+            description,
+        };
+
+        match ln_client.hold_invoice_state(hash).await {
+            Ok(state) => {
+                if let Some(drift) = hold_invoice_drift(status, state) {
+                    report.push(discrepancy(
+                        state.as_str_name().to_lowercase(),
+                        drift.to_string(),
+                    ));
+                }
+            }
+            Err(e) => report.push(discrepancy(
+                "unknown".to_string(),
+                format!("hold invoice lookup failed: {e}"),
+            )),
+        }
+    }
+
+    Ok(report)
+}
+
+/// Hash of the hold invoice of an order and its state in the node
+pub async fn order_payment_state(
+    pool: &SqlitePool,
+    ln_client: &mut dyn LightningBackend,
+    order_id: Uuid,
+) -> Result<(String, PaymentState), MostroError> {
+    let hash = find_order_hash(pool, order_id)
+        .await?
+        .ok_or(MostroCantDo(CantDoReason::NotFound))?;
+    let state = ln_client.payment_state(&hash).await?;
+    Ok((hash, state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+    use crate::lightning::mock::MockBackend;
+    use sqlx_crud::Crud;
+
+    #[test]
+    fn test_hold_invoice_drift() {
+        assert!(hold_invoice_drift(Status::Active, InvoiceState::Accepted).is_none());
+        assert!(hold_invoice_drift(Status::Success, InvoiceState::Settled).is_none());
+        assert!(hold_invoice_drift(Status::WaitingPayment, InvoiceState::Open).is_none());
+        assert!(hold_invoice_drift(Status::Active, InvoiceState::Canceled).is_some());
+        assert!(hold_invoice_drift(Status::Canceled, InvoiceState::Accepted).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_report_flags_canceled_hold_invoice() {
+        let pool = test_pool().await;
+        let mut backend = MockBackend::default();
+
+        let mut orders = Vec::new();
+        for (status, state) in [
+            (Status::Active, InvoiceState::Canceled),
+            (Status::Active, InvoiceState::Accepted),
+        ] {
+            let hash = Uuid::new_v4().simple().to_string();
+            backend.invoice_states.insert(hash.clone(), state);
+            let order = Order {
+                id: Uuid::new_v4(),
+                kind: "sell".to_string(),
+                status: status.to_string(),
+                hash: Some(hash),
+                ..Default::default()
+            }
+            .create(&pool)
+            .await
+            .unwrap();
+            orders.push(order);
+        }
+
+        let report = reconciliation_report(&pool, &mut backend, 0).await.unwrap();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].order_id, orders[0].id);
+        assert_eq!(report[0].status, "active");
+        assert_eq!(report[0].hold_invoice_state, "canceled");
+    }
+
+    #[tokio::test]
+    async fn test_order_payment_state() {
+        let pool = test_pool().await;
+        let mut backend = MockBackend::default();
+
+        for (state, expected) in [
+            (InvoiceState::Accepted, PaymentState::Accepted),
+            (InvoiceState::Settled, PaymentState::Settled),
+            (InvoiceState::Canceled, PaymentState::Canceled),
+        ] {
+            let hash = Uuid::new_v4().simple().to_string();
+            backend.invoice_states.insert(hash.clone(), state);
+            let order = Order {
+                id: Uuid::new_v4(),
+                hash: Some(hash.clone()),
+                ..Default::default()
+            }
+            .create(&pool)
+            .await
+            .unwrap();
+            let (found, payment_state) = order_payment_state(&pool, &mut backend, order.id)
+                .await
+                .unwrap();
+            assert_eq!(found, hash);
+            assert_eq!(payment_state, expected);
+        }
+        assert_eq!(PaymentState::Accepted.as_str(), "accepted");
+
+        // No hold invoice yet, or no order
+        let order = Order {
+            id: Uuid::new_v4(),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+        for order_id in [order.id, Uuid::new_v4()] {
+            assert!(matches!(
+                order_payment_state(&pool, &mut backend, order_id).await,
+                Err(MostroCantDo(CantDoReason::NotFound))
+            ));
+        }
+    }
+}
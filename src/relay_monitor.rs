@@ -0,0 +1,209 @@
+//! Relay connection monitoring.
+//!
+//! When every relay drops, the notifications stop without any error and the
+//! node silently stops receiving messages. The monitor checks the relays at an
+//! interval, reconnects with backoff while none is connected and once one is
+//! back it subscribes again to the gift wraps sent to Mostro, asking for the
+//! events of the outage so messages sent meanwhile are not lost.
+
+use mostro_core::prelude::*;
+use nostr_sdk::prelude::*;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+/// Id of the subscription to the gift wraps sent to Mostro, subscribing
+/// again with it replaces the previous subscription
+pub const GIFT_WRAP_SUBSCRIPTION: &str = "mostro-gift-wraps";
+
+/// Seconds between checks while relays are connected
+const CHECK_INTERVAL_SECS: u64 = 30;
+/// Seconds before the first reconnection attempt, doubled on each attempt
+const RECONNECT_BASE_SECS: u64 = 5;
+/// Longest wait between reconnection attempts
+const RECONNECT_MAX_SECS: u64 = 300;
+
+/// Gift wraps sent to Mostro from now on
+pub fn gift_wrap_filter(mostro_pubkey: PublicKey) -> Filter {
+    Filter::new()
+        .pubkey(mostro_pubkey)
+        .kind(nostr_sdk::Kind::GiftWrap)
+        .limit(0)
+}
+
+/// Connection to the relays, implemented by the Nostr client
+#[tonic::async_trait]
+pub trait RelayLink {
+    async fn connected_relays(&self) -> usize;
+    async fn reconnect(&self);
+    async fn resubscribe(&self, filter: Filter) -> Result<(), MostroError>;
+}
+
+#[tonic::async_trait]
+impl RelayLink for Client {
+    async fn connected_relays(&self) -> usize {
+        self.relays()
+            .await
+            .values()
+            .filter(|relay| relay.is_connected())
+            .count()
+    }
+
+    async fn reconnect(&self) {
+        self.connect().await;
+    }
+
+    async fn resubscribe(&self, filter: Filter) -> Result<(), MostroError> {
+        self.subscribe_with_id(SubscriptionId::new(GIFT_WRAP_SUBSCRIPTION), filter, None)
+            .await
+            .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?;
+        Ok(())
+    }
+}
+
+pub struct RelayMonitor {
+    mostro_pubkey: PublicKey,
+    /// Seconds before the outage asked to the relays, older messages are
+    /// discarded anyway
+    lookback_secs: u64,
+    /// When no relay was connected anymore
+    down_since: Option<Timestamp>,
+    attempts: u32,
+}
+
+impl RelayMonitor {
+    pub fn new(mostro_pubkey: PublicKey, lookback_secs: u64) -> Self {
+        Self {
+            mostro_pubkey,
+            lookback_secs,
+            down_since: None,
+            attempts: 0,
+        }
+    }
+
+    /// Check the relays at `now`, returns how long to wait for the next check
+    pub async fn check(&mut self, link: &impl RelayLink, now: Timestamp) -> Duration {
+        if link.connected_relays().await == 0 {
+            let down_since = *self.down_since.get_or_insert_with(|| {
+                warn!("No relay connected, messages are not received");
+                now
+            });
+            self.attempts += 1;
+            info!(
+                "Reconnecting to relays, attempt {} after {}s without relays",
+                self.attempts,
+                now.as_u64().saturating_sub(down_since.as_u64())
+            );
+            link.reconnect().await;
+            let backoff = RECONNECT_BASE_SECS.saturating_mul(1 << (self.attempts - 1).min(16));
+            return Duration::from_secs(backoff.min(RECONNECT_MAX_SECS));
+        }
+
+        if let Some(down_since) = self.down_since {
+            let since = Timestamp::from(down_since.as_u64().saturating_sub(self.lookback_secs));
+            let filter = Filter::new()
+                .pubkey(self.mostro_pubkey)
+                .kind(nostr_sdk::Kind::GiftWrap)
+                .since(since);
+            match link.resubscribe(filter).await {
+                Ok(()) => {
+                    info!(
+                        "Relays back, subscribed again after a gap of {}s",
+                        now.as_u64().saturating_sub(down_since.as_u64())
+                    );
+                    self.down_since = None;
+                    self.attempts = 0;
+                }
+                // Tried again on the next check
+                Err(e) => error!("Error subscribing again to relays: {e}"),
+            }
+        }
+        Duration::from_secs(CHECK_INTERVAL_SECS)
+    }
+}
+
+/// Watch the relays of `client` until shutdown
+pub async fn monitor_relays(
+    client: Client,
+    mut monitor: RelayMonitor,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        let wait = monitor.check(&client, Timestamp::now()).await;
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = shutdown.changed() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    /// Relays answering the connected count of each check in turn
+    #[derive(Default)]
+    struct MockLink {
+        connected: Mutex<VecDeque<usize>>,
+        reconnects: Mutex<u32>,
+        filters: Mutex<Vec<Filter>>,
+    }
+
+    #[tonic::async_trait]
+    impl RelayLink for MockLink {
+        async fn connected_relays(&self) -> usize {
+            self.connected.lock().unwrap().pop_front().unwrap_or(1)
+        }
+
+        async fn reconnect(&self) {
+            *self.reconnects.lock().unwrap() += 1;
+        }
+
+        async fn resubscribe(&self, filter: Filter) -> Result<(), MostroError> {
+            self.filters.lock().unwrap().push(filter);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscription_resumed_after_disconnect() {
+        let link = MockLink {
+            connected: Mutex::new(VecDeque::from([2, 0, 0, 1, 1])),
+            ..Default::default()
+        };
+        let mostro_pubkey = Keys::generate().public_key();
+        let mut monitor = RelayMonitor::new(mostro_pubkey, 60);
+
+        let interval = Duration::from_secs(CHECK_INTERVAL_SECS);
+        assert_eq!(monitor.check(&link, Timestamp::from(1_000)).await, interval);
+        // Relays drop, reconnections back off
+        assert_eq!(
+            monitor.check(&link, Timestamp::from(1_030)).await,
+            Duration::from_secs(RECONNECT_BASE_SECS)
+        );
+        assert_eq!(
+            monitor.check(&link, Timestamp::from(1_035)).await,
+            Duration::from_secs(RECONNECT_BASE_SECS * 2)
+        );
+        assert_eq!(*link.reconnects.lock().unwrap(), 2);
+        assert!(link.filters.lock().unwrap().is_empty());
+
+        // Back, the subscription is created again covering the outage
+        assert_eq!(monitor.check(&link, Timestamp::from(1_045)).await, interval);
+        {
+            let filters = link.filters.lock().unwrap();
+            assert_eq!(filters.len(), 1);
+            assert_eq!(filters[0].since, Some(Timestamp::from(970)));
+            assert!(filters[0]
+                .kinds
+                .as_ref()
+                .is_some_and(|kinds| kinds.contains(&nostr_sdk::Kind::GiftWrap)));
+        }
+
+        // Nothing to do while relays stay connected
+        monitor.check(&link, Timestamp::from(1_075)).await;
+        assert_eq!(link.filters.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,226 @@
+//! Order events published with per relay confirmation.
+//!
+//! Sending an event succeeds as soon as one relay takes it, so an order can
+//! be missing from most relays without anyone noticing. Order events are sent
+//! checking which relays accepted them: relays rejecting them are tried again
+//! `publish_retries` times, and a warning is logged when fewer than
+//! `publish_quorum` relays accepted.
+
+use crate::config::settings::Settings;
+use mostro_core::prelude::*;
+use nostr_sdk::prelude::*;
+use std::collections::{HashMap, HashSet};
+use tracing::warn;
+
+/// Relays accepting and rejecting an event
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PublishReport {
+    pub accepted: HashSet<RelayUrl>,
+    /// Reason given by each relay rejecting the event
+    pub rejected: HashMap<RelayUrl, String>,
+}
+
+/// Sends events to relays, implemented by the Nostr client
+#[tonic::async_trait]
+pub trait EventPublisher {
+    /// Send `event` to every relay
+    async fn publish(&self, event: &Event) -> Result<PublishReport, MostroError>;
+    /// Send `event` to `relays` only
+    async fn publish_to(
+        &self,
+        relays: Vec<RelayUrl>,
+        event: &Event,
+    ) -> Result<PublishReport, MostroError>;
+}
+
+impl From<Output<EventId>> for PublishReport {
+    fn from(output: Output<EventId>) -> Self {
+        Self {
+            accepted: output.success,
+            rejected: output.failed,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl EventPublisher for Client {
+    async fn publish(&self, event: &Event) -> Result<PublishReport, MostroError> {
+        self.send_event(event)
+            .await
+            .map(PublishReport::from)
+            .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))
+    }
+
+    async fn publish_to(
+        &self,
+        relays: Vec<RelayUrl>,
+        event: &Event,
+    ) -> Result<PublishReport, MostroError> {
+        self.send_event_to(relays, event)
+            .await
+            .map(PublishReport::from)
+            .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))
+    }
+}
+
+/// Send `event`, retrying the relays rejecting it up to `retries` times while
+/// fewer than `quorum` accepted it. A 0 quorum only sends it once.
+pub async fn publish_with_quorum(
+    publisher: &impl EventPublisher,
+    event: &Event,
+    quorum: usize,
+    retries: u32,
+) -> Result<PublishReport, MostroError> {
+    let mut report = publisher.publish(event).await?;
+    let mut attempt = 0;
+    while report.accepted.len() < quorum && !report.rejected.is_empty() && attempt < retries {
+        attempt += 1;
+        let relays: Vec<RelayUrl> = report.rejected.keys().cloned().collect();
+        match publisher.publish_to(relays, event).await {
+            Ok(retried) => {
+                for relay in retried.accepted {
+                    report.rejected.remove(&relay);
+                    report.accepted.insert(relay);
+                }
+                report.rejected.extend(retried.rejected);
+            }
+            // The relays keep their first rejection
+            Err(e) => warn!("Event {}: retry {attempt} failed: {e}", event.id),
+        }
+    }
+    if report.accepted.len() < quorum {
+        let reasons: Vec<String> = report
+            .rejected
+            .iter()
+            .map(|(relay, reason)| format!("{relay}: {reason}"))
+            .collect();
+        warn!(
+            "Event {} accepted by {} relays, below the quorum of {quorum}. Rejected by {}",
+            event.id,
+            report.accepted.len(),
+            reasons.join(", ")
+        );
+    }
+    Ok(report)
+}
+
+/// Publish an order event with the quorum and retries of the settings
+pub async fn publish_order_event(
+    publisher: &impl EventPublisher,
+    event: &Event,
+) -> Result<PublishReport, MostroError> {
+    let mostro_settings = Settings::get_mostro();
+    publish_with_quorum(
+        publisher,
+        event,
+        mostro_settings.publish_quorum,
+        mostro_settings.publish_retries,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Relays where `flaky` rejects the first `rejections` events and
+    /// `down` rejects every event
+    struct MockRelays {
+        healthy: RelayUrl,
+        flaky: RelayUrl,
+        down: RelayUrl,
+        rejections: Mutex<u32>,
+        retried: Mutex<Vec<Vec<RelayUrl>>>,
+    }
+
+    impl MockRelays {
+        fn new(rejections: u32) -> Self {
+            Self {
+                healthy: RelayUrl::parse("wss://relay.one").unwrap(),
+                flaky: RelayUrl::parse("wss://relay.two").unwrap(),
+                down: RelayUrl::parse("wss://relay.three").unwrap(),
+                rejections: Mutex::new(rejections),
+                retried: Mutex::default(),
+            }
+        }
+
+        fn send(&self, relays: &[RelayUrl]) -> PublishReport {
+            let mut report = PublishReport::default();
+            for relay in relays {
+                let mut rejections = self.rejections.lock().unwrap();
+                if *relay == self.down || (*relay == self.flaky && *rejections > 0) {
+                    if *relay == self.flaky {
+                        *rejections -= 1;
+                    }
+                    report
+                        .rejected
+                        .insert(relay.clone(), "rate-limited".to_string());
+                } else {
+                    report.accepted.insert(relay.clone());
+                }
+            }
+            report
+        }
+    }
+
+    #[tonic::async_trait]
+    impl EventPublisher for MockRelays {
+        async fn publish(&self, _event: &Event) -> Result<PublishReport, MostroError> {
+            Ok(self.send(&[self.healthy.clone(), self.flaky.clone(), self.down.clone()]))
+        }
+
+        async fn publish_to(
+            &self,
+            relays: Vec<RelayUrl>,
+            _event: &Event,
+        ) -> Result<PublishReport, MostroError> {
+            self.retried.lock().unwrap().push(relays.clone());
+            Ok(self.send(&relays))
+        }
+    }
+
+    fn order_event() -> Event {
+        EventBuilder::text_note("order")
+            .sign_with_keys(&Keys::generate())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rejecting_relays_retried() {
+        let event = order_event();
+
+        // The flaky relay takes the event on the retry
+        let relays = MockRelays::new(1);
+        let report = publish_with_quorum(&relays, &event, 2, 2).await.unwrap();
+        assert_eq!(
+            report.accepted,
+            HashSet::from([relays.healthy.clone(), relays.flaky.clone()])
+        );
+        assert_eq!(report.rejected.len(), 1);
+        assert!(report.rejected.contains_key(&relays.down));
+        {
+            let retried = relays.retried.lock().unwrap();
+            assert_eq!(retried.len(), 1);
+            assert_eq!(retried[0].len(), 2);
+        }
+
+        // A quorum of every relay can't be reached, the down relay is retried
+        // until retries run out
+        let relays = MockRelays::new(1);
+        let report = publish_with_quorum(&relays, &event, 3, 2).await.unwrap();
+        assert_eq!(report.accepted.len(), 2);
+        assert!(report.rejected.contains_key(&relays.down));
+        {
+            let retried = relays.retried.lock().unwrap();
+            assert_eq!(retried.len(), 2);
+            assert_eq!(retried[1], vec![relays.down.clone()]);
+        }
+
+        // Without quorum the event is sent once
+        let relays = MockRelays::new(1);
+        let report = publish_with_quorum(&relays, &event, 0, 2).await.unwrap();
+        assert_eq!(report.accepted.len(), 1);
+        assert!(relays.retried.lock().unwrap().is_empty());
+    }
+}
@@ -1,7 +1,7 @@
 //! RPC server implementation for admin operations
 
 use crate::config::settings::Settings;
-use crate::lightning::LndConnector;
+use crate::lightning::LightningBackend;
 use crate::rpc::service::AdminServiceImpl;
 use nostr_sdk::Keys;
 use sqlx::{Pool, Sqlite};
@@ -32,7 +32,7 @@ impl RpcServer {
         &self,
         my_keys: Keys,
         pool: Arc<Pool<Sqlite>>,
-        ln_client: Arc<tokio::sync::Mutex<LndConnector>>,
+        ln_client: Arc<tokio::sync::Mutex<Box<dyn LightningBackend>>>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let addr = format!("{}:{}", self.listen_address, self.port)
             .parse()
@@ -1,10 +1,18 @@
 //! RPC service implementation for admin operations
 
-use crate::lightning::LndConnector;
+use crate::app::admin_settle_split::BuyerShare;
+use crate::lightning::LightningBackend;
 use crate::rpc::admin::{
-    admin_service_server::AdminService, AddSolverRequest, AddSolverResponse, CancelOrderRequest,
-    CancelOrderResponse, SettleOrderRequest, SettleOrderResponse, TakeDisputeRequest,
-    TakeDisputeResponse,
+    admin_service_server::AdminService, AddSolverRequest, AddSolverResponse, AnnounceRequest,
+    AnnounceResponse, CancelOrderRequest, CancelOrderResponse, CurrencyTradingResponse,
+    DrainStatusResponse, GetDisabledCurrenciesRequest, GetDrainStatusRequest,
+    GetPaymentStatusRequest, GetPaymentStatusResponse, GetReconciliationReportRequest,
+    GetReconciliationReportResponse, GetSolverStatsRequest, GetSolverStatsResponse,
+    KillSwitchResponse, ListOrdersRequest, ListOrdersResponse, OrderDiscrepancy, OrderSummary,
+    ReassignDisputeRequest, ReassignDisputeResponse, RepublishOrderRequest, RepublishOrderResponse,
+    SetCurrencyTradingRequest, SetDrainModeRequest, SetKillSwitchRequest, SettleOrderRequest,
+    SettleOrderResponse, SettleOrderSplitRequest, SettleOrderSplitResponse, SolverStats,
+    TakeDisputeRequest, TakeDisputeResponse,
 };
 use nostr_sdk::{nips::nip59::UnwrappedGift, Keys};
 use sqlx::{Pool, Sqlite};
@@ -12,18 +20,23 @@ use std::sync::Arc;
 use tonic::{Request, Response, Status};
 use tracing::{error, info};
 
+/// Orders listed when the request doesn't set a limit
+const LIST_ORDERS_DEFAULT_LIMIT: u32 = 50;
+/// Largest page of orders a request can ask for
+const LIST_ORDERS_MAX_LIMIT: u32 = 500;
+
 /// Implementation of the AdminService gRPC service
 pub struct AdminServiceImpl {
     keys: Keys,
     pool: Arc<Pool<Sqlite>>,
-    ln_client: Arc<tokio::sync::Mutex<LndConnector>>,
+    ln_client: Arc<tokio::sync::Mutex<Box<dyn LightningBackend>>>,
 }
 
 impl AdminServiceImpl {
     pub fn new(
         keys: Keys,
         pool: Arc<Pool<Sqlite>>,
-        ln_client: Arc<tokio::sync::Mutex<LndConnector>>,
+        ln_client: Arc<tokio::sync::Mutex<Box<dyn LightningBackend>>>,
     ) -> Self {
         Self {
             keys,
@@ -68,7 +81,7 @@ impl AdminServiceImpl {
         };
 
         let mut ln_client = self.ln_client.lock().await;
-        admin_cancel_action(msg, &event, &self.keys, &self.pool, &mut ln_client)
+        admin_cancel_action(msg, &event, &self.keys, &self.pool, ln_client.as_mut())
             .await
             .map_err(|e| format!("Admin cancel failed: {}", e))?;
 
@@ -107,13 +120,39 @@ impl AdminServiceImpl {
         };
 
         let mut ln_client = self.ln_client.lock().await;
-        admin_settle_action(msg, &event, &self.keys, &self.pool, &mut ln_client)
+        admin_settle_action(msg, &event, &self.keys, &self.pool, ln_client.as_mut())
             .await
             .map_err(|e| format!("Admin settle failed: {}", e))?;
 
         Ok(())
     }
 
+    async fn call_admin_settle_split(
+        &self,
+        order_id: String,
+        share: BuyerShare,
+        buyer_invoice: Option<String>,
+        seller_invoice: String,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::app::admin_settle_split::admin_settle_split_action;
+        use uuid::Uuid;
+
+        let mut ln_client = self.ln_client.lock().await;
+        admin_settle_split_action(
+            Uuid::parse_str(&order_id)?,
+            share,
+            buyer_invoice.as_deref(),
+            &seller_invoice,
+            &self.keys,
+            &self.pool,
+            ln_client.as_mut(),
+        )
+        .await
+        .map_err(|e| format!("Admin split settle failed: {}", e))?;
+
+        Ok(())
+    }
+
     async fn call_admin_add_solver(
         &self,
         solver_pubkey: String,
@@ -188,6 +227,224 @@ impl AdminServiceImpl {
 
         Ok(())
     }
+
+    async fn call_admin_reassign_dispute(
+        &self,
+        dispute_id: String,
+        solver_pubkey: String,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::app::admin_reassign_dispute::admin_reassign_dispute_action;
+        use nostr_sdk::PublicKey;
+        use uuid::Uuid;
+
+        admin_reassign_dispute_action(
+            Uuid::parse_str(&dispute_id)?,
+            PublicKey::parse(&solver_pubkey)?,
+            &self.keys,
+            &self.pool,
+        )
+        .await
+        .map_err(|e| format!("Admin reassign dispute failed: {}", e))?;
+
+        Ok(())
+    }
+
+    async fn call_admin_announce(
+        &self,
+        message: String,
+        severity: String,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::app::admin_announce::{admin_announce_action, Severity};
+
+        admin_announce_action(
+            &message,
+            severity
+                .parse::<Severity>()
+                .map_err(|e| format!("Invalid severity: {}", e))?,
+            &self.keys.public_key(),
+            &self.keys,
+            &self.pool,
+        )
+        .await
+        .map_err(|e| format!("Admin announce failed: {}", e))?;
+
+        Ok(())
+    }
+
+    async fn call_get_solver_stats(
+        &self,
+        solver_pubkey: Option<String>,
+    ) -> Result<Vec<SolverStats>, Box<dyn std::error::Error + Send + Sync>> {
+        use crate::db::find_solver_stats;
+        use nostr_sdk::PublicKey;
+
+        // Accept both hex and bech32 pubkeys
+        let solver_pubkey = solver_pubkey
+            .map(|pk| PublicKey::parse(&pk).map(|pk| pk.to_hex()))
+            .transpose()?;
+
+        let stats = find_solver_stats(&self.pool, solver_pubkey.as_deref())
+            .await
+            .map_err(|e| format!("Get solver stats failed: {}", e))?;
+
+        Ok(stats
+            .into_iter()
+            .map(|s| SolverStats {
+                average_resolution_seconds: s.average_resolution_seconds(),
+                solver_pubkey: s.solver_pubkey,
+                disputes_taken: s.disputes_taken,
+                disputes_resolved: s.disputes_resolved,
+                buyer_favor: s.buyer_favor,
+                seller_favor: s.seller_favor,
+                sla_breaches: s.sla_breaches,
+            })
+            .collect())
+    }
+
+    async fn call_get_reconciliation_report(
+        &self,
+    ) -> Result<Vec<OrderDiscrepancy>, Box<dyn std::error::Error + Send + Sync>> {
+        use crate::config::settings::Settings;
+        use crate::reconciliation::reconciliation_report;
+        use nostr_sdk::Timestamp;
+
+        let days = Settings::get_mostro().reconciliation_report_days;
+        if days == 0 {
+            return Err("Reconciliation report is disabled".into());
+        }
+        let finished_since = Timestamp::now().as_u64() as i64 - days as i64 * 86400;
+
+        let mut ln_client = self.ln_client.lock().await;
+        let report = reconciliation_report(&self.pool, ln_client.as_mut(), finished_since)
+            .await
+            .map_err(|e| format!("Reconciliation report failed: {}", e))?;
+
+        Ok(report
+            .into_iter()
+            .map(|d| OrderDiscrepancy {
+                order_id: d.order_id.to_string(),
+                status: d.status,
+                hold_invoice_state: d.hold_invoice_state,
+                description: d.description,
+            })
+            .collect())
+    }
+
+    async fn call_get_payment_status(
+        &self,
+        order_id: String,
+    ) -> Result<(String, String), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::reconciliation::order_payment_state;
+        use uuid::Uuid;
+
+        let order_id = Uuid::parse_str(&order_id)?;
+        let mut ln_client = self.ln_client.lock().await;
+        let (hash, state) = order_payment_state(&self.pool, ln_client.as_mut(), order_id)
+            .await
+            .map_err(|e| format!("Payment status lookup failed: {}", e))?;
+
+        Ok((hash, state.as_str().to_string()))
+    }
+
+    async fn call_admin_republish(
+        &self,
+        order_id: String,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::app::admin_republish::admin_republish_action;
+        use uuid::Uuid;
+
+        admin_republish_action(
+            Uuid::parse_str(&order_id)?,
+            &self.keys.public_key(),
+            &self.keys,
+            &self.pool,
+        )
+        .await
+        .map_err(|e| format!("Admin republish failed: {}", e))?;
+
+        Ok(())
+    }
+
+    async fn call_list_orders(
+        &self,
+        req: ListOrdersRequest,
+    ) -> Result<Vec<OrderSummary>, Box<dyn std::error::Error + Send + Sync>> {
+        use crate::db::find_orders_by_status;
+        use mostro_core::order::Status as OrderStatus;
+        use std::str::FromStr;
+
+        let status = OrderStatus::from_str(&req.status)
+            .map_err(|_| format!("Invalid order status {}", req.status))?;
+        let limit = req
+            .limit
+            .unwrap_or(LIST_ORDERS_DEFAULT_LIMIT)
+            .min(LIST_ORDERS_MAX_LIMIT);
+        let orders = find_orders_by_status(
+            &self.pool,
+            status,
+            limit as i64,
+            req.offset.unwrap_or_default() as i64,
+        )
+        .await
+        .map_err(|e| format!("List orders failed: {}", e))?;
+
+        Ok(orders
+            .into_iter()
+            .map(|o| OrderSummary {
+                order_id: o.id.to_string(),
+                kind: o.kind,
+                status: o.status,
+                amount: o.amount,
+                fiat_code: o.fiat_code,
+                fiat_amount: o.fiat_amount,
+                created_at: o.created_at,
+            })
+            .collect())
+    }
+
+    async fn currency_trading_response(&self) -> CurrencyTradingResponse {
+        use crate::db::find_disabled_fiat_codes;
+
+        match find_disabled_fiat_codes(&self.pool).await {
+            Ok(disabled_fiat_codes) => CurrencyTradingResponse {
+                success: true,
+                error_message: None,
+                disabled_fiat_codes,
+            },
+            Err(e) => {
+                error!("Get disabled currencies failed: {}", e);
+                CurrencyTradingResponse {
+                    success: false,
+                    error_message: Some(e.to_string()),
+                    disabled_fiat_codes: Vec::new(),
+                }
+            }
+        }
+    }
+
+    async fn drain_status_response(&self) -> DrainStatusResponse {
+        use crate::drain::drain_status;
+
+        match drain_status(&self.pool).await {
+            Ok(status) => DrainStatusResponse {
+                success: true,
+                error_message: None,
+                draining: status.draining,
+                in_flight_orders: status.in_flight_orders,
+                safe_to_stop: status.safe_to_stop(),
+            },
+            Err(e) => {
+                error!("Get drain status failed: {}", e);
+                DrainStatusResponse {
+                    success: false,
+                    error_message: Some(e.to_string()),
+                    draining: crate::drain::is_draining(),
+                    in_flight_orders: 0,
+                    safe_to_stop: false,
+                }
+            }
+        }
+    }
 }
 
 #[tonic::async_trait]
@@ -236,6 +493,41 @@ impl AdminService for AdminServiceImpl {
         }
     }
 
+    async fn settle_order_split(
+        &self,
+        request: Request<SettleOrderSplitRequest>,
+    ) -> Result<Response<SettleOrderSplitResponse>, Status> {
+        let req = request.into_inner();
+        let share = match (req.buyer_bps, req.seller_bps) {
+            (None, None) => BuyerShare::Sats(req.buyer_amount),
+            (buyer, seller) => BuyerShare::BasisPoints {
+                buyer: buyer.unwrap_or_default(),
+                seller: seller.unwrap_or_default(),
+            },
+        };
+        info!(
+            "Received split settle request for order: {}, buyer share: {:?}",
+            req.order_id, share
+        );
+
+        match self
+            .call_admin_settle_split(req.order_id, share, req.buyer_invoice, req.seller_invoice)
+            .await
+        {
+            Ok(()) => Ok(Response::new(SettleOrderSplitResponse {
+                success: true,
+                error_message: None,
+            })),
+            Err(e) => {
+                error!("Split settle order failed: {}", e);
+                Ok(Response::new(SettleOrderSplitResponse {
+                    success: false,
+                    error_message: Some(e.to_string()),
+                }))
+            }
+        }
+    }
+
     async fn add_solver(
         &self,
         request: Request<AddSolverRequest>,
@@ -291,6 +583,259 @@ impl AdminService for AdminServiceImpl {
             }
         }
     }
+
+    async fn get_solver_stats(
+        &self,
+        request: Request<GetSolverStatsRequest>,
+    ) -> Result<Response<GetSolverStatsResponse>, Status> {
+        let req = request.into_inner();
+        info!("Received get solver stats request");
+
+        match self.call_get_solver_stats(req.solver_pubkey).await {
+            Ok(stats) => Ok(Response::new(GetSolverStatsResponse {
+                success: true,
+                error_message: None,
+                stats,
+            })),
+            Err(e) => {
+                error!("Get solver stats failed: {}", e);
+                Ok(Response::new(GetSolverStatsResponse {
+                    success: false,
+                    error_message: Some(e.to_string()),
+                    stats: vec![],
+                }))
+            }
+        }
+    }
+
+    async fn get_reconciliation_report(
+        &self,
+        _request: Request<GetReconciliationReportRequest>,
+    ) -> Result<Response<GetReconciliationReportResponse>, Status> {
+        info!("Received get reconciliation report request");
+
+        match self.call_get_reconciliation_report().await {
+            Ok(discrepancies) => Ok(Response::new(GetReconciliationReportResponse {
+                success: true,
+                error_message: None,
+                discrepancies,
+            })),
+            Err(e) => {
+                error!("Get reconciliation report failed: {}", e);
+                Ok(Response::new(GetReconciliationReportResponse {
+                    success: false,
+                    error_message: Some(e.to_string()),
+                    discrepancies: vec![],
+                }))
+            }
+        }
+    }
+
+    async fn get_payment_status(
+        &self,
+        request: Request<GetPaymentStatusRequest>,
+    ) -> Result<Response<GetPaymentStatusResponse>, Status> {
+        let req = request.into_inner();
+        info!(
+            "Received get payment status request for order: {}",
+            req.order_id
+        );
+
+        match self.call_get_payment_status(req.order_id).await {
+            Ok((hash, state)) => Ok(Response::new(GetPaymentStatusResponse {
+                success: true,
+                error_message: None,
+                hash,
+                state,
+            })),
+            Err(e) => {
+                error!("Get payment status failed: {}", e);
+                Ok(Response::new(GetPaymentStatusResponse {
+                    success: false,
+                    error_message: Some(e.to_string()),
+                    hash: String::new(),
+                    state: String::new(),
+                }))
+            }
+        }
+    }
+
+    async fn republish_order(
+        &self,
+        request: Request<RepublishOrderRequest>,
+    ) -> Result<Response<RepublishOrderResponse>, Status> {
+        let req = request.into_inner();
+        info!(
+            "Received republish order request for order: {}",
+            req.order_id
+        );
+
+        match self.call_admin_republish(req.order_id).await {
+            Ok(()) => Ok(Response::new(RepublishOrderResponse {
+                success: true,
+                error_message: None,
+            })),
+            Err(e) => {
+                error!("Republish order failed: {}", e);
+                Ok(Response::new(RepublishOrderResponse {
+                    success: false,
+                    error_message: Some(e.to_string()),
+                }))
+            }
+        }
+    }
+
+    async fn set_drain_mode(
+        &self,
+        request: Request<SetDrainModeRequest>,
+    ) -> Result<Response<DrainStatusResponse>, Status> {
+        let req = request.into_inner();
+        info!("Received set drain mode request: {}", req.enabled);
+
+        crate::drain::set_draining(req.enabled);
+        Ok(Response::new(self.drain_status_response().await))
+    }
+
+    async fn get_drain_status(
+        &self,
+        _request: Request<GetDrainStatusRequest>,
+    ) -> Result<Response<DrainStatusResponse>, Status> {
+        info!("Received get drain status request");
+
+        Ok(Response::new(self.drain_status_response().await))
+    }
+
+    async fn set_currency_trading(
+        &self,
+        request: Request<SetCurrencyTradingRequest>,
+    ) -> Result<Response<CurrencyTradingResponse>, Status> {
+        use crate::db::set_fiat_code_enabled;
+
+        let req = request.into_inner();
+        info!(
+            "Received set currency trading request: {} {}",
+            req.fiat_code, req.enabled
+        );
+
+        let fiat_code = req.fiat_code.trim();
+        if fiat_code.is_empty() {
+            return Ok(Response::new(CurrencyTradingResponse {
+                success: false,
+                error_message: Some("Missing fiat code".to_string()),
+                disabled_fiat_codes: Vec::new(),
+            }));
+        }
+        let now = nostr_sdk::Timestamp::now().as_u64() as i64;
+        if let Err(e) = set_fiat_code_enabled(&self.pool, fiat_code, req.enabled, now).await {
+            error!("Set currency trading failed: {}", e);
+            return Ok(Response::new(CurrencyTradingResponse {
+                success: false,
+                error_message: Some(e.to_string()),
+                disabled_fiat_codes: Vec::new(),
+            }));
+        }
+        Ok(Response::new(self.currency_trading_response().await))
+    }
+
+    async fn get_disabled_currencies(
+        &self,
+        _request: Request<GetDisabledCurrenciesRequest>,
+    ) -> Result<Response<CurrencyTradingResponse>, Status> {
+        info!("Received get disabled currencies request");
+
+        Ok(Response::new(self.currency_trading_response().await))
+    }
+
+    async fn set_kill_switch(
+        &self,
+        request: Request<SetKillSwitchRequest>,
+    ) -> Result<Response<KillSwitchResponse>, Status> {
+        use crate::kill_switch::{is_kill_switch_engaged, set_kill_switch};
+
+        let req = request.into_inner();
+        info!("Received set kill switch request: {}", req.engaged);
+
+        set_kill_switch(req.engaged);
+        Ok(Response::new(KillSwitchResponse {
+            success: true,
+            error_message: None,
+            engaged: is_kill_switch_engaged(),
+        }))
+    }
+
+    async fn reassign_dispute(
+        &self,
+        request: Request<ReassignDisputeRequest>,
+    ) -> Result<Response<ReassignDisputeResponse>, Status> {
+        let req = request.into_inner();
+        info!(
+            "Received reassign dispute request for dispute: {} to solver: {}",
+            req.dispute_id, req.solver_pubkey
+        );
+
+        match self
+            .call_admin_reassign_dispute(req.dispute_id, req.solver_pubkey)
+            .await
+        {
+            Ok(()) => Ok(Response::new(ReassignDisputeResponse {
+                success: true,
+                error_message: None,
+            })),
+            Err(e) => {
+                error!("Reassign dispute failed: {}", e);
+                Ok(Response::new(ReassignDisputeResponse {
+                    success: false,
+                    error_message: Some(e.to_string()),
+                }))
+            }
+        }
+    }
+
+    async fn announce(
+        &self,
+        request: Request<AnnounceRequest>,
+    ) -> Result<Response<AnnounceResponse>, Status> {
+        let req = request.into_inner();
+        info!("Received announce request with severity: {}", req.severity);
+
+        match self.call_admin_announce(req.message, req.severity).await {
+            Ok(()) => Ok(Response::new(AnnounceResponse {
+                success: true,
+                error_message: None,
+            })),
+            Err(e) => {
+                error!("Announce failed: {}", e);
+                Ok(Response::new(AnnounceResponse {
+                    success: false,
+                    error_message: Some(e.to_string()),
+                }))
+            }
+        }
+    }
+
+    async fn list_orders(
+        &self,
+        request: Request<ListOrdersRequest>,
+    ) -> Result<Response<ListOrdersResponse>, Status> {
+        let req = request.into_inner();
+        info!("Received list orders request for status: {}", req.status);
+
+        match self.call_list_orders(req).await {
+            Ok(orders) => Ok(Response::new(ListOrdersResponse {
+                success: true,
+                error_message: None,
+                orders,
+            })),
+            Err(e) => {
+                error!("List orders failed: {}", e);
+                Ok(Response::new(ListOrdersResponse {
+                    success: false,
+                    error_message: Some(e.to_string()),
+                    orders: vec![],
+                }))
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -299,7 +844,7 @@ mod tests {
 
     // Note: We skip the admin service creation test that requires LND
     // since it would require a real Lightning node connection.
-    // In a production environment, you would mock the LndConnector.
+    // In a production environment, you would mock the LightningBackend.
 
     #[test]
     fn test_rpc_request_response_structure() {
@@ -359,6 +904,87 @@ mod tests {
         assert!(take_dispute_resp.success);
     }
 
+    #[tokio::test]
+    async fn test_get_solver_stats() {
+        use crate::db::{add_solver_dispute_resolved, add_solver_dispute_taken, test_pool};
+        use crate::lightning::mock::MockBackend;
+        use nostr_sdk::ToBech32;
+
+        let pool = test_pool().await;
+        let solver = Keys::generate().public_key();
+        let other = Keys::generate().public_key();
+        for _ in 0..3 {
+            add_solver_dispute_taken(&pool, &solver.to_hex())
+                .await
+                .unwrap();
+        }
+        add_solver_dispute_resolved(&pool, &solver.to_hex(), 400, true, false)
+            .await
+            .unwrap();
+        add_solver_dispute_resolved(&pool, &solver.to_hex(), 800, false, true)
+            .await
+            .unwrap();
+        add_solver_dispute_taken(&pool, &other.to_hex())
+            .await
+            .unwrap();
+
+        let ln_client: Box<dyn LightningBackend> = Box::new(MockBackend::default());
+        let service = AdminServiceImpl::new(
+            Keys::generate(),
+            Arc::new(pool),
+            Arc::new(tokio::sync::Mutex::new(ln_client)),
+        );
+
+        // A solver given by npub
+        let resp = service
+            .get_solver_stats(Request::new(GetSolverStatsRequest {
+                solver_pubkey: Some(solver.to_bech32().unwrap()),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(resp.success);
+        assert_eq!(
+            resp.stats,
+            vec![SolverStats {
+                solver_pubkey: solver.to_hex(),
+                disputes_taken: 3,
+                disputes_resolved: 2,
+                average_resolution_seconds: 600,
+                buyer_favor: 1,
+                seller_favor: 1,
+                sla_breaches: 1,
+            }]
+        );
+
+        // Every solver
+        let resp = service
+            .get_solver_stats(Request::new(GetSolverStatsRequest {
+                solver_pubkey: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(resp.success);
+        assert_eq!(resp.stats.len(), 2);
+        assert!(resp
+            .stats
+            .iter()
+            .any(|s| s.solver_pubkey == other.to_hex() && s.disputes_resolved == 0));
+
+        // An invalid pubkey is reported in the response
+        let resp = service
+            .get_solver_stats(Request::new(GetSolverStatsRequest {
+                solver_pubkey: Some("npub1...".to_string()),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!resp.success);
+        assert!(resp.error_message.is_some());
+        assert!(resp.stats.is_empty());
+    }
+
     #[test]
     fn test_error_response_creation() {
         let error_resp = CancelOrderResponse {
@@ -1,8 +1,14 @@
+use crate::app::dispute::{
+    escalate_unassigned_disputes, open_automatic_dispute, open_stale_trade_dispute,
+};
 use crate::app::release::do_payment;
 use crate::bitcoin_price::BitcoinPriceManager;
 use crate::config;
 use crate::db::*;
-use crate::lightning::LndConnector;
+use crate::flow;
+use crate::kill_switch::check_funds_movable;
+use crate::lightning::connect_backend;
+use crate::order_locks::{lock_and_reload, lock_order};
 use crate::util;
 use crate::util::get_nostr_client;
 use crate::LN_STATUS;
@@ -11,11 +17,11 @@ use crate::{Keys, PublicKey};
 use chrono::{TimeDelta, Utc};
 use config::*;
 use mostro_core::prelude::*;
-use nostr_sdk::EventBuilder;
+use nostr_sdk::{EventBuilder, Timestamp};
 use nostr_sdk::{Kind as NostrKind, Tag};
 use sqlx_crud::Crud;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{watch, RwLock};
 use tracing::{error, info};
 use util::{get_keys, get_nostr_relays, send_dm, update_order_event};
 
@@ -26,14 +32,230 @@ pub async fn start_scheduler() {
     job_update_rate_events().await;
     job_cancel_orders().await;
     job_retry_failed_payments().await;
+    job_retry_split_legs().await;
     job_info_event_send().await;
     job_relay_list().await;
     job_update_bitcoin_prices().await;
     job_flush_messages_queue().await;
+    job_expire_order_reservations().await;
+    job_republish_expired_hold_invoices().await;
+    job_dispute_overdue_orders().await;
+    job_dispute_stale_active_trades().await;
+    job_escalate_unassigned_disputes().await;
+    crate::metrics::job_push_metrics().await;
+    #[cfg(feature = "metrics")]
+    crate::metrics::job_serve_metrics().await;
+    #[cfg(feature = "metrics")]
+    crate::metrics::job_serve_health().await;
 
     info!("Scheduler Started");
 }
 
+/// Republish orders whose hold invoice expired or was canceled in the node
+/// while waiting for the seller payment
+async fn job_republish_expired_hold_invoices() {
+    let pool = get_db_pool();
+    let keys = match get_keys() {
+        Ok(keys) => keys,
+        Err(e) => return error!("{e}"),
+    };
+    let mut ln_client = match connect_backend().await {
+        Ok(client) => client,
+        Err(e) => return error!("{e}"),
+    };
+
+    tokio::spawn(async move {
+        loop {
+            match flow::find_expired_hold_invoice_orders(&pool, ln_client.as_mut()).await {
+                Ok(orders) => {
+                    for order in orders {
+                        if let Err(e) = flow::hold_invoice_expired(&pool, &keys, order).await {
+                            error!("{e}");
+                        }
+                    }
+                }
+                Err(e) => error!("{e}"),
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+        }
+    });
+}
+
+/// Seconds an order has to complete once funds are held. Capped to half the
+/// hold invoice lifetime, about 10 minutes per block, so solvers have time
+/// to act before funds go back to the seller.
+fn completion_deadline_secs(configured: u64, hold_invoice_cltv_delta: u32) -> u64 {
+    configured.min(hold_invoice_cltv_delta as u64 * 600 / 2)
+}
+
+/// Orders disputed automatically once a time limit passes
+#[derive(Debug, Clone, Copy)]
+enum DisputeSweep {
+    /// Funds held past the completion deadline
+    CompletionDeadline,
+    /// Active trades where the buyer didn't send fiat
+    StaleActiveTrade,
+}
+
+/// Every minute, open a dispute on the orders of `sweep` past `secs` seconds
+fn spawn_dispute_sweep(sweep: DisputeSweep, secs: u64) {
+    let pool = get_db_pool();
+    let keys = match get_keys() {
+        Ok(keys) => keys,
+        Err(e) => return error!("{e}"),
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let cutoff = Utc::now().timestamp() - secs as i64;
+            let found = match sweep {
+                DisputeSweep::CompletionDeadline => {
+                    find_orders_past_completion_deadline(&pool, cutoff).await
+                }
+                DisputeSweep::StaleActiveTrade => find_stale_active_orders(&pool, cutoff).await,
+            };
+            match found {
+                Ok(orders) => {
+                    for order in orders {
+                        let opened = match sweep {
+                            DisputeSweep::CompletionDeadline => {
+                                info!(
+                                    "Order Id {}: completion deadline passed, opening dispute",
+                                    order.id
+                                );
+                                open_automatic_dispute(&pool, &keys, order).await
+                            }
+                            DisputeSweep::StaleActiveTrade => {
+                                info!(
+                                    "Order Id {}: active without fiat sent for {} seconds, opening dispute",
+                                    order.id, secs
+                                );
+                                open_stale_trade_dispute(&pool, &keys, order, secs).await
+                            }
+                        };
+                        if let Err(e) = opened {
+                            error!("{e}");
+                        }
+                    }
+                }
+                Err(e) => error!("{e}"),
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+        }
+    });
+}
+
+/// Open a dispute on orders that didn't complete before their deadline
+async fn job_dispute_overdue_orders() {
+    let deadline = completion_deadline_secs(
+        Settings::get_mostro().completion_deadline_secs,
+        Settings::get_ln().hold_invoice_cltv_delta,
+    );
+    if deadline == 0 {
+        return;
+    }
+    spawn_dispute_sweep(DisputeSweep::CompletionDeadline, deadline);
+}
+
+/// Open a dispute on active orders where the buyer didn't send fiat in
+/// `active_trade_timeout_secs`
+async fn job_dispute_stale_active_trades() {
+    let timeout = Settings::get_mostro().active_trade_timeout_secs;
+    if timeout == 0 {
+        return;
+    }
+    spawn_dispute_sweep(DisputeSweep::StaleActiveTrade, timeout);
+}
+
+/// Tell the solvers about disputes nobody took for `dispute_escalation_secs`
+async fn job_escalate_unassigned_disputes() {
+    let escalation_secs = Settings::get_mostro().dispute_escalation_secs;
+    if escalation_secs == 0 {
+        return;
+    }
+    let pool = get_db_pool();
+
+    tokio::spawn(async move {
+        loop {
+            let now = Utc::now().timestamp();
+            match escalate_unassigned_disputes(&pool, now, escalation_secs).await {
+                Ok(escalations) => {
+                    for escalation in escalations {
+                        info!(
+                            "Order Id {}: dispute unassigned for {} seconds, notifying solvers",
+                            escalation.order_id, escalation.waiting_secs
+                        );
+                        for solver in &escalation.solvers {
+                            util::enqueue_order_msg(
+                                None,
+                                Some(escalation.order_id),
+                                Action::Dispute,
+                                Some(Payload::TextMessage(escalation.text())),
+                                *solver,
+                                None,
+                            )
+                            .await;
+                        }
+                    }
+                }
+                Err(e) => error!("{e}"),
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+        }
+    });
+}
+
+/// Release expired order reservations, orders still pending go back to the book
+async fn job_expire_order_reservations() {
+    let pool = get_db_pool();
+    let keys = match get_keys() {
+        Ok(keys) => keys,
+        Err(e) => return error!("{e}"),
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let now = Utc::now().timestamp();
+            match remove_expired_order_reservations(&pool, now).await {
+                Ok(order_ids) => {
+                    for order_id in order_ids {
+                        let Ok((_order_lock, Some(order))) = lock_and_reload(&pool, order_id).await
+                        else {
+                            continue;
+                        };
+                        // Orders taken meanwhile are not pending anymore
+                        if order.check_status(Status::Pending).is_err() {
+                            continue;
+                        }
+                        info!("Order Id {}: reservation expired, republishing", order.id);
+                        if let Ok(order_updated) =
+                            update_order_event(&keys, Status::Pending, &order).await
+                        {
+                            let _ = order_updated.update(&*pool).await;
+                        }
+                    }
+                }
+                Err(e) => error!("{e}"),
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+        }
+    });
+}
+
+/// Wait for the queued messages to be sent by the flush job, up to `timeout`
+pub async fn drain_messages_queue(timeout: std::time::Duration) {
+    let started = std::time::Instant::now();
+    while started.elapsed() < timeout {
+        if MESSAGE_QUEUES.queue_order_msg.read().await.is_empty()
+            && MESSAGE_QUEUES.queue_order_cantdo.read().await.is_empty()
+        {
+            return;
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(250)).await;
+    }
+    error!("Shutting down with unsent messages in the queue");
+}
+
 async fn job_flush_messages_queue() {
     // Clone for closure owning with Arc
     let order_msg_list = MESSAGE_QUEUES.queue_order_msg.clone();
@@ -155,6 +377,17 @@ async fn job_info_event_send() {
 
             let _ = client.send_event(&info_ev).await;
 
+            // Order templates clients use to pre-fill order forms
+            let templates = &Settings::get_mostro().order_templates;
+            if !templates.is_empty() {
+                match crate::nip33::order_templates_event(&mostro_keys, templates) {
+                    Ok(templates_ev) => {
+                        let _ = client.send_event(&templates_ev).await;
+                    }
+                    Err(e) => error!("{e}"),
+                }
+            }
+
             tokio::time::sleep(tokio::time::Duration::from_secs(interval)).await;
         }
     });
@@ -176,9 +409,22 @@ async fn job_retry_failed_payments() {
             );
 
             if let Ok(payment_failed_list) = crate::db::find_failed_payment(&pool).await {
-                for payment_failed in payment_failed_list.into_iter() {
+                for found in payment_failed_list.into_iter() {
+                    // Not paid again if the buyer asked for the payment meanwhile
+                    let Ok((_order_lock, Some(payment_failed))) =
+                        lock_and_reload(&pool, found.id).await
+                    else {
+                        continue;
+                    };
+                    if !payment_failed.failed_payment
+                        || payment_failed
+                            .check_status(Status::SettledHoldInvoice)
+                            .is_err()
+                    {
+                        continue;
+                    }
                     if payment_failed.payment_attempts < retries_number {
-                        if let Err(e) = do_payment(payment_failed.clone(), None).await {
+                        if let Err(e) = do_payment(payment_failed, None).await {
                             error!("{e}");
                         }
                     }
@@ -189,6 +435,33 @@ async fn job_retry_failed_payments() {
     });
 }
 
+/// Retry the payments of split dispute settlements which failed
+async fn job_retry_split_legs() {
+    let ln_settings = Settings::get_ln();
+    let retries_number = ln_settings.payment_attempts as i64;
+    let interval = ln_settings.payment_retries_interval as u64;
+    let pool = get_db_pool();
+    let mut ln_client = match connect_backend().await {
+        Ok(client) => client,
+        Err(e) => return error!("{e}"),
+    };
+
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = crate::app::admin_settle_split::retry_split_legs(
+                &pool,
+                ln_client.as_mut(),
+                retries_number,
+            )
+            .await
+            {
+                error!("{e}");
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(interval)).await;
+        }
+    });
+}
+
 async fn job_update_rate_events() {
     // Clone for closure owning with Arc
     let queue_order_rate = MESSAGE_QUEUES.queue_order_rate.clone();
@@ -242,10 +515,10 @@ async fn job_cancel_orders() {
         }
     };
 
-    let mut ln_client = if let Ok(client) = LndConnector::new().await {
+    let mut ln_client = if let Ok(client) = connect_backend().await {
         client
     } else {
-        return error!("Failed to create LND client");
+        return error!("Failed to create lightning client");
     };
     let mostro_settings = Settings::get_mostro();
     let exp_seconds = mostro_settings.expiration_seconds;
@@ -255,12 +528,26 @@ async fn job_cancel_orders() {
             info!("Check for order to republish for late actions of users");
 
             if let Ok(older_orders_list) = crate::db::find_order_by_seconds(&pool).await {
-                for order in older_orders_list.into_iter() {
+                for found in older_orders_list.into_iter() {
+                    // Left as it is when it moved since it was found
+                    let Ok((_order_lock, Some(order))) = lock_and_reload(&pool, found.id).await
+                    else {
+                        continue;
+                    };
+                    if order.status != found.status || order.taken_at != found.taken_at {
+                        continue;
+                    }
                     // Check if order is a sell order and Buyer is not sending the invoice for too much time.
                     // Same if seller is not paying hold invoice
                     if order.status == Status::WaitingBuyerInvoice.to_string()
                         || order.status == Status::WaitingPayment.to_string()
                     {
+                        // The order waits until its hold invoice can be canceled
+                        if order.hash.is_some()
+                            && check_funds_movable("hold invoice cancellation").is_err()
+                        {
+                            continue;
+                        }
                         // If hold invoice is paid return funds to seller
                         // We return funds to seller
                         if let Some(hash) = order.hash.as_ref() {
@@ -272,10 +559,7 @@ async fn job_cancel_orders() {
                         let mut order = order.clone();
                         // We re-publish the event with Pending status
                         // and update on local database
-                        if order.price_from_api {
-                            order.amount = 0;
-                            order.fee = 0;
-                        }
+                        util::reset_api_quotes(&mut order);
 
                         // Initialize reset status to pending, change in case of specifici needs of order
                         let mut new_status = Status::Pending;
@@ -337,6 +621,23 @@ async fn job_cancel_orders() {
                             };
                             info!("Order Id {}: Reset to status {:?}", &order.id, new_status);
                         }
+                        // Orders abandoned too many times are canceled instead of republished
+                        if new_status == Status::Pending {
+                            match util::republish_limit_reached(&pool, order.id).await {
+                                Ok(true) => {
+                                    if let Err(e) = util::cancel_republish_capped_order(
+                                        &pool, &keys, &order, None,
+                                    )
+                                    .await
+                                    {
+                                        error!("{e}");
+                                    }
+                                    continue;
+                                }
+                                Ok(false) => {}
+                                Err(e) => error!("{e}"),
+                            }
+                        }
                         if new_status == Status::Pending {
                             let _ = update_order_to_initial_state(
                                 &pool,
@@ -392,6 +693,14 @@ async fn job_expire_pending_older_orders() {
             info!("Check older orders and mark them Expired - check is done every minute");
             if let Ok(older_orders_list) = crate::db::find_order_by_date(&pool).await {
                 for order in older_orders_list.iter() {
+                    // Orders taken meanwhile are not pending anymore
+                    let Ok((_order_lock, Some(order))) = lock_and_reload(&pool, order.id).await
+                    else {
+                        continue;
+                    };
+                    if order.check_status(Status::Pending).is_err() {
+                        continue;
+                    }
                     tracing::info!(
                         "Order id {} - created at {} is expired",
                         order.id,
@@ -399,7 +708,7 @@ async fn job_expire_pending_older_orders() {
                     );
                     // We update the order id with the new event_id
                     if let Ok(order_updated) =
-                        crate::util::update_order_event(&keys, Status::Expired, order).await
+                        crate::util::update_order_event(&keys, Status::Expired, &order).await
                     {
                         let _ = order_updated.update(&pool).await;
                     }
@@ -419,6 +728,77 @@ async fn job_expire_pending_older_orders() {
     });
 }
 
+/// Cancel orders pending for more than `pending_order_ttl_secs` and notify
+/// their creators, stops when the shutdown signal is received
+pub async fn job_cancel_stale_pending_orders(mut shutdown: watch::Receiver<bool>) {
+    let ttl = Settings::get_mostro().pending_order_ttl_secs;
+    if ttl == 0 {
+        return;
+    }
+    let pool = get_db_pool();
+    let keys = match get_keys() {
+        Ok(keys) => keys,
+        Err(e) => return error!("{e}"),
+    };
+
+    loop {
+        let pending_before = Timestamp::now().as_u64().saturating_sub(ttl) as i64;
+        match find_stale_pending_orders(&pool, pending_before).await {
+            Ok(order_ids) => {
+                for order_id in order_ids {
+                    // Canceled under the lock, unless a taker got it meanwhile
+                    let _order_lock = lock_order(Some(order_id)).await;
+                    let order =
+                        match cancel_stale_pending_order(&pool, order_id, pending_before).await {
+                            Ok(Some(order)) => order,
+                            Ok(None) => continue,
+                            Err(e) => {
+                                error!("{e}");
+                                continue;
+                            }
+                        };
+                    info!(
+                        "Order Id {}: pending for more than {} seconds, canceling",
+                        order.id, ttl
+                    );
+                    if let Ok(order_updated) =
+                        update_order_event(&keys, Status::Canceled, &order).await
+                    {
+                        let _ = order_updated.update(&*pool).await;
+                    }
+                    match order.get_creator_pubkey() {
+                        Ok(creator_pubkey) => {
+                            let trade_index = match order.is_sell_order() {
+                                Ok(_) => order.trade_index_seller,
+                                Err(_) => order.trade_index_buyer,
+                            };
+                            util::enqueue_order_msg(
+                                None,
+                                Some(order.id),
+                                Action::Canceled,
+                                None,
+                                creator_pubkey,
+                                trade_index,
+                            )
+                            .await;
+                        }
+                        Err(e) => error!("Order Id {}: {e}", order.id),
+                    }
+                }
+            }
+            Err(e) => error!("{e}"),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(60)) => {}
+            _ = shutdown.changed() => {
+                info!("Stopping stale pending orders sweep");
+                return;
+            }
+        }
+    }
+}
+
 async fn job_update_bitcoin_prices() {
     tokio::spawn(async {
         loop {
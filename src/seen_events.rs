@@ -0,0 +1,94 @@
+//! Recently handled gift wraps.
+//!
+//! Relays connected through several connections can deliver the same gift
+//! wrap more than once. Outer event ids are remembered for a while so copies
+//! are discarded before unwrapping them.
+
+use nostr_sdk::EventId;
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct SeenEvents {
+    capacity: usize,
+    ttl: Duration,
+    ids: HashSet<EventId>,
+    arrivals: VecDeque<(Instant, EventId)>,
+}
+
+impl SeenEvents {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            ids: HashSet::new(),
+            arrivals: VecDeque::new(),
+        }
+    }
+
+    /// Remember the event, returns false if it was already seen
+    pub fn insert(&mut self, id: EventId, now: Instant) -> bool {
+        // Forget expired ids, and the oldest ones when full
+        while let Some((arrival, oldest)) = self.arrivals.front().copied() {
+            if now.duration_since(arrival) < self.ttl && self.arrivals.len() < self.capacity {
+                break;
+            }
+            self.arrivals.pop_front();
+            self.ids.remove(&oldest);
+        }
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.arrivals.push_back((now, id));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::{EventBuilder, Keys};
+
+    #[test]
+    fn test_duplicate_event_handled_once() {
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("wrap")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let mut seen = SeenEvents::new(100, Duration::from_secs(600));
+        let now = Instant::now();
+
+        // The same event delivered by two relays
+        let mut handled = 0;
+        for delivery in [event.clone(), event.clone()] {
+            if seen.insert(delivery.id, now) {
+                handled += 1;
+            }
+        }
+        assert_eq!(handled, 1);
+
+        // Forgotten once the ttl passes
+        assert!(seen.insert(event.id, now + Duration::from_secs(601)));
+    }
+
+    #[test]
+    fn test_capacity_bounded() {
+        let keys = Keys::generate();
+        let mut seen = SeenEvents::new(2, Duration::from_secs(600));
+        let now = Instant::now();
+        let ids: Vec<EventId> = (0..3)
+            .map(|i| {
+                EventBuilder::text_note(format!("wrap {i}"))
+                    .sign_with_keys(&keys)
+                    .unwrap()
+                    .id
+            })
+            .collect();
+        for id in &ids {
+            assert!(seen.insert(*id, now));
+        }
+        assert!(seen.ids.len() <= 2);
+        // The oldest id was dropped to make room
+        assert!(seen.insert(ids[0], now));
+    }
+}
@@ -5,13 +5,15 @@ use crate::config::*;
 use crate::db;
 use crate::db::is_user_present;
 use crate::flow;
+use crate::kill_switch::check_funds_movable;
 use crate::lightning;
 use crate::lightning::invoice::is_valid_invoice;
-use crate::lightning::LndConnector;
+use crate::lightning::LightningBackend;
 use crate::lnurl::HTTP_CLIENT;
 use crate::messages;
-use crate::models::Yadio;
-use crate::nip33::{new_event, order_to_tags};
+use crate::models::{PaymentMethod, UserStats, Yadio};
+use crate::nip33::{self, new_event, order_to_tags};
+use crate::relay_publish::publish_order_event;
 use crate::NOSTR_CLIENT;
 
 use chrono::Duration;
@@ -26,7 +28,6 @@ use sqlx_crud::Crud;
 use std::collections::HashMap;
 use std::fmt::Write;
 use std::str::FromStr;
-use std::thread;
 use tokio::sync::mpsc::channel;
 use tracing::info;
 use uuid::Uuid;
@@ -68,8 +69,10 @@ pub async fn retries_yadio_request(
     Ok((Some(res), fiat_list_check))
 }
 
-pub fn get_bitcoin_price(fiat_code: &str) -> Result<f64, MostroError> {
-    BitcoinPriceManager::get_price(fiat_code)
+/// Cached price of the currency, fetched from the price providers when the
+/// cache doesn't have it yet
+pub async fn get_bitcoin_price(fiat_code: &str) -> Result<f64, MostroError> {
+    BitcoinPriceManager::get_price_or_fetch(fiat_code).await
 }
 
 /// Request market quote from Yadio to have sats amount at actual market price
@@ -104,7 +107,7 @@ pub async fn get_market_quote(
                     "API price request failed retrying - {} tentatives left.",
                     (MAX_RETRY - retries_num)
                 );
-                thread::sleep(std::time::Duration::from_secs(2));
+                tokio::time::sleep(std::time::Duration::from_secs(2)).await;
             }
         };
     }
@@ -146,10 +149,46 @@ pub async fn get_market_quote(
 }
 
 pub fn get_fee(amount: i64) -> i64 {
-    let mostro_settings = Settings::get_mostro();
-    // We calculate the bot fee
-    let split_fee = (mostro_settings.fee * amount as f64) / 2.0;
-    split_fee.round() as i64
+    FeePolicy::from_settings(Settings::get_mostro()).split_fee(amount)
+}
+
+/// How the bot fee of a trade is computed: a percentage of the amount plus
+/// a flat amount, kept between a min and a max when they are set
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FeePolicy {
+    pub rate: f64,
+    pub flat_sats: u64,
+    /// 0 leaves the fee without a min
+    pub min_sats: u64,
+    /// 0 leaves the fee without a max
+    pub max_sats: u64,
+}
+
+impl FeePolicy {
+    pub fn from_settings(settings: &MostroSettings) -> Self {
+        Self {
+            rate: settings.fee,
+            flat_sats: settings.fee_flat_sats,
+            min_sats: settings.fee_min_sats,
+            max_sats: settings.fee_max_sats,
+        }
+    }
+
+    /// Half of the bot fee for an amount, each party pays one half. Orders
+    /// without an amount yet have no fee
+    pub fn split_fee(&self, amount: i64) -> i64 {
+        if amount <= 0 {
+            return 0;
+        }
+        let mut fee = self.rate * amount as f64 + self.flat_sats as f64;
+        if self.min_sats > 0 {
+            fee = fee.max(self.min_sats as f64);
+        }
+        if self.max_sats > 0 {
+            fee = fee.min(self.max_sats as f64);
+        }
+        (fee / 2.0).round() as i64
+    }
 }
 
 /// Calculates the expiration timestamp for an order.
@@ -227,16 +266,18 @@ pub async fn get_tags_for_new_order(
 ) -> Result<Option<Tags>, MostroError> {
     match is_user_present(pool, identity_pubkey.to_string()).await {
         Ok(user) => {
+            let trade_stats = db::get_user_stats(pool, &identity_pubkey.to_string()).await?;
             // We transform the order fields to tags to use in the event
             order_to_tags(
                 new_order_db,
                 Some((user.total_rating, user.total_reviews, user.created_at)),
+                trade_stats,
             )
         }
         Err(_) => {
             // We transform the order fields to tags to use in the event
             if identity_pubkey == trade_pubkey {
-                order_to_tags(new_order_db, Some((0.0, 0, 0)))
+                order_to_tags(new_order_db, Some((0.0, 0, 0)), UserStats::default())
             } else {
                 Err(MostroInternalErr(ServiceError::InvalidPubkey))
             }
@@ -249,12 +290,15 @@ pub async fn get_tags_for_new_order(
 ///
 /// This asynchronous function performs the following steps:
 /// - Prepares a new order record from the provided order data and public keys.
-/// - Inserts the new order into the database.
+/// - Inserts the new order into the database with the event it was created from.
 /// - Determines order tags based on privacy settings using `check_full_privacy_order`.
 /// - Constructs and publishes a Nostr event representing the order.
 /// - Updates the order record with the generated event ID.
 /// - Enqueues an acknowledgement message for the order.
 ///
+/// Returns the id of the new order, or None when an order was already created
+/// from `creation_event_id`.
+///
 /// # Examples
 ///
 /// ```rust
@@ -273,8 +317,9 @@ pub async fn get_tags_for_new_order(
 /// let trade_pubkey = /* trade public key */;
 /// let request_id = Some(100);
 /// let trade_index = Some(1);
+/// let creation_event_id = "<id of the rumor event>";
 ///
-/// publish_order(&pool, &keys, &new_order, initiator_pubkey, identity_pubkey, trade_pubkey, request_id, trade_index).await?;
+/// publish_order(&pool, &keys, &new_order, initiator_pubkey, identity_pubkey, trade_pubkey, request_id, trade_index, Vec::new(), &creation_event_id).await?;
 /// # Ok(())
 /// # }
 /// ```
@@ -287,7 +332,9 @@ pub async fn publish_order(
     trade_pubkey: PublicKey,
     request_id: Option<u64>,
     trade_index: Option<i64>,
-) -> Result<(), MostroError> {
+    extra_tags: Vec<Tag>,
+    creation_event_id: &str,
+) -> Result<Option<Uuid>, MostroError> {
     // Prepare a new default order
     let new_order_db = match prepare_new_order(
         new_order,
@@ -304,12 +351,12 @@ pub async fn publish_order(
         }
     };
 
-    // CRUD order creation
-    let mut order = new_order_db
-        .clone()
-        .create(pool)
-        .await
-        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+    // A copy of the same event may be creating the order meanwhile
+    let Some(mut order) =
+        db::create_order_from_event(pool, new_order_db.clone(), creation_event_id).await?
+    else {
+        return Ok(None);
+    };
     let order_id = order.id;
     info!("New order saved Id: {}", order_id);
 
@@ -318,6 +365,7 @@ pub async fn publish_order(
     let event = if let Some(tags) =
         get_tags_for_new_order(&new_order_db, pool, &identity_pubkey, &trade_pubkey).await?
     {
+        let tags = Tags::from_list(tags.into_iter().chain(extra_tags).collect());
         new_event(keys, "", order_id.to_string(), tags)
             .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?
     } else {
@@ -347,13 +395,14 @@ pub async fn publish_order(
     )
     .await;
 
-    NOSTR_CLIENT
-        .get()
-        .unwrap()
-        .send_event(&event)
-        .await
-        .map(|_s| ())
-        .map_err(|err| MostroInternalErr(ServiceError::NostrError(err.to_string())))
+    let client = get_nostr_client()?;
+    let report = publish_order_event(client, &event).await?;
+    if report.accepted.is_empty() {
+        return Err(MostroInternalErr(ServiceError::NostrError(format!(
+            "Order Id {order_id}: no relay accepted the order event"
+        ))));
+    }
+    Ok(Some(order_id))
 }
 
 async fn prepare_new_order(
@@ -428,6 +477,34 @@ async fn prepare_new_order(
     Ok(new_order_db)
 }
 
+/// Longest operator name attached to outgoing messages
+const MAX_OPERATOR_NAME_CHARS: usize = 32;
+
+/// Tag identifying the operator in outgoing messages, none when no name is
+/// configured. Names longer than 32 characters are refused.
+pub fn operator_tag(operator_name: &str) -> Result<Option<Tag>, MostroError> {
+    let operator_name = operator_name.trim();
+    if operator_name.is_empty() {
+        return Ok(None);
+    }
+    if operator_name.chars().count() > MAX_OPERATOR_NAME_CHARS {
+        return Err(MostroInternalErr(ServiceError::UnexpectedError(format!(
+            "Operator name must be at most {MAX_OPERATOR_NAME_CHARS} characters"
+        ))));
+    }
+    Ok(Some(Tag::custom(
+        TagKind::Custom(std::borrow::Cow::Borrowed("operator")),
+        vec![operator_name.to_string()],
+    )))
+}
+
+/// Rumor of a direct message from Mostro, clients can show the operator tag
+fn dm_rumor(sender_pubkey: PublicKey, content: String, operator: Option<Tag>) -> UnsignedEvent {
+    EventBuilder::text_note(content)
+        .tags(operator)
+        .build(sender_pubkey)
+}
+
 pub async fn send_dm(
     receiver_pubkey: PublicKey,
     sender_keys: &Keys,
@@ -446,8 +523,11 @@ pub async fn send_dm(
     let content = (message, Option::<String>::None);
     let content = serde_json::to_string(&content)
         .map_err(|_| MostroInternalErr(ServiceError::MessageSerializationError))?;
-    // We create the rumor
-    let rumor = EventBuilder::text_note(content).build(sender_keys.public_key());
+    // We create the rumor, the operator name is validated at startup
+    let operator = operator_tag(&Settings::get_mostro().operator_name)
+        .ok()
+        .flatten();
+    let rumor = dm_rumor(sender_keys.public_key(), content, operator);
     let mut tags: Vec<Tag> = Vec::with_capacity(1 + usize::from(expiration.is_some()));
 
     if let Some(timestamp) = expiration {
@@ -518,7 +598,7 @@ pub async fn update_user_rating_event(
 async fn get_ratings_for_pending_order(
     order_updated: &Order,
     status: Status,
-) -> Result<Option<(f64, i64, i64)>, MostroError> {
+) -> Result<Option<((f64, i64, i64), UserStats)>, MostroError> {
     if status == Status::Pending {
         let identity_pubkey = match order_updated.is_sell_order() {
             Ok(_) => order_updated
@@ -538,15 +618,15 @@ async fn get_ratings_for_pending_order(
                 .map_err(MostroInternalErr)?,
         };
 
-        match is_user_present(&get_db_pool(), identity_pubkey.clone()).await {
+        let pool = get_db_pool();
+        match is_user_present(&pool, identity_pubkey.clone()).await {
             Ok(user) => Ok(Some((
-                user.total_rating,
-                user.total_reviews,
-                user.created_at,
+                (user.total_rating, user.total_reviews, user.created_at),
+                db::get_user_stats(&pool, &identity_pubkey).await?,
             ))),
             Err(_) => {
                 if identity_pubkey == trade_pubkey.to_string() {
-                    Ok(Some((0.0, 0, 0)))
+                    Ok(Some(((0.0, 0, 0), UserStats::default())))
                 } else {
                     Err(MostroInternalErr(ServiceError::InvalidPubkey))
                 }
@@ -557,6 +637,32 @@ async fn get_ratings_for_pending_order(
     }
 }
 
+/// Events of an order status change: the replaceable order event built from
+/// `tags` and, with `audit`, the status change event of the audit trail.
+/// Updates keeping the status have no status change event
+fn status_update_events(
+    keys: &Keys,
+    order_updated: &Order,
+    old_status: &str,
+    tags: Option<Tags>,
+    audit: bool,
+) -> Result<(Option<Event>, Option<Event>), MostroError> {
+    let order_event = tags
+        .map(|tags| new_event(keys, "", order_updated.id.to_string(), tags))
+        .transpose()
+        .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?;
+    let status_event = if audit && old_status != order_updated.status {
+        Some(
+            nip33::status_change_event(keys, order_updated.id, old_status, &order_updated.status)
+                .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?,
+        )
+    } else {
+        None
+    };
+
+    Ok((order_event, status_event))
+}
+
 pub async fn update_order_event(
     keys: &Keys,
     status: Status,
@@ -567,31 +673,75 @@ pub async fn update_order_event(
     order_updated.status = status.to_string();
 
     // Include rating tag for pending orders
-    let reputation_data = get_ratings_for_pending_order(&order_updated, status).await?;
+    let (reputation_data, trade_stats) =
+        match get_ratings_for_pending_order(&order_updated, status).await? {
+            Some((reputation_data, trade_stats)) => (Some(reputation_data), trade_stats),
+            None => (None, UserStats::default()),
+        };
 
     // We transform the order fields to tags to use in the event
-    if let Some(tags) = order_to_tags(&order_updated, reputation_data)? {
-        // nip33 kind with order id as identifier and order fields as tags
-        let event = new_event(keys, "", order.id.to_string(), tags)
-            .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?;
-
+    let mut tags = order_to_tags(&order_updated, reputation_data, trade_stats)?;
+    // Orders back to pending are published with their restrictions again
+    if status == Status::Pending {
+        let restrictions = order_restriction_tags(&get_db_pool(), order_updated.id).await?;
+        tags = tags.map(|tags| Tags::from_list(tags.into_iter().chain(restrictions).collect()));
+    }
+    let (order_event, status_event) = status_update_events(
+        keys,
+        &order_updated,
+        &order.status,
+        tags,
+        Settings::get_mostro().status_change_events,
+    )?;
+    if let Some(event) = order_event {
         info!("Sending replaceable event: {event:#?}");
 
         // We update the order with the new event_id
         order_updated.event_id = event.id.to_string();
 
         if let Ok(client) = get_nostr_client() {
-            if client.send_event(&event).await.is_err() {
+            if publish_order_event(client, &event).await.is_err() {
                 tracing::warn!("order id : {} is expired", order_updated.id)
             }
         }
     };
+    if let Some(event) = status_event {
+        if let Ok(client) = get_nostr_client() {
+            if let Err(e) = client.send_event(&event).await {
+                tracing::error!(
+                    "Order Id {}: failed to publish status change: {}",
+                    order_updated.id,
+                    e
+                );
+            }
+        }
+    }
 
     info!(
         "Order Id: {} updated Nostr new Status: {}",
         order.id,
         status.to_string()
     );
+    crate::metrics::record_status_change(status);
+
+    // Keep every event of the order, the replaceable one only shows the last
+    if let Some(pool) = DB_POOL.get() {
+        if let Err(e) = db::add_order_event(
+            pool,
+            order_updated.id,
+            &order_updated.event_id,
+            &order_updated.status,
+            Timestamp::now().as_u64() as i64,
+        )
+        .await
+        {
+            tracing::error!(
+                "Order Id {}: failed to record event history: {}",
+                order_updated.id,
+                e
+            );
+        }
+    }
 
     println!(
         "Inside update_order_event order_updated status {:?} - order id {:?}",
@@ -628,6 +778,24 @@ pub async fn connect_nostr() -> Result<Client, MostroError> {
     Ok(client)
 }
 
+/// Hold invoice expiry of an order, the one asked by the seller clamped to the
+/// operator bounds or the global expiry when unset. A 0 bound is not enforced.
+fn hold_invoice_expiry(requested: Option<u64>, default: u64, min: u64, max: u64) -> u64 {
+    let Some(requested) = requested else {
+        return default;
+    };
+    let requested = if min > 0 {
+        requested.max(min)
+    } else {
+        requested
+    };
+    if max > 0 {
+        requested.min(max)
+    } else {
+        requested
+    }
+}
+
 pub async fn show_hold_invoice(
     my_keys: &Keys,
     payment_request: Option<String>,
@@ -636,12 +804,22 @@ pub async fn show_hold_invoice(
     mut order: Order,
     request_id: Option<u64>,
 ) -> Result<(), MostroError> {
-    let mut ln_client = lightning::LndConnector::new().await?;
+    let mut ln_client = lightning::connect_backend().await?;
     // Add fee of seller to hold invoice
     let new_amount = order.amount + order.fee;
+    let pool = db::connect()
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+    let ln_settings = Settings::get_ln();
+    let expiry_secs = hold_invoice_expiry(
+        db::find_order_invoice_expiry(&pool, order.id).await?,
+        ln_settings.hold_invoice_expiry_secs,
+        ln_settings.min_hold_invoice_expiry_secs,
+        ln_settings.max_hold_invoice_expiry_secs,
+    );
 
     // Now we generate the hold invoice that seller should pay
-    let (invoice_response, preimage, hash) = ln_client
+    let (invoice_payment_request, preimage, hash) = ln_client
         .create_hold_invoice(
             &messages::hold_invoice_description(
                 &order.id.to_string(),
@@ -650,6 +828,7 @@ pub async fn show_hold_invoice(
             )
             .map_err(|e| MostroInternalErr(ServiceError::HoldInvoiceError(e.to_string())))?,
             new_amount,
+            expiry_secs,
         )
         .await
         .map_err(|e| MostroInternalErr(ServiceError::HoldInvoiceError(e.to_string())))?;
@@ -665,9 +844,6 @@ pub async fn show_hold_invoice(
     order.seller_pubkey = Some(seller_pubkey.to_string());
 
     // We need to publish a new event with the new status
-    let pool = db::connect()
-        .await
-        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
     let order_updated = update_order_event(my_keys, Status::WaitingPayment, &order)
         .await
         .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?;
@@ -685,7 +861,7 @@ pub async fn show_hold_invoice(
         Action::PayInvoice,
         Some(Payload::PaymentRequest(
             Some(new_order),
-            invoice_response.payment_request,
+            invoice_payment_request,
             None,
         )),
         *seller_pubkey,
@@ -711,7 +887,7 @@ pub async fn show_hold_invoice(
 
 // Create function to reuse in case of resubscription
 pub async fn invoice_subscribe(hash: Vec<u8>, request_id: Option<u64>) -> Result<(), MostroError> {
-    let mut ln_client_invoices = lightning::LndConnector::new().await?;
+    let mut ln_client_invoices = lightning::connect_backend().await?;
     let (tx, mut rx) = channel(100);
 
     let invoice_task = {
@@ -771,12 +947,465 @@ pub async fn get_market_amount_and_fee(
     Ok((new_sats_amount, fee))
 }
 
+/// Market amount and fee for an order taken at market price, when the maker set
+/// a price band the current price must be inside it
+pub async fn get_order_market_amount_and_fee(
+    pool: &Pool<Sqlite>,
+    order: &Order,
+) -> Result<(i64, i64), MostroError> {
+    match db::find_order_price_band(pool, order.id).await? {
+        Some(band) => {
+            let price = get_bitcoin_price(&order.fiat_code).await?;
+            let amount = band
+                .sats_for(order.fiat_amount, price)
+                .map_err(MostroCantDo)?;
+            info!(
+                "Order Id {}: price {} inside band {}-{}",
+                order.id, price, band.min_price, band.max_price
+            );
+            Ok((amount, get_fee(amount)))
+        }
+        None => get_market_amount_and_fee(order.fiat_amount, &order.fiat_code, order.premium)
+            .await
+            .map_err(|_| MostroInternalErr(ServiceError::WrongAmountError)),
+    }
+}
+
+/// Reset the api quotes of an order going back to pending, the fee policy is
+/// only read for fixed price orders
+pub fn reset_api_quotes(order: &mut Order) {
+    reset_quotes_with(order, || FeePolicy::from_settings(Settings::get_mostro()));
+}
+
+/// Market price orders get a new quote when taken again, fixed price orders
+/// keep their amount with the fee of the current policy
+fn reset_quotes_with(order: &mut Order, policy: impl FnOnce() -> FeePolicy) {
+    if order.price_from_api {
+        order.amount = 0;
+        order.fee = 0;
+    } else {
+        order.fee = policy().split_fee(order.amount);
+    }
+}
+
+/// Tells if a pending order being taken needs a new market quote
+fn needs_market_quote(order: &Order, enforce: bool) -> bool {
+    order.has_no_amount() || (enforce && order.price_from_api)
+}
+
+/// Set amount and fee of a taken order, `quote` is the new market amount if any
+fn set_take_amount_and_fee(
+    order: &mut Order,
+    quote: Option<i64>,
+    policy: &FeePolicy,
+    enforce: bool,
+) {
+    if let Some(amount) = quote {
+        order.amount = amount;
+        order.fee = policy.split_fee(amount);
+    } else if enforce {
+        order.fee = policy.split_fee(order.amount);
+    }
+}
+
+/// Recompute amount and fee of a pending order being (re)taken.
+///
+/// Market price orders get a new quote, price band included, and with
+/// `enforce_fee_recompute` fixed amount orders get their fee computed again
+/// so every pricing mode is handled the same after a republish.
+pub async fn recompute_take_amount_and_fee(
+    pool: &Pool<Sqlite>,
+    order: &mut Order,
+) -> Result<(), MostroError> {
+    let mostro_settings = Settings::get_mostro();
+    let enforce = mostro_settings.enforce_fee_recompute;
+    let quote = if needs_market_quote(order, enforce) {
+        Some(get_order_market_amount_and_fee(pool, order).await?.0)
+    } else {
+        None
+    };
+    set_take_amount_and_fee(
+        order,
+        quote,
+        &FeePolicy::from_settings(mostro_settings),
+        enforce,
+    );
+
+    Ok(())
+}
+
+/// Tells if an order republished `republish_count` times went over the limit
+fn exceeds_republish_cap(republish_count: i64, max_republish_cycles: u32) -> bool {
+    max_republish_cycles > 0 && republish_count > max_republish_cycles as i64
+}
+
+/// Counts a new republish of the order and tells if it went over the configured maximum
+pub async fn republish_limit_reached(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<bool, MostroError> {
+    republish_limit_reached_with(pool, order_id, Settings::get_mostro().max_republish_cycles).await
+}
+
+async fn republish_limit_reached_with(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    max_republish_cycles: u32,
+) -> Result<bool, MostroError> {
+    let count = db::increment_order_republish_count(pool, order_id).await?;
+    Ok(exceeds_republish_cap(count, max_republish_cycles))
+}
+
+/// Cancel an order which reached the republish limit and notify its creator
+pub async fn cancel_republish_capped_order(
+    pool: &SqlitePool,
+    my_keys: &Keys,
+    order: &Order,
+    request_id: Option<u64>,
+) -> Result<(), MostroError> {
+    info!(
+        "Order Id {}: reached max republish cycles, canceling it",
+        order.id
+    );
+    let order_updated = update_order_event(my_keys, Status::Canceled, order)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::NostrError(e.to_string())))?;
+    order_updated
+        .update(pool)
+        .await
+        .map_err(|e| MostroInternalErr(ServiceError::DbAccessError(e.to_string())))?;
+
+    let creator_pubkey = order.get_creator_pubkey().map_err(MostroInternalErr)?;
+    enqueue_order_msg(
+        request_id,
+        Some(order.id),
+        Action::Canceled,
+        None,
+        creator_pubkey,
+        None,
+    )
+    .await;
+
+    Ok(())
+}
+
+/// Update the stats of the solver which resolved a dispute, errors are only logged
+/// as they must not stop the resolution
+pub async fn record_dispute_resolution(pool: &SqlitePool, dispute: &Dispute, buyer_favor: bool) {
+    let Some(solver_pubkey) = dispute.solver_pubkey.as_ref() else {
+        return;
+    };
+    let resolution_seconds = (Timestamp::now().as_u64() as i64 - dispute.taken_at).max(0);
+    let sla = Settings::get_mostro().dispute_sla_seconds;
+    let sla_breached = sla > 0 && resolution_seconds > sla as i64;
+    if let Err(e) = db::add_solver_dispute_resolved(
+        pool,
+        solver_pubkey,
+        resolution_seconds,
+        buyer_favor,
+        sla_breached,
+    )
+    .await
+    {
+        tracing::error!("Failed to update stats of solver {}: {}", solver_pubkey, e);
+    }
+}
+
+/// Take `penalty` points from the rating of the party a dispute was resolved
+/// against, the seller when the buyer is paid and the buyer when the seller
+/// is refunded. Once per dispute, errors are only logged as they must not stop
+/// the resolution
+pub async fn penalize_dispute_loser(
+    pool: &SqlitePool,
+    order: &Order,
+    dispute: &Dispute,
+    buyer_favor: bool,
+    penalty: f64,
+) {
+    if penalty <= 0.0 {
+        return;
+    }
+    let (party, loser) = if buyer_favor {
+        (
+            "seller",
+            order.get_master_seller_pubkey(MOSTRO_DB_PASSWORD.get()),
+        )
+    } else {
+        (
+            "buyer",
+            order.get_master_buyer_pubkey(MOSTRO_DB_PASSWORD.get()),
+        )
+    };
+    let loser = match loser {
+        Ok(loser) => loser,
+        Err(e) => return tracing::error!("Order Id {}: can't penalize the {party}: {e}", order.id),
+    };
+    let reason = format!("Dispute of order {} resolved against the {party}", order.id);
+    match db::add_dispute_penalty(pool, dispute.id, &loser, penalty, &reason).await {
+        Ok(true) => info!("{reason}, {penalty} rating points taken from {loser}"),
+        Ok(false) => {}
+        Err(e) => tracing::error!("Order Id {}: can't penalize the {party}: {e}", order.id),
+    }
+}
+
+/// The node keys must never be a trade party, a crafted message using them
+/// would break the fund flows
+pub fn check_not_node_pubkey(my_keys: &Keys, parties: &[PublicKey]) -> Result<(), MostroError> {
+    let node_pubkey = my_keys.public_key();
+    if parties.contains(&node_pubkey) {
+        tracing::error!(
+            "Rejected message using the node pubkey {} as a trade party",
+            node_pubkey
+        );
+        return Err(MostroCantDo(CantDoReason::InvalidPubkey));
+    }
+    Ok(())
+}
+
+/// Users can have up to `max_active` orders open or being traded, 0 disables
+/// the limit
+async fn check_active_orders_limit_with(
+    pool: &SqlitePool,
+    identity: &PublicKey,
+    max_active: u32,
+) -> Result<(), MostroError> {
+    if max_active == 0 {
+        return Ok(());
+    }
+    let active = db::count_active_orders(pool, &identity.to_string()).await?;
+    if active >= max_active as i64 {
+        info!("User {identity} reached the limit of {max_active} active orders");
+        return Err(MostroCantDo(CantDoReason::PendingOrderExists));
+    }
+    Ok(())
+}
+
+/// Checks the user can create or take one more order
+pub async fn check_active_orders_limit(
+    pool: &SqlitePool,
+    identity: &PublicKey,
+) -> Result<(), MostroError> {
+    check_active_orders_limit_with(
+        pool,
+        identity,
+        Settings::get_mostro().max_active_orders_per_user,
+    )
+    .await
+}
+
+/// True when the taker is the maker, either with the same trade key or with
+/// a new trade key derived from the same identity
+fn is_self_trade(
+    maker_trade_pubkey: &str,
+    maker_identity: Option<&str>,
+    taker: &UnwrappedGift,
+) -> bool {
+    maker_trade_pubkey == taker.rumor.pubkey.to_string()
+        || maker_identity.is_some_and(|identity| identity == taker.sender.to_string())
+}
+
+/// Makers can't take their own orders, wash trades would inflate their
+/// reputation and trade volume
+pub fn check_not_self_trade(order: &Order, event: &UnwrappedGift) -> Result<(), MostroError> {
+    // The maker identity is stored encrypted in the side of the maker
+    let maker_identity = if order.is_sell_order().is_ok() {
+        order.master_seller_pubkey.clone()
+    } else {
+        order.master_buyer_pubkey.clone()
+    }
+    .and_then(|identity| CryptoUtils::decrypt_data(identity, MOSTRO_DB_PASSWORD.get()).ok());
+
+    if is_self_trade(&order.creator_pubkey, maker_identity.as_deref(), event) {
+        info!("Order Id {}: rejected a take from its maker", order.id);
+        return Err(MostroCantDo(CantDoReason::IsNotYourOrder));
+    }
+    Ok(())
+}
+
+/// Max sats a taker with `completed_trades` can take. The schedule lists
+/// `(min completed trades, max sats)` steps, the last step reached applies and
+/// a max of 0 lifts the cap.
+fn taker_trade_cap(completed_trades: i64, schedule: &[(u32, u64)]) -> Option<u64> {
+    schedule
+        .iter()
+        .filter(|(min_trades, _)| completed_trades >= *min_trades as i64)
+        .max_by_key(|(min_trades, _)| *min_trades)
+        .and_then(|(_, max_sats)| (*max_sats > 0).then_some(*max_sats))
+}
+
+/// Checks the taker trade history allows taking `amount` sats. Completed trades
+/// are the ones counted in the trade stats of the identity, takers in privacy
+/// mode have none.
+async fn check_taker_trade_limit_with(
+    pool: &SqlitePool,
+    amount: i64,
+    taker: &PublicKey,
+    schedule: &[(u32, u64)],
+) -> Result<(), MostroError> {
+    if schedule.is_empty() {
+        return Ok(());
+    }
+    let completed_trades = db::get_user_stats(pool, &taker.to_string())
+        .await?
+        .completed_trades;
+    if let Some(cap) = taker_trade_cap(completed_trades, schedule) {
+        if amount > cap as i64 {
+            info!(
+                "Taker {} with {} completed trades can't take {} sats, cap is {}",
+                taker, completed_trades, amount, cap
+            );
+            return Err(MostroCantDo(CantDoReason::OutOfRangeSatsAmount));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the taker identity can take an order of this amount under the
+/// graduated trust schedule of the operator
+pub async fn check_taker_trade_limit(
+    pool: &SqlitePool,
+    order: &Order,
+    taker: &PublicKey,
+) -> Result<(), MostroError> {
+    check_taker_trade_limit_with(
+        pool,
+        order.amount,
+        taker,
+        &Settings::get_mostro().taker_trade_limits,
+    )
+    .await
+}
+
+/// Checks the taker identity has the rating asked by the maker, takers
+/// without ratings are let through unless `deny_unrated` is set. Mostro-core
+/// has no reason for a low reputation, takers refused get `InvalidPeer`.
+async fn check_taker_reputation_with(
+    pool: &SqlitePool,
+    order: &Order,
+    taker: &PublicKey,
+    deny_unrated: bool,
+) -> Result<(), MostroError> {
+    let Some(min_rating) = db::find_order_min_taker_rating(pool, order.id).await? else {
+        return Ok(());
+    };
+    let rating = is_user_present(pool, taker.to_string())
+        .await
+        .ok()
+        .filter(|user| user.total_reviews > 0)
+        .map(|user| user.total_rating);
+    let allowed = match rating {
+        Some(rating) => rating >= min_rating,
+        None => !deny_unrated,
+    };
+    if !allowed {
+        info!(
+            "Order Id {}: taker {} rated {:?} can't take it, minimum is {}",
+            order.id, taker, rating, min_rating
+        );
+        return Err(MostroCantDo(CantDoReason::InvalidPeer));
+    }
+    Ok(())
+}
+
+/// Checks the taker identity has the minimum rating the maker asked for
+pub async fn check_taker_reputation(
+    pool: &SqlitePool,
+    order: &Order,
+    taker: &PublicKey,
+) -> Result<(), MostroError> {
+    check_taker_reputation_with(
+        pool,
+        order,
+        taker,
+        Settings::get_mostro().deny_unrated_takers,
+    )
+    .await
+}
+
+/// Checks the maker was seen in the last `window_secs`, so takers don't commit
+/// funds or invoices to a counterparty that went away. The protocol has no
+/// ping, so the maker is seen when they send any message to Mostro and when
+/// their order was published, at creation or going back to pending.
+async fn check_maker_alive_with(
+    pool: &SqlitePool,
+    order: &Order,
+    window_secs: u64,
+    now: i64,
+) -> Result<(), MostroError> {
+    if window_secs == 0 {
+        return Ok(());
+    }
+    let last_message = crate::db::find_last_seen(pool, &order.creator_pubkey).await?;
+    let published = crate::db::find_status_reached_at(pool, order.id, &Status::Pending.to_string())
+        .await?
+        .unwrap_or(order.created_at);
+    let last_seen = last_message.unwrap_or_default().max(published);
+    if now - last_seen > window_secs as i64 {
+        info!(
+            "Order Id {}: maker not seen in the last {} seconds, take aborted",
+            order.id, window_secs
+        );
+        return Err(MostroCantDo(CantDoReason::InvalidPeer));
+    }
+    Ok(())
+}
+
+pub async fn check_maker_alive(pool: &SqlitePool, order: &Order) -> Result<(), MostroError> {
+    check_maker_alive_with(
+        pool,
+        order,
+        Settings::get_mostro().maker_liveness_window_secs,
+        Timestamp::now().as_u64() as i64,
+    )
+    .await
+}
+
+/// Checks trading in the order currency was not turned off by the operator,
+/// trades already running in it are not affected
+pub async fn check_fiat_code_enabled(
+    pool: &SqlitePool,
+    fiat_code: &str,
+) -> Result<(), MostroError> {
+    if crate::db::is_fiat_code_disabled(pool, fiat_code).await? {
+        info!("Trading in {fiat_code} is disabled by the operator");
+        return Err(MostroCantDo(CantDoReason::InvalidParameters));
+    }
+    Ok(())
+}
+
+/// Fee receipt for the fee collected in a trade, none when receipts are disabled
+fn fee_receipt(keys: &Keys, order: &Order, enabled: bool) -> Option<Event> {
+    // Buyer and seller pay half of the fee each
+    let fee = order.fee * 2;
+    if !enabled || fee <= 0 {
+        return None;
+    }
+    nip33::fee_receipt_event(keys, fee).ok()
+}
+
+/// Publish a public receipt of the fee collected when a hold invoice is settled,
+/// errors are only logged as they must not stop the release
+pub async fn publish_fee_receipt(my_keys: &Keys, order: &Order) {
+    let Some(event) = fee_receipt(my_keys, order, Settings::get_mostro().fee_receipts_enabled)
+    else {
+        return;
+    };
+    match get_nostr_client() {
+        Ok(client) => {
+            if let Err(e) = client.send_event(&event).await {
+                tracing::error!("Failed to publish fee receipt: {}", e);
+            }
+        }
+        Err(e) => tracing::error!("Failed to publish fee receipt: {}", e),
+    }
+}
+
 /// Set order sats amount, this used when a buyer takes a sell order
 pub async fn set_waiting_invoice_status(
     order: &mut Order,
     buyer_pubkey: PublicKey,
     request_id: Option<u64>,
-) -> Result<i64> {
+) -> Result<i64, MostroError> {
     let kind = OrderKind::from_str(&order.kind)
         .map_err(|_| MostroCantDo(CantDoReason::InvalidOrderKind))?;
     let status = Status::WaitingBuyerInvoice;
@@ -828,18 +1457,141 @@ pub async fn set_waiting_invoice_status(
     Ok(order.amount)
 }
 
-/// Send message to buyer and seller to vote for counterpart
-pub async fn rate_counterpart(
-    buyer_pubkey: &PublicKey,
-    seller_pubkey: &PublicKey,
+/// Estimated minutes to settle the fiat payment of an order. Orders can list
+/// several payment methods, the slowest one with a configured average is used.
+pub fn estimate_settlement_minutes(
+    payment_method: &str,
+    averages: &HashMap<String, u32>,
+) -> Option<u32> {
+    payment_method
+        .split(',')
+        .filter_map(|method| {
+            let method = method.trim();
+            averages
+                .iter()
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case(method))
+                .map(|(_, minutes)| *minutes)
+        })
+        .max()
+}
+
+/// Message with the estimated fiat settlement time of an order, `SmallOrder`
+/// has no field for it so it goes as a json text answering the take action
+pub fn settlement_estimate_msg(
     order: &Order,
+    action: Action,
     request_id: Option<u64>,
-) -> Result<()> {
-    // Send dm to counterparts
-    // to buyer
-    enqueue_order_msg(
-        request_id,
-        Some(order.id),
+    trade_index: Option<i64>,
+    averages: &HashMap<String, u32>,
+) -> Option<Message> {
+    let minutes = estimate_settlement_minutes(&order.payment_method, averages)?;
+    let estimate = serde_json::json!({ "settlement_estimate_minutes": minutes });
+
+    Some(Message::new_order(
+        Some(order.id),
+        request_id,
+        trade_index,
+        action,
+        Some(Payload::TextMessage(estimate.to_string())),
+    ))
+}
+
+/// Send the taker the estimated fiat settlement time after the take confirmation
+pub async fn enqueue_settlement_estimate(
+    order: &Order,
+    action: Action,
+    taker_pubkey: PublicKey,
+    trade_index: Option<i64>,
+    request_id: Option<u64>,
+) {
+    let mostro_settings = Settings::get_mostro();
+    if !mostro_settings.settlement_estimates {
+        return;
+    }
+    if let Some(message) = settlement_estimate_msg(
+        order,
+        action,
+        request_id,
+        trade_index,
+        &mostro_settings.payment_method_settlement_minutes,
+    ) {
+        MESSAGE_QUEUES
+            .queue_order_msg
+            .write()
+            .await
+            .push((message, taker_pubkey));
+    }
+}
+
+/// Longest memo a party can attach to an order, in characters
+pub const MAX_MEMO_LENGTH: usize = 280;
+
+/// Checks a memo is not empty nor too long
+pub fn check_memo(memo: &str) -> Result<(), MostroError> {
+    if memo.trim().is_empty() || memo.chars().count() > MAX_MEMO_LENGTH {
+        return Err(MostroCantDo(CantDoReason::InvalidParameters));
+    }
+    Ok(())
+}
+
+/// Memo sent by the maker in the order or by the taker in the take message
+pub fn get_memo(event: &UnwrappedGift, in_order: bool) -> Result<Option<String>, MostroError> {
+    let memo = if in_order {
+        get_order_extension::<String>(event, "memo")
+    } else {
+        get_message_extension::<String>(event, "memo")
+    };
+    if let Some(memo) = &memo {
+        check_memo(memo)?;
+    }
+    Ok(memo)
+}
+
+/// Memos are direct messages about the order, apart from the take answers
+/// so clients handling those get the messages they already know
+fn memo_msg(order_id: Uuid, memo: &str, request_id: Option<u64>) -> Message {
+    Message::new_dm(
+        Some(order_id),
+        request_id,
+        Action::SendDm,
+        Some(Payload::TextMessage(memo.to_string())),
+    )
+}
+
+/// Pass the memos of a taken order to the other party, the memo of the maker
+/// goes to the taker and the one of the taker to the maker
+pub async fn enqueue_memos(
+    pool: &SqlitePool,
+    order: &Order,
+    taker_pubkey: PublicKey,
+    taker_memo: Option<&str>,
+    request_id: Option<u64>,
+) -> Result<(), MostroError> {
+    let mut queue = Vec::new();
+    if let Some(memo) = db::find_order_memo(pool, order.id).await? {
+        queue.push((memo_msg(order.id, &memo, request_id), taker_pubkey));
+    }
+    if let Some(memo) = taker_memo {
+        let maker_pubkey = PublicKey::from_str(&order.creator_pubkey)
+            .map_err(|_| MostroInternalErr(ServiceError::InvalidPubkey))?;
+        queue.push((memo_msg(order.id, memo, None), maker_pubkey));
+    }
+    MESSAGE_QUEUES.queue_order_msg.write().await.extend(queue);
+    Ok(())
+}
+
+/// Send message to buyer and seller to vote for counterpart
+pub async fn rate_counterpart(
+    buyer_pubkey: &PublicKey,
+    seller_pubkey: &PublicKey,
+    order: &Order,
+    request_id: Option<u64>,
+) -> Result<()> {
+    // Send dm to counterparts
+    // to buyer
+    enqueue_order_msg(
+        request_id,
+        Some(order.id),
         Action::Rate,
         None,
         *buyer_pubkey,
@@ -864,7 +1616,7 @@ pub async fn rate_counterpart(
 #[allow(clippy::too_many_arguments)]
 pub async fn settle_seller_hold_invoice(
     event: &UnwrappedGift,
-    ln_client: &mut LndConnector,
+    ln_client: &mut dyn LightningBackend,
     action: Action,
     is_admin: bool,
     order: &Order,
@@ -882,8 +1634,14 @@ pub async fn settle_seller_hold_invoice(
     }
 
     // Settling the hold invoice
+    check_funds_movable("hold invoice settlement")?;
     if let Some(preimage) = order.preimage.as_ref() {
-        ln_client.settle_hold_invoice(preimage).await?;
+        lightning::retry::settle_hold_invoice(
+            ln_client,
+            preimage,
+            &lightning::retry::RetryPolicy::from_settings(),
+        )
+        .await?;
         info!("{action}: Order Id {}: hold invoice settled", order.id);
     } else {
         return Err(MostroCantDo(CantDoReason::InvalidInvoice));
@@ -930,22 +1688,83 @@ pub async fn enqueue_order_msg(
         .push((message, destination_key));
 }
 
-pub fn get_fiat_amount_requested(order: &Order, msg: &Message) -> Option<i64> {
-    // Check if order is range and get amount request after checking boundaries
-    // set order fiat amount to the value requested preparing for hold invoice
-    if order.is_range_order() {
-        if let Some(amount_buyer) = msg.get_inner_message_kind().get_amount() {
-            info!("amount_buyer: {amount_buyer}");
-            match Some(amount_buyer) <= order.max_amount && Some(amount_buyer) >= order.min_amount {
-                true => Some(amount_buyer),
-                false => None,
-            }
-        } else {
-            None
+/// Message about an order built field by field, the action is set when
+/// creating it and the recipient when sending it so they can't be swapped
+#[derive(Debug, Clone)]
+pub struct OrderMsg {
+    order_id: Uuid,
+    action: Action,
+    request_id: Option<u64>,
+    payload: Option<Payload>,
+    trade_index: Option<i64>,
+}
+
+impl OrderMsg {
+    pub fn new(order_id: Uuid, action: Action) -> Self {
+        Self {
+            order_id,
+            action,
+            request_id: None,
+            payload: None,
+            trade_index: None,
         }
-    } else {
-        // If order is not a range order return an Option with fiat amount of the order
-        Some(order.fiat_amount)
+    }
+
+    /// Request id of the message answered, only for its sender
+    pub fn request(mut self, request_id: Option<u64>) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    pub fn payload(mut self, payload: Option<Payload>) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    pub fn trade_index(mut self, trade_index: Option<i64>) -> Self {
+        self.trade_index = trade_index;
+        self
+    }
+
+    pub fn message(&self) -> Message {
+        Message::new_order(
+            Some(self.order_id),
+            self.request_id,
+            self.trade_index,
+            self.action.clone(),
+            self.payload.clone(),
+        )
+    }
+
+    /// Queue the message for `destination_key`
+    pub async fn send_to(self, destination_key: PublicKey) {
+        let message = self.message();
+        MESSAGE_QUEUES
+            .queue_order_msg
+            .write()
+            .await
+            .push((message, destination_key));
+    }
+}
+
+/// Fiat amount a taker trades: the amount picked within the range of a range
+/// order, or the fiat amount of other orders. Picks outside the range are
+/// refused, range orders can't be taken without a pick
+pub fn get_fiat_amount_requested(order: &Order, msg: &Message) -> Result<i64, MostroError> {
+    if !order.is_range_order() {
+        return Ok(order.fiat_amount);
+    }
+    let amount_requested = msg
+        .get_inner_message_kind()
+        .get_amount()
+        .ok_or(MostroCantDo(CantDoReason::InvalidAmount))?;
+    info!(
+        "Order Id {}: fiat amount requested {amount_requested}",
+        order.id
+    );
+    match (order.min_amount, order.max_amount) {
+        (Some(min), Some(max)) if (min..=max).contains(&amount_requested) => Ok(amount_requested),
+        _ => Err(MostroCantDo(CantDoReason::OutOfRangeFiatAmount)),
     }
 }
 
@@ -995,10 +1814,93 @@ pub async fn get_order(msg: &Message, pool: &Pool<Sqlite>) -> Result<Order, Most
     if let Some(order) = order {
         Ok(order)
     } else {
-        Err(MostroInternalErr(ServiceError::InvalidOrderId))
+        // Answered to the sender, otherwise they would think the message was lost
+        tracing::warn!("Order Id {order_id} not found");
+        Err(MostroCantDo(CantDoReason::NotFound))
     }
 }
 
+/// Id of the rumor, computed if the sender did not set it
+pub fn rumor_event_id(event: &UnwrappedGift) -> String {
+    let mut rumor = event.rumor.clone();
+    rumor.ensure_id();
+    rumor.id.map(|id| id.to_hex()).unwrap_or_default()
+}
+
+/// Reads an optional field of the order payload which is not part of `SmallOrder`.
+///
+/// Clients can add extra fields next to the standard ones in the order they send,
+/// they are ignored when the message is parsed so we look for them in the raw
+/// rumor content.
+pub fn get_order_extension<T: serde::de::DeserializeOwned>(
+    event: &UnwrappedGift,
+    field: &str,
+) -> Option<T> {
+    let content: serde_json::Value = serde_json::from_str(&event.rumor.content).ok()?;
+    // Content is a tuple of message and signature, message is wrapped by its kind
+    let message = content.get(0)?.as_object()?.values().next()?;
+    let value = message.get("payload")?.get("order")?.get(field)?;
+    serde_json::from_value(value.clone()).ok()
+}
+
+/// Reads an optional field of the message which is not part of `MessageKind`,
+/// sent next to the payload like the payment method chosen by a taker.
+pub fn get_message_extension<T: serde::de::DeserializeOwned>(
+    event: &UnwrappedGift,
+    field: &str,
+) -> Option<T> {
+    let content: serde_json::Value = serde_json::from_str(&event.rumor.content).ok()?;
+    let message = content.get(0)?.as_object()?.values().next()?;
+    serde_json::from_value(message.get(field)?.clone()).ok()
+}
+
+/// Checks the payment method chosen by the taker is one the maker accepts,
+/// orders without a set of methods can be taken with any
+pub fn check_payment_method_accepted(
+    accepted: &[PaymentMethod],
+    chosen: Option<&str>,
+) -> Result<(), MostroError> {
+    if accepted.is_empty() {
+        return Ok(());
+    }
+    let chosen = chosen
+        .ok_or(MostroCantDo(CantDoReason::InvalidParameters))?
+        .parse::<PaymentMethod>()
+        .map_err(MostroCantDo)?;
+    if !accepted.contains(&chosen) {
+        return Err(MostroCantDo(CantDoReason::InvalidParameters));
+    }
+    Ok(())
+}
+
+/// Tags of the restrictions the maker set on an order, published so takers
+/// know them before taking it
+pub async fn order_restriction_tags(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<Vec<Tag>, MostroError> {
+    let mut tags = Vec::new();
+    let methods = db::find_order_payment_methods(pool, order_id).await?;
+    if !methods.is_empty() {
+        tags.push(nip33::payment_methods_tag(&methods));
+    }
+    if let Some(min_rating) = db::find_order_min_taker_rating(pool, order_id).await? {
+        tags.push(nip33::min_taker_rating_tag(min_rating));
+    }
+    Ok(tags)
+}
+
+/// Checks the payment method declared in a take message against the order
+pub async fn check_take_payment_method(
+    pool: &SqlitePool,
+    order: &Order,
+    event: &UnwrappedGift,
+) -> Result<(), MostroError> {
+    let accepted = db::find_order_payment_methods(pool, order.id).await?;
+    let chosen = get_message_extension::<String>(event, "payment_method");
+    check_payment_method_accepted(&accepted, chosen.as_deref())
+}
+
 pub async fn validate_invoice(msg: &Message, order: &Order) -> Result<Option<String>, MostroError> {
     // init payment request to None
     let mut payment_request = None;
@@ -1023,6 +1925,39 @@ pub async fn validate_invoice(msg: &Message, order: &Order) -> Result<Option<Str
     Ok(payment_request)
 }
 
+/// Count a finished trade in the stats of both parties. Only trades paid to
+/// the buyer count, canceled ones are left out.
+pub async fn record_trade_stats(
+    pool: &SqlitePool,
+    order: &Order,
+    status: Status,
+) -> Result<(), MostroError> {
+    let password = MOSTRO_DB_PASSWORD.get();
+    let pubkeys = [
+        order.get_master_buyer_pubkey(password),
+        order.get_master_seller_pubkey(password),
+    ]
+    .into_iter()
+    .filter_map(Result::ok)
+    .collect::<Vec<_>>();
+    record_trade_stats_for(pool, status, order.amount, &pubkeys).await
+}
+
+async fn record_trade_stats_for(
+    pool: &SqlitePool,
+    status: Status,
+    amount: i64,
+    pubkeys: &[String],
+) -> Result<(), MostroError> {
+    if !matches!(status, Status::Success | Status::CompletedByAdmin) {
+        return Ok(());
+    }
+    for pubkey in pubkeys {
+        db::add_completed_trade(pool, pubkey, amount).await?;
+    }
+    Ok(())
+}
+
 pub async fn notify_taker_reputation(
     pool: &Pool<Sqlite>,
     order: &Order,
@@ -1112,6 +2047,31 @@ mod tests {
     use mostro_core::order::Order;
     use std::sync::Once;
     use uuid::uuid;
+
+    #[test]
+    fn test_order_msg_builder_matches_new_order() {
+        let order_id = Uuid::new_v4();
+        let payload = Some(Payload::TextMessage("reason".to_string()));
+        let built = OrderMsg::new(order_id, Action::Canceled)
+            .request(Some(7))
+            .payload(payload.clone())
+            .trade_index(Some(3))
+            .message();
+        let expected =
+            Message::new_order(Some(order_id), Some(7), Some(3), Action::Canceled, payload);
+        assert_eq!(built.as_json().unwrap(), expected.as_json().unwrap());
+
+        // Unset fields are empty
+        let built = OrderMsg::new(order_id, Action::CooperativeCancelAccepted).message();
+        let expected = Message::new_order(
+            Some(order_id),
+            None,
+            None,
+            Action::CooperativeCancelAccepted,
+            None,
+        );
+        assert_eq!(built.as_json().unwrap(), expected.as_json().unwrap());
+    }
     // Setup function to initialize common settings or data before tests
     static INIT: Once = Once::new();
 
@@ -1121,6 +2081,409 @@ mod tests {
         });
     }
 
+    #[tokio::test]
+    async fn test_only_completed_trades_counted() {
+        let pool = crate::db::test_pool().await;
+        let buyer = Keys::generate().public_key().to_string();
+        let seller = Keys::generate().public_key().to_string();
+        let parties = [buyer.clone(), seller.clone()];
+
+        record_trade_stats_for(&pool, Status::Success, 50_000, &parties)
+            .await
+            .unwrap();
+        record_trade_stats_for(&pool, Status::CooperativelyCanceled, 80_000, &parties)
+            .await
+            .unwrap();
+
+        for pubkey in [&buyer, &seller] {
+            let stats = db::get_user_stats(&pool, pubkey).await.unwrap();
+            assert_eq!(
+                stats,
+                UserStats {
+                    completed_trades: 1,
+                    volume_sats: 50_000
+                }
+            );
+        }
+        let stats = db::get_user_stats(&pool, &Keys::generate().public_key().to_string())
+            .await
+            .unwrap();
+        assert_eq!(stats, UserStats::default());
+    }
+
+    #[test]
+    fn test_settlement_estimate_for_payment_method() {
+        let averages =
+            HashMap::from([("SEPA".to_string(), 1440), ("face to face".to_string(), 60)]);
+        let order = Order {
+            payment_method: "Face to face, sepa".to_string(),
+            ..Default::default()
+        };
+
+        let msg =
+            settlement_estimate_msg(&order, Action::TakeSell, Some(1), Some(2), &averages).unwrap();
+        let kind = msg.get_inner_message_kind();
+        assert_eq!(kind.action, Action::TakeSell);
+        assert_eq!(kind.request_id, Some(1));
+        assert!(matches!(
+            &kind.payload,
+            Some(Payload::TextMessage(text)) if text == r#"{"settlement_estimate_minutes":1440}"#
+        ));
+
+        // No average configured for the method
+        let order = Order {
+            payment_method: "bank transfer".to_string(),
+            ..Default::default()
+        };
+        assert!(settlement_estimate_msg(&order, Action::TakeSell, None, None, &averages).is_none());
+    }
+
+    #[test]
+    fn test_outgoing_messages_carry_operator() {
+        let keys = Keys::generate();
+        let operator = operator_tag(" Mostro Lagos ").unwrap();
+        let rumor = dm_rumor(keys.public_key(), "{}".to_string(), operator);
+        let tag = rumor
+            .tags
+            .iter()
+            .find(|tag| tag.as_slice().first().map(String::as_str) == Some("operator"))
+            .unwrap();
+        assert_eq!(tag.as_slice()[1], "Mostro Lagos");
+
+        // No name configured, no tag
+        let rumor = dm_rumor(
+            keys.public_key(),
+            "{}".to_string(),
+            operator_tag("").unwrap(),
+        );
+        assert!(rumor.tags.is_empty());
+        assert!(operator_tag(&"x".repeat(33)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unresponsive_maker_aborts_take() {
+        let pool = crate::db::test_pool().await;
+        let now = 1_700_000_000;
+        let order = Order {
+            id: Uuid::new_v4(),
+            status: Status::Pending.to_string(),
+            creator_pubkey: Keys::generate().public_key().to_string(),
+            ..Default::default()
+        };
+
+        // Never seen and long gone makers abort the take
+        assert!(matches!(
+            check_maker_alive_with(&pool, &order, 120, now).await,
+            Err(MostroCantDo(CantDoReason::InvalidPeer))
+        ));
+        crate::db::record_seen(&pool, &order.creator_pubkey, now - 3600)
+            .await
+            .unwrap();
+        assert!(check_maker_alive_with(&pool, &order, 120, now)
+            .await
+            .is_err());
+        assert!(check_maker_alive_with(&pool, &order, 0, now).await.is_ok());
+
+        crate::db::record_seen(&pool, &order.creator_pubkey, now - 30)
+            .await
+            .unwrap();
+        assert!(check_maker_alive_with(&pool, &order, 120, now)
+            .await
+            .is_ok());
+
+        // A maker just publishing the order is alive
+        let order = Order {
+            id: Uuid::new_v4(),
+            creator_pubkey: Keys::generate().public_key().to_string(),
+            created_at: now - 60,
+            ..order
+        };
+        assert!(check_maker_alive_with(&pool, &order, 120, now)
+            .await
+            .is_ok());
+        // The order taken and back to pending is published again
+        let order = Order {
+            created_at: now - 3600,
+            ..order
+        };
+        assert!(check_maker_alive_with(&pool, &order, 120, now)
+            .await
+            .is_err());
+        crate::db::add_order_event(&pool, order.id, "abc", "pending", now - 60)
+            .await
+            .unwrap();
+        assert!(check_maker_alive_with(&pool, &order, 120, now)
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn test_hold_invoice_expiry_unset_uses_default() {
+        assert_eq!(hold_invoice_expiry(None, 3600, 600, 7200), 3600);
+        assert_eq!(hold_invoice_expiry(None, 0, 600, 7200), 0);
+    }
+
+    #[test]
+    fn test_hold_invoice_expiry_in_range() {
+        assert_eq!(hold_invoice_expiry(Some(1800), 3600, 600, 7200), 1800);
+        // No bounds configured
+        assert_eq!(hold_invoice_expiry(Some(90_000), 3600, 0, 0), 90_000);
+    }
+
+    #[test]
+    fn test_hold_invoice_expiry_clamped() {
+        assert_eq!(hold_invoice_expiry(Some(60), 3600, 600, 7200), 600);
+        assert_eq!(hold_invoice_expiry(Some(86_400), 3600, 600, 7200), 7200);
+        assert_eq!(hold_invoice_expiry(Some(60), 3600, 0, 7200), 60);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_fiat_code_blocks_trading() {
+        let pool = crate::db::test_pool().await;
+
+        crate::db::set_fiat_code_enabled(&pool, "ves", false, 1_700_000_000)
+            .await
+            .unwrap();
+        assert!(matches!(
+            check_fiat_code_enabled(&pool, "VES").await,
+            Err(MostroCantDo(CantDoReason::InvalidParameters))
+        ));
+        // Other currencies keep trading
+        assert!(check_fiat_code_enabled(&pool, "USD").await.is_ok());
+        assert_eq!(
+            crate::db::find_disabled_fiat_codes(&pool).await.unwrap(),
+            vec!["VES".to_string()]
+        );
+
+        crate::db::set_fiat_code_enabled(&pool, "VES", true, 1_700_000_100)
+            .await
+            .unwrap();
+        assert!(check_fiat_code_enabled(&pool, "VES").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_new_taker_capped_seasoned_not() {
+        let pool = crate::db::test_pool().await;
+        let schedule = [(0, 50_000), (3, 200_000), (10, 0)];
+        let (new_taker, seasoned_taker) = (Keys::generate(), Keys::generate());
+        for _ in 0..10 {
+            crate::db::add_completed_trade(&pool, &seasoned_taker.public_key().to_string(), 1_000)
+                .await
+                .unwrap();
+        }
+        // Ratings received don't count as completed trades
+        sqlx::query("INSERT INTO users (pubkey, total_reviews, created_at) VALUES (?1, 25, 0)")
+            .bind(new_taker.public_key().to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let new_taker = new_taker.public_key();
+        assert!(
+            check_taker_trade_limit_with(&pool, 50_000, &new_taker, &schedule)
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            check_taker_trade_limit_with(&pool, 100_000, &new_taker, &schedule).await,
+            Err(MostroCantDo(CantDoReason::OutOfRangeSatsAmount))
+        ));
+        assert!(check_taker_trade_limit_with(
+            &pool,
+            5_000_000,
+            &seasoned_taker.public_key(),
+            &schedule
+        )
+        .await
+        .is_ok());
+
+        assert_eq!(taker_trade_cap(4, &schedule), Some(200_000));
+        // No schedule configured
+        assert!(
+            check_taker_trade_limit_with(&pool, 5_000_000, &new_taker, &[])
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_memo_length() {
+        assert!(check_memo("SEPA reference 4711").is_ok());
+        assert!(check_memo(&"ñ".repeat(MAX_MEMO_LENGTH)).is_ok());
+        for memo in ["   ".to_string(), "a".repeat(MAX_MEMO_LENGTH + 1)] {
+            assert!(matches!(
+                check_memo(&memo),
+                Err(MostroCantDo(CantDoReason::InvalidParameters))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memos_delivered_to_counterparty() {
+        let pool = crate::db::test_pool().await;
+        let (maker, taker) = (Keys::generate().public_key(), Keys::generate().public_key());
+        let order = Order {
+            id: Uuid::new_v4(),
+            creator_pubkey: maker.to_string(),
+            ..Default::default()
+        };
+        db::add_order_memo(&pool, order.id, "Reference MOSTRO-1")
+            .await
+            .unwrap();
+
+        enqueue_memos(&pool, &order, taker, Some("Paying from Revolut"), None)
+            .await
+            .unwrap();
+
+        let queued = MESSAGE_QUEUES.queue_order_msg.read().await;
+        let memos: Vec<(String, PublicKey)> = queued
+            .iter()
+            .filter(|(msg, _)| msg.get_inner_message_kind().id == Some(order.id))
+            .filter_map(|(msg, pubkey)| {
+                // Direct messages, not another take answer
+                assert!(matches!(msg, Message::Dm(_)));
+                assert_eq!(msg.get_inner_message_kind().action, Action::SendDm);
+                match &msg.get_inner_message_kind().payload {
+                    Some(Payload::TextMessage(memo)) => Some((memo.clone(), *pubkey)),
+                    _ => None,
+                }
+            })
+            .collect();
+        assert_eq!(
+            memos,
+            vec![
+                ("Reference MOSTRO-1".to_string(), taker),
+                ("Paying from Revolut".to_string(), maker),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispute_loser_penalized_once() {
+        use mostro_core::user::User;
+
+        let pool = crate::db::test_pool().await;
+        let (seller, buyer) = (
+            Keys::generate().public_key().to_string(),
+            Keys::generate().public_key().to_string(),
+        );
+        for pubkey in [&seller, &buyer] {
+            db::add_new_user(
+                &pool,
+                User {
+                    pubkey: pubkey.clone(),
+                    total_reviews: 10,
+                    total_rating: 4.5,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        }
+        let order = Order {
+            id: Uuid::new_v4(),
+            master_seller_pubkey: Some(seller.clone()),
+            master_buyer_pubkey: Some(buyer.clone()),
+            ..Default::default()
+        };
+        let rating = |pubkey: &String| {
+            let (pool, pubkey) = (pool.clone(), pubkey.clone());
+            async move { is_user_present(&pool, pubkey).await.unwrap().total_rating }
+        };
+
+        // Settled for the buyer, resolving the same dispute again doesn't
+        // penalize twice
+        let dispute = Dispute::new(order.id, Status::Active.to_string());
+        penalize_dispute_loser(&pool, &order, &dispute, true, 1.5).await;
+        penalize_dispute_loser(&pool, &order, &dispute, true, 1.5).await;
+        assert_eq!(rating(&seller).await, 3.0);
+        assert_eq!(rating(&buyer).await, 4.5);
+        let reason: String =
+            sqlx::query_scalar("SELECT reason FROM dispute_penalties WHERE dispute_id = ?1")
+                .bind(dispute.id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert!(reason.contains("resolved against the seller"));
+
+        // Canceled by the solver, the buyer lost
+        let canceled = Dispute::new(order.id, Status::Active.to_string());
+        penalize_dispute_loser(&pool, &order, &canceled, false, 1.0).await;
+        assert_eq!(rating(&buyer).await, 3.5);
+        assert_eq!(
+            db::find_user_penalties(&pool, &buyer).await.unwrap().len(),
+            1
+        );
+
+        // Ratings don't go below zero
+        let other = Dispute::new(order.id, Status::Active.to_string());
+        penalize_dispute_loser(&pool, &order, &other, true, 5.0).await;
+        assert_eq!(rating(&seller).await, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_taker_reputation() {
+        let pool = crate::db::test_pool().await;
+        let order = Order {
+            id: Uuid::new_v4(),
+            ..Default::default()
+        };
+        db::add_order_min_taker_rating(&pool, order.id, 4.0)
+            .await
+            .unwrap();
+        let (trusted, doubtful, unrated) = (Keys::generate(), Keys::generate(), Keys::generate());
+        for (keys, rating) in [(&trusted, 4.5), (&doubtful, 3.2)] {
+            sqlx::query(
+                "INSERT INTO users (pubkey, total_reviews, total_rating, created_at) VALUES (?1, 8, ?2, 0)",
+            )
+            .bind(keys.public_key().to_string())
+            .bind(rating)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        // Sufficient rating
+        assert!(
+            check_taker_reputation_with(&pool, &order, &trusted.public_key(), true)
+                .await
+                .is_ok()
+        );
+        // Insufficient rating
+        assert!(matches!(
+            check_taker_reputation_with(&pool, &order, &doubtful.public_key(), false).await,
+            Err(MostroCantDo(CantDoReason::InvalidPeer))
+        ));
+        // No history, up to the operator
+        assert!(
+            check_taker_reputation_with(&pool, &order, &unrated.public_key(), false)
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            check_taker_reputation_with(&pool, &order, &unrated.public_key(), true).await,
+            Err(MostroCantDo(CantDoReason::InvalidPeer))
+        ));
+
+        // Orders without a minimum are open to anyone
+        let open_order = Order {
+            id: Uuid::new_v4(),
+            ..Default::default()
+        };
+        assert!(
+            check_taker_reputation_with(&pool, &open_order, &doubtful.public_key(), true)
+                .await
+                .is_ok()
+        );
+
+        // Takers see the minimum in the order event
+        let tags = order_restriction_tags(&pool, order.id).await.unwrap();
+        assert_eq!(
+            tags[0].as_slice(),
+            ["min_taker_rating", "4"].map(String::from)
+        );
+    }
+
     #[test]
     fn test_bytes_to_string() {
         initialize();
@@ -1211,6 +2574,497 @@ mod tests {
             Some(Payload::Amount(order.amount)),
         ));
         let amount = get_fiat_amount_requested(&order, &message);
-        assert_eq!(amount, Some(1000));
+        assert_eq!(amount.unwrap(), 1000);
+
+        let take = |amount: Option<i64>| {
+            Message::Order(MessageKind::new(
+                Some(uuid),
+                Some(1),
+                Some(1),
+                Action::TakeSell,
+                amount.map(Payload::Amount),
+            ))
+        };
+        // At the boundaries
+        assert_eq!(
+            get_fiat_amount_requested(&order, &take(Some(500))).unwrap(),
+            500
+        );
+        assert_eq!(
+            get_fiat_amount_requested(&order, &take(Some(2000))).unwrap(),
+            2000
+        );
+        // Out of the range
+        for amount in [499, 2001, 0, -1000] {
+            assert!(matches!(
+                get_fiat_amount_requested(&order, &take(Some(amount))),
+                Err(MostroCantDo(CantDoReason::OutOfRangeFiatAmount))
+            ));
+        }
+        // Range orders need a pick
+        assert!(matches!(
+            get_fiat_amount_requested(&order, &take(None)),
+            Err(MostroCantDo(CantDoReason::InvalidAmount))
+        ));
+        // Other orders trade their whole fiat amount
+        let order = Order {
+            fiat_amount: 100,
+            ..Default::default()
+        };
+        assert_eq!(
+            get_fiat_amount_requested(&order, &take(Some(50))).unwrap(),
+            100
+        );
+    }
+
+    #[tokio::test]
+    async fn test_order_canceled_after_republish_cap() {
+        crate::config::init_test_settings();
+        let pool = crate::db::test_pool().await;
+        let keys = Keys::generate();
+        let seller = Keys::generate().public_key();
+        let hash = "cd".repeat(32);
+        let max_republish_cycles = 2;
+        let order = Order {
+            id: uuid::Uuid::new_v4(),
+            kind: "sell".to_string(),
+            status: Status::Pending.to_string(),
+            price_from_api: true,
+            creator_pubkey: seller.to_string(),
+            seller_pubkey: Some(seller.to_string()),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+        let take = |order_id: Uuid| {
+            sqlx::query("UPDATE orders SET status = 'waiting-payment', hash = ?1 WHERE id = ?2")
+                .bind(hash.clone())
+                .bind(order_id)
+                .execute(&pool)
+        };
+
+        // Takers abandon the order, the first two times it goes back to the
+        // book and the third one cancels it
+        for cycle in 1..=3 {
+            take(order.id).await.unwrap();
+            let mut abandoned = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+            assert!(
+                crate::flow::reset_expired_hold_invoice_order(&pool, &mut abandoned)
+                    .await
+                    .unwrap()
+            );
+            let capped = republish_limit_reached_with(&pool, order.id, max_republish_cycles)
+                .await
+                .unwrap();
+            assert_eq!(capped, cycle == 3);
+            if capped {
+                cancel_republish_capped_order(&pool, &keys, &abandoned, None)
+                    .await
+                    .unwrap();
+            }
+        }
+        let canceled = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert_eq!(canceled.status, Status::Canceled.to_string());
+        {
+            let queued = crate::config::MESSAGE_QUEUES.queue_order_msg.read().await;
+            let notices = queued
+                .iter()
+                .filter(|(msg, pubkey)| {
+                    let kind = msg.get_inner_message_kind();
+                    *pubkey == seller
+                        && kind.id == Some(order.id)
+                        && matches!(kind.action, Action::Canceled)
+                })
+                .count();
+            assert_eq!(notices, 1);
+        }
+
+        // The canceled order is not republished again nor counted
+        let mut stale = canceled.clone();
+        stale.hash = Some(hash.clone());
+        assert!(
+            !crate::flow::reset_expired_hold_invoice_order(&pool, &mut stale)
+                .await
+                .unwrap()
+        );
+        let order = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert_eq!(order.status, Status::Canceled.to_string());
+        let count: i64 = sqlx::query_scalar(
+            "SELECT republish_count FROM order_republish_counts WHERE order_id = ?1",
+        )
+        .bind(order.id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(count, 3);
+
+        // No limit configured
+        assert!(!exceeds_republish_cap(count, 0));
+    }
+
+    #[test]
+    fn test_fee_recomputed_after_republish() {
+        initialize();
+        let policy = FeePolicy {
+            rate: 0.006,
+            ..Default::default()
+        };
+
+        // Fixed price: amount is kept, a stale fee is recomputed on republish
+        let mut order = Order {
+            amount: 100_000,
+            fee: 999,
+            price_from_api: false,
+            ..Default::default()
+        };
+        reset_quotes_with(&mut order, || policy);
+        assert_eq!((order.amount, order.fee), (100_000, 300));
+        assert!(!needs_market_quote(&order, true));
+        set_take_amount_and_fee(&mut order, None, &policy, true);
+        assert_eq!((order.amount, order.fee), (100_000, 300));
+
+        // Market price: republish clears the quote, the new one sets the fee
+        let mut order = Order {
+            amount: 50_000,
+            fee: 150,
+            price_from_api: true,
+            ..Default::default()
+        };
+        reset_quotes_with(&mut order, || policy);
+        assert_eq!((order.amount, order.fee), (0, 0));
+        assert!(needs_market_quote(&order, false));
+        set_take_amount_and_fee(&mut order, Some(80_000), &policy, false);
+        assert_eq!((order.amount, order.fee), (80_000, 240));
+
+        // Market price with a quote left over is quoted again when enforced
+        assert!(!needs_market_quote(&order, false));
+        assert!(needs_market_quote(&order, true));
+        set_take_amount_and_fee(&mut order, Some(60_000), &policy, true);
+        assert_eq!((order.amount, order.fee), (60_000, 180));
+
+        // Price band: the band amount gets the fee of its own amount
+        let band = crate::models::PriceBand {
+            min_price: 90_000.0,
+            max_price: 110_000.0,
+        };
+        let mut order = Order {
+            fiat_amount: 100,
+            price_from_api: true,
+            ..Default::default()
+        };
+        reset_quotes_with(&mut order, || policy);
+        let quote = band.sats_for(order.fiat_amount, 100_000.0).unwrap();
+        set_take_amount_and_fee(&mut order, Some(quote), &policy, true);
+        assert_eq!(order.amount, quote);
+        assert_eq!(order.fee, policy.split_fee(quote));
+    }
+
+    #[test]
+    fn test_fee_policy_amounts() {
+        // Percentage only, as before the policy
+        let policy = FeePolicy {
+            rate: 0.006,
+            ..Default::default()
+        };
+        assert_eq!(policy.split_fee(100_000), 300);
+        assert_eq!(policy.split_fee(0), 0);
+
+        // Percentage plus flat, bounded by min and max
+        let policy = FeePolicy {
+            rate: 0.01,
+            flat_sats: 100,
+            min_sats: 500,
+            max_sats: 20_000,
+        };
+        // 10 + 100 is raised to the min
+        assert_eq!(policy.split_fee(1_000), 250);
+        // 1_000 + 100 is in range
+        assert_eq!(policy.split_fee(100_000), 550);
+        // 50_000 + 100 is capped
+        assert_eq!(policy.split_fee(5_000_000), 10_000);
+        // No amount yet, no fee even with a min
+        assert_eq!(policy.split_fee(0), 0);
+    }
+
+    #[test]
+    fn test_status_change_publishes_audit_event() {
+        let keys = Keys::generate();
+        let order = Order {
+            id: Uuid::new_v4(),
+            status: Status::FiatSent.to_string(),
+            ..Default::default()
+        };
+        let tags = || {
+            Some(Tags::from_list(vec![Tag::custom(
+                TagKind::Custom(std::borrow::Cow::Borrowed("s")),
+                vec![order.status.clone()],
+            )]))
+        };
+
+        let (order_event, status_event) =
+            status_update_events(&keys, &order, &Status::Active.to_string(), tags(), true).unwrap();
+        assert_eq!(
+            order_event.unwrap().kind,
+            nostr_sdk::Kind::Custom(NOSTR_REPLACEABLE_EVENT_KIND)
+        );
+        let status_event = status_event.unwrap();
+        assert_eq!(
+            status_event.kind,
+            nostr_sdk::Kind::Custom(nip33::STATUS_CHANGE_EVENT_KIND)
+        );
+        let tag = |name: &str| {
+            status_event
+                .tags
+                .iter()
+                .find(|tag| tag.as_slice()[0] == name)
+                .map(|tag| tag.as_slice()[1].clone())
+        };
+        assert_eq!(tag("order"), Some(order.id.to_string()));
+        assert_eq!(tag("from"), Some(Status::Active.to_string()));
+        assert_eq!(tag("s"), Some(Status::FiatSent.to_string()));
+
+        // Disabled, or the status didn't change
+        let (_, status_event) =
+            status_update_events(&keys, &order, &Status::Active.to_string(), tags(), false)
+                .unwrap();
+        assert!(status_event.is_none());
+        let (_, status_event) =
+            status_update_events(&keys, &order, &order.status, tags(), true).unwrap();
+        assert!(status_event.is_none());
+    }
+
+    #[test]
+    fn test_node_pubkey_rejected_as_party() {
+        initialize();
+        let node_keys = Keys::generate();
+        let user_keys = Keys::generate();
+        // Crafted new order message signed with the node keys
+        let event = UnwrappedGift {
+            sender: node_keys.public_key(),
+            rumor: UnsignedEvent::new(
+                user_keys.public_key(),
+                Timestamp::now(),
+                nostr_sdk::Kind::GiftWrap,
+                Vec::new(),
+                "",
+            ),
+        };
+
+        let result = check_not_node_pubkey(&node_keys, &[event.sender, event.rumor.pubkey]);
+        assert!(matches!(
+            result,
+            Err(MostroCantDo(CantDoReason::InvalidPubkey))
+        ));
+        assert!(check_not_node_pubkey(&node_keys, &[user_keys.public_key()]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_active_orders_limit() {
+        let pool = crate::db::test_pool().await;
+        let user = Keys::generate().public_key();
+        let max_active = 3;
+
+        // Orders taken as buyer and created as seller both count
+        for (kind, status) in [
+            ("sell", Status::Active),
+            ("buy", Status::Pending),
+            ("sell", Status::FiatSent),
+        ] {
+            assert!(check_active_orders_limit_with(&pool, &user, max_active)
+                .await
+                .is_ok());
+            let (buyer, seller) = if kind == "sell" {
+                (Some(user.to_string()), None)
+            } else {
+                (None, Some(user.to_string()))
+            };
+            Order {
+                id: Uuid::new_v4(),
+                kind: kind.to_string(),
+                status: status.to_string(),
+                master_buyer_pubkey: buyer,
+                master_seller_pubkey: seller,
+                ..Default::default()
+            }
+            .create(&pool)
+            .await
+            .unwrap();
+        }
+
+        // The next take is rejected
+        assert!(matches!(
+            check_active_orders_limit_with(&pool, &user, max_active).await,
+            Err(MostroCantDo(CantDoReason::PendingOrderExists))
+        ));
+        // Other users and finished orders are not counted
+        let other = Keys::generate().public_key();
+        assert!(check_active_orders_limit_with(&pool, &other, max_active)
+            .await
+            .is_ok());
+        assert!(check_active_orders_limit_with(&pool, &user, 0)
+            .await
+            .is_ok());
+        assert_eq!(
+            db::count_active_orders(&pool, &user.to_string())
+                .await
+                .unwrap(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_self_trade_detected() {
+        let maker = Keys::generate();
+        let maker_trade_key = Keys::generate();
+        let taker_trade_key = Keys::generate();
+        let gift = |identity: &Keys, trade_key: &Keys| UnwrappedGift {
+            sender: identity.public_key(),
+            rumor: UnsignedEvent::new(
+                trade_key.public_key(),
+                Timestamp::now(),
+                nostr_sdk::Kind::GiftWrap,
+                Vec::new(),
+                "",
+            ),
+        };
+        let creator = maker_trade_key.public_key().to_string();
+        let identity = maker.public_key().to_string();
+
+        // Same trade key as the maker
+        let event = gift(&taker_trade_key, &maker_trade_key);
+        assert!(is_self_trade(&creator, None, &event));
+        // New trade key from the maker identity
+        let event = gift(&maker, &taker_trade_key);
+        assert!(is_self_trade(&creator, Some(&identity), &event));
+        // Someone else
+        let event = gift(&taker_trade_key, &taker_trade_key);
+        assert!(!is_self_trade(&creator, Some(&identity), &event));
+    }
+
+    #[test]
+    fn test_fee_receipt_published_when_enabled() {
+        initialize();
+        let keys = Keys::generate();
+        let order = Order {
+            fee: 300,
+            ..Default::default()
+        };
+
+        let event = fee_receipt(&keys, &order, true).unwrap();
+        assert_eq!(
+            event.kind,
+            nostr_sdk::Kind::Custom(nip33::FEE_RECEIPT_EVENT_KIND)
+        );
+        assert!(event.content.is_empty());
+        let tags: Vec<Vec<String>> = event.tags.iter().map(|t| t.clone().to_vec()).collect();
+        // 600 sats collected are only shown as a bucket
+        assert!(tags.contains(&vec![
+            "amount_bucket".to_string(),
+            "100".to_string(),
+            "1000".to_string()
+        ]));
+        assert!(tags.iter().all(|t| t[0] != "p" && t[0] != "d"));
+        assert_eq!(event.created_at.as_u64() % 3600, 0);
+
+        // Nothing is published with the flag off or without fee
+        assert!(fee_receipt(&keys, &order, false).is_none());
+        assert!(fee_receipt(&keys, &Order::default(), true).is_none());
+    }
+
+    #[test]
+    fn test_get_order_extension() {
+        initialize();
+        let keys = Keys::generate();
+        let content = r#"[{"order":{"version":1,"id":null,"action":"new-order","payload":{"order":{"kind":"sell","amount":0,"fiat_code":"VES","fiat_amount":100,"payment_method":"face to face","premium":0,"price_band":{"min_price":90000.0,"max_price":100000.0}}}}},null]"#;
+        let event = UnwrappedGift {
+            sender: keys.public_key(),
+            rumor: UnsignedEvent::new(
+                keys.public_key(),
+                Timestamp::now(),
+                nostr_sdk::Kind::GiftWrap,
+                Vec::new(),
+                content,
+            ),
+        };
+        let band: Option<crate::models::PriceBand> = get_order_extension(&event, "price_band");
+        assert_eq!(
+            band,
+            Some(crate::models::PriceBand {
+                min_price: 90_000.0,
+                max_price: 100_000.0
+            })
+        );
+        let missing: Option<String> = get_order_extension(&event, "memo");
+        assert!(missing.is_none());
+    }
+
+    fn take_event(keys: &Keys, payment_method: Option<&str>) -> UnwrappedGift {
+        let extension = payment_method
+            .map(|method| format!(r#","payment_method":"{method}""#))
+            .unwrap_or_default();
+        let content = format!(
+            r#"[{{"order":{{"version":1,"id":null,"action":"take-sell","payload":null{extension}}}}},null]"#
+        );
+        UnwrappedGift {
+            sender: keys.public_key(),
+            rumor: UnsignedEvent::new(
+                keys.public_key(),
+                Timestamp::now(),
+                nostr_sdk::Kind::GiftWrap,
+                Vec::new(),
+                content,
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_take_payment_method() {
+        let pool = crate::db::test_pool().await;
+        let keys = Keys::generate();
+        let order = Order {
+            id: Uuid::new_v4(),
+            ..Default::default()
+        };
+        db::add_order_payment_methods(&pool, order.id, &[PaymentMethod::Sepa, PaymentMethod::Cash])
+            .await
+            .unwrap();
+
+        // Matching method, case doesn't matter
+        let event = take_event(&keys, Some("SEPA"));
+        assert!(check_take_payment_method(&pool, &order, &event)
+            .await
+            .is_ok());
+
+        // A method the maker doesn't accept, or none at all
+        for method in [Some("paypal"), Some("wire"), None] {
+            let event = take_event(&keys, method);
+            assert!(matches!(
+                check_take_payment_method(&pool, &order, &event).await,
+                Err(MostroCantDo(CantDoReason::InvalidParameters))
+            ));
+        }
+
+        // Orders without methods are taken with any
+        let open_order = Order {
+            id: Uuid::new_v4(),
+            ..Default::default()
+        };
+        let event = take_event(&keys, None);
+        assert!(check_take_payment_method(&pool, &open_order, &event)
+            .await
+            .is_ok());
+
+        // Both orders publish what they accept
+        let tags = order_restriction_tags(&pool, order.id).await.unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(
+            tags[0].as_slice(),
+            ["payment_methods", "sepa", "cash"].map(String::from)
+        );
+        assert!(order_restriction_tags(&pool, open_order.id)
+            .await
+            .unwrap()
+            .is_empty());
     }
 }
@@ -0,0 +1,224 @@
+//! Bounded pool of workers handling incoming messages.
+//!
+//! Messages of different orders are handled at the same time, up to the pool
+//! size. Each handler takes its own lightning connector from a
+//! [`crate::lightning::BackendPool`] of the same size, so a slow lightning call
+//! only delays the messages of its own order.
+//! Messages of the same order are queued and handled one after another in
+//! arrival order, the actions of an order never race each other.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Notify, Semaphore};
+use uuid::Uuid;
+
+type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Pending jobs of each key with a job running
+type Lanes = Arc<Mutex<HashMap<Uuid, VecDeque<Job>>>>;
+
+#[derive(Clone)]
+pub struct WorkerPool {
+    permits: Arc<Semaphore>,
+    lanes: Lanes,
+    /// Jobs dispatched and not finished yet
+    pending: Arc<AtomicUsize>,
+    finished: Arc<Notify>,
+}
+
+impl WorkerPool {
+    /// Pool running up to `size` jobs at once, at least one
+    pub fn new(size: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(size.max(1))),
+            lanes: Arc::new(Mutex::new(HashMap::new())),
+            pending: Arc::new(AtomicUsize::new(0)),
+            finished: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Run `job` on a free worker. Jobs with the same key run in the order
+    /// they were dispatched, jobs without a key have no ordering
+    pub fn dispatch<F>(&self, key: Option<Uuid>, job: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let (pending, finished) = (self.pending.clone(), self.finished.clone());
+        let job: Job = Box::pin(async move {
+            job.await;
+            pending.fetch_sub(1, Ordering::SeqCst);
+            finished.notify_waiters();
+        });
+        let permits = self.permits.clone();
+        let Some(key) = key else {
+            tokio::spawn(run_job(permits, job));
+            return;
+        };
+
+        // A running lane takes the job after the ones already queued
+        {
+            let mut lanes = match self.lanes.lock() {
+                Ok(lanes) => lanes,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            if let Some(queue) = lanes.get_mut(&key) {
+                queue.push_back(job);
+                return;
+            }
+            lanes.insert(key, VecDeque::new());
+        }
+
+        let lanes = self.lanes.clone();
+        tokio::spawn(async move {
+            let mut next = Some(job);
+            while let Some(job) = next {
+                run_job(permits.clone(), job).await;
+                next = next_job(&lanes, key);
+            }
+        });
+    }
+
+    /// Waits until every job dispatched is finished
+    pub async fn idle(&self) {
+        loop {
+            // Created before the check so a job finishing in between wakes it
+            let finished = self.finished.notified();
+            if self.pending.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            finished.await;
+        }
+    }
+}
+
+async fn run_job(permits: Arc<Semaphore>, job: Job) {
+    // The semaphore is never closed
+    let Ok(_permit) = permits.acquire_owned().await else {
+        return;
+    };
+    job.await;
+}
+
+/// Next job of a lane, the lane is closed once it has none left
+fn next_job(lanes: &Lanes, key: Uuid) -> Option<Job> {
+    let mut lanes = match lanes.lock() {
+        Ok(lanes) => lanes,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let next = lanes.get_mut(&key).and_then(|queue| queue.pop_front());
+    if next.is_none() {
+        lanes.remove(&key);
+    }
+    next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::Barrier;
+
+    #[tokio::test]
+    async fn test_orders_run_concurrently() {
+        let pool = WorkerPool::new(2);
+        // Each job waits for the other one, they only finish if both run at once
+        let barrier = Arc::new(Barrier::new(2));
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        for _ in 0..2 {
+            let barrier = barrier.clone();
+            let tx = tx.clone();
+            pool.dispatch(Some(Uuid::new_v4()), async move {
+                barrier.wait().await;
+                let _ = tx.send(()).await;
+            });
+        }
+
+        for _ in 0..2 {
+            let done = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await;
+            assert!(done.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_same_order_serialized() {
+        let pool = WorkerPool::new(4);
+        let order_id = Uuid::new_v4();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for step in 0..3u64 {
+            let log = log.clone();
+            pool.dispatch(Some(order_id), async move {
+                log.lock().unwrap().push(format!("start {step}"));
+                // Earlier jobs take longer, they would finish last if not serialized
+                tokio::time::sleep(Duration::from_millis(30 * (3 - step))).await;
+                log.lock().unwrap().push(format!("end {step}"));
+            });
+        }
+        // Another order is not queued behind them
+        let other = log.clone();
+        pool.dispatch(Some(Uuid::new_v4()), async move {
+            other.lock().unwrap().push("other".to_string());
+        });
+
+        pool.idle().await;
+
+        let log = log.lock().unwrap().clone();
+        let order_log: Vec<&str> = log
+            .iter()
+            .map(String::as_str)
+            .filter(|entry| *entry != "other")
+            .collect();
+        assert_eq!(
+            order_log,
+            ["start 0", "end 0", "start 1", "end 1", "start 2", "end 2"]
+        );
+        // The other order ran while the first job was still running
+        assert!(
+            log.iter().position(|entry| entry == "other")
+                < log.iter().position(|entry| entry == "end 0")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_node_call_not_blocking_other_order() {
+        use crate::lightning::mock::MockBackend;
+        use crate::lightning::{BackendPool, LightningBackend};
+
+        let pool = WorkerPool::new(2);
+        let backends: Vec<Box<dyn LightningBackend>> = (0..2)
+            .map(|_| {
+                Box::new(MockBackend {
+                    payment_delay: Duration::from_millis(500),
+                    ..Default::default()
+                }) as Box<dyn LightningBackend>
+            })
+            .collect();
+        let ln_client = Arc::new(BackendPool::new(backends));
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        // The first order waits for a slow payment
+        let (slow_client, slow_log) = (ln_client.clone(), log.clone());
+        pool.dispatch(Some(Uuid::new_v4()), async move {
+            let (tx, _rx) = tokio::sync::mpsc::channel(1);
+            let _ = slow_client
+                .get()
+                .await
+                .send_payment("lnbc-slow", 1_000, tx)
+                .await;
+            slow_log.lock().unwrap().push("slow");
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // The second one talks to the node meanwhile
+        let (other_client, other_log) = (ln_client.clone(), log.clone());
+        pool.dispatch(Some(Uuid::new_v4()), async move {
+            let _ = other_client.get().await.cancel_hold_invoice("ab").await;
+            other_log.lock().unwrap().push("other");
+        });
+
+        pool.idle().await;
+        assert_eq!(*log.lock().unwrap(), ["other", "slow"]);
+    }
+}